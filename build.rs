@@ -0,0 +1,245 @@
+//! Hashes every file in `assets/` for `src/asset_manifest.rs`. With the `prebaked-atlas` feature,
+//! also packs the images in `assets/` into one atlas PNG ahead of time and generates
+//! `TextureRect` constants for them, see `src/prebaked_atlas.rs`. Without it the game packs its
+//! images at startup.
+
+#[path = "src/atlas_packer.rs"]
+#[allow(dead_code)]
+mod atlas_packer;
+
+fn main() -> Result<(), anyhow::Error> {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/atlas_packer.rs");
+    asset_manifest::bake()?;
+    #[cfg(feature = "prebaked-atlas")]
+    bake_atlas::bake()?;
+    Ok(())
+}
+
+mod asset_manifest {
+    use std::{
+        env,
+        fmt::Write as _,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use anyhow::{Context, Error};
+
+    use crate::atlas_packer::stable_hash;
+
+    pub fn bake() -> Result<(), Error> {
+        println!("cargo:rerun-if-changed=assets");
+        let out_dir = PathBuf::from(env::var_os("OUT_DIR").context("OUT_DIR is not set")?);
+
+        let mut files = Vec::new();
+        list_files(Path::new("assets"), "", &mut files)?;
+        files.sort();
+
+        let mut module = String::new();
+        writeln!(module, "// Generated by build.rs from assets/, do not edit")?;
+        writeln!(module)?;
+        writeln!(module, "pub const ASSET_HASHES: &[(&str, u64)] = &[")?;
+        for (relative, path) in &files {
+            let bytes =
+                fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+            writeln!(
+                module,
+                "    ({:?}, {:#018x}),",
+                relative,
+                stable_hash(&bytes)
+            )?;
+        }
+        writeln!(module, "];")?;
+        fs::write(out_dir.join("asset_manifest.rs"), module)?;
+        Ok(())
+    }
+
+    // Every file under `dir` with its path from the assets folder, `/`-separated and starting
+    // with `prefix`
+    fn list_files(dir: &Path, prefix: &str, out: &mut Vec<(String, PathBuf)>) -> Result<(), Error> {
+        for entry in
+            fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?
+        {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                list_files(&path, &format!("{}{}/", prefix, name), out)?;
+            } else {
+                out.push((format!("{}{}", prefix, name), path));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "prebaked-atlas")]
+mod bake_atlas {
+    use std::{
+        env,
+        ffi::OsStr,
+        fmt::Write as _,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use anyhow::{bail, Context, Error};
+
+    use crate::atlas_packer::{packing_order, stable_hash, AtlasPacker};
+
+    // These have to match `graphics::TEXTURE_ATLAS_SIZE` and the padding in game.rs, the game
+    // checks that they do when it loads the atlas
+    const ATLAS_SIZE: (u32, u32) = (1024, 1024);
+    const ATLAS_PADDING: u32 = 1;
+    // Images the atlas may store on their side, like the ones the game loads rotatable
+    const ROTATABLE: &[&str] = &["player"];
+
+    struct Image {
+        name: String,
+        pixels: Vec<u8>,
+        size: (u32, u32),
+    }
+
+    pub fn bake() -> Result<(), Error> {
+        println!("cargo:rerun-if-changed=assets");
+        let out_dir = PathBuf::from(env::var_os("OUT_DIR").context("OUT_DIR is not set")?);
+
+        let mut images = load_images(Path::new("assets"))?;
+        // for solid shapes, see `graphics::render_rect_filled`
+        images.push(Image {
+            name: "white".to_owned(),
+            pixels: vec![255; 4],
+            size: (1, 1),
+        });
+
+        let mut packer = AtlasPacker::new(ATLAS_SIZE, ATLAS_PADDING);
+        let sizes: Vec<(u32, u32)> = images.iter().map(|image| image.size).collect();
+        let mut placed = vec![None; images.len()];
+        for i in packing_order(&sizes) {
+            let Image { name, size, .. } = &images[i];
+            let rotated = ROTATABLE.contains(&name.as_str()) && packer.prefers_rotated(*size, 0);
+            let placed_size = if rotated { (size.1, size.0) } else { *size };
+            let texels = packer.place(placed_size, 0).with_context(|| {
+                format!(
+                    "No space for {} at {}x{} in the prebaked atlas",
+                    name, size.0, size.1
+                )
+            })?;
+            placed[i] = Some((texels, rotated));
+        }
+        let placed: Vec<([u32; 4], bool)> = placed.into_iter().map(Option::unwrap).collect();
+
+        // only as tall as the images reach, the game packs its own images below them
+        let height = placed
+            .iter()
+            .map(|(texels, _)| texels[3])
+            .max()
+            .unwrap_or(0);
+        let mut pixels = vec![0; (ATLAS_SIZE.0 * height) as usize * 4];
+        for (image, (texels, rotated)) in images.iter().zip(&placed) {
+            let (width, image_height) = image.size;
+            for y in 0..image_height {
+                for x in 0..width {
+                    // turned clockwise, the top row runs down the right edge
+                    let (atlas_x, atlas_y) = if *rotated {
+                        (texels[0] + image_height - 1 - y, texels[1] + x)
+                    } else {
+                        (texels[0] + x, texels[1] + y)
+                    };
+                    let from = (y * width + x) as usize * 4;
+                    let to = (atlas_y * ATLAS_SIZE.0 + atlas_x) as usize * 4;
+                    pixels[to..to + 4].copy_from_slice(&image.pixels[from..from + 4]);
+                }
+            }
+        }
+        let png_path = out_dir.join("prebaked_atlas.png");
+        image::save_buffer(
+            &png_path,
+            &pixels,
+            ATLAS_SIZE.0,
+            height,
+            image::ColorType::Rgba8,
+        )
+        .context("Could not save the prebaked atlas")?;
+        let png = fs::read(&png_path)?;
+
+        let mut module = String::new();
+        writeln!(module, "// Generated by build.rs from assets/, do not edit")?;
+        writeln!(module)?;
+        writeln!(
+            module,
+            "pub const ATLAS_SIZE: (u32, u32) = {:?};",
+            ATLAS_SIZE
+        )?;
+        writeln!(module, "pub const ATLAS_PADDING: u32 = {};", ATLAS_PADDING)?;
+        writeln!(
+            module,
+            "pub const PNG_HASH: u64 = {:#018x};",
+            stable_hash(&png)
+        )?;
+        for (image, (texels, rotated)) in images.iter().zip(&placed) {
+            writeln!(
+                module,
+                "pub const {}: TextureRect = TextureRect {{ texels: {:?}, atlas_size: ATLAS_SIZE, \
+                 rotated: {} }};",
+                image.name.to_uppercase(),
+                texels,
+                rotated
+            )?;
+        }
+        writeln!(module, "pub const TEXTURES: &[(&str, TextureRect)] = &[")?;
+        for image in &images {
+            writeln!(
+                module,
+                "    ({:?}, {}),",
+                image.name,
+                image.name.to_uppercase()
+            )?;
+        }
+        writeln!(module, "];")?;
+        fs::write(out_dir.join("prebaked_atlas.rs"), module)?;
+        Ok(())
+    }
+
+    // Every PNG right in `dir` as RGBA, named by its file name without the extension and sorted by
+    // name so the layout only changes when the images do
+    fn load_images(dir: &Path) -> Result<Vec<Image>, Error> {
+        let mut paths = Vec::new();
+        for entry in
+            fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("png")) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut images = Vec::with_capacity(paths.len());
+        for path in paths {
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) if is_identifier(name) => name.to_owned(),
+                _ => bail!(
+                    "{} needs a name that works as a Rust identifier to be prebaked",
+                    path.display()
+                ),
+            };
+            let image = image::open(&path)
+                .with_context(|| format!("Could not decode {}", path.display()))?
+                .to_rgba();
+            let size = image.dimensions();
+            images.push(Image {
+                name,
+                pixels: image.into_raw(),
+                size,
+            });
+        }
+        Ok(images)
+    }
+
+    fn is_identifier(name: &str) -> bool {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+}
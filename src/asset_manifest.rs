@@ -0,0 +1,63 @@
+//! Hashes of every file in `assets/` as `build.rs` found them when the game was built, so loose
+//! files read at runtime can be told apart from the ones built in.
+
+use crate::atlas_packer::stable_hash;
+
+include!(concat!(env!("OUT_DIR"), "/asset_manifest.rs"));
+
+/// How a loose file compares to the one the game was built with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AssetCheck {
+    Matches,
+    Changed,
+    /// Not in the assets folder when the game was built.
+    Unknown,
+}
+
+/// Checks `bytes` against the manifest entry for `relative`, a path in the assets folder split
+/// by either separator.
+pub fn check(relative: &str, bytes: &[u8]) -> AssetCheck {
+    check_in(ASSET_HASHES, relative, bytes)
+}
+
+fn check_in(manifest: &[(&str, u64)], relative: &str, bytes: &[u8]) -> AssetCheck {
+    let relative = relative.replace('\\', "/");
+    match manifest.iter().find(|(path, _)| *path == relative) {
+        Some(&(_, hash)) if hash == stable_hash(bytes) => AssetCheck::Matches,
+        Some(_) => AssetCheck::Changed,
+        None => AssetCheck::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_never_change() {
+        // FNV-1a's published test values, so manifests from other builds stay comparable
+        assert_eq!(stable_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn the_manifest_matches_the_built_in_assets() {
+        let blue = include_bytes!("../assets/rooms/blue.rum");
+        assert_eq!(check("rooms/blue.rum", blue), AssetCheck::Matches);
+        assert_eq!(check("rooms\\blue.rum", blue), AssetCheck::Matches);
+        assert_eq!(
+            check("player.png", include_bytes!("../assets/player.png")),
+            AssetCheck::Matches
+        );
+    }
+
+    #[test]
+    fn edited_and_unknown_files_are_told_apart() {
+        let manifest = [("a.rum", stable_hash(b"a")), ("b.rum", stable_hash(b"b"))];
+        assert_eq!(check_in(&manifest, "a.rum", b"a"), AssetCheck::Matches);
+        assert_eq!(check_in(&manifest, "b.rum", b"a"), AssetCheck::Changed);
+        assert_eq!(check_in(&manifest, "c.rum", b"a"), AssetCheck::Unknown);
+        assert_eq!(check_in(&[], "a.rum", b"a"), AssetCheck::Unknown);
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    borrow::Cow,
+    env,
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::asset_manifest::{self, AssetCheck};
+
+const ASSETS_ENV_VAR: &str = "LD48_ASSETS";
+const ASSETS_DIR_NAME: &str = "assets";
+// How many parent directories of the executable and working directory to search, so that
+// `cargo run` from inside the workspace still finds the assets
+const MAX_PARENT_LEVELS: usize = 3;
+// A directory is only accepted as the asset root if all of these exist inside it
+const MARKER_FILES: &[&str] = &["player.png", "block.png", "rooms/blue.rum"];
+
+/// The directory loose asset files are read from at runtime. All access to loose files should go
+/// through this so that running from the wrong working directory gives a useful message.
+pub struct AssetRoot {
+    root: PathBuf,
+}
+
+impl AssetRoot {
+    /// Searches, in order, the `LD48_ASSETS` environment variable, the executable's directory and
+    /// the current working directory (each with up to 3 parents) for the assets folder.
+    pub fn locate() -> Result<AssetRoot, AssetRootNotFound> {
+        let exe_dir = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf));
+        AssetRoot::locate_in(candidate_dirs(
+            env::var_os(ASSETS_ENV_VAR),
+            exe_dir,
+            env::current_dir().ok(),
+        ))
+    }
+
+    // The first of `candidates` with all the marker files in it
+    fn locate_in(candidates: Vec<PathBuf>) -> Result<AssetRoot, AssetRootNotFound> {
+        let mut checked = Vec::new();
+        for candidate in candidates {
+            let missing = missing_markers(&candidate);
+            if missing.is_empty() {
+                log::info!("Using asset root {}", candidate.display());
+                return Ok(AssetRoot { root: candidate });
+            }
+            checked.push((candidate, missing));
+        }
+        Err(AssetRootNotFound { checked })
+    }
+
+    /// Like `locate`, but for callers that can fall back to embedded data. Logs a single warning
+    /// for the whole fallback rather than one per file.
+    pub fn locate_or_embedded() -> Option<AssetRoot> {
+        match AssetRoot::locate() {
+            Ok(root) => Some(root),
+            Err(e) => {
+                log::warn!("{}Falling back to embedded assets.", e);
+                None
+            }
+        }
+    }
+
+    /// Resolves a `/`-separated path relative to the asset root. Backslashes are accepted as well
+    /// so paths written on windows work everywhere.
+    pub fn path(&self, relative: &str) -> PathBuf {
+        join_relative(&self.root, relative)
+    }
+
+    pub fn read(&self, relative: &str) -> io::Result<Vec<u8>> {
+        let path = self.path(relative);
+        std::fs::read(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not read {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Reads each of `files`, given as a path and the embedded data to use when it can't be read.
+    /// Logs a single warning for all the files that fell back, and one for all the files that
+    /// differ from the ones the game was built with.
+    pub fn read_or_embedded(&self, files: &[(&str, &'static [u8])]) -> Vec<Cow<'static, [u8]>> {
+        let mut missing = Vec::new();
+        let mut edited = Vec::new();
+        let contents = files
+            .iter()
+            .map(|&(relative, embedded)| match self.read(relative) {
+                Ok(bytes) => {
+                    if asset_manifest::check(relative, &bytes) != AssetCheck::Matches {
+                        edited.push(relative);
+                    }
+                    Cow::Owned(bytes)
+                }
+                Err(_) => {
+                    missing.push(relative);
+                    Cow::Borrowed(embedded)
+                }
+            })
+            .collect();
+        if !missing.is_empty() {
+            log::warn!("{}", self.fallback_message(&missing));
+        }
+        if !edited.is_empty() {
+            log::warn!("{}", self.edited_message(&edited));
+        }
+        contents
+    }
+
+    fn fallback_message(&self, missing: &[&str]) -> String {
+        format!(
+            "Could not read {} from {}, using the embedded ones instead.",
+            missing.join(", "),
+            self.root.display()
+        )
+    }
+
+    // Fine while editing levels, but otherwise a sign of an assets folder from another version
+    fn edited_message(&self, edited: &[&str]) -> String {
+        format!(
+            "{} in {} differ from the ones the game was built with.",
+            edited.join(", "),
+            self.root.display()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct AssetRootNotFound {
+    checked: Vec<(PathBuf, Vec<&'static str>)>,
+}
+
+impl fmt::Display for AssetRootNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Could not find the assets folder. Checked:")?;
+        for (path, missing) in &self.checked {
+            writeln!(f, "  {} (missing {})", path.display(), missing.join(", "))?;
+        }
+        writeln!(
+            f,
+            "Set {} to the assets folder or run the game from the project root.",
+            ASSETS_ENV_VAR
+        )
+    }
+}
+
+impl std::error::Error for AssetRootNotFound {}
+
+// The directory from the environment variable, then the assets folder next to the executable and
+// the working directory or up to `MAX_PARENT_LEVELS` above them
+fn candidate_dirs(
+    env_dir: Option<OsString>,
+    exe_dir: Option<PathBuf>,
+    working_dir: Option<PathBuf>,
+) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(dir) = env_dir {
+        candidates.push(PathBuf::from(dir));
+    }
+
+    for base in exe_dir.into_iter().chain(working_dir) {
+        for dir in base.ancestors().take(MAX_PARENT_LEVELS + 1) {
+            let candidate = dir.join(ASSETS_DIR_NAME);
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    candidates
+}
+
+fn missing_markers(dir: &Path) -> Vec<&'static str> {
+    MARKER_FILES
+        .iter()
+        .copied()
+        .filter(|marker| !join_relative(dir, marker).is_file())
+        .collect()
+}
+
+fn join_relative(base: &Path, relative: &str) -> PathBuf {
+    let mut path = base.to_path_buf();
+    for component in relative.split(&['/', '\\'][..]) {
+        if !component.is_empty() {
+            path.push(component);
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // A directory under the system temp dir, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("ld48-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn add(&self, relative: &str, contents: &[u8]) {
+            let path = join_relative(&self.0, relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        // An assets folder in `relative` with all the marker files
+        fn add_assets(&self, relative: &str) -> PathBuf {
+            for marker in MARKER_FILES {
+                self.add(&format!("{}/{}", relative, marker), b"");
+            }
+            join_relative(&self.0, relative)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn candidates_come_in_order_up_to_the_parent_limit() {
+        let candidates = candidate_dirs(
+            Some(OsString::from("/custom")),
+            Some(PathBuf::from("/a/b/c/d/e")),
+            Some(PathBuf::from("/a/b/c")),
+        );
+        let expected: Vec<PathBuf> = [
+            "/custom",
+            "/a/b/c/d/e/assets",
+            "/a/b/c/d/assets",
+            "/a/b/c/assets",
+            "/a/b/assets",
+            // the working dir's own candidates, without the ones already listed
+            "/a/assets",
+            "/assets",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(candidates, expected);
+
+        let candidates = candidate_dirs(None, None, Some(PathBuf::from("/w")));
+        assert_eq!(
+            candidates,
+            [PathBuf::from("/w/assets"), PathBuf::from("/assets")]
+        );
+    }
+
+    #[test]
+    fn first_complete_candidate_is_the_root() {
+        let dir = TempDir::new("first-complete");
+        // missing a marker, so it is passed over
+        dir.add("partial/player.png", b"");
+        let partial = dir.0.join("partial");
+        let env_root = dir.add_assets("from-env");
+        let exe_root = dir.add_assets("exe/assets");
+
+        let root = AssetRoot::locate_in(vec![partial.clone(), env_root.clone(), exe_root.clone()]);
+        assert_eq!(root.unwrap().root, env_root);
+        let root = AssetRoot::locate_in(vec![partial, dir.0.join("nowhere"), exe_root.clone()]);
+        assert_eq!(root.unwrap().root, exe_root);
+    }
+
+    #[test]
+    fn not_found_lists_what_was_checked() {
+        let dir = TempDir::new("not-found");
+        dir.add("partial/block.png", b"");
+        let partial = dir.0.join("partial");
+        let nowhere = dir.0.join("nowhere");
+
+        let error = AssetRoot::locate_in(vec![partial.clone(), nowhere.clone()])
+            .err()
+            .unwrap();
+        let message = error.to_string();
+        assert!(message.contains(&format!(
+            "{} (missing player.png, rooms/blue.rum)",
+            partial.display()
+        )));
+        assert!(message.contains(&format!(
+            "{} (missing player.png, block.png, rooms/blue.rum)",
+            nowhere.display()
+        )));
+        assert!(message.contains(ASSETS_ENV_VAR));
+    }
+
+    #[test]
+    fn missing_files_fall_back_together() {
+        let dir = TempDir::new("fall-back");
+        let root = AssetRoot {
+            root: dir.add_assets("assets"),
+        };
+        dir.add("assets/rooms/red.rum", b"loose red");
+
+        let files = [
+            ("rooms/red.rum", &b"embedded red"[..]),
+            ("rooms/green.rum", &b"embedded green"[..]),
+            ("rooms\\red.rum", &b"embedded red"[..]),
+            ("rooms/blue.rum", &b"embedded blue"[..]),
+            ("rooms/gone.rum", &b"embedded gone"[..]),
+        ];
+        let contents = root.read_or_embedded(&files);
+        let contents: Vec<&[u8]> = contents.iter().map(|bytes| &bytes[..]).collect();
+        assert_eq!(
+            contents,
+            [
+                &b"loose red"[..],
+                b"embedded green",
+                b"loose red",
+                b"",
+                b"embedded gone"
+            ]
+        );
+        assert_eq!(
+            root.fallback_message(&["rooms/green.rum", "rooms/gone.rum"]),
+            format!(
+                "Could not read rooms/green.rum, rooms/gone.rum from {}, using the embedded ones \
+                 instead.",
+                root.root.display()
+            )
+        );
+    }
+
+    #[test]
+    fn edited_files_are_listed_together() {
+        let dir = TempDir::new("edited");
+        let root = AssetRoot {
+            root: dir.add_assets("assets"),
+        };
+        assert_eq!(
+            root.edited_message(&["rooms/red.rum", "rooms/blue.rum"]),
+            format!(
+                "rooms/red.rum, rooms/blue.rum in {} differ from the ones the game was built with.",
+                root.root.display()
+            )
+        );
+    }
+
+    #[test]
+    fn both_separators_split_paths() {
+        let base = Path::new("base");
+        let expected = base.join("rooms").join("red.rum");
+        assert_eq!(join_relative(base, "rooms/red.rum"), expected);
+        assert_eq!(join_relative(base, "rooms\\red.rum"), expected);
+        assert_eq!(join_relative(base, "/rooms//red.rum"), expected);
+    }
+}
@@ -0,0 +1,556 @@
+//! The rect packing behind `TextureAtlas`, on its own without any GL or entry bookkeeping so
+//! `build.rs` can include it to bake the atlas ahead of time. Only uses std for that reason.
+
+use std::{cmp::Reverse, mem};
+
+/// Places rects in a fixed size area with the skyline bottom-left algorithm, reusing freed space
+/// first. Rects are `[min_x, min_y, max_x, max_y]` texels with y going down from the top.
+#[derive(Clone, Debug)]
+pub struct AtlasPacker {
+    size: (u32, u32),
+    // Texels kept free between rects and along the area's edges
+    padding: u32,
+    // Rects placed and not freed yet, each with its gutter and its padding to the top left
+    placed: Vec<[u32; 4]>,
+    // Space given back by `free` that is still above the skyline, including each rect's padding
+    // to its top left. Tried before the skyline.
+    free_rects: Vec<[u32; 4]>,
+    // Lowest free texel row across the area, as segments from left to right covering the whole
+    // width minus the padding. Rects are placed on top of it, with y going down.
+    skyline: Vec<SkylineSegment>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SkylineSegment {
+    x: u32,
+    y: u32,
+    width: u32,
+}
+
+impl AtlasPacker {
+    pub fn new(size: (u32, u32), padding: u32) -> AtlasPacker {
+        let width = size.0.saturating_sub(padding);
+        AtlasPacker {
+            size,
+            padding,
+            placed: Vec::new(),
+            free_rects: Vec::new(),
+            skyline: vec![SkylineSegment { x: 0, y: 0, width }],
+        }
+    }
+
+    /// A packer with `occupied` already placed, each of them with its gutter, for going on with
+    /// a layout made elsewhere. Later rects go on top of them, space between them left unused.
+    #[cfg(feature = "prebaked-atlas")]
+    pub fn with_occupied(size: (u32, u32), padding: u32, occupied: &[[u32; 4]]) -> AtlasPacker {
+        let mut packer = AtlasPacker::new(size, padding);
+        packer.placed = occupied
+            .iter()
+            .map(|&outer| packer.outer_footprint(outer))
+            .collect();
+        packer.rebuild_skyline();
+        packer
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub fn padding(&self) -> u32 {
+        self.padding
+    }
+
+    /// Where an image of `size` with `gutter` goes, leaving the gutter out, or `None` if it
+    /// doesn't fit. Every rect takes up its padding to the top left, the area's right and bottom
+    /// padding is left out of the skyline.
+    pub fn place(&mut self, size: (u32, u32), gutter: u32) -> Option<[u32; 4]> {
+        let footprint = self.footprint(size, gutter);
+        let (x, y) = match self.take_free_rect(footprint) {
+            Some(position) => position,
+            None => {
+                let (index, x, y) = self.find_position(footprint)?;
+                // the space left under it where the skyline is higher stays free
+                let gaps: Vec<[u32; 4]> = self
+                    .skyline_under(x, x + footprint.0, 0)
+                    .into_iter()
+                    .map(|under| [under[0], under[3], under[2], y])
+                    .filter(|gap| gap[1] < gap[3])
+                    .collect();
+                self.raise_skyline(index, x, y + footprint.1, footprint.0);
+                self.free_rects.extend(gaps);
+                self.merge_free_rects();
+                (x, y)
+            }
+        };
+        self.placed.push([x, y, x + footprint.0, y + footprint.1]);
+        let (min_x, min_y) = (x + self.padding + gutter, y + self.padding + gutter);
+        Some([min_x, min_y, min_x + size.0, min_y + size.1])
+    }
+
+    /// Gives the space of a placed rect back, `outer` being its texels with the gutter.
+    /// The skyline drops back to what is left under it, so freeing the rects placed last gives
+    /// their space back whole.
+    pub fn free(&mut self, outer: [u32; 4]) {
+        let footprint = self.outer_footprint(outer);
+        self.placed.retain(|placed| *placed != footprint);
+        self.free_rects.push(footprint);
+        self.merge_free_rects();
+        self.rebuild_skyline();
+
+        // free space that ended up under the skyline is found through it now
+        let free_rects = mem::take(&mut self.free_rects);
+        for free in free_rects {
+            let above = self.skyline_under(free[0], free[2], free[1]);
+            self.free_rects.extend(
+                above
+                    .into_iter()
+                    .map(|part| [part[0], free[1], part[2], part[3].min(free[3])])
+                    .filter(|part| part[1] < part[3]),
+            );
+        }
+        self.merge_free_rects();
+    }
+
+    /// The biggest image size by area that a place without a gutter would still find space for,
+    /// or `(0, 0)` if there is none.
+    pub fn largest_free_extent(&self) -> (u32, u32) {
+        let usable = self.usable();
+        let free_footprints = self
+            .free_rects
+            .iter()
+            .map(|free| (free[2] - free[0], free[3] - free[1]));
+        // every run of neighbouring skyline segments, up to the highest of them
+        let skyline_footprints = (0..self.skyline.len()).flat_map(|i| {
+            self.skyline[i..]
+                .iter()
+                .scan((0, 0), move |(y, width), segment| {
+                    *y = segment.y.max(*y);
+                    *width += segment.width;
+                    Some((*width, usable.1.saturating_sub(*y)))
+                })
+        });
+        free_footprints
+            .chain(skyline_footprints)
+            .map(|(width, height)| {
+                (
+                    width.saturating_sub(self.padding),
+                    height.saturating_sub(self.padding),
+                )
+            })
+            .max_by_key(|&(width, height)| width as u64 * height as u64)
+            .filter(|&(width, height)| width > 0 && height > 0)
+            .unwrap_or((0, 0))
+    }
+
+    /// Whether `size` turned on its side goes in a smaller free rect, or failing that ends up
+    /// with its bottom edge higher on the skyline. Upright wins ties.
+    pub fn prefers_rotated(&self, size: (u32, u32), gutter: u32) -> bool {
+        if size.0 == size.1 {
+            return false;
+        }
+        let upright = self.footprint(size, gutter);
+        let turned = self.footprint((size.1, size.0), gutter);
+        let free_area = |footprint| self.best_free_rect(footprint).map(|(_, free)| area(free));
+        match (free_area(upright), free_area(turned)) {
+            (Some(upright), Some(turned)) => return turned < upright,
+            (None, Some(_)) => return true,
+            (Some(_), None) => return false,
+            (None, None) => {}
+        }
+        let bottom = |footprint: (u32, u32)| {
+            self.find_position(footprint)
+                .map(|(_, _, y)| y + footprint.1)
+        };
+        match (bottom(upright), bottom(turned)) {
+            (Some(upright), Some(turned)) => turned < upright,
+            (upright, turned) => upright.is_none() && turned.is_some(),
+        }
+    }
+
+    // The stretches of the skyline from `x` to `end`, as rects from `top` down to each of them
+    fn skyline_under(&self, x: u32, end: u32, top: u32) -> Vec<[u32; 4]> {
+        self.skyline
+            .iter()
+            .filter(|segment| segment.x < end && x < segment.x + segment.width)
+            .map(|segment| {
+                [
+                    segment.x.max(x),
+                    top,
+                    (segment.x + segment.width).min(end),
+                    segment.y.max(top),
+                ]
+            })
+            .collect()
+    }
+
+    // Space taken by a placed rect with its gutter, `outer`, and its padding
+    fn outer_footprint(&self, outer: [u32; 4]) -> [u32; 4] {
+        [
+            outer[0].saturating_sub(self.padding),
+            outer[1].saturating_sub(self.padding),
+            outer[2],
+            outer[3],
+        ]
+    }
+
+    // Space taken by an image with its gutter and padding
+    fn footprint(&self, size: (u32, u32), gutter: u32) -> (u32, u32) {
+        (
+            size.0 + gutter * 2 + self.padding,
+            size.1 + gutter * 2 + self.padding,
+        )
+    }
+
+    fn usable(&self) -> (u32, u32) {
+        (
+            self.size.0.saturating_sub(self.padding),
+            self.size.1.saturating_sub(self.padding),
+        )
+    }
+
+    // The smallest free rect a footprint fits in
+    fn best_free_rect(&self, footprint: (u32, u32)) -> Option<(usize, [u32; 4])> {
+        self.free_rects
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, free)| {
+                free[2] - free[0] >= footprint.0 && free[3] - free[1] >= footprint.1
+            })
+            .min_by_key(|(_, free)| area(*free))
+    }
+
+    // Places a footprint in the smallest free rect it fits in, splitting off what's left to the
+    // right of it and below it
+    fn take_free_rect(&mut self, footprint: (u32, u32)) -> Option<(u32, u32)> {
+        let (index, free) = self.best_free_rect(footprint)?;
+        self.free_rects.remove(index);
+        let (right, bottom) = (free[0] + footprint.0, free[1] + footprint.1);
+        let rest = [
+            [right, free[1], free[2], bottom],
+            [free[0], bottom, free[2], free[3]],
+        ];
+        for rect in &rest {
+            if rect[0] < rect[2] && rect[1] < rect[3] {
+                self.free_rects.push(*rect);
+            }
+        }
+        Some((free[0], free[1]))
+    }
+
+    // Joins free rects that share a whole edge, until none do
+    fn merge_free_rects(&mut self) {
+        'merge: loop {
+            for i in 0..self.free_rects.len() {
+                for j in 0..self.free_rects.len() {
+                    let (a, b) = (self.free_rects[i], self.free_rects[j]);
+                    let joined = if a[1] == b[1] && a[3] == b[3] && a[2] == b[0] {
+                        [a[0], a[1], b[2], a[3]]
+                    } else if a[0] == b[0] && a[2] == b[2] && a[3] == b[1] {
+                        [a[0], a[1], a[2], b[3]]
+                    } else {
+                        continue;
+                    };
+                    self.free_rects[i] = joined;
+                    self.free_rects.remove(j);
+                    continue 'merge;
+                }
+            }
+            break;
+        }
+    }
+
+    // The segment to place a footprint at and its top left corner. Picks the spot where its
+    // bottom edge ends up highest, then the leftmost.
+    fn find_position(&self, footprint: (u32, u32)) -> Option<(usize, u32, u32)> {
+        let usable = self.usable();
+        let mut best: Option<(usize, u32, u32)> = None;
+        for (i, segment) in self.skyline.iter().enumerate() {
+            if segment.x + footprint.0 > usable.0 {
+                break;
+            }
+            // rests on the highest segment under it
+            let mut y = 0;
+            let mut covered = 0;
+            for under in &self.skyline[i..] {
+                if covered >= footprint.0 {
+                    break;
+                }
+                y = y.max(under.y);
+                covered += under.width;
+            }
+            if y + footprint.1 > usable.1 {
+                continue;
+            }
+            let better = match best {
+                Some((_, _, best_y)) => y < best_y,
+                None => true,
+            };
+            if better {
+                best = Some((i, segment.x, y));
+            }
+        }
+        best
+    }
+
+    // Puts a segment at `top` from `x` over `width` texels into the skyline, starting at the
+    // segment at `index`, and cuts back the segments under it
+    fn raise_skyline(&mut self, index: usize, x: u32, top: u32, width: u32) {
+        self.skyline
+            .insert(index, SkylineSegment { x, y: top, width });
+        let end = x + width;
+        let next = index + 1;
+        while next < self.skyline.len() {
+            let segment = &mut self.skyline[next];
+            if segment.x >= end {
+                break;
+            }
+            let segment_end = segment.x + segment.width;
+            if segment_end <= end {
+                self.skyline.remove(next);
+            } else {
+                segment.x = end;
+                segment.width = segment_end - end;
+                break;
+            }
+        }
+        self.merge_skyline();
+    }
+
+    // Every stretch of the skyline between the edges of placed rects at the lowest bottom of the
+    // rects above it
+    fn rebuild_skyline(&mut self) {
+        let usable_width = self.usable().0;
+        let mut edges: Vec<u32> = self
+            .placed
+            .iter()
+            .flat_map(|placed| vec![placed[0], placed[2]])
+            .chain(vec![0, usable_width])
+            .filter(|&x| x <= usable_width)
+            .collect();
+        edges.sort_unstable();
+        edges.dedup();
+        self.skyline.clear();
+        for pair in edges.windows(2) {
+            let (x, end) = (pair[0], pair[1]);
+            let y = self
+                .placed
+                .iter()
+                .filter(|placed| placed[0] <= x && end <= placed[2])
+                .map(|placed| placed[3])
+                .max()
+                .unwrap_or(0);
+            self.skyline.push(SkylineSegment {
+                x,
+                y,
+                width: end - x,
+            });
+        }
+        self.merge_skyline();
+    }
+
+    // Neighbours at the same height become one, so wide rects see one segment
+    fn merge_skyline(&mut self) {
+        let mut i = 0;
+        while i + 1 < self.skyline.len() {
+            if self.skyline[i].y == self.skyline[i + 1].y {
+                self.skyline[i].width += self.skyline[i + 1].width;
+                self.skyline.remove(i + 1);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Indices into `sizes` in the order they pack best in, tallest first and then widest first.
+/// Sizes that are the same keep their order, so the packing stays deterministic.
+pub fn packing_order(sizes: &[(u32, u32)]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| Reverse((sizes[i].1, sizes[i].0)));
+    order
+}
+
+/// FNV-1a of `bytes`. Unlike std's hashers it is the same on every target and compiler version,
+/// so a hash worked out by `build.rs` can be checked by the game.
+#[cfg(any(feature = "prebaked-atlas", not(target_arch = "wasm32")))]
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// `rect` grown by `amount` on every side.
+pub fn grow(rect: [u32; 4], amount: u32) -> [u32; 4] {
+    [
+        rect[0].saturating_sub(amount),
+        rect[1].saturating_sub(amount),
+        rect[2] + amount,
+        rect[3] + amount,
+    ]
+}
+
+pub fn area(rect: [u32; 4]) -> u64 {
+    (rect[2] - rect[0]) as u64 * (rect[3] - rect[1]) as u64
+}
+
+/// Whether two rects share any texels.
+pub fn intersects(a: [u32; 4], b: [u32; 4]) -> bool {
+    a[0] < b[2] && b[0] < a[2] && a[1] < b[3] && b[1] < a[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+
+    // Places every size in order and returns the rects that fit
+    fn place_all(packer: &mut AtlasPacker, sizes: &[(u32, u32)]) -> Vec<[u32; 4]> {
+        sizes
+            .iter()
+            .filter_map(|&size| packer.place(size, 0))
+            .collect()
+    }
+
+    fn random_sizes(seed: u64, count: usize, max: u32) -> Vec<(u32, u32)> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        (0..count)
+            .map(|_| (rng.gen_range(1, max + 1), rng.gen_range(1, max + 1)))
+            .collect()
+    }
+
+    // The scan the atlas used before the skyline packer: tries spots row by row, skipping past
+    // what's in the way. Its overlap test left out the check for rects to the right, so anything
+    // in the same rows counts as in the way. It started rows after the first at x 0, which
+    // underflows with the padding, so they start at 1 here.
+    fn scan_place(placed: &mut Vec<[u32; 4]>, atlas_size: (u32, u32), size: (u32, u32)) -> bool {
+        let mut y = 1;
+        let mut x = 1;
+        while y + size.1 < atlas_size.1 {
+            let mut next_y = atlas_size.1;
+            while x + size.0 < atlas_size.0 {
+                let padded = grow([x, y, x + size.0, y + size.1], 1);
+                let mut any_intersect = false;
+                let in_the_way = |rect: &&[u32; 4]| {
+                    padded[0] < rect[2] && padded[1] < rect[3] && rect[1] < padded[3]
+                };
+                for rect in placed.iter().filter(in_the_way) {
+                    next_y = next_y.min(rect[3] + 1);
+                    x = x.max(rect[2] + 1);
+                    any_intersect = true;
+                }
+                if !any_intersect {
+                    placed.push([x, y, x + size.0, y + size.1]);
+                    return true;
+                }
+            }
+            x = 1;
+            y = next_y;
+        }
+        false
+    }
+
+    #[test]
+    fn random_sizes_never_overlap() {
+        for seed in 0..4 {
+            let mut packer = AtlasPacker::new((512, 512), 2);
+            let sizes = random_sizes(seed, 300, 40);
+            let placed = place_all(&mut packer, &sizes);
+            assert!(placed.len() > 100, "only {} rects fit", placed.len());
+            for (i, a) in placed.iter().enumerate() {
+                assert!(
+                    a[0] >= 2 && a[1] >= 2 && a[2] + 2 <= 512 && a[3] + 2 <= 512,
+                    "{:?} is in the edge padding",
+                    a
+                );
+                for b in &placed[i + 1..] {
+                    assert!(!intersects(grow(*a, 2), *b), "{:?} and {:?} overlap", a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gutters_stay_clear_of_other_rects() {
+        let mut packer = AtlasPacker::new((256, 256), 1);
+        let mut outers = Vec::new();
+        for (i, size) in random_sizes(7, 200, 20).into_iter().enumerate() {
+            let gutter = i as u32 % 3;
+            if let Some(texels) = packer.place(size, gutter) {
+                outers.push(grow(texels, gutter));
+            }
+        }
+        for (i, a) in outers.iter().enumerate() {
+            for b in &outers[i + 1..] {
+                assert!(!intersects(grow(*a, 1), *b), "{:?} and {:?} overlap", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn freed_space_is_reused() {
+        let mut packer = AtlasPacker::new((64, 64), 1);
+        let first = packer.place((30, 30), 0).unwrap();
+        packer.place((30, 30), 0).unwrap();
+        packer.free(first);
+        assert_eq!(packer.place((30, 30), 0), Some(first));
+    }
+
+    #[test]
+    fn freeing_the_top_of_the_pile_lowers_the_skyline() {
+        let mut packer = AtlasPacker::new((64, 64), 1);
+        let bottom = packer.place((62, 20), 0).unwrap();
+        let top = packer.place((10, 30), 0).unwrap();
+        packer.free(top);
+        // only fits if the space above `bottom` is one piece again
+        assert_eq!(packer.place((62, 41), 0), Some([1, 22, 63, 63]));
+        packer.free(bottom);
+        assert_eq!(packer.place((62, 20), 0), Some(bottom));
+    }
+
+    #[test]
+    fn gaps_under_wide_rects_stay_free() {
+        let mut packer = AtlasPacker::new((64, 64), 1);
+        place_all(&mut packer, &[(20, 10), (20, 30), (20, 5)]);
+        // rests on the 30 texel one, over gaps on both sides of it
+        assert_eq!(packer.place((62, 5), 0), Some([1, 32, 63, 37]));
+        assert_eq!(packer.place((19, 19), 0), Some([1, 12, 20, 31]));
+        assert_eq!(packer.place((20, 24), 0), Some([43, 7, 63, 31]));
+    }
+
+    #[test]
+    fn packs_more_than_the_old_scan() {
+        let atlas_size = (256, 256);
+        let sizes = random_sizes(1, 400, 32);
+
+        let mut packer = AtlasPacker::new(atlas_size, 1);
+        let skyline_area: u64 = place_all(&mut packer, &sizes).into_iter().map(area).sum();
+        let mut scanned = Vec::new();
+        for &size in &sizes {
+            scan_place(&mut scanned, atlas_size, size);
+        }
+        let scan_area: u64 = scanned.into_iter().map(area).sum();
+        assert!(
+            skyline_area > scan_area,
+            "skyline packed {} texels, the scan {}",
+            skyline_area,
+            scan_area
+        );
+    }
+
+    #[test]
+    fn largest_free_extent_fits() {
+        let mut packer = AtlasPacker::new((128, 128), 1);
+        place_all(&mut packer, &random_sizes(3, 20, 30));
+        let extent = packer.largest_free_extent();
+        assert!(extent.0 > 0 && extent.1 > 0);
+        assert!(packer.place(extent, 0).is_some());
+    }
+
+    #[test]
+    fn packing_order_is_tallest_then_widest_and_stable() {
+        let sizes = [(2, 5), (8, 5), (3, 9), (2, 5), (1, 1)];
+        assert_eq!(packing_order(&sizes), vec![2, 1, 0, 3, 4]);
+    }
+}
@@ -1,305 +1,346 @@
-use std::{collections::HashMap, sync::Arc};
-
-use euclid::{
-    default::{Box2D, Point2D, Rect, Size2D, Transform2D, Vector2D},
-    point2, size2, vec2, Angle,
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    sync::Arc,
 };
-use palette::{Hsv, LinSrgb};
+
+use euclid::{point2, size2, vec2, Angle, Box2D, Rect, Transform2D, Vector2D};
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::asset_paths::AssetRoot;
 use crate::{
     constants::{MUSIC_VOLUME, SCREEN_SIZE, TICK_DT, TILE_SIZE, ZOOM_LEVEL},
     gl, graphics,
-    graphics::{load_image, load_raw_image, render_sprite, Sprite, Vertex, TEXTURE_ATLAS_SIZE},
-    input::{InputEvent, Key, MouseButton},
-    mixer::{Audio, AudioInstanceHandle, Mixer},
+    graphics::{
+        Anchor, Animation, AutotileSheet, BatchImage, Camera2D, Color, EmitterParams, Font, Mesh,
+        PaletteSwap, ParticleSystem, PlayMode, PostProcess, QuadBatcher, QuadSink, RenderQueue,
+        Sprite, SpriteBatch, SpritePosition, TextAlign, TextLayout, Tilemap, UiLayer, Vertex,
+        PALETTE_SIZE, TEXTURE_ATLAS_SIZE,
+    },
+    input::{Axis, GamepadButton, InputEvent, InputState, Key, MouseButton},
+    mixer::{Audio, AudioFuture, AudioInstanceHandle, Mixer, SoundPosition},
+    platform,
+    presence::{Presence, PresenceReporter, PresenceState},
+    resilience::Crash,
+    spaces::{self, RoomPoint, RoomRect, RoomSpace, RoomVector, TilePoint, UiRect},
+    strings::{self, Strings, Text},
     texture_atlas::{TextureAtlas, TextureRect},
+    texture_registry::TextureRegistry,
 };
 
 pub struct Game {
     program: gl::Program,
+    // Recolors the palette swapped UI sprites
+    palette_program: gl::Program,
     room_vertex_buffer: gl::VertexBuffer,
-    vertex_buffer: gl::VertexBuffer,
-    ui_buffer: gl::VertexBuffer,
-    atlas_texture: gl::Texture,
+    room_index_buffer: gl::IndexBuffer,
+    entity_batch: SpriteBatch,
+    entity_queue: RenderQueue,
+    ui: UiLayer,
+    // Everything is drawn through this, a passthrough unless given another shader
+    post_process: PostProcess,
+    textures: TextureRegistry,
+    // Set with F10, saves the atlas with its layout outlined on the next draw
+    dump_atlas: bool,
+    white_pixel: TextureRect,
 
     mixer: Arc<Mixer>,
     run_sound: Audio,
     run_handle: Option<AudioInstanceHandle>,
     jump_sound: Audio,
+    // The start of the jump sound, played with L to measure how late triggered sounds start
+    marker_click: Audio,
     land_sound: Audio,
     stop_sound: Audio,
     enter_sound: Audio,
+    // Loops the enter sound from the room block nearest the player, so it calls from deeper in
+    portal_hum: Option<AudioInstanceHandle>,
 
+    music: AudioFuture,
     music_handle: AudioInstanceHandle,
 
-    mouse_pos: Point2D<f32>,
+    input: InputState,
+    paused: bool,
     muted: bool,
-    mute_icon_rect: Rect<f32>,
+    music_volume: f32,
+    // Toggled with F, renders at twice the resolution and filters down
+    supersample: bool,
+    // Toggled with P, draws moving sprites on whole art pixels
+    pixel_snap: bool,
+    // Toggled with C, outlines the player's rects and the room's enter regions, fills the tiles
+    // the collision solver checked last tick and the one under the mouse, and shows the player's
+    // velocity
+    show_collision: bool,
+    solver_tiles: Vec<TilePoint>,
+    hovered_tile: Option<TilePoint>,
+    // Cycled with F3
+    debug_view: DebugView,
+    mute_icon_rect: UiRect,
     mute_icon: Sprite,
-
-    controls: Controls,
+    font: Font,
+    // Nine-sliced behind the title card
+    panel: TextureRect,
+    // Shown once the player is in a room for the first time
+    title_card: Option<TitleCard>,
+    // The room blocks again, for drawing over the baked room
+    room_block_sprites: HashMap<RoomColor, Sprite>,
+
+    // Seconds since jump was pressed, so a jump pressed just before landing still happens
+    since_jump: f32,
     player: Player,
+    // Counts down from PLAYER_FLASH_TIME after entering a room, or from LAND_FLASH_TIME after
+    // landing, flashing the player white
+    player_flash: f32,
 
     dust_sprite: Sprite,
+    // Started for each new bit of dust, which goes away once it finishes
+    dust_puff: Animation,
     dust_spawn_timer: f32,
     dust: Vec<Dust>,
+    particles: ParticleSystem,
 
     rng: SmallRng,
 
     rooms: HashMap<RoomColor, Room>,
-    room_textures: HashMap<RoomColor, gl::Texture>,
+    room_textures: HashMap<RoomColor, gl::RenderTexture>,
+    strings: Strings,
+    // None when the game ignores progress
+    visited_rooms: Option<VisitedRooms>,
 
     current_room: RoomColor,
     enter_room: Option<RoomTransitionIn>,
+    breadcrumb: Breadcrumb,
+
+    presence: PresenceReporter,
+    time: f32,
+    playtime: f32,
+}
+
+/// What `--resilient` mode restores the game to after a panic: where the player was and how they
+/// got there. Everything else starts over.
+#[derive(Clone, Debug)]
+pub struct GameSnapshot {
+    room: RoomColor,
+    breadcrumb: Breadcrumb,
+    player_position: RoomPoint,
+    playtime: f32,
+}
+
+#[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+impl GameSnapshot {
+    /// A game that just started in the room named `room` with the player at `player_position`,
+    /// for starting somewhere else than the first room. `None` if there is no such room.
+    pub fn starting_in(room: &str, player_position: RoomPoint) -> Option<GameSnapshot> {
+        RoomColor::from_name(room).map(|room| GameSnapshot {
+            room,
+            breadcrumb: Breadcrumb::new(room),
+            player_position,
+            playtime: 0.,
+        })
+    }
+}
+
+/// Whether the game keeps the player's progress.
+pub enum ProgressMode {
+    /// Loads the rooms visited before and saves each new one, showing its title card.
+    Saved,
+    /// Neither loads nor saves progress and shows no title cards, so the game looks the same
+    /// whatever was saved. For visual tests.
+    #[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+    Ignored,
+}
+
+/// Where the game reads room levels from.
+pub enum LevelSource {
+    /// The levels built into the game.
+    Embedded,
+    /// Loose files in the assets folder, so levels can be edited without rebuilding. Any that
+    /// can't be read are the built in ones instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    Loose(AssetRoot),
 }
 
 impl Game {
-    pub fn new(gl_context: &mut gl::Context, mixer: Arc<Mixer>) -> Self {
-        let vertex_shader = unsafe {
-            gl_context
-                .create_shader(gl::ShaderType::Vertex, include_str!("shaders/shader.vert"))
-                .unwrap()
-        };
-        let fragment_shader = unsafe {
+    pub fn new(
+        gl_context: &mut gl::Context,
+        mixer: Arc<Mixer>,
+        presence: Box<dyn Presence>,
+        level_source: LevelSource,
+        progress: ProgressMode,
+    ) -> Self {
+        let mut program = create_sprite_program(gl_context, false);
+        let palette_program = create_sprite_program(gl_context, true);
+
+        let max_texture_size = gl_context.limits().max_texture_size;
+        assert!(
+            TEXTURE_ATLAS_SIZE.width <= max_texture_size
+                && TEXTURE_ATLAS_SIZE.height <= max_texture_size,
+            "The {}x{} texture atlas is larger than the maximum texture size of {}",
+            TEXTURE_ATLAS_SIZE.width,
+            TEXTURE_ATLAS_SIZE.height,
+            max_texture_size
+        );
+        let atlas_texture = unsafe {
             gl_context
-                .create_shader(
-                    gl::ShaderType::Fragment,
-                    include_str!("shaders/shader.frag"),
+                .create_texture(
+                    color_texture_format(gl_context),
+                    TEXTURE_ATLAS_SIZE.width,
+                    TEXTURE_ATLAS_SIZE.height,
+                    gl::TextureFilter::NEAREST,
                 )
                 .unwrap()
         };
+        unsafe {
+            gl_context.label_texture(&atlas_texture, "atlas");
+            gl_context.label_program(&program, "sprite program");
+        }
+        #[cfg(not(feature = "prebaked-atlas"))]
+        let mut textures = {
+            let mut atlas = TextureAtlas::new(TEXTURE_ATLAS_SIZE.to_tuple(), ATLAS_PADDING);
+            // outgrowing the atlas doubles it rather than failing to start. The prebaked atlas
+            // can't, its images are only in the texture.
+            atlas.set_auto_grow(Some(max_texture_size));
+            TextureRegistry::new(atlas, atlas_texture)
+        };
+        #[cfg(feature = "prebaked-atlas")]
+        let mut textures = unsafe { prebaked_textures(atlas_texture) }
+            .expect("Could not load the prebaked texture atlas");
 
-        let mut program = unsafe {
+        let mut room_vertex_buffer = unsafe {
             gl_context
-                .create_program(&gl::ProgramDescriptor {
-                    vertex_shader: &vertex_shader,
-                    fragment_shader: &fragment_shader,
-                    uniforms: &[
-                        gl::UniformEntry {
-                            name: "u_transform",
-                            ty: gl::UniformType::Mat3,
-                        },
-                        gl::UniformEntry {
-                            name: "u_texture",
-                            ty: gl::UniformType::Texture,
-                        },
-                        gl::UniformEntry {
-                            name: "u_alpha",
-                            ty: gl::UniformType::Float,
-                        },
-                    ],
-                    vertex_format: gl::VertexFormat {
-                        stride: std::mem::size_of::<Vertex>(),
-                        attributes: &[
-                            gl::VertexAttribute {
-                                name: "a_pos",
-                                ty: gl::VertexAttributeType::Float,
-                                size: 2,
-                                offset: 0,
-                            },
-                            gl::VertexAttribute {
-                                name: "a_uv",
-                                ty: gl::VertexAttributeType::Float,
-                                size: 2,
-                                offset: 2 * 4,
-                            },
-                            gl::VertexAttribute {
-                                name: "a_color",
-                                ty: gl::VertexAttributeType::Float,
-                                size: 4,
-                                offset: 4 * 4,
-                            },
-                        ],
-                    },
-                })
+                .create_vertex_buffer(gl::BufferUsage::Static)
                 .unwrap()
         };
-
-        let mut atlas_texture = unsafe {
+        let mut room_index_buffer = unsafe {
             gl_context
-                .create_texture(
-                    gl::TextureFormat::RGBAFloat,
-                    TEXTURE_ATLAS_SIZE.width,
-                    TEXTURE_ATLAS_SIZE.height,
-                )
+                .create_index_buffer(gl::BufferUsage::Static)
                 .unwrap()
         };
-        let mut atlas = TextureAtlas::new((TEXTURE_ATLAS_SIZE.width, TEXTURE_ATLAS_SIZE.height));
-
-        let vertex_buffer = unsafe { gl_context.create_vertex_buffer().unwrap() };
-        let ui_buffer = unsafe { gl_context.create_vertex_buffer().unwrap() };
-
-        let mut room_vertex_buffer = unsafe { gl_context.create_vertex_buffer().unwrap() };
-        let room_vertices = vec![
-            Vertex {
-                position: [0.0, 0.0],
-                uv: [0.0, 0.0],
-                color: [1., 1., 1., 1.],
-            },
-            Vertex {
-                position: [ROOM_SIZE.0 as f32, 0.0],
-                uv: [1.0, 0.0],
-                color: [1., 1., 1., 1.],
-            },
-            Vertex {
-                position: [0.0, ROOM_SIZE.1 as f32],
-                uv: [0.0, 1.0],
-                color: [1., 1., 1., 1.],
-            },
-            Vertex {
-                position: [ROOM_SIZE.0 as f32, 0.0],
-                uv: [1.0, 0.0],
-                color: [1., 1., 1., 1.],
-            },
-            Vertex {
-                position: [ROOM_SIZE.0 as f32, ROOM_SIZE.1 as f32],
-                uv: [1.0, 1.0],
-                color: [1., 1., 1., 1.],
-            },
-            Vertex {
-                position: [0.0, ROOM_SIZE.1 as f32],
-                uv: [0.0, 1.0],
-                color: [1., 1., 1., 1.],
-            },
-        ];
-        unsafe { room_vertex_buffer.write(&room_vertices) };
-
-        let controls = Controls::default();
-
-        let tile_sheet = unsafe {
-            load_image(
-                include_bytes!("../assets/block.png"),
-                &mut atlas,
-                &mut atlas_texture,
-            )
+        unsafe {
+            gl_context.label_buffer(&room_vertex_buffer, "room quad vertices");
+            gl_context.label_index_buffer(&room_index_buffer, "room quad indices");
         }
-        .unwrap();
-
-        let tile_images = TileImages::new(tile_sheet);
+        // the current room, then the room being entered, which is drawn into its block during a
+        // transition and rewritten for each one
+        let whole_room = Box2D::new(
+            point2(0., 0.),
+            point2(ROOM_SIZE.0 as f32, ROOM_SIZE.1 as f32),
+        );
+        let mut room_mesh = Mesh::with_quad_capacity(2);
+        room_mesh.push_quad(room_quad(whole_room));
+        room_mesh.push_quad(room_quad(whole_room));
+        unsafe { room_mesh.write(&mut room_vertex_buffer, &mut room_index_buffer) };
 
         let mut rooms = HashMap::new();
         let mut room_textures = HashMap::new();
         let mut room_blocks = HashMap::new();
 
-        let room_list = vec![
-            (
-                RoomColor::Red,
-                parse_room(include_str!("../assets/rooms/red.rum")),
-            ),
-            (
-                RoomColor::Orange,
-                parse_room(include_str!("../assets/rooms/orange.rum")),
-            ),
-            (
-                RoomColor::Yellow,
-                parse_room(include_str!("../assets/rooms/yellow.rum")),
-            ),
-            (
-                RoomColor::Green,
-                parse_room(include_str!("../assets/rooms/green.rum")),
-            ),
-            (
-                RoomColor::Turquoise,
-                parse_room(include_str!("../assets/rooms/turquoise.rum")),
-            ),
-            (
-                RoomColor::Aqua,
-                parse_room(include_str!("../assets/rooms/aqua.rum")),
-            ),
-            (
-                RoomColor::Chetwood,
-                parse_room(include_str!("../assets/rooms/chetwood.rum")),
-            ),
-            (
-                RoomColor::Blue,
-                parse_room(include_str!("../assets/rooms/blue.rum")),
-            ),
-            (
-                RoomColor::Purple,
-                parse_room(include_str!("../assets/rooms/purple.rum")),
-            ),
-            (
-                RoomColor::Magenta,
-                parse_room(include_str!("../assets/rooms/magenta.rum")),
-            ),
-            (
-                RoomColor::Ferrish,
-                parse_room(include_str!("../assets/rooms/ferrish.rum")),
-            ),
-        ];
+        let levels: Vec<Cow<'static, [u8]>> = match &level_source {
+            LevelSource::Embedded => ROOM_LEVELS
+                .iter()
+                .map(|&(_, _, level)| Cow::Borrowed(level))
+                .collect(),
+            #[cfg(not(target_arch = "wasm32"))]
+            LevelSource::Loose(asset_root) => {
+                let files: Vec<(&str, &'static [u8])> = ROOM_LEVELS
+                    .iter()
+                    .map(|&(_, path, level)| (path, level))
+                    .collect();
+                asset_root.read_or_embedded(&files)
+            }
+        };
+        let room_list: Vec<(RoomColor, Room)> = ROOM_LEVELS
+            .iter()
+            .zip(levels)
+            .map(
+                |(&(color, path, _), level)| match std::str::from_utf8(&level) {
+                    Ok(level) => (color, parse_room(level)),
+                    Err(e) => panic!("{} is not valid UTF-8: {}", path, e),
+                },
+            )
+            .collect();
+
+        // every texture goes into the atlas in one batch, so they can be packed tallest first
+        let room_block_images: Vec<(String, Vec<u8>)> = room_list
+            .iter()
+            .map(|(color, room)| (room_block_name(*color), create_room_block(room, *color)))
+            .collect();
+        let mut batch = asset_images();
+        for (name, image) in &room_block_images {
+            batch.push((name, BatchImage::Raw(image, ROOM_BLOCK_IMAGE_SIZE.into())));
+        }
+        unsafe { textures.insert_batch(&batch) }.expect("Could not load the textures");
+        let texture = |name: &str| textures.get(name).unwrap();
+        let atlas = textures.atlas();
+        log::info!(
+            "Texture atlas {:.1}% used by {} entries, {} texels free",
+            atlas.utilization() * 100.,
+            atlas.entry_count(),
+            atlas.free_area()
+        );
 
-        // first create  room blocks
-        for (color, room) in &room_list {
-            let room_block_image = create_room_block(&room, *color);
-            let room_block_texture = unsafe {
-                load_raw_image(
-                    &room_block_image,
-                    ROOM_BLOCK_IMAGE_SIZE.0,
-                    ROOM_BLOCK_IMAGE_SIZE.1,
-                    &mut atlas,
-                    &mut atlas_texture,
-                )
-                .unwrap()
-            };
-            room_blocks.insert(*color, room_block_texture);
+        let tile_sheet = AutotileSheet::new(
+            texture("block"),
+            size2(TILE_SIZE as u32, TILE_SIZE as u32),
+            vec2(8, 8),
+        );
+        for (color, _) in &room_list {
+            room_blocks.insert(*color, texture(&room_block_name(*color)));
         }
 
-        for (color, room) in room_list {
-            let room_buffer =
-                build_room_vertex_buffer(gl_context, &room_blocks, color, &room, &tile_images);
-            let room_pixel_size = Size2D::new(ROOM_SIZE.0, ROOM_SIZE.1).to_f32() * TILE_SIZE;
-            let transform = Transform2D::scale(
-                1.0 / room_pixel_size.width as f32,
-                1.0 / room_pixel_size.height as f32,
+        let room_block_sprites = room_blocks
+            .iter()
+            .map(|(color, texture)| {
+                // the block image has a pixel of border around its tile
+                let mut sprite = Sprite::new(*texture, 1, point2(1., 1.));
+                sprite.set_transform(Transform2D::scale(1. / TILE_SIZE, 1. / TILE_SIZE));
+                // scaled while entering a room, which would pick up the atlas neighbours too
+                sprite.set_uv_inset(TILE_UV_INSET);
+                (*color, sprite)
+            })
+            .collect();
+
+        unsafe { gl_context.push_debug_group("room prebake") };
+        let mut room_quads =
+            unsafe { QuadBatcher::new(gl_context, gl::BufferUsage::Dynamic).unwrap() };
+        let transform = spaces::room_to_texture_clip(ROOM_SIZE);
+        program
+            .set_uniform_by_name(
+                "u_transform",
+                gl::Uniform::Mat3(spaces::to_mat3(&transform)),
             )
-            .then_scale(TILE_SIZE as f32, TILE_SIZE as f32)
-            .then_scale(2., 2.)
-            .then_translate(vec2(-1.0, -1.0));
-            program
-                .set_uniform(
-                    0,
-                    gl::Uniform::Mat3([
-                        [transform.m11, transform.m12, 0.0],
-                        [transform.m21, transform.m22, 0.0],
-                        [transform.m31, transform.m32, 1.0],
-                    ]),
-                )
-                .unwrap();
-            program
-                .set_uniform(1, gl::Uniform::Texture(&atlas_texture))
-                .unwrap();
-            program.set_uniform(2, gl::Uniform::Float(1.0)).unwrap();
-
+            .unwrap();
+        program
+            .set_uniform_by_name("u_texture", gl::Uniform::Texture(textures.texture()))
+            .unwrap();
+        program
+            .set_uniform_by_name("u_alpha", gl::Uniform::Float(1.0))
+            .unwrap();
+        let mut room_texture_bytes = 0;
+        for (color, room) in room_list {
+            build_room_quads(&room_blocks, color, &room, &tile_sheet, &mut room_quads);
             unsafe {
-                let room_texture = gl_context
-                    .create_texture(
-                        gl::TextureFormat::RGBAFloat,
-                        room_pixel_size.width as u32,
-                        room_pixel_size.height as u32,
-                    )
-                    .unwrap();
-                let room_render_target = gl_context.create_texture_render_target(&room_texture);
-
-                program
-                    .render_vertices(&room_buffer, gl::RenderTarget::Texture(&room_render_target))
-                    .unwrap();
+                let room_texture =
+                    build_room_texture(gl_context, &program, &mut room_quads, ROOM_RENDER_SCALE);
+                gl_context.label_texture(room_texture.texture(), &format!("{:?} room", color));
+                let (width, height) = room_texture.size();
+                room_texture_bytes += width as usize * height as usize * 4;
                 room_textures.insert(color, room_texture);
             }
 
             rooms.insert(color, room);
         }
+        unsafe { gl_context.pop_debug_group() };
+        log::info!(
+            "Baked {} room textures at {}x, {} KiB",
+            room_textures.len(),
+            ROOM_RENDER_SCALE,
+            room_texture_bytes / 1024
+        );
 
-        let player_rect = unsafe {
-            load_image(
-                include_bytes!("../assets/player.png"),
-                &mut atlas,
-                &mut atlas_texture,
-            )
-        }
-        .unwrap();
-
-        let player = Player::new(player_rect, point2(2., 2.));
+        let player = Player::new(texture("player"), point2(2., 2.));
 
         let run_sound = mixer.load_ogg(include_bytes!("../assets/run.ogg")).unwrap();
         let jump_sound = mixer
@@ -314,127 +355,260 @@ impl Game {
         let enter_sound = mixer
             .load_ogg(include_bytes!("../assets/enter.ogg"))
             .unwrap();
-        let music_sound = mixer
-            .load_ogg(include_bytes!("../assets/music.ogg"))
-            .unwrap();
-
-        let music_handle = mixer.play(&music_sound, MUSIC_VOLUME, true);
-
-        let mute_texture = unsafe {
-            load_image(
-                include_bytes!("../assets/music_icon.png"),
-                &mut atlas,
-                &mut atlas_texture,
-            )
-            .unwrap()
-        };
-
-        let ui_zoom = 2.;
-        let mut mute_icon = Sprite::new(mute_texture, 2, point2(0.0, 0.0));
-        mute_icon.set_transform(Transform2D::scale(ui_zoom, ui_zoom));
-        let mute_icon_rect = Rect::new(
-            point2(8., SCREEN_SIZE.1 as f32 - 8. - 11. * ui_zoom),
-            size2(9., 11.) * ui_zoom,
+        // the music takes a while to decode, so it starts once it's ready instead of holding up
+        // the first frame
+        let mut music = mixer.load_ogg_async(include_bytes!("../assets/music.ogg"));
+        let music_handle = mixer.play_when_ready(&mut music, MUSIC_VOLUME, true);
+
+        let mut mute_icon = Sprite::new(texture("music_icon"), 2, point2(0.0, 0.0));
+        mute_icon.set_transform(Transform2D::scale(UI_ZOOM, UI_ZOOM));
+        // 8 pixels in from the top left corner of the window
+        let mute_icon_rect =
+            UiLayer::anchored(Anchor::TopLeft, vec2(8., 8.), size2(9., 11.) * UI_ZOOM);
+
+        let panel = texture("panel");
+        let font = Font::monospace(
+            texture("font"),
+            FONT_CHARS,
+            size2(FONT_GLYPH_SIZE.0, FONT_GLYPH_SIZE.1),
         );
 
-        let dust_texture = unsafe {
-            load_image(
-                include_bytes!("../assets/dust.png"),
-                &mut atlas,
-                &mut atlas_texture,
+        let dust_sheet = graphics::load_aseprite(
+            include_bytes!("../assets/dust.json"),
+            texture("dust"),
+            point2(2., 2.),
+        )
+        .expect("Could not load the dust sheet");
+        let mut dust_sprite = dust_sheet.sprite().clone();
+        dust_sprite.set_transform(Transform2D::scale(1. / TILE_SIZE, 1. / TILE_SIZE));
+        let dust_puff = dust_sheet
+            .animation("puff")
+            .expect("The dust sheet has no puff tag");
+
+        let white_pixel = texture("white");
+
+        // the entity and UI batches are rewritten every frame, the room buffers only once
+        let post_process = unsafe {
+            PostProcess::new(
+                gl_context,
+                size2(SCREEN_SIZE.0, SCREEN_SIZE.1),
+                graphics::PASSTHROUGH_POST_SHADER,
             )
             .unwrap()
         };
-        let mut dust_sprite = Sprite::new(dust_texture, 3, point2(2., 2.));
-        dust_sprite.set_transform(Transform2D::scale(1. / TILE_SIZE, 1. / TILE_SIZE));
+        let (entity_batch, ui) = unsafe {
+            let entity_batch = SpriteBatch::new(gl_context, Rc::clone(textures.texture())).unwrap();
+            entity_batch.label(gl_context, "entities");
+            let ui = UiLayer::new(gl_context, Rc::clone(textures.texture())).unwrap();
+            (entity_batch, ui)
+        };
 
         let rng = SmallRng::seed_from_u64(0);
 
         Game {
             program,
+            palette_program,
             room_vertex_buffer,
-            vertex_buffer,
-            ui_buffer,
-            atlas_texture,
+            room_index_buffer,
+            entity_batch,
+            entity_queue: RenderQueue::new(),
+            ui,
+            post_process,
+            textures,
+            dump_atlas: false,
+            white_pixel,
 
             mixer,
             run_sound,
             run_handle: None,
+            marker_click: jump_sound.slice(0., MARKER_CLICK_SECS),
             jump_sound,
             land_sound,
             stop_sound,
             enter_sound,
+            portal_hum: None,
 
+            music,
             music_handle,
 
-            mouse_pos: Point2D::zero(),
+            input: InputState::default(),
+            paused: false,
             muted: false,
+            music_volume: MUSIC_VOLUME,
+            supersample: false,
+            pixel_snap: true,
+            show_collision: false,
+            solver_tiles: Vec::new(),
+            hovered_tile: None,
+            debug_view: DebugView::Normal,
             mute_icon_rect,
             mute_icon,
+            font,
+            panel,
+            title_card: None,
+            room_block_sprites,
 
-            controls,
+            since_jump: 0.,
             player,
+            player_flash: 0.,
 
             dust_sprite,
+            dust_puff,
             dust_spawn_timer: 0.,
             dust: Vec::new(),
+            particles: ParticleSystem::new(PARTICLE_CAPACITY, 1),
 
             rng,
 
             rooms,
             room_textures,
+            strings: Strings::english(),
+            visited_rooms: match progress {
+                ProgressMode::Saved => Some(VisitedRooms::load(RoomColor::Blue)),
+                #[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+                ProgressMode::Ignored => None,
+            },
 
             current_room: RoomColor::Blue,
             enter_room: None,
+            breadcrumb: Breadcrumb::new(RoomColor::Blue),
+
+            presence: PresenceReporter::new(presence),
+            time: 0.,
+            playtime: 0.,
         }
     }
 
     pub fn update(&mut self, inputs: &[InputEvent]) {
-        for input in inputs {
-            match input {
-                InputEvent::KeyDown(Key::W) | InputEvent::KeyDown(Key::Space) => {
-                    self.controls.since_jump = 0.0;
-                }
-                InputEvent::KeyDown(Key::A) => {
-                    self.controls.left = true;
-                }
-                InputEvent::KeyUp(Key::A) => {
-                    self.controls.left = false;
-                }
-                InputEvent::KeyDown(Key::D) => {
-                    self.controls.right = true;
-                }
-                InputEvent::KeyUp(Key::D) => {
-                    self.controls.right = false;
-                }
-                InputEvent::MouseMove(position) => {
-                    self.mouse_pos = point2(position.x, SCREEN_SIZE.1 as f32 - position.y);
-                }
-                InputEvent::MouseDown(button) => {
-                    if let MouseButton::Left = button {
-                        if self.mute_icon_rect.contains(self.mouse_pos) {
-                            self.muted = !self.muted;
-                            if self.muted {
-                                self.mixer.set_volume(&self.music_handle, 0.);
-                            } else {
-                                self.mixer.set_volume(&self.music_handle, MUSIC_VOLUME)
-                            }
-                        }
-                    }
-                }
-                _ => {}
+        self.input.begin_tick();
+        for event in inputs {
+            self.input.handle_event(event);
+        }
+        let input = &self.input;
+
+        if JUMP_KEYS.iter().any(|&key| input.key_pressed(key))
+            || input.button_pressed(GamepadButton::South)
+        {
+            self.since_jump = 0.0;
+        }
+        // chords with Ctrl or Alt belong to the OS or the browser, like Ctrl+C or Alt+F4
+        let modifiers = input.modifiers();
+        let debug_key_pressed = |key| input.key_pressed(key) && !modifiers.ctrl && !modifiers.alt;
+        if debug_key_pressed(Key::C) {
+            self.show_collision = !self.show_collision;
+        }
+        let hovered: TilePoint = room_camera()
+            .screen_to_world(input.mouse_pos())
+            .floor()
+            .to_i32();
+        let in_room = (0..ROOM_SIZE.0 as i32).contains(&hovered.x)
+            && (0..ROOM_SIZE.1 as i32).contains(&hovered.y);
+        self.hovered_tile = if in_room { Some(hovered) } else { None };
+        if input.key_pressed(Key::F3) {
+            self.debug_view = self.debug_view.next();
+            log::info!("Debug view {:?}", self.debug_view);
+        }
+        if input.key_pressed(Key::F10) {
+            self.dump_atlas = true;
+        }
+        if debug_key_pressed(Key::F) {
+            self.supersample = !self.supersample;
+            log::info!(
+                "Supersampling {}",
+                if self.supersample { "on" } else { "off" }
+            );
+        }
+        if debug_key_pressed(Key::P) {
+            self.pixel_snap = !self.pixel_snap;
+            log::info!(
+                "Pixel snapping {}",
+                if self.pixel_snap { "on" } else { "off" }
+            );
+        }
+        if debug_key_pressed(Key::L) {
+            // what the last click waited for, this one is measured by the next press
+            log::info!(
+                "Trigger latency {:.1}ms on top of the output buffer",
+                self.mixer.take_trigger_latency() * 1000.
+            );
+            self.mixer.play(&self.marker_click, 1.0, false);
+        }
+        // for trying out --resilient, in debug builds only
+        if cfg!(debug_assertions) && debug_key_pressed(Key::K) {
+            panic!("Panic injected with K");
+        }
+        if debug_key_pressed(Key::H) {
+            let private = !self.presence.private();
+            self.presence.set_private(private);
+            log::info!(
+                "Room and depth {} from presence",
+                if private { "hidden" } else { "shown" }
+            );
+        }
+        if input.mouse_pressed(MouseButton::Left)
+            && UiLayer::hit(self.mute_icon_rect, input.mouse_pos())
+        {
+            self.muted = !self.muted;
+            if self.muted {
+                self.mixer.set_volume(&self.music_handle, 0.);
+            } else {
+                self.mixer.set_volume(&self.music_handle, self.music_volume)
+            }
+        }
+        // scrolling over the music icon turns the music up or down
+        let wheel_y = input.wheel_delta().y;
+        if wheel_y != 0. && UiLayer::hit(self.mute_icon_rect, input.mouse_pos()) {
+            let step = if wheel_y > 0. {
+                MUSIC_VOLUME_STEP
+            } else {
+                -MUSIC_VOLUME_STEP
+            };
+            self.music_volume = (self.music_volume + step).max(0.).min(1.);
+            if !self.muted {
+                self.mixer.set_volume(&self.music_handle, self.music_volume);
+            }
+        }
+        self.paused = !input.focused();
+
+        if let Err(e) = self.mixer.poll_loading(&mut self.music) {
+            log::error!("Could not load music: {}", e);
+        }
+
+        self.time += TICK_DT;
+        self.presence.report(
+            PresenceState::new(
+                &self.room_display(self.current_room).name,
+                self.breadcrumb.depth(),
+                self.playtime,
+                self.paused,
+            ),
+            self.time,
+        );
+
+        if self.paused {
+            return;
+        }
+        self.playtime += TICK_DT;
+        self.player_flash = (self.player_flash - TICK_DT).max(0.);
+
+        // any press skips the title card
+        if let Some(title_card) = &mut self.title_card {
+            title_card.age += TICK_DT;
+            if title_card.finished() || self.input.any_pressed() {
+                self.title_card = None;
             }
         }
 
+        self.particles.update(TICK_DT);
         for i in (0..self.dust.len()).rev() {
-            let age = {
+            let finished = {
                 let dust = &mut self.dust[i];
                 dust.position += dust.velocity * TICK_DT;
                 dust.velocity *= 0.9;
-                dust.age += TICK_DT;
-                dust.age
+                dust.animation.update(TICK_DT);
+                dust.animation.finished()
             };
-            if age >= DUST_LIFE_TIME {
+            if finished {
                 self.dust.swap_remove(i);
             }
         }
@@ -443,6 +617,7 @@ impl Game {
             enter_room.timer += TICK_DT;
             if enter_room.timer > ENTER_ROOM_TIME {
                 self.current_room = enter_room.color;
+                self.breadcrumb.enter(enter_room.color);
                 let player_offset = vec2(0.5, -self.player.collision_rect.min_y());
                 self.player.position = match enter_room.entrance {
                     RoomEntrance::Left => {
@@ -474,14 +649,80 @@ impl Game {
                     }
                 };
                 self.player.velocity = Vector2D::zero();
+                self.player_flash = PLAYER_FLASH_TIME;
+                self.particles.emit(EmitterParams {
+                    position: self.player.position.to_untyped(),
+                    count: 16,
+                    velocity_range: (vec2(-4., -4.), vec2(4., 4.)),
+                    lifetime_range: (0.3, 0.6),
+                    size_range: (1. / TILE_SIZE, 2. / TILE_SIZE),
+                    color_over_life: (Color::WHITE, Color::WHITE.with_alpha(0.)),
+                    gravity: vec2(0., 0.),
+                    tex_coords: self.white_pixel,
+                });
                 self.enter_room = None;
+
+                let current_room = self.current_room;
+                if let Some(visited_rooms) = &mut self.visited_rooms {
+                    if visited_rooms.visit(current_room) {
+                        if let Err(e) = platform::save_progress(&visited_rooms.to_save()) {
+                            log::error!("{:?}", e);
+                        }
+                        let display = self.room_display(current_room);
+                        match &display.flavor {
+                            Some(flavor) => log::info!("Entered {}: {}", display.name, flavor),
+                            None => log::info!("Entered {}", display.name),
+                        }
+                        // only now that the room has grown in, so it never covers a transition
+                        self.title_card = Some(TitleCard::new(display));
+                    }
+                }
             } else {
                 return;
             }
         }
 
+        // Not updated during room transitions, so positional sounds hold still until the player
+        // arrives
+        self.mixer.set_listener(
+            SoundPosition {
+                area: self.current_room as usize,
+                position: self.player.position,
+            },
+            ROOM_SIZE.0 as f32,
+        );
+
         let room = self.rooms.get(&self.current_room).unwrap();
 
+        match (
+            room.nearest_room_block(self.player.position),
+            &self.portal_hum,
+        ) {
+            (Some(position), Some(handle)) => self.mixer.set_position(
+                handle,
+                SoundPosition {
+                    area: self.current_room as usize,
+                    position,
+                },
+            ),
+            (Some(position), None) => {
+                self.portal_hum = Some(self.mixer.play_at(
+                    &self.enter_sound,
+                    PORTAL_HUM_VOLUME,
+                    true,
+                    SoundPosition {
+                        area: self.current_room as usize,
+                        position,
+                    },
+                ));
+            }
+            (None, Some(handle)) => {
+                self.mixer.stop(handle);
+                self.portal_hum = None;
+            }
+            (None, None) => {}
+        }
+
         // Player controls
         let coyote_time = 0.1;
         let jump_buffer_time = 0.05;
@@ -492,25 +733,14 @@ impl Game {
         let fall_speed = 15.;
         let gravity = -30.;
         let jump_speed = 11.5;
+        // letting go of jump on the way up cuts the jump short at this speed
+        let jump_cut_speed = 4.;
 
-        let mut x_dir: f32 = 0.;
-        if self.controls.right {
-            x_dir += 1.;
-        }
-        if self.controls.left {
-            x_dir -= 1.;
-        }
-
-        if x_dir.abs() > 0.0001 && self.player.velocity.x.abs() > 0. {
-            if self.player.animation_timer < 0. {
-                self.player.animation_timer = 0.;
-            }
-            self.player.flip = x_dir < 0.;
+        let x_dir = run_direction(&self.input);
 
-            self.player.animation_timer =
-                (self.player.animation_timer + TICK_DT) % RUN_ANIMATION_TIME;
-        } else {
-            self.player.animation_timer = -1.;
+        let running = x_dir.abs() > 0.0001 && self.player.velocity.x.abs() > 0.;
+        if running {
+            self.player.sprite.set_flip_x(x_dir < 0.);
         }
 
         let on_ground = self.player.since_on_ground == 0.;
@@ -526,13 +756,14 @@ impl Game {
             self.dust_spawn_timer = 0.;
             if let Some(handle) = self.run_handle.take() {
                 if on_ground {
-                    self.mixer.play(&self.stop_sound, 0.5, false);
+                    self.mixer
+                        .play_debounced(&self.stop_sound, 0.5, false, SOUND_DEBOUNCE_TIME);
                 }
                 self.mixer.set_looping(&handle, false);
             }
         }
 
-        let dust = &mut self.dust;
+        let (dust, dust_puff) = (&mut self.dust, &self.dust_puff);
         let player_position = self.player.position;
         let player_rect = self
             .player
@@ -546,7 +777,8 @@ impl Game {
             dust.push(Dust {
                 position: point2(player_position.x + x_offset, player_rect.min_y()),
                 velocity: Vector2D::from_angle_and_length(a, speed),
-                age: 0.0,
+                animation: dust_puff.clone(),
+                turn: Angle::degrees(90. * rng.gen_range(0, 4) as f32),
             });
         };
         while self.dust_spawn_timer > DUST_SPAWN_TIME {
@@ -569,24 +801,29 @@ impl Game {
         self.player.velocity.x = self.player.velocity.x.min(run_speed).max(-run_speed);
         self.player.velocity.y = self.player.velocity.y.min(fall_speed).max(-fall_speed);
 
-        let jumped = self.controls.since_jump < jump_buffer_time;
+        let jumped = self.since_jump < jump_buffer_time;
         if jumped && self.player.since_on_ground < coyote_time {
             self.mixer.play(&self.jump_sound, 1.0, false);
 
             self.player.velocity.y = jump_speed;
-            self.controls.since_jump = jump_buffer_time;
+            self.since_jump = jump_buffer_time;
             self.player.since_on_ground = coyote_time;
         }
+        if jump_released(&self.input) && self.player.velocity.y > jump_cut_speed {
+            self.player.velocity.y = jump_cut_speed;
+        }
 
         self.player.velocity += vec2(0., gravity) * TICK_DT;
 
         self.player.since_on_ground += TICK_DT;
-        self.controls.since_jump += TICK_DT;
+        self.since_jump += TICK_DT;
 
         // Player collision
         let mut colliding;
+        let solver_tiles = &mut self.solver_tiles;
+        solver_tiles.clear();
 
-        let mut corrections: Vec<Vector2D<f32>> = Vec::new();
+        let mut corrections: Vec<RoomVector> = Vec::new();
         let mut new_pos = self.player.position + self.player.velocity * TICK_DT;
         let mut i = 0;
         loop {
@@ -604,8 +841,11 @@ impl Game {
                 player_rect.size - size2(0.0002, 0.002),
             );
             room.for_each_tile_in_rect(shrunk_player_rect, |pos, tile| {
+                if !solver_tiles.contains(&pos) {
+                    solver_tiles.push(pos);
+                }
                 if tile != Tile::Empty {
-                    let tile_rect = Rect::new(point2(pos.x as f32, pos.y as f32), size2(1., 1.));
+                    let tile_rect = Rect::new(pos.to_f32(), size2(1., 1.));
 
                     // push the player right
                     corrections.push(vec2(tile_rect.max_x() - player_rect.min_x(), 0.));
@@ -683,11 +923,36 @@ impl Game {
             for _ in 0..10 {
                 spawn_dust(2.);
             }
-            self.mixer.play(&self.land_sound, 1.0, false);
+            let color = room_block_colors(self.current_room).border;
+            self.particles.emit(EmitterParams {
+                position: point2(self.player.position.x, player_rect.min_y()),
+                count: 8,
+                velocity_range: (vec2(-3., 0.5), vec2(3., 3.)),
+                lifetime_range: (0.15, 0.35),
+                size_range: (1. / TILE_SIZE, 2. / TILE_SIZE),
+                color_over_life: (color, color.with_alpha(0.)),
+                gravity: vec2(0., -15.),
+                tex_coords: self.white_pixel,
+            });
+            self.mixer
+                .play_debounced(&self.land_sound, 1.0, false, SOUND_DEBOUNCE_TIME);
+            self.player_flash = self.player_flash.max(LAND_FLASH_TIME);
         }
 
         self.player.position = new_pos;
 
+        let state = if self.player.velocity.y > 0. {
+            PlayerState::Jump
+        } else if self.player.velocity.y < 0. {
+            PlayerState::Fall
+        } else if running {
+            PlayerState::Run
+        } else {
+            PlayerState::Idle
+        };
+        self.player.set_state(state);
+        self.player.animation.update(TICK_DT);
+
         // Player block interaction
         let player_interact_rect = self
             .player
@@ -743,6 +1008,7 @@ impl Game {
         });
 
         if entered {
+            self.title_card = None;
             self.mixer.play(&self.enter_sound, 1.0, false);
             if let Some(handle) = self.run_handle.take() {
                 self.mixer.set_looping(&handle, false)
@@ -751,55 +1017,80 @@ impl Game {
     }
 
     pub fn draw(&mut self, context: &mut gl::Context) {
-        unsafe {
-            let bg_color = room_block_colors(self.current_room).background;
-            context.clear(
-                gl::RenderTarget::Screen,
-                [
-                    bg_color.0 as f32 / 255.,
-                    bg_color.1 as f32 / 255.,
-                    bg_color.2 as f32 / 255.,
-                    1.0,
-                ],
-            );
+        if self.dump_atlas {
+            self.dump_atlas = false;
+            let saved = unsafe {
+                graphics::atlas_png(context, self.textures.texture(), self.textures.atlas())
+            }
+            .and_then(|png| platform::save_file(ATLAS_DUMP_FILE, &png));
+            match saved {
+                Ok(()) => log::info!("Saved the texture atlas to {}", ATLAS_DUMP_FILE),
+                Err(e) => log::error!("Could not save the texture atlas: {:?}", e),
+            }
+            // the rects numbered in the order they were added, next to the texture
+            let svg = self.textures.atlas().debug_layout_svg();
+            match platform::save_file(ATLAS_LAYOUT_FILE, svg.as_bytes()) {
+                Ok(()) => log::info!("Saved the atlas layout to {}", ATLAS_LAYOUT_FILE),
+                Err(e) => log::error!("Could not save the atlas layout: {:?}", e),
+            }
         }
 
-        let player_frame = if self.player.velocity.y > 0. {
-            7
-        } else if self.player.velocity.y < 0. {
-            8
-        } else if self.player.animation_timer > 0. {
-            1 + (self.player.animation_timer / RUN_ANIMATION_TIME * 6.).floor() as usize
-        } else {
-            0
-        };
-        let player_x_flip = if self.player.flip { -1. } else { 1. };
+        // the rooms and entities, the next room fading in over them while entering it, and the UI
+        // on top go through separate passes
+        let mut world = unsafe {
+            context.push_debug_group("draw");
+            let render_scale = if self.supersample { 2. } else { 1. };
+            if let Err(e) = context.set_render_scale(render_scale) {
+                log::error!("Could not set render scale: {}", e);
+                self.supersample = false;
+            }
+            self.post_process.begin(context).unwrap();
+            if self.debug_view == DebugView::Wireframe && !context.supports_polygon_mode() {
+                log::warn!("Wireframe needs desktop GL, showing overdraw instead");
+                self.debug_view = DebugView::Overdraw;
+            }
+            context.set_polygon_mode(if self.debug_view == DebugView::Wireframe {
+                gl::PolygonMode::Line
+            } else {
+                gl::PolygonMode::Fill
+            });
+            let overdraw = self.debug_view == DebugView::Overdraw;
+            let overdraw_color = if overdraw {
+                OVERDRAW_COLOR
+            } else {
+                Color::TRANSPARENT
+            };
+            self.program
+                .set_uniform_by_name(
+                    "u_overdraw",
+                    gl::Uniform::Float4(
+                        overdraw_color.r,
+                        overdraw_color.g,
+                        overdraw_color.b,
+                        overdraw_color.a,
+                    ),
+                )
+                .unwrap();
 
-        let mut dust_vertices = Vec::new();
-        for dust in &self.dust {
-            let frame = ((dust.age / DUST_LIFE_TIME) * 3.).floor() as usize;
-            let color = room_block_colors(self.current_room).border;
-            render_sprite(
-                &self.dust_sprite,
-                frame,
-                dust.position,
-                [
-                    color.0 as f32 / 255.,
-                    color.1 as f32 / 255.,
-                    color.2 as f32 / 255.,
-                    1.0,
-                ],
-                &mut dust_vertices,
-            );
-        }
+            let bg_color = room_block_colors(self.current_room).background;
+            let world = context
+                .pass(gl::RenderTarget::Screen)
+                .clear(if overdraw { Color::BLACK } else { bg_color }.to_array());
+            if overdraw {
+                world.blend(gl::BlendMode::Additive)
+            } else {
+                world
+            }
+        };
 
-        let mut entity_vertices = Vec::new();
+        let player_frame = self.player.animation.current_frame();
 
+        self.entity_batch.begin();
         self.program
-            .set_uniform(2, gl::Uniform::Float(1.0))
+            .set_uniform_by_name("u_alpha", gl::Uniform::Float(1.0))
             .unwrap();
 
-        if let Some(enter_room) = &self.enter_room {
+        if let Some(enter_room) = self.enter_room {
             let player_offset = vec2(0.5, -self.player.collision_rect.min_y());
             let room_entrance = self
                 .rooms
@@ -837,383 +1128,744 @@ impl Game {
                     + (room_entrance.to_f32().to_vector() + player_offset) / TILE_SIZE;
                 outside_entrance_pos + (room_entrance_pos - outside_entrance_pos) * r
             };
-            self.player.sprite.set_transform(
-                Transform2D::translation(-7.5, -7.5)
-                    .then_scale(1. / TILE_SIZE * player_x_flip, 1. / TILE_SIZE)
-                    .then_scale(player_scale, player_scale),
-            );
-            render_sprite(
-                &self.player.sprite,
-                player_frame,
-                player_pos,
-                [1., 1., 1., 1.],
-                &mut entity_vertices,
-            );
+            self.queue_entities(player_frame, player_pos, player_scale);
 
             let room_position = enter_room.position.to_f32().to_vector();
 
-            let camera_bl = enter_room.position.to_f32().to_vector() * ratio;
-            let from_camera_tr = point2(ROOM_SIZE.0, ROOM_SIZE.1).to_f32();
-            let to_camera_tr = enter_room.position.to_f32() + vec2(1.0, 1.0);
-            let camera_tr = from_camera_tr + (to_camera_tr - from_camera_tr) * ratio;
-            let camera_scale = ROOM_SIZE.0 as f32 / (camera_tr.x - camera_bl.x);
-            let transform = Transform2D::translation(-camera_bl.x, -camera_bl.y)
-                .then_scale(camera_scale, camera_scale)
-                .then_scale(1.0 / SCREEN_SIZE.0 as f32, 1.0 / SCREEN_SIZE.0 as f32)
-                .then_scale(ZOOM_LEVEL, ZOOM_LEVEL)
-                .then_scale(TILE_SIZE as f32, TILE_SIZE as f32)
-                .then_scale(2., 2.)
-                .then_translate(vec2(-1.0, -1.0));
+            // zooms from the whole current room to the room block being entered
+            let room_camera = room_camera();
+            let block_camera = Camera2D {
+                center: enter_room.position.to_f32() + vec2(0.5, 0.5),
+                zoom: room_camera.zoom * ROOM_SIZE.0 as f32,
+                ..room_camera
+            };
+            let camera = room_camera.lerp(&block_camera, ratio);
             self.program
-                .set_uniform(
-                    0,
-                    gl::Uniform::Mat3([
-                        [transform.m11, transform.m12, 0.0],
-                        [transform.m21, transform.m22, 0.0],
-                        [transform.m31, transform.m32, 1.0],
-                    ]),
-                )
+                .set_uniform_by_name("u_transform", gl::Uniform::Mat3(camera.view_matrix()))
                 .unwrap();
 
             unsafe {
-                self.vertex_buffer.write(&entity_vertices);
-
+                self.draw_background(&mut world);
                 self.program
-                    .set_uniform(
-                        1,
-                        gl::Uniform::Texture(self.room_textures.get(&self.current_room).unwrap()),
+                    .set_uniform_by_name(
+                        "u_texture",
+                        gl::Uniform::Texture(
+                            self.room_textures
+                                .get(&self.current_room)
+                                .unwrap()
+                                .texture(),
+                        ),
                     )
                     .unwrap();
-                self.program
-                    .render_vertices(&self.room_vertex_buffer, gl::RenderTarget::Screen)
-                    .unwrap();
-
-                self.program
-                    .set_uniform(1, gl::Uniform::Texture(&self.atlas_texture))
-                    .unwrap();
-
-                self.program
-                    .render_vertices(&self.vertex_buffer, gl::RenderTarget::Screen)
+                world
+                    .draw_indexed_range(
+                        &self.program,
+                        &self.room_vertex_buffer,
+                        &self.room_index_buffer,
+                        0,
+                        6,
+                    )
                     .unwrap();
 
-                self.vertex_buffer.write(&dust_vertices);
-                self.program
-                    .render_vertices(&self.vertex_buffer, gl::RenderTarget::Screen)
+                self.entity_queue.sort_and_flush(&mut self.entity_batch);
+                self.entity_batch
+                    .flush(&mut self.program, &mut world)
                     .unwrap();
 
                 let alpha = ((ratio - 0.5) / 0.5).max(0.0);
                 self.program
-                    .set_uniform(2, gl::Uniform::Float(alpha))
+                    .set_uniform_by_name("u_alpha", gl::Uniform::Float(alpha))
                     .unwrap();
 
-                let sub_room_transform =
-                    Transform2D::scale(1. / ROOM_SIZE.0 as f32, 1. / ROOM_SIZE.1 as f32)
-                        .then_translate(room_position)
-                        .then(&transform);
-                self.program
-                    .set_uniform(
-                        0,
-                        gl::Uniform::Mat3([
-                            [sub_room_transform.m11, sub_room_transform.m12, 0.0],
-                            [sub_room_transform.m21, sub_room_transform.m22, 0.0],
-                            [sub_room_transform.m31, sub_room_transform.m32, 1.0],
-                        ]),
-                    )
+                // places the next room inside its block in the current room, only its quad of
+                // the room buffer changes
+                let block = Box2D::new(
+                    room_position.to_point(),
+                    (room_position + vec2(1., 1.)).to_point(),
+                );
+                self.room_vertex_buffer
+                    .write_at(4, &room_quad(block))
                     .unwrap();
 
                 self.program
-                    .set_uniform(
-                        1,
+                    .set_uniform_by_name(
+                        "u_texture",
                         gl::Uniform::Texture(
-                            self.room_textures.get(&enter_room.color).as_ref().unwrap(),
+                            self.room_textures.get(&enter_room.color).unwrap().texture(),
                         ),
                     )
                     .unwrap();
-                self.program
-                    .render_vertices(&self.room_vertex_buffer, gl::RenderTarget::Screen)
+                let mut overlay = context.pass(gl::RenderTarget::Screen);
+                if self.debug_view == DebugView::Overdraw {
+                    overlay = overlay.blend(gl::BlendMode::Additive);
+                }
+                overlay
+                    .draw_indexed_range(
+                        &self.program,
+                        &self.room_vertex_buffer,
+                        &self.room_index_buffer,
+                        6,
+                        6,
+                    )
                     .unwrap();
             }
         } else {
-            let transform =
-                Transform2D::scale(1.0 / SCREEN_SIZE.0 as f32, 1.0 / SCREEN_SIZE.0 as f32)
-                    .then_scale(ZOOM_LEVEL, ZOOM_LEVEL)
-                    .then_scale(TILE_SIZE as f32, TILE_SIZE as f32)
-                    .then_scale(2., 2.)
-                    .then_translate(vec2(-1.0, -1.0));
             self.program
-                .set_uniform(
-                    0,
-                    gl::Uniform::Mat3([
-                        [transform.m11, transform.m12, 0.0],
-                        [transform.m21, transform.m22, 0.0],
-                        [transform.m31, transform.m32, 1.0],
-                    ]),
+                .set_uniform_by_name(
+                    "u_transform",
+                    gl::Uniform::Mat3(room_camera().view_matrix()),
                 )
                 .unwrap();
 
-            self.player.sprite.set_transform(
-                Transform2D::translation(-7.5, -7.5)
-                    .then_scale(1. / TILE_SIZE * player_x_flip, 1. / TILE_SIZE),
-            );
-            render_sprite(
-                &self.player.sprite,
-                player_frame,
-                self.player.position,
-                [1., 1., 1., 1.],
-                &mut entity_vertices,
-            );
+            self.player
+                .sprite
+                .set_flash(Color::WHITE, self.player_flash / PLAYER_FLASH_TIME);
+            self.queue_room_block_pulse();
+            self.queue_entities(player_frame, self.player.position, 1.);
+            if self.show_collision {
+                self.queue_collision_overlay();
+            }
 
             unsafe {
-                self.vertex_buffer.write(&entity_vertices);
+                self.draw_background(&mut world);
                 self.program
-                    .set_uniform(1, gl::Uniform::Texture(&self.atlas_texture))
-                    .unwrap();
-                self.program
-                    .render_vertices(&self.vertex_buffer, gl::RenderTarget::Screen)
-                    .unwrap();
-
-                self.vertex_buffer.write(&dust_vertices);
-                self.program
-                    .render_vertices(&self.vertex_buffer, gl::RenderTarget::Screen)
-                    .unwrap();
-
-                self.program
-                    .set_uniform(
-                        1,
+                    .set_uniform_by_name(
+                        "u_texture",
                         gl::Uniform::Texture(
-                            self.room_textures.get(&self.current_room).as_ref().unwrap(),
+                            self.room_textures
+                                .get(&self.current_room)
+                                .unwrap()
+                                .texture(),
                         ),
                     )
                     .unwrap();
-                self.program
-                    .render_vertices(&self.room_vertex_buffer, gl::RenderTarget::Screen)
+                world
+                    .draw_indexed_range(
+                        &self.program,
+                        &self.room_vertex_buffer,
+                        &self.room_index_buffer,
+                        0,
+                        6,
+                    )
+                    .unwrap();
+
+                self.entity_queue.sort_and_flush(&mut self.entity_batch);
+                self.entity_batch
+                    .flush(&mut self.program, &mut world)
                     .unwrap();
             }
         }
 
-        let mut ui_vertices = Vec::new();
+        self.ui.begin();
 
-        render_sprite(
+        // pulse the music icon with the output level
+        let (left_level, right_level) = self.mixer.peak_levels();
+        let icon_scale = UI_ZOOM * (1. + MUTE_ICON_PULSE * left_level.max(right_level));
+        // also shown as muted while the browser is blocking audio until the first click
+        let show_muted = self.muted || !platform::audio_running();
+        self.mute_icon.set_color(if show_muted {
+            MUTED_ICON_COLOR
+        } else {
+            Color::WHITE
+        });
+        // drawn in the current room's colors
+        self.ui
+            .set_palette_swap(Some(PaletteSwap::from_room_color(self.current_room)));
+        self.ui.draw_sprite_with(
             &self.mute_icon,
-            if self.muted { 0 } else { 1 },
+            if show_muted { 0 } else { 1 },
             self.mute_icon_rect.min(),
-            [1., 1., 1., 1.],
-            &mut ui_vertices,
+            &Transform2D::scale(icon_scale, icon_scale),
+            Color::WHITE,
         );
+        self.ui.set_palette_swap(None);
+        if let Some(title_card) = &self.title_card {
+            let panel_color = room_block_colors(self.current_room).inner;
+            title_card.draw(&self.font, self.panel, panel_color, &mut self.ui);
+        }
         unsafe {
+            // the UI is always drawn normally
+            context.set_polygon_mode(gl::PolygonMode::Fill);
             self.program
-                .set_uniform(1, gl::Uniform::Texture(&self.atlas_texture))
+                .set_uniform_by_name("u_overdraw", gl::Uniform::Float4(0., 0., 0., 0.))
                 .unwrap();
-
-            let transform =
-                Transform2D::scale(1.0 / SCREEN_SIZE.0 as f32, 1.0 / SCREEN_SIZE.0 as f32)
-                    .then_scale(2., 2.)
-                    .then_translate(vec2(-1.0, -1.0));
-            self.program
-                .set_uniform(
-                    0,
-                    gl::Uniform::Mat3([
-                        [transform.m11, transform.m12, 0.0],
-                        [transform.m21, transform.m22, 0.0],
-                        [transform.m31, transform.m32, 1.0],
-                    ]),
-                )
-                .unwrap();
-            self.ui_buffer.write(&ui_vertices);
-            self.program
-                .render_vertices(&self.ui_buffer, gl::RenderTarget::Screen)
+            let mut ui = context.pass(gl::RenderTarget::Screen);
+            self.ui
+                .flush(&mut self.program, &mut self.palette_program, &mut ui)
                 .unwrap();
+            self.post_process.set_time(self.time).unwrap();
+            self.post_process.finish(context).unwrap();
+            context.pop_debug_group();
         }
     }
 }
 
-struct TileImages {
-    // top left
-    tl_outer_corner: TextureRect,
-    tl_horz: TextureRect,
-    tl_vert: TextureRect,
-    tl_inner_corner: TextureRect,
-    tl_solid: TextureRect,
-
-    // top right
-    tr_outer_corner: TextureRect,
-    tr_horz: TextureRect,
-    tr_vert: TextureRect,
-    tr_inner_corner: TextureRect,
-    tr_solid: TextureRect,
-
-    // bottom left
-    bl_outer_corner: TextureRect,
-    bl_horz: TextureRect,
-    bl_vert: TextureRect,
-    bl_inner_corner: TextureRect,
-    bl_solid: TextureRect,
-
-    // bottom right
-    br_outer_corner: TextureRect,
-    br_horz: TextureRect,
-    br_vert: TextureRect,
-    br_inner_corner: TextureRect,
-    br_solid: TextureRect,
-}
+impl Game {
+    // A gradient behind the current room, lighter towards the top, with the program's current
+    // transform. The entity batch has to be empty, since it is flushed for this.
+    unsafe fn draw_background(&mut self, pass: &mut gl::RenderPass) {
+        let colors = room_block_colors(self.current_room);
+        let top = colors.background.lerp(Color::WHITE, BACKGROUND_GRADIENT);
+        let bottom = colors.background.lerp(colors.inner, BACKGROUND_GRADIENT);
+        graphics::render_rect_gradient(
+            Box2D::<f32, RoomSpace>::new(
+                point2(0., 0.),
+                point2(ROOM_SIZE.0 as f32, ROOM_SIZE.1 as f32),
+            ),
+            self.white_pixel,
+            [bottom, bottom, top, top],
+            &mut self.entity_batch,
+        );
+        self.entity_batch.flush(&mut self.program, pass).unwrap();
+    }
 
-impl TileImages {
-    pub fn new(tex: TextureRect) -> TileImages {
-        let to_origin = vec2(tex[0], tex[1]);
-        let tl_rect = Rect::new(point2(0, 0) + to_origin, size2(8, 8));
-        let tr_rect = Rect::new(point2(8, 0) + to_origin, size2(7, 8));
-        let bl_rect = Rect::new(point2(0, 8) + to_origin, size2(8, 7));
-        let br_rect = Rect::new(point2(8, 8) + to_origin, size2(7, 7));
-        let to_texture_rect = |rect: Rect<u32>| -> TextureRect {
-            [rect.min_x(), rect.min_y(), rect.max_x(), rect.max_y()]
+    // The player scaled by `player_scale`, then dust and particles over it. Snapped to the room's
+    // art pixels unless the camera is zooming into a room block.
+    fn queue_entities(&mut self, player_frame: usize, player_pos: RoomPoint, player_scale: f32) {
+        let pixel_snap = if self.pixel_snap && self.enter_room.is_none() {
+            Some(TILE_SIZE)
+        } else {
+            None
         };
-
-        TileImages {
-            tl_outer_corner: to_texture_rect(tl_rect),
-            tl_horz: to_texture_rect(tl_rect.translate(vec2(15, 0))),
-            tl_vert: to_texture_rect(tl_rect.translate(vec2(30, 0))),
-            tl_inner_corner: to_texture_rect(tl_rect.translate(vec2(45, 0))),
-            tl_solid: to_texture_rect(tl_rect.translate(vec2(60, 0))),
-
-            tr_outer_corner: to_texture_rect(tr_rect),
-            tr_horz: to_texture_rect(tr_rect.translate(vec2(15, 0))),
-            tr_vert: to_texture_rect(tr_rect.translate(vec2(30, 0))),
-            tr_inner_corner: to_texture_rect(tr_rect.translate(vec2(45, 0))),
-            tr_solid: to_texture_rect(tr_rect.translate(vec2(60, 0))),
-
-            bl_outer_corner: to_texture_rect(bl_rect),
-            bl_horz: to_texture_rect(bl_rect.translate(vec2(15, 0))),
-            bl_vert: to_texture_rect(bl_rect.translate(vec2(30, 0))),
-            bl_inner_corner: to_texture_rect(bl_rect.translate(vec2(45, 0))),
-            bl_solid: to_texture_rect(bl_rect.translate(vec2(60, 0))),
-
-            br_outer_corner: to_texture_rect(br_rect),
-            br_horz: to_texture_rect(br_rect.translate(vec2(15, 0))),
-            br_vert: to_texture_rect(br_rect.translate(vec2(30, 0))),
-            br_inner_corner: to_texture_rect(br_rect.translate(vec2(45, 0))),
-            br_solid: to_texture_rect(br_rect.translate(vec2(60, 0))),
+        let player_sprite = &self.player.sprite;
+        self.entity_queue.push(Z_PLAYER, |out| match pixel_snap {
+            Some(pixels_per_unit) => graphics::render_sprite_snapped(
+                player_sprite,
+                player_frame,
+                SpritePosition::Room(player_pos),
+                pixels_per_unit,
+                out,
+            ),
+            None => graphics::render_sprite_with(
+                player_sprite,
+                player_frame,
+                SpritePosition::Room(player_pos),
+                &Transform2D::scale(player_scale / TILE_SIZE, player_scale / TILE_SIZE),
+                Color::WHITE,
+                out,
+            ),
+        });
+        self.dust_sprite
+            .set_color(room_block_colors(self.current_room).border);
+        let (dust, dust_sprite) = (&self.dust, &self.dust_sprite);
+        self.entity_queue.push(Z_PARTICLES, |out| {
+            render_dust(dust, dust_sprite, pixel_snap, out)
+        });
+        let particles = &self.particles;
+        self.entity_queue
+            .push(Z_PARTICLES, |out| particles.render(out));
+    }
+
+    // Enterable room blocks the player can reach pulse toward white, drawn again over the baked
+    // room
+    fn queue_room_block_pulse(&mut self) {
+        let room = self.rooms.get(&self.current_room).unwrap();
+        let interact_rect = self
+            .player
+            .interact_rect
+            .translate(self.player.position.to_vector());
+        let rooms = &self.rooms;
+        let mut blocks = Vec::new();
+        room.for_each_tile_in_rect(interact_rect, |pos, tile| {
+            if let Tile::Room(color) = tile {
+                let block = rooms.get(&color).unwrap();
+                if block.left_entrance.is_some()
+                    || block.top_entrance.is_some()
+                    || block.right_entrance.is_some()
+                {
+                    blocks.push((pos, color));
+                }
+            }
+        });
+
+        let pulse = 0.5 - 0.5 * (self.time * ROOM_BLOCK_PULSE_RATE * std::f32::consts::TAU).cos();
+        for (_, color) in &blocks {
+            self.room_block_sprites
+                .get_mut(color)
+                .unwrap()
+                .set_flash(Color::WHITE, ROOM_BLOCK_PULSE * pulse);
         }
+        let sprites = &self.room_block_sprites;
+        self.entity_queue.push(Z_ROOM_BLOCKS, |out| {
+            for (pos, color) in blocks {
+                graphics::render_sprite(
+                    sprites.get(&color).unwrap(),
+                    0,
+                    SpritePosition::Room(pos.to_f32()),
+                    out,
+                );
+            }
+        });
     }
-}
 
-fn build_room_vertex_buffer(
-    gl_context: &mut gl::Context,
-    room_block_textures: &HashMap<RoomColor, TextureRect>,
-    room_color: RoomColor,
-    room: &Room,
-    tile_images: &TileImages,
-) -> gl::VertexBuffer {
-    let mut vertices: Vec<Vertex> = Vec::with_capacity(ROOM_CELLS as usize * 4 * 4);
-    let get_tile = |x: i32, y: i32| -> Tile {
-        if x < 0 || x >= ROOM_SIZE.0 as i32 || y < 0 || y >= ROOM_SIZE.1 as i32 {
-            Tile::Solid
-        } else {
-            let cell = (y as u32 * ROOM_SIZE.0 + x as u32) as usize;
-            room.tiles[cell]
+    fn queue_collision_overlay(&mut self) {
+        let thickness = 1. / TILE_SIZE;
+        let white = self.white_pixel;
+        let enter_regions = self.enter_regions();
+        let solver_tiles = &self.solver_tiles;
+        let hovered_tile = self.hovered_tile;
+        let player_offset = self.player.position.to_vector();
+        let interact_rect = self.player.interact_rect.translate(player_offset);
+        let collision_rect = self.player.collision_rect.translate(player_offset);
+        let velocity = self.player.velocity;
+        self.entity_queue.push(Z_DEBUG, |out| {
+            for tile in solver_tiles {
+                graphics::render_rect_filled(
+                    Box2D::new(tile.to_f32(), tile.to_f32() + vec2(1., 1.)),
+                    white,
+                    Color::rgba(0.25, 0.25, 0., 0.25),
+                    out,
+                );
+            }
+            if let Some(tile) = hovered_tile {
+                graphics::render_rect_filled(
+                    Box2D::new(tile.to_f32(), tile.to_f32() + vec2(1., 1.)),
+                    white,
+                    Color::rgba(0.25, 0.25, 0.25, 0.25),
+                    out,
+                );
+            }
+            for region in enter_regions {
+                graphics::render_rect_outline(
+                    region.to_box2d(),
+                    thickness,
+                    white,
+                    Color::rgba(0., 1., 0., 1.),
+                    out,
+                );
+            }
+            graphics::render_rect_outline(
+                interact_rect.to_box2d(),
+                thickness,
+                white,
+                Color::rgba(0., 0.5, 1., 1.),
+                out,
+            );
+            graphics::render_rect_outline(
+                collision_rect.to_box2d(),
+                thickness,
+                white,
+                Color::rgba(1., 0., 0., 1.),
+                out,
+            );
+            // where the player would be in VELOCITY_LINE_TIME at this speed
+            let center = collision_rect.center();
+            graphics::render_line(
+                center,
+                center + velocity * VELOCITY_LINE_TIME,
+                thickness,
+                white,
+                Color::rgba(1., 1., 0., 1.),
+                out,
+            );
+        });
+    }
+
+    // Where standing enters a room block of the current room, for the collision overlay
+    fn enter_regions(&self) -> Vec<RoomRect> {
+        let room = self.rooms.get(&self.current_room).unwrap();
+        let mut regions = Vec::new();
+        for (cell, tile) in room.tiles.iter().enumerate() {
+            if let Tile::Room(color) = tile {
+                let pos = point2(
+                    (cell as u32 % ROOM_SIZE.0) as f32,
+                    (cell as u32 / ROOM_SIZE.0) as f32,
+                );
+                let block = self.rooms.get(color).unwrap();
+                for (entrance, offset) in &[
+                    (block.left_entrance, vec2(-1., 0.)),
+                    (block.top_entrance, vec2(0., 1.)),
+                    (block.right_entrance, vec2(1., 0.)),
+                ] {
+                    if entrance.is_some() {
+                        regions.push(Rect::new(pos + *offset, size2(1., 1.)));
+                    }
+                }
+            }
         }
+        regions
+    }
+
+    pub fn snapshot(&self) -> GameSnapshot {
+        GameSnapshot {
+            room: self.current_room,
+            breadcrumb: self.breadcrumb.clone(),
+            player_position: self.player.position,
+            playtime: self.playtime,
+        }
+    }
+
+    /// Puts the player back where `snapshot` was taken, standing still. Meant for a game that
+    /// was just created.
+    pub fn restore(&mut self, snapshot: &GameSnapshot) {
+        self.current_room = snapshot.room;
+        self.breadcrumb = snapshot.breadcrumb.clone();
+        self.player.position = snapshot.player_position;
+        self.player.velocity = Vector2D::zero();
+        self.playtime = snapshot.playtime;
+    }
+
+    fn room_display(&self, color: RoomColor) -> RoomDisplay {
+        room_display(self.rooms.get(&color).unwrap(), color, &self.strings)
+    }
+}
+
+// A room's name and flavor text as resolved through `strings`. Rooms without a name, or with a
+// key that's missing, go by their color instead.
+fn room_display(room: &Room, color: RoomColor, strings: &Strings) -> RoomDisplay {
+    let resolve = |text: &Option<Text>| {
+        text.as_ref()
+            .and_then(|text| text.resolve(strings))
+            .map(str::to_owned)
     };
+    RoomDisplay {
+        // the enum name is already a capitalized color
+        name: resolve(&room.name).unwrap_or_else(|| format!("{:?}", color)),
+        flavor: resolve(&room.flavor),
+    }
+}
 
-    let colors = room_block_colors(room_color);
-    let v_color = [
-        colors.inner.0 as f32 / 255.,
-        colors.inner.1 as f32 / 255.,
-        colors.inner.2 as f32 / 255.,
-        1.0,
-    ];
+/// What the player picked on the `ErrorScreen`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorChoice {
+    Restore,
+    Quit,
+}
 
-    let mut room_blocks = Vec::new();
-    for (cell, tile) in room.tiles.iter().enumerate() {
-        let y = (cell as u32 / ROOM_SIZE.0) as i32;
-        let x = (cell as u32 % ROOM_SIZE.0) as i32;
-        if *tile == Tile::Empty {
-            continue;
-        }
+/// Shown instead of the game after it panicked in `--resilient` mode, saying what happened and
+/// offering to restore the game or quit. Only needs GL, so it works whatever state the game was
+/// left in.
+pub struct ErrorScreen {
+    program: gl::Program,
+    palette_program: gl::Program,
+    ui: UiLayer,
+    font: Font,
+    panel: TextureRect,
+    input: InputState,
+    text: String,
+    can_restore: bool,
+}
 
-        // draw room blocks later
-        match tile {
-            Tile::Room(color) => {
-                room_blocks.push(((x, y), color));
-                continue;
-            }
-            _ => {}
-        }
-
-        let (tl, t, tr, l, r, bl, b, br) = (
-            get_tile(x - 1, y + 1) == Tile::Solid,
-            get_tile(x, y + 1) == Tile::Solid,
-            get_tile(x + 1, y + 1) == Tile::Solid,
-            get_tile(x - 1, y) == Tile::Solid,
-            get_tile(x + 1, y) == Tile::Solid,
-            get_tile(x - 1, y - 1) == Tile::Solid,
-            get_tile(x, y - 1) == Tile::Solid,
-            get_tile(x + 1, y - 1) == Tile::Solid,
+impl ErrorScreen {
+    /// `dump_file` is where the flight recorder's dump was saved, if it could be.
+    pub fn new(
+        gl_context: &mut gl::Context,
+        crash: &Crash,
+        dump_file: Option<&str>,
+    ) -> ErrorScreen {
+        let program = create_sprite_program(gl_context, false);
+        let palette_program = create_sprite_program(gl_context, true);
+        let mut atlas = TextureAtlas::new(ERROR_ATLAS_SIZE, ATLAS_PADDING);
+        let mut texture = unsafe {
+            gl_context
+                .create_texture(
+                    color_texture_format(gl_context),
+                    ERROR_ATLAS_SIZE.0,
+                    ERROR_ATLAS_SIZE.1,
+                    gl::TextureFilter::NEAREST,
+                )
+                .unwrap()
+        };
+        let images = [
+            BatchImage::Encoded(include_bytes!("../assets/font.png")),
+            BatchImage::Encoded(include_bytes!("../assets/panel.png")),
+        ];
+        let rects = unsafe { graphics::load_images_batch(&images, &mut atlas, &mut texture) }
+            .expect("Could not load the error screen textures");
+        let ui = unsafe { UiLayer::new(gl_context, Rc::new(texture)) }.unwrap();
+        let font = Font::monospace(
+            rects[0],
+            FONT_CHARS,
+            size2(FONT_GLYPH_SIZE.0, FONT_GLYPH_SIZE.1),
         );
 
-        let rect = Box2D::new(
-            point2(x as f32, y as f32),
-            point2((x + 1) as f32, (y + 1) as f32),
-        );
-        let mid = Point2D::new(x as f32 + (8. / TILE_SIZE), y as f32 + (7. / TILE_SIZE));
-
-        // top left rect
-        let tl_box = Box2D::new(point2(rect.min.x, mid.y), point2(mid.x, rect.max.y));
-        if !tl && t && l {
-            graphics::render_quad(tl_box, tile_images.tl_inner_corner, v_color, &mut vertices);
-        } else if !l && !t {
-            graphics::render_quad(tl_box, tile_images.tl_outer_corner, v_color, &mut vertices);
-        } else if l && !t {
-            graphics::render_quad(tl_box, tile_images.tl_horz, v_color, &mut vertices);
-        } else if !l && t {
-            graphics::render_quad(tl_box, tile_images.tl_vert, v_color, &mut vertices);
-        } else {
-            graphics::render_quad(tl_box, tile_images.tl_solid, v_color, &mut vertices);
-        }
-
-        // top right rect
-        let tr_box = Box2D::new(point2(mid.x, mid.y), rect.max);
-        if !tr && t && r {
-            graphics::render_quad(tr_box, tile_images.tr_inner_corner, v_color, &mut vertices);
-        } else if !r && !t {
-            graphics::render_quad(tr_box, tile_images.tr_outer_corner, v_color, &mut vertices);
-        } else if r && !t {
-            graphics::render_quad(tr_box, tile_images.tr_horz, v_color, &mut vertices);
-        } else if !r && t {
-            graphics::render_quad(tr_box, tile_images.tr_vert, v_color, &mut vertices);
-        } else {
-            graphics::render_quad(tr_box, tile_images.tr_solid, v_color, &mut vertices);
-        }
-
-        // bottom left rect
-        let bl_box = Box2D::new(rect.min, mid);
-        if !bl && b & l {
-            graphics::render_quad(bl_box, tile_images.bl_inner_corner, v_color, &mut vertices);
-        } else if !l && !b {
-            graphics::render_quad(bl_box, tile_images.bl_outer_corner, v_color, &mut vertices);
-        } else if l && !b {
-            graphics::render_quad(bl_box, tile_images.bl_horz, v_color, &mut vertices);
-        } else if !l && b {
-            graphics::render_quad(bl_box, tile_images.bl_vert, v_color, &mut vertices);
+        let saved = match dump_file {
+            Some(file) => format!("What happened is saved in {}.", file),
+            None => "What happened could not be saved.".to_owned(),
+        };
+        let choices = if crash.can_restore {
+            "R: restore   Q: quit"
         } else {
-            graphics::render_quad(bl_box, tile_images.bl_solid, v_color, &mut vertices);
-        }
-
-        // bottom right rect
-        let br_box = Box2D::new(point2(mid.x, rect.min.y), point2(rect.max.x, mid.y));
-        if !br && b & r {
-            graphics::render_quad(br_box, tile_images.br_inner_corner, v_color, &mut vertices);
-        } else if !r && !b {
-            graphics::render_quad(br_box, tile_images.br_outer_corner, v_color, &mut vertices);
-        } else if r && !b {
-            graphics::render_quad(br_box, tile_images.br_horz, v_color, &mut vertices);
-        } else if !r && b {
-            graphics::render_quad(br_box, tile_images.br_vert, v_color, &mut vertices);
+            "It broke again right after restoring.\n\nQ: quit"
+        };
+        let text = format!(
+            "Something went wrong:\n{}\n\n{}\n\n{}",
+            strings::truncate(&crash.message, ERROR_MESSAGE_CHARS),
+            saved,
+            choices
+        );
+        ErrorScreen {
+            program,
+            palette_program,
+            ui,
+            font,
+            panel: rects[1],
+            input: InputState::default(),
+            text: text.to_uppercase(),
+            can_restore: crash.can_restore,
+        }
+    }
+
+    pub fn update(&mut self, inputs: &[InputEvent]) -> Option<ErrorChoice> {
+        self.input.begin_tick();
+        for event in inputs {
+            self.input.handle_event(event);
+        }
+        let input = &self.input;
+        if self.can_restore
+            && (input.key_pressed(Key::R) || input.button_pressed(GamepadButton::South))
+        {
+            Some(ErrorChoice::Restore)
+        } else if input.key_pressed(Key::Q)
+            || input.key_pressed(Key::Escape)
+            || input.button_pressed(GamepadButton::East)
+        {
+            Some(ErrorChoice::Quit)
         } else {
-            graphics::render_quad(br_box, tile_images.br_solid, v_color, &mut vertices);
+            None
         }
     }
 
-    for ((x, y), color) in room_blocks {
-        let room_block_box = Box2D::new(
+    pub fn draw(&mut self, context: &mut gl::Context) {
+        let layout = TextLayout {
+            align: TextAlign::Center,
+            max_width: Some(ERROR_TEXT_WIDTH),
+            scale: UI_ZOOM,
+            ..TextLayout::default()
+        };
+        let text_size = layout.measure(&self.font, &self.text);
+        let rect = UiLayer::anchored(
+            Anchor::Center,
+            vec2(0., 0.),
+            size2(ERROR_TEXT_WIDTH, text_size.height) + size2(2., 2.) * TITLE_CARD_PADDING,
+        );
+        self.ui.begin();
+        self.ui
+            .draw_nine_slice(rect, self.panel, PANEL_BORDER, UI_ZOOM, ERROR_PANEL_COLOR);
+        let top_left = point2(
+            rect.min_x() + TITLE_CARD_PADDING,
+            rect.max_y() - TITLE_CARD_PADDING,
+        );
+        self.ui
+            .draw_text(&layout, &self.font, &self.text, top_left, Color::WHITE);
+
+        unsafe {
+            // a panic in the middle of drawing the game can leave these set
+            context.redirect_screen(None);
+            if let Err(e) = context.set_render_scale(1.) {
+                log::error!("Could not set render scale: {}", e);
+            }
+            context.set_polygon_mode(gl::PolygonMode::Fill);
+
+            let mut pass = context
+                .pass(gl::RenderTarget::Screen)
+                .clear(Color::BLACK.to_array());
+            self.ui
+                .flush(&mut self.program, &mut self.palette_program, &mut pass)
+                .unwrap();
+        }
+    }
+}
+
+// Format for the atlas and room textures. With sRGB on and GL doing the conversions they are
+// stored as sRGB, otherwise the shader converts and they keep the sRGB values as they are.
+fn color_texture_format(gl_context: &gl::Context) -> gl::TextureFormat {
+    if gl_context.srgb() && gl_context.srgb_framebuffer() {
+        gl::TextureFormat::SRGBA
+    } else {
+        gl::TextureFormat::RGBA8
+    }
+}
+
+/// The regular in-room view, where the room fills the screen.
+fn room_camera() -> Camera2D<RoomSpace> {
+    Camera2D::new(
+        point2(ROOM_SIZE.0 as f32 / 2., ROOM_SIZE.1 as f32 / 2.),
+        TILE_SIZE * ZOOM_LEVEL,
+        size2(SCREEN_SIZE.0 as f32, SCREEN_SIZE.1 as f32),
+    )
+}
+
+// A quad showing a whole room texture over `rect`
+fn room_quad(rect: Box2D<f32, RoomSpace>) -> [Vertex; 4] {
+    let corner = |x: f32, y: f32| Vertex {
+        position: [
+            lerp(x, rect.min.x, rect.max.x),
+            lerp(y, rect.min.y, rect.max.y),
+        ],
+        uv: [x, y],
+        color: Color::WHITE.to_array(),
+        flash: [0.; 4],
+    };
+    [
+        corner(0., 0.),
+        corner(1., 0.),
+        corner(0., 1.),
+        corner(1., 1.),
+    ]
+}
+
+// The dust sprite's origin is on a whole pixel in its middle, so snapping its position snaps the
+// whole quad, turned or not
+fn render_dust<S: QuadSink>(dust: &[Dust], sprite: &Sprite, pixel_snap: Option<f32>, out: &mut S) {
+    for dust in dust {
+        let frame = dust.animation.current_frame();
+        let position = match pixel_snap {
+            Some(pixels_per_unit) => graphics::snap_to_pixel_grid(dust.position, pixels_per_unit),
+            None => dust.position,
+        };
+        let position = SpritePosition::Room(position);
+        graphics::render_sprite_rotated(sprite, frame, position, dust.turn, out);
+    }
+}
+
+// The program everything is drawn with, or its variant that recolors sprites from u_palette, see
+// graphics::PaletteSwap
+fn create_sprite_program(gl_context: &mut gl::Context, palette: bool) -> gl::Program {
+    let vertex_shader = unsafe {
+        gl_context
+            .create_shader(gl::ShaderType::Vertex, include_str!("shaders/shader.vert"))
+            .unwrap()
+    };
+    let palette_size = PALETTE_SIZE.to_string();
+    let mut defines = Vec::new();
+    if palette {
+        defines.push(("PALETTE", palette_size.as_str()));
+        if gl_context.srgb() && gl_context.srgb_framebuffer() {
+            defines.push(("LINEAR_SAMPLES", "1"));
+        }
+    }
+    if gl_context.srgb() && !gl_context.srgb_framebuffer() {
+        defines.push(("SRGB", "1"));
+    }
+    let fragment_shader = unsafe {
+        gl_context
+            .create_shader_with_defines(
+                gl::ShaderType::Fragment,
+                include_str!("shaders/shader.frag"),
+                &defines,
+            )
+            .unwrap()
+    };
+
+    let mut uniforms = vec![
+        gl::UniformEntry {
+            name: "u_transform",
+            ty: gl::UniformType::Mat3,
+            array_len: 0,
+            default: Some(gl::Uniform::Mat3([
+                [1., 0., 0.],
+                [0., 1., 0.],
+                [0., 0., 1.],
+            ])),
+        },
+        gl::UniformEntry {
+            name: "u_texture",
+            ty: gl::UniformType::Texture,
+            array_len: 0,
+            default: None,
+        },
+        gl::UniformEntry {
+            name: "u_alpha",
+            ty: gl::UniformType::Float,
+            array_len: 0,
+            default: Some(gl::Uniform::Float(1.0)),
+        },
+        gl::UniformEntry {
+            name: "u_overdraw",
+            ty: gl::UniformType::Float4,
+            array_len: 0,
+            default: Some(gl::Uniform::Float4(0., 0., 0., 0.)),
+        },
+    ];
+    if palette {
+        uniforms.push(gl::UniformEntry {
+            name: "u_palette",
+            ty: gl::UniformType::Float4Array,
+            array_len: PALETTE_SIZE,
+            default: None,
+        });
+    }
+
+    unsafe {
+        gl_context
+            .create_program(&gl::ProgramDescriptor {
+                vertex_shader: &vertex_shader,
+                fragment_shader: &fragment_shader,
+                uniforms: &uniforms,
+                vertex_format: gl::VertexFormat {
+                    stride: std::mem::size_of::<Vertex>(),
+                    instance_stride: 0,
+                    attributes: &[
+                        gl::VertexAttribute {
+                            name: "a_pos",
+                            ty: gl::VertexAttributeType::Float,
+                            size: 2,
+                            offset: 0,
+                            normalized: false,
+                            integer: false,
+                            divisor: 0,
+                        },
+                        gl::VertexAttribute {
+                            name: "a_uv",
+                            ty: gl::VertexAttributeType::Float,
+                            size: 2,
+                            offset: 2 * 4,
+                            normalized: false,
+                            integer: false,
+                            divisor: 0,
+                        },
+                        gl::VertexAttribute {
+                            name: "a_color",
+                            ty: gl::VertexAttributeType::Float,
+                            size: 4,
+                            offset: 4 * 4,
+                            normalized: false,
+                            integer: false,
+                            divisor: 0,
+                        },
+                        gl::VertexAttribute {
+                            name: "a_flash",
+                            ty: gl::VertexAttributeType::Float,
+                            size: 4,
+                            offset: 8 * 4,
+                            normalized: false,
+                            integer: false,
+                            divisor: 0,
+                        },
+                    ],
+                },
+            })
+            .unwrap()
+    }
+}
+
+fn build_room_quads(
+    room_block_textures: &HashMap<RoomColor, TextureRect>,
+    room_color: RoomColor,
+    room: &Room,
+    tile_sheet: &AutotileSheet,
+    out: &mut QuadBatcher,
+) {
+    let mut tilemap = Tilemap::new(
+        size2(ROOM_SIZE.0, ROOM_SIZE.1),
+        room.tiles.to_vec(),
+        tile_sheet.clone(),
+        |tile| *tile == Tile::Solid,
+    );
+    tilemap.set_color(room_block_colors(room_color).inner);
+    tilemap.set_uv_inset(TILE_UV_INSET);
+    tilemap.render(out);
+
+    // room blocks go over the walls around them
+    for (cell, tile) in room.tiles.iter().enumerate() {
+        let color = match tile {
+            Tile::Room(color) => color,
+            _ => continue,
+        };
+        let x = (cell as u32 % ROOM_SIZE.0) as i32;
+        let y = (cell as u32 / ROOM_SIZE.0) as i32;
+        let room_block_box: Box2D<f32, RoomSpace> = Box2D::new(
             point2(x as f32 - 1. / TILE_SIZE, y as f32 - 1. / TILE_SIZE),
             point2(
                 (x + 1) as f32 + 1. / TILE_SIZE,
@@ -1223,16 +1875,40 @@ fn build_room_vertex_buffer(
         graphics::render_quad(
             room_block_box,
             *room_block_textures.get(color).unwrap(),
-            [1., 1., 1., 1.],
-            &mut vertices,
+            Color::WHITE,
+            out,
         );
     }
+}
 
-    unsafe {
-        let mut buffer = gl_context.create_vertex_buffer().unwrap();
-        buffer.write(&vertices);
-        buffer
-    }
+// Renders the quads from build_room_quads into a new texture at `scale` times the art's
+// resolution. The room is mapped onto the whole texture, so it's drawn the same at any scale.
+unsafe fn build_room_texture(
+    gl_context: &mut gl::Context,
+    program: &gl::Program,
+    room_quads: &mut QuadBatcher,
+    scale: f32,
+) -> gl::RenderTexture {
+    let room_pixel_size = spaces::room_pixel_size(ROOM_SIZE, scale);
+    let mut room_texture = gl_context
+        .create_render_texture(
+            color_texture_format(gl_context),
+            room_pixel_size.width as u32,
+            room_pixel_size.height as u32,
+        )
+        .unwrap();
+    // the next room is shrunk into its block during the transition, where nearest sampling makes
+    // the edges crawl. Scaled up it stays crisp.
+    room_texture.texture_mut().set_filter(gl::TextureFilter {
+        min: gl::MinFilter::Linear,
+        mag: gl::MagFilter::Nearest,
+    });
+
+    let mut pass = gl_context
+        .pass(gl::RenderTarget::Texture(&room_texture))
+        .clear(Color::TRANSPARENT.to_array());
+    room_quads.flush(program, &mut pass).unwrap();
+    room_texture
 }
 
 fn create_room_block(room: &Room, color: RoomColor) -> Vec<u8> {
@@ -1240,8 +1916,9 @@ fn create_room_block(room: &Room, color: RoomColor) -> Vec<u8> {
 
     let mut image =
         vec![0; ROOM_BLOCK_IMAGE_SIZE.0 as usize * ROOM_BLOCK_IMAGE_SIZE.1 as usize * 4];
-    let mut set_pixel = |x: u32, y: u32, color: (u8, u8, u8)| {
-        let y = ROOM_BLOCK_IMAGE_SIZE.1 - 1 - y;
+    let mut set_pixel = |x: u32, y: u32, color: Color| {
+        let color = color.to_rgb8();
+        let y = spaces::y_up_to_image_row(y, ROOM_BLOCK_IMAGE_SIZE.1);
         let index = (y * ROOM_BLOCK_IMAGE_SIZE.0 + x) as usize * 4;
         image[index] = color.0;
         image[index + 1] = color.1;
@@ -1313,35 +1990,132 @@ fn create_room_block(room: &Room, color: RoomColor) -> Vec<u8> {
     image
 }
 
-#[derive(Default)]
-struct Controls {
-    left: bool,
-    right: bool,
-    since_jump: f32,
+/// Debug renderings of the world, the UI is always drawn normally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DebugView {
+    Normal,
+    /// Triangle outlines, on desktop GL only
+    Wireframe,
+    /// Every fragment adds OVERDRAW_COLOR, so brighter areas are drawn more often
+    Overdraw,
+}
+
+impl DebugView {
+    fn next(self) -> DebugView {
+        match self {
+            DebugView::Normal => DebugView::Wireframe,
+            DebugView::Wireframe => DebugView::Overdraw,
+            DebugView::Overdraw => DebugView::Normal,
+        }
+    }
+}
+
+// Additively blended, so about ten layers saturate
+// How far the background's top and bottom move towards white and the room's inner color
+const BACKGROUND_GRADIENT: f32 = 0.15;
+
+const OVERDRAW_COLOR: Color = Color::rgba(0.1, 0.05, 0.02, 1.);
+
+// Sort keys for the entity queue. The background is the clear color and the room is drawn before
+// the queue, the next room's overlay during a transition after it.
+const Z_ROOM_BLOCKS: f32 = 1.;
+const Z_PLAYER: f32 = 2.;
+const Z_PARTICLES: f32 = 3.;
+const Z_DEBUG: f32 = 4.;
+
+// How far ahead the collision overlay's velocity line reaches, in seconds
+const VELOCITY_LINE_TIME: f32 = 0.25;
+
+// 1 to run right, -1 to run left and 0 for neither or both, from the keys, d-pad and left stick
+fn run_direction(input: &InputState) -> f32 {
+    let stick_x = input.axis(Axis::LeftStickX);
+    let left = input.key_held(Key::A)
+        || input.button_held(GamepadButton::DPadLeft)
+        || stick_x < -STICK_DEAD_ZONE;
+    let right = input.key_held(Key::D)
+        || input.button_held(GamepadButton::DPadRight)
+        || stick_x > STICK_DEAD_ZONE;
+    match (left, right) {
+        (true, false) => -1.,
+        (false, true) => 1.,
+        _ => 0.,
+    }
+}
+
+// Whether any of the jump inputs was let go of this tick
+fn jump_released(input: &InputState) -> bool {
+    JUMP_KEYS.iter().any(|&key| input.key_released(key))
+        || input.button_released(GamepadButton::South)
 }
 
+const JUMP_KEYS: [Key; 2] = [Key::W, Key::Space];
+
+// How far the stick has to be pushed to run, the player runs at full speed past it like with the
+// keys
+const STICK_DEAD_ZONE: f32 = 0.3;
+
+const UI_ZOOM: f32 = 2.;
+// How much the music icon grows at full output level
+const MUTE_ICON_PULSE: f32 = 0.2;
+const MUTED_ICON_COLOR: Color = Color::rgba(0.6, 0.6, 0.6, 1.);
+// How much the music volume changes each tick the mouse wheel turns over the music icon
+const MUSIC_VOLUME_STEP: f32 = 0.1;
+
+// The glyphs in assets/font.png, in 6x8 cells with the letters in upper case only
+const FONT_CHARS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.,'-!?:";
+const FONT_GLYPH_SIZE: (u32, u32) = (6, 8);
+
+const TITLE_CARD_TIME: f32 = 2.5;
+// At each end of TITLE_CARD_TIME
+const TITLE_CARD_FADE_TIME: f32 = 0.5;
+const TITLE_CARD_NAME_SCALE: f32 = 3.;
+const TITLE_CARD_FLAVOR_SCALE: f32 = 2.;
+// Names are cut to fit one line of the card
+const TITLE_CARD_NAME_CHARS: usize = 30;
+// TITLE_CARD_NAME_CHARS at the name's scale
+const TITLE_CARD_TEXT_WIDTH: f32 = 540.;
+const TITLE_CARD_PADDING: f32 = 12.;
+// Between the name and the flavor text
+const TITLE_CARD_GAP: f32 = 8.;
+// The border of assets/panel.png, in texels
+const PANEL_BORDER: u32 = 2;
+// Fits assets/font.png and assets/panel.png
+const ERROR_ATLAS_SIZE: (u32, u32) = (128, 64);
+const ERROR_TEXT_WIDTH: f32 = 540.;
+const ERROR_MESSAGE_CHARS: usize = 200;
+const ERROR_PANEL_COLOR: Color = Color::rgba(0.45, 0.12, 0.12, 1.);
+const MARKER_CLICK_SECS: f32 = 0.02;
+// Quiet enough that the nearest room block calls without drowning out the music
+const PORTAL_HUM_VOLUME: f32 = 0.1;
+
+// How far an enterable room block next to the player pulses toward white, and how many times a
+// second
+const ROOM_BLOCK_PULSE: f32 = 0.35;
+const ROOM_BLOCK_PULSE_RATE: f32 = 1.5;
+
 const RUN_ANIMATION_TIME: f32 = 0.5;
+// Collision solver jitter can retrigger landing sounds within a few frames
+const SOUND_DEBOUNCE_TIME: f32 = 0.1;
 
 struct Player {
-    position: Point2D<f32>,
-    velocity: Vector2D<f32>,
+    position: RoomPoint,
+    velocity: RoomVector,
 
     since_on_ground: f32,
 
     sprite: Sprite,
-    flip: bool,
-    animation_timer: f32,
+    state: PlayerState,
+    animation: Animation,
 
-    collision_rect: Rect<f32>,
-    interact_rect: Rect<f32>,
+    collision_rect: RoomRect,
+    interact_rect: RoomRect,
 }
 
 impl Player {
-    pub fn new(texture: TextureRect, position: Point2D<f32>) -> Player {
-        let mut player_sprite = Sprite::new(texture, 9, point2(0., 0.));
-        player_sprite.set_transform(
-            Transform2D::translation(-7.5, -7.5).then_scale(1. / TILE_SIZE, 1. / TILE_SIZE),
-        );
+    pub fn new(texture: TextureRect, position: RoomPoint) -> Player {
+        // centered on the player's position
+        let mut player_sprite = Sprite::new(texture, 9, point2(7.5, 7.5));
+        player_sprite.set_transform(Transform2D::scale(1. / TILE_SIZE, 1. / TILE_SIZE));
 
         Player {
             position,
@@ -1350,8 +2124,8 @@ impl Player {
             since_on_ground: 9999.,
 
             sprite: player_sprite,
-            flip: false,
-            animation_timer: -1.,
+            state: PlayerState::Idle,
+            animation: PlayerState::Idle.animation(),
 
             collision_rect: Rect::new(
                 point2(-3.0 / TILE_SIZE, -7.5 / TILE_SIZE),
@@ -1363,20 +2137,111 @@ impl Player {
             ),
         }
     }
+
+    // Restarts the animation when the state changes
+    fn set_state(&mut self, state: PlayerState) {
+        if state != self.state {
+            self.state = state;
+            self.animation = state.animation();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PlayerState {
+    Idle,
+    Run,
+    Jump,
+    Fall,
 }
 
+impl PlayerState {
+    // Frames of the player sprite
+    fn animation(self) -> Animation {
+        match self {
+            PlayerState::Idle => Animation::uniform(vec![0], 1., PlayMode::Loop),
+            PlayerState::Run => {
+                Animation::uniform((1..=6).collect(), RUN_ANIMATION_TIME / 6., PlayMode::Loop)
+            }
+            PlayerState::Jump => Animation::uniform(vec![7], 1., PlayMode::Loop),
+            PlayerState::Fall => Animation::uniform(vec![8], 1., PlayMode::Loop),
+        }
+    }
+}
+
+const PARTICLE_CAPACITY: usize = 256;
+
 const DUST_SPAWN_TIME: f32 = 0.025;
-const DUST_LIFE_TIME: f32 = 0.2;
 
 struct Dust {
-    position: Point2D<f32>,
-    velocity: Vector2D<f32>,
-    age: f32,
+    position: RoomPoint,
+    velocity: RoomVector,
+    animation: Animation,
+    // A random quarter turn so the puffs don't all look the same
+    turn: Angle<f32>,
 }
 
 const ROOM_SIZE: (u32, u32) = (15, 15);
 // ROOM_SIZE.0 * ROOM_SIZE.1
 const ROOM_CELLS: usize = 225;
+// Each room's level, with its path in the assets folder and the built in copy
+const ROOM_LEVELS: [(RoomColor, &str, &[u8]); 11] = [
+    (
+        RoomColor::Red,
+        "rooms/red.rum",
+        include_bytes!("../assets/rooms/red.rum"),
+    ),
+    (
+        RoomColor::Orange,
+        "rooms/orange.rum",
+        include_bytes!("../assets/rooms/orange.rum"),
+    ),
+    (
+        RoomColor::Yellow,
+        "rooms/yellow.rum",
+        include_bytes!("../assets/rooms/yellow.rum"),
+    ),
+    (
+        RoomColor::Green,
+        "rooms/green.rum",
+        include_bytes!("../assets/rooms/green.rum"),
+    ),
+    (
+        RoomColor::Turquoise,
+        "rooms/turquoise.rum",
+        include_bytes!("../assets/rooms/turquoise.rum"),
+    ),
+    (
+        RoomColor::Aqua,
+        "rooms/aqua.rum",
+        include_bytes!("../assets/rooms/aqua.rum"),
+    ),
+    (
+        RoomColor::Chetwood,
+        "rooms/chetwood.rum",
+        include_bytes!("../assets/rooms/chetwood.rum"),
+    ),
+    (
+        RoomColor::Blue,
+        "rooms/blue.rum",
+        include_bytes!("../assets/rooms/blue.rum"),
+    ),
+    (
+        RoomColor::Purple,
+        "rooms/purple.rum",
+        include_bytes!("../assets/rooms/purple.rum"),
+    ),
+    (
+        RoomColor::Magenta,
+        "rooms/magenta.rum",
+        include_bytes!("../assets/rooms/magenta.rum"),
+    ),
+    (
+        RoomColor::Ferrish,
+        "rooms/ferrish.rum",
+        include_bytes!("../assets/rooms/ferrish.rum"),
+    ),
+];
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Tile {
@@ -1385,6 +2250,87 @@ enum Tile {
     Room(RoomColor),
 }
 
+// The images from assets/ for the atlas batch
+#[cfg(not(feature = "prebaked-atlas"))]
+fn asset_images() -> Vec<(&'static str, BatchImage<'static>)> {
+    vec![
+        (
+            "block",
+            BatchImage::Encoded(include_bytes!("../assets/block.png")),
+        ),
+        // a long strip of frames, which the atlas may store on its side, build.rs has it listed
+        // the same way
+        (
+            "player",
+            BatchImage::EncodedRotatable(include_bytes!("../assets/player.png")),
+        ),
+        (
+            "music_icon",
+            BatchImage::Encoded(include_bytes!("../assets/music_icon.png")),
+        ),
+        (
+            "dust",
+            BatchImage::Encoded(include_bytes!("../assets/dust.png")),
+        ),
+        (
+            "font",
+            BatchImage::Encoded(include_bytes!("../assets/font.png")),
+        ),
+        (
+            "panel",
+            BatchImage::Encoded(include_bytes!("../assets/panel.png")),
+        ),
+        // for solid shapes, see `graphics::render_rect_filled`
+        ("white", BatchImage::Raw(&[255; 4], size2(1, 1))),
+    ]
+}
+
+// Everything from assets/ is in the prebaked atlas already
+#[cfg(feature = "prebaked-atlas")]
+fn asset_images() -> Vec<(&'static str, BatchImage<'static>)> {
+    Vec::new()
+}
+
+// The registry with the images `build.rs` packed already uploaded and named, which leaves the
+// rest of the atlas for the images made at startup
+#[cfg(feature = "prebaked-atlas")]
+unsafe fn prebaked_textures(mut texture: gl::Texture) -> Result<TextureRegistry, anyhow::Error> {
+    use crate::prebaked_atlas;
+
+    prebaked_atlas::check()?;
+    let size = TEXTURE_ATLAS_SIZE.to_tuple();
+    if prebaked_atlas::ATLAS_SIZE != size || prebaked_atlas::ATLAS_PADDING != ATLAS_PADDING {
+        anyhow::bail!(
+            "The prebaked atlas is {}x{} with a padding of {}, the game's is {}x{} with {}",
+            prebaked_atlas::ATLAS_SIZE.0,
+            prebaked_atlas::ATLAS_SIZE.1,
+            prebaked_atlas::ATLAS_PADDING,
+            size.0,
+            size.1,
+            ATLAS_PADDING
+        );
+    }
+    let image = image::load_from_memory(prebaked_atlas::PNG)?.to_rgba();
+    let (width, height) = image.dimensions();
+    texture.write(0, 0, width, height, &image.into_raw())?;
+
+    let rects: Vec<TextureRect> = prebaked_atlas::TEXTURES
+        .iter()
+        .map(|(_, rect)| *rect)
+        .collect();
+    let atlas = TextureAtlas::with_rects(size, ATLAS_PADDING, &rects)?;
+    let mut textures = TextureRegistry::new(atlas, texture);
+    for (name, rect) in prebaked_atlas::TEXTURES {
+        textures.insert_rect(name, *rect)?;
+    }
+    Ok(textures)
+}
+
+// Name of a room's block preview in the texture registry, like "room_block_red"
+fn room_block_name(color: RoomColor) -> String {
+    format!("room_block_{:?}", color).to_lowercase()
+}
+
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 enum RoomColor {
     Red,
@@ -1401,6 +2347,14 @@ enum RoomColor {
 }
 
 impl RoomColor {
+    // The color with the enum name `name`, as saved in progress
+    fn from_name(name: &str) -> Option<RoomColor> {
+        ROOM_LEVELS
+            .iter()
+            .map(|(color, _, _)| *color)
+            .find(|color| format!("{:?}", color) == name)
+    }
+
     fn hue(&self) -> f32 {
         match self {
             RoomColor::Red => 0.,
@@ -1419,37 +2373,33 @@ impl RoomColor {
 }
 
 const ROOM_BLOCK_IMAGE_SIZE: (u32, u32) = (17, 17);
+// Texels between atlas entries. Everything in the atlas is sampled nearest and inset by half a
+// texel, so one is enough.
+const ATLAS_PADDING: u32 = 1;
+const ATLAS_DUMP_FILE: &str = "atlas_dump.png";
+const ATLAS_LAYOUT_FILE: &str = "atlas_layout.svg";
+// Resolution rooms are baked at relative to their art. At the zoom level every texel lands on
+// one screen pixel, and the next room keeps its detail while it grows out of its block during
+// the transition.
+const ROOM_RENDER_SCALE: f32 = ZOOM_LEVEL;
+// Quarter tiles sit right next to each other in the atlas, so their UVs are pulled in to keep
+// filtering from picking up the neighbouring quarter at the seams
+const TILE_UV_INSET: f32 = graphics::HALF_TEXEL;
 
 struct RoomBlockColors {
-    background: (u8, u8, u8),
-    inner: (u8, u8, u8),
-    border: (u8, u8, u8),
-    outer_border: (u8, u8, u8),
+    background: Color,
+    inner: Color,
+    border: Color,
+    outer_border: Color,
 }
 
 impl RoomBlockColors {
     pub fn new(hue: f32) -> RoomBlockColors {
         RoomBlockColors {
-            background: LinSrgb::from(Hsv::<palette::encoding::srgb::Srgb, f32>::from_components(
-                (hue, 0.21, 0.7),
-            ))
-            .into_format()
-            .into_components(),
-            inner: LinSrgb::from(Hsv::<palette::encoding::srgb::Srgb, f32>::from_components(
-                (hue, 0.35, 0.6),
-            ))
-            .into_format()
-            .into_components(),
-            border: LinSrgb::from(Hsv::<palette::encoding::srgb::Srgb, f32>::from_components(
-                (hue, 0.36, 0.47),
-            ))
-            .into_format()
-            .into_components(),
-            outer_border: LinSrgb::from(
-                Hsv::<palette::encoding::srgb::Srgb, f32>::from_components((hue, 0.42, 0.3)),
-            )
-            .into_format()
-            .into_components(),
+            background: Color::from_hsv(hue, 0.21, 0.7),
+            inner: Color::from_hsv(hue, 0.35, 0.6),
+            border: Color::from_hsv(hue, 0.36, 0.47),
+            outer_border: Color::from_hsv(hue, 0.42, 0.3),
         }
     }
 }
@@ -1458,10 +2408,28 @@ fn room_block_colors(color: RoomColor) -> RoomBlockColors {
     RoomBlockColors::new(color.hue())
 }
 
+impl PaletteSwap {
+    /// A room's block colors from darkest to lightest, for grayscale sprites drawn from black
+    /// outlines to white highlights.
+    fn from_room_color(color: RoomColor) -> PaletteSwap {
+        let colors = room_block_colors(color);
+        PaletteSwap::new([
+            colors.outer_border,
+            colors.border,
+            colors.inner,
+            colors.background,
+        ])
+    }
+}
+
 const ENTER_ROOM_TIME: f32 = 0.5;
+const PLAYER_FLASH_TIME: f32 = 0.3;
+// Landing flashes the player at LAND_FLASH_TIME / PLAYER_FLASH_TIME strength
+const LAND_FLASH_TIME: f32 = 0.1;
 
+#[derive(Clone, Copy)]
 struct RoomTransitionIn {
-    position: Point2D<i32>,
+    position: TilePoint,
     entrance: RoomEntrance,
     color: RoomColor,
     timer: f32,
@@ -1476,17 +2444,244 @@ enum RoomEntrance {
 
 struct Room {
     tiles: [Tile; ROOM_CELLS],
-    left_entrance: Option<Point2D<i32>>,
-    top_entrance: Option<Point2D<i32>>,
-    right_entrance: Option<Point2D<i32>>,
+    left_entrance: Option<TilePoint>,
+    top_entrance: Option<TilePoint>,
+    right_entrance: Option<TilePoint>,
+    name: Option<Text>,
+    flavor: Option<Text>,
+}
+
+// The rooms the player went through to get to the current one, outermost first. Coming back into
+// one of them goes back up to it.
+#[derive(Clone, Debug)]
+struct Breadcrumb {
+    rooms: Vec<RoomColor>,
+}
+
+impl Breadcrumb {
+    fn new(first_room: RoomColor) -> Breadcrumb {
+        Breadcrumb {
+            rooms: vec![first_room],
+        }
+    }
+
+    fn enter(&mut self, room: RoomColor) {
+        match self.rooms.iter().position(|&on_the_way| on_the_way == room) {
+            Some(i) => self.rooms.truncate(i + 1),
+            None => self.rooms.push(room),
+        }
+    }
+
+    // How many rooms in from the first one the current one is
+    fn depth(&self) -> u32 {
+        self.rooms.len() as u32 - 1
+    }
+}
+
+/// How a room is referred to in anything shown to the player.
+#[derive(Debug, PartialEq)]
+struct RoomDisplay {
+    name: String,
+    flavor: Option<String>,
+}
+
+// A room's name and flavor text in the middle of the screen, fading in and out
+struct TitleCard {
+    display: RoomDisplay,
+    age: f32,
+}
+
+impl TitleCard {
+    fn new(display: RoomDisplay) -> TitleCard {
+        TitleCard { display, age: 0. }
+    }
+
+    fn finished(&self) -> bool {
+        self.age >= TITLE_CARD_TIME
+    }
+
+    fn alpha(&self) -> f32 {
+        let fade_in = self.age / TITLE_CARD_FADE_TIME;
+        let fade_out = (TITLE_CARD_TIME - self.age) / TITLE_CARD_FADE_TIME;
+        fade_in.min(fade_out).max(0.).min(1.)
+    }
+
+    // Centered on the screen, on `panel` tinted with `panel_color`
+    fn draw(&self, font: &Font, panel: TextureRect, panel_color: Color, ui: &mut UiLayer) {
+        let name = strings::truncate(&self.display.name, TITLE_CARD_NAME_CHARS).to_uppercase();
+        let flavor = self
+            .display
+            .flavor
+            .as_ref()
+            .map(|flavor| flavor.to_uppercase());
+        let name_layout = TextLayout {
+            align: TextAlign::Center,
+            max_width: Some(TITLE_CARD_TEXT_WIDTH),
+            scale: TITLE_CARD_NAME_SCALE,
+            ..TextLayout::default()
+        };
+        let flavor_layout = TextLayout {
+            scale: TITLE_CARD_FLAVOR_SCALE,
+            ..name_layout
+        };
+
+        let name_size = name_layout.measure(font, &name);
+        let flavor_size = flavor
+            .as_ref()
+            .map(|flavor| flavor_layout.measure(font, flavor));
+        let mut text_size = name_size;
+        if let Some(flavor_size) = flavor_size {
+            text_size.width = text_size.width.max(flavor_size.width);
+            text_size.height += TITLE_CARD_GAP + flavor_size.height;
+        }
+        let rect = UiLayer::anchored(
+            Anchor::Center,
+            vec2(0., 0.),
+            size2(text_size.width, text_size.height) + size2(2., 2.) * TITLE_CARD_PADDING,
+        );
+
+        let alpha = self.alpha();
+        let color = Color::WHITE.with_alpha(alpha);
+        ui.draw_nine_slice(
+            rect,
+            panel,
+            PANEL_BORDER,
+            UI_ZOOM,
+            panel_color.with_alpha(alpha),
+        );
+        // the layouts center each line in a block TITLE_CARD_TEXT_WIDTH wide
+        let left = rect.center().x - TITLE_CARD_TEXT_WIDTH / 2.;
+        let top = rect.max_y() - TITLE_CARD_PADDING;
+        ui.draw_text(&name_layout, font, &name, point2(left, top), color);
+        if let Some(flavor) = &flavor {
+            let flavor_top = top - name_size.height - TITLE_CARD_GAP;
+            ui.draw_text(
+                &flavor_layout,
+                font,
+                flavor,
+                point2(left, flavor_top),
+                color,
+            );
+        }
+    }
+}
+
+// The rooms the player has been in, saved as a `visited = Red, Blue` line so that title cards
+// only show on first visits
+struct VisitedRooms {
+    rooms: HashSet<RoomColor>,
+}
+
+impl VisitedRooms {
+    fn new(first_room: RoomColor) -> VisitedRooms {
+        VisitedRooms {
+            rooms: std::iter::once(first_room).collect(),
+        }
+    }
+
+    // The newest save that passes `check_save`, or just `first_room` if there is none
+    fn load(first_room: RoomColor) -> VisitedRooms {
+        let saves = match platform::load_progress() {
+            Ok(saves) => saves,
+            Err(e) => {
+                log::error!("{:?}", e);
+                Vec::new()
+            }
+        };
+        match pick_save(&saves) {
+            Some((0, save)) => VisitedRooms::from_save(first_room, save),
+            Some((age, save)) => {
+                log::warn!(
+                    "The latest progress save was damaged, so the one from {} saves before it \
+                     was loaded instead",
+                    age
+                );
+                VisitedRooms::from_save(first_room, save)
+            }
+            None => VisitedRooms::new(first_room),
+        }
+    }
+
+    // Rooms that don't exist and anything else in `save` are skipped with a warning
+    fn from_save(first_room: RoomColor, save: &str) -> VisitedRooms {
+        let mut visited = VisitedRooms::new(first_room);
+        for line in save.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let names = match line.find('=') {
+                Some(i) if line[..i].trim() == VISITED_ROOMS_ENTRY => &line[i + 1..],
+                _ => {
+                    log::warn!("Ignoring unknown progress entry '{}'", line);
+                    continue;
+                }
+            };
+            for name in names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+            {
+                match RoomColor::from_name(name) {
+                    Some(color) => {
+                        visited.rooms.insert(color);
+                    }
+                    None => log::warn!("Ignoring unknown visited room '{}'", name),
+                }
+            }
+        }
+        visited
+    }
+
+    // True if `room` wasn't visited before
+    fn visit(&mut self, room: RoomColor) -> bool {
+        self.rooms.insert(room)
+    }
+
+    fn to_save(&self) -> String {
+        let names: Vec<String> = ROOM_LEVELS
+            .iter()
+            .filter(|(color, _, _)| self.rooms.contains(color))
+            .map(|(color, _, _)| format!("{:?}", color))
+            .collect();
+        format!("{} = {}\n", VISITED_ROOMS_ENTRY, names.join(", "))
+    }
+}
+
+const VISITED_ROOMS_ENTRY: &str = "visited";
+
+// Whether `save` looks like something `VisitedRooms::to_save` wrote: whole lines, with a visited
+// entry naming only rooms that exist. Other entries are left for `from_save` to skip.
+fn check_save(save: &str) -> Result<(), anyhow::Error> {
+    if !save.ends_with('\n') {
+        anyhow::bail!("Progress ends partway through a line");
+    }
+    let names = save
+        .lines()
+        .filter_map(|line| {
+            let i = line.find('=')?;
+            Some(&line[i + 1..]).filter(|_| line[..i].trim() == VISITED_ROOMS_ENTRY)
+        })
+        .next()
+        .ok_or_else(|| anyhow::format_err!("Progress has no {} entry", VISITED_ROOMS_ENTRY))?;
+    for name in names.split(',').map(str::trim) {
+        if RoomColor::from_name(name).is_none() {
+            anyhow::bail!("Progress names a room '{}' that doesn't exist", name);
+        }
+    }
+    Ok(())
+}
+
+// The first of `saves`, which are newest first, that passes `check_save` and how many saves
+// older than the newest it is. If none pass, the newest is still better than nothing.
+fn pick_save(saves: &[String]) -> Option<(usize, &str)> {
+    for (age, save) in saves.iter().enumerate() {
+        match check_save(save) {
+            Ok(()) => return Some((age, save)),
+            Err(e) => log::warn!("Skipping progress save {}: {}", age, e),
+        }
+    }
+    saves.first().map(|save| (0, save.as_str()))
 }
 
 impl Room {
-    pub fn for_each_tile_in_rect(
-        &self,
-        bound_rect: Rect<f32>,
-        mut f: impl FnMut(Point2D<i32>, Tile),
-    ) {
+    pub fn for_each_tile_in_rect(&self, bound_rect: RoomRect, mut f: impl FnMut(TilePoint, Tile)) {
         let min_x = (bound_rect.min_x()).floor() as i32;
         let max_x = (bound_rect.max_x()).floor() as i32;
         let min_y = (bound_rect.min_y()).floor() as i32;
@@ -1506,7 +2701,23 @@ impl Room {
         }
     }
 
-    fn entrance(&self, entrance: RoomEntrance) -> Option<Point2D<i32>> {
+    // The center of the room block closest to `position`, if the room has any
+    fn nearest_room_block(&self, position: RoomPoint) -> Option<RoomPoint> {
+        let distance = |center: &RoomPoint| (*center - position).square_length();
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter(|(_, tile)| matches!(tile, Tile::Room(_)))
+            .map(|(cell, _)| {
+                point2(
+                    (cell as u32 % ROOM_SIZE.0) as f32 + 0.5,
+                    (cell as u32 / ROOM_SIZE.0) as f32 + 0.5,
+                )
+            })
+            .min_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap())
+    }
+
+    fn entrance(&self, entrance: RoomEntrance) -> Option<TilePoint> {
         match entrance {
             RoomEntrance::Left => self.left_entrance,
             RoomEntrance::Top => self.top_entrance,
@@ -1522,17 +2733,14 @@ fn parse_room(level: &str) -> Room {
     let mut top_entrance = None;
     let mut right_entrance = None;
 
-    for (y, line) in level.lines().enumerate() {
-        if y >= ROOM_SIZE.1 as usize {
-            break;
-        }
+    let mut lines = level.lines();
+    for (row, line) in lines.by_ref().take(ROOM_SIZE.1 as usize).enumerate() {
         for (x, c) in line.chars().enumerate() {
             if x >= ROOM_SIZE.0 as usize {
                 break;
             }
 
-            // flip y
-            let y = ROOM_SIZE.1 as usize - 1 - y;
+            let y = spaces::text_row_to_tile_y(row, ROOM_SIZE.1) as usize;
             let cell = y * ROOM_SIZE.0 as usize + x;
             let tile = match c {
                 ' ' => Tile::Empty,
@@ -1567,14 +2775,292 @@ fn parse_room(level: &str) -> Room {
         }
     }
 
+    // directives follow the tiles, one per line
+    let mut name = None;
+    let mut flavor = None;
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (directive, value) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        match directive {
+            "name" => name = Some(Text::parse(value)),
+            "flavor" => flavor = Some(Text::parse(value)),
+            other => {
+                panic!("Unrecognized room directive '{}'", other);
+            }
+        }
+    }
+
     Room {
         tiles,
         left_entrance,
         top_entrance,
         right_entrance,
+        name,
+        flavor,
     }
 }
 
 fn lerp(x: f32, a: f32, b: f32) -> f32 {
     a + (b - a) * x
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_room_block_is_the_closest_center() {
+        let mut level = vec![" ".repeat(15); 15];
+        // text rows run top down, so these are on tile rows 13 and 2
+        level[1].replace_range(3..4, "R");
+        level[12].replace_range(10..11, "B");
+        let room = parse_room(&level.join("\n"));
+        assert_eq!(
+            room.nearest_room_block(point2(2., 12.)),
+            Some(point2(3.5, 13.5))
+        );
+        assert_eq!(
+            room.nearest_room_block(point2(14., 0.)),
+            Some(point2(10.5, 2.5))
+        );
+
+        let empty = parse_room(&vec![" ".repeat(15); 15].join("\n"));
+        assert_eq!(empty.nearest_room_block(point2(2., 12.)), None);
+    }
+
+    #[test]
+    fn breadcrumb_depth_goes_back_up() {
+        let mut breadcrumb = Breadcrumb::new(RoomColor::Blue);
+        assert_eq!(breadcrumb.depth(), 0);
+        breadcrumb.enter(RoomColor::Red);
+        breadcrumb.enter(RoomColor::Green);
+        breadcrumb.enter(RoomColor::Aqua);
+        assert_eq!(breadcrumb.depth(), 3);
+        // back into a room on the way down
+        breadcrumb.enter(RoomColor::Red);
+        assert_eq!(breadcrumb.depth(), 1);
+        breadcrumb.enter(RoomColor::Aqua);
+        assert_eq!(breadcrumb.depth(), 2);
+        breadcrumb.enter(RoomColor::Blue);
+        assert_eq!(breadcrumb.depth(), 0);
+    }
+
+    // An empty room with `directives` after its tiles
+    fn room_with(directives: &str) -> Room {
+        parse_room(&format!(
+            "{}\n{}",
+            vec![" ".repeat(15); 15].join("\n"),
+            directives
+        ))
+    }
+
+    #[test]
+    fn room_directives_are_keys_or_literals() {
+        let room = room_with("\nname key:room.blue.name\nflavor   It hums faintly.  \n");
+        assert_eq!(room.name, Some(Text::Key("room.blue.name".to_owned())));
+        assert_eq!(
+            room.flavor,
+            Some(Text::Literal("It hums faintly.".to_owned()))
+        );
+
+        let room = room_with("");
+        assert_eq!(room.name, None);
+        assert_eq!(room.flavor, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unrecognized room directive 'title'")]
+    fn unknown_room_directives_panic() {
+        room_with("title The Cobalt Antechamber");
+    }
+
+    #[test]
+    fn room_names_fall_back_to_the_color() {
+        let strings = Strings::parse("room.red.name = The Ember Vestibule");
+        let display = |directives| room_display(&room_with(directives), RoomColor::Red, &strings);
+        assert_eq!(
+            display("name The Rust Workshop\nflavor Still cooling."),
+            RoomDisplay {
+                name: "The Rust Workshop".to_owned(),
+                flavor: Some("Still cooling.".to_owned()),
+            }
+        );
+        assert_eq!(
+            display("name key:room.red.name\nflavor key:room.red.flavor"),
+            RoomDisplay {
+                name: "The Ember Vestibule".to_owned(),
+                flavor: None,
+            }
+        );
+        // never the raw key
+        assert_eq!(display("name key:room.red.nmae").name, "Red");
+        assert_eq!(display("").name, "Red");
+    }
+
+    #[test]
+    fn first_visits_survive_saving_and_loading() {
+        let mut visited = VisitedRooms::new(RoomColor::Blue);
+        assert!(!visited.visit(RoomColor::Blue));
+        assert!(visited.visit(RoomColor::Purple));
+        assert!(visited.visit(RoomColor::Red));
+        assert!(!visited.visit(RoomColor::Red));
+        let save = visited.to_save();
+        assert_eq!(save, "visited = Red, Blue, Purple\n");
+
+        let mut loaded = VisitedRooms::from_save(RoomColor::Blue, &save);
+        assert!(!loaded.visit(RoomColor::Red));
+        assert!(!loaded.visit(RoomColor::Purple));
+        assert!(loaded.visit(RoomColor::Green));
+
+        // what can't be read is left out, and the first room is always visited
+        let mut loaded =
+            VisitedRooms::from_save(RoomColor::Blue, "best = 3\nvisited = Mauve, Red,, Purple\n");
+        assert_eq!(loaded.to_save(), "visited = Red, Blue, Purple\n");
+        assert!(!loaded.visit(RoomColor::Blue));
+        let empty = VisitedRooms::from_save(RoomColor::Blue, "");
+        assert_eq!(empty.to_save(), "visited = Blue\n");
+    }
+
+    #[test]
+    fn damaged_saves_fall_back_to_the_newest_good_backup() {
+        let good = VisitedRooms::from_save(RoomColor::Blue, "visited = Blue, Red\n").to_save();
+        assert!(check_save(&good).is_ok());
+        // cut off partway through writing, naming a room that doesn't exist, or missing the
+        // entry altogether
+        for damaged in &[
+            "visited = Blue, R",
+            "visited = Blue, Rd\n",
+            "best = 3\n",
+            "",
+        ] {
+            assert!(check_save(damaged).is_err(), "{:?}", damaged);
+        }
+
+        let saves = vec![
+            "visited = Blue, Red, Gr".to_owned(),
+            "visited = Blue, Red\n".to_owned(),
+            "visited = Blue\n".to_owned(),
+        ];
+        assert_eq!(pick_save(&saves), Some((1, "visited = Blue, Red\n")));
+        assert_eq!(pick_save(&saves[1..]), Some((0, "visited = Blue, Red\n")));
+        // nothing good is left, so the newest is read for whatever can be read from it
+        assert_eq!(pick_save(&saves[..1]), Some((0, "visited = Blue, Red, Gr")));
+        assert_eq!(pick_save(&[]), None);
+    }
+
+    #[test]
+    fn title_cards_fade_in_and_out() {
+        let mut card = TitleCard::new(RoomDisplay {
+            name: "Red".to_owned(),
+            flavor: None,
+        });
+        let mut alpha_at = |age| {
+            card.age = age;
+            (card.alpha(), card.finished())
+        };
+        assert_eq!(alpha_at(0.), (0., false));
+        assert_eq!(alpha_at(TITLE_CARD_FADE_TIME / 2.), (0.5, false));
+        assert_eq!(alpha_at(TITLE_CARD_FADE_TIME), (1., false));
+        assert_eq!(alpha_at(TITLE_CARD_TIME / 2.), (1., false));
+        assert_eq!(
+            alpha_at(TITLE_CARD_TIME - TITLE_CARD_FADE_TIME / 2.),
+            (0.5, false)
+        );
+        assert_eq!(alpha_at(TITLE_CARD_TIME), (0., true));
+        assert_eq!(alpha_at(TITLE_CARD_TIME + 1.), (0., true));
+    }
+
+    #[cfg(feature = "headless")]
+    mod headless {
+        use super::super::*;
+        use crate::{
+            graphics::{load_images_batch, BatchImage, Mesh},
+            platform::headless_gl_for_test,
+        };
+
+        // One row across two 2x2 tiles drawn side by side at 2.7 pixels per texel with linear
+        // filtering, with the tiles' UVs pulled in by `inset`
+        fn render_seam(inset: f32) -> Option<Vec<u8>> {
+            let mut gl = headless_gl_for_test(24, 4)?;
+            let context = &mut gl.context;
+            let red = [255, 0, 0, 255];
+            let green = [0, 255, 0, 255];
+            let blue = [0, 0, 255, 255];
+            unsafe {
+                let mut texture = context
+                    .create_texture(gl::TextureFormat::RGBA8, 8, 2, gl::TextureFilter::LINEAR)
+                    .unwrap();
+                texture.write(0, 0, 8, 2, &[0; 8 * 2 * 4]).unwrap();
+                // no padding, so each tile has another right next to it in the atlas
+                let mut texture_atlas = TextureAtlas::new((8, 2), 0);
+                let tiles: Vec<Vec<u8>> = [red, green, blue].iter().map(|c| c.repeat(4)).collect();
+                let images: Vec<BatchImage> = tiles
+                    .iter()
+                    .map(|tile| BatchImage::Raw(tile, size2(2, 2)))
+                    .collect();
+                let rects = load_images_batch(&images, &mut texture_atlas, &mut texture).unwrap();
+
+                // pixel x to clip space, across the 24 pixel wide screen
+                let x = |pixels: f32| pixels / 12. - 1.;
+                let mut mesh = Mesh::with_quad_capacity(2);
+                for (i, &rect) in [rects[0], rects[2]].iter().enumerate() {
+                    let left = 0.3 + i as f32 * 5.4;
+                    let quad: Box2D<f32, ()> =
+                        Box2D::new(point2(x(left), -1.), point2(x(left + 5.4), 1.));
+                    graphics::render_quad_inset(quad, rect, Color::WHITE, inset, &mut mesh);
+                }
+                let mut vertex_buffer = context
+                    .create_vertex_buffer(gl::BufferUsage::Static)
+                    .unwrap();
+                let mut index_buffer = context
+                    .create_index_buffer(gl::BufferUsage::Static)
+                    .unwrap();
+                mesh.write(&mut vertex_buffer, &mut index_buffer);
+                let mut program = create_sprite_program(context, false);
+                program
+                    .set_uniform_by_name("u_texture", gl::Uniform::Texture(&texture))
+                    .unwrap();
+                context.clear(
+                    gl::RenderTarget::Screen,
+                    gl::ClearOptions::color([0., 0., 0., 1.]),
+                );
+                program
+                    .render_indexed(&vertex_buffer, &index_buffer, gl::RenderTarget::Screen)
+                    .unwrap();
+                Some(
+                    context
+                        .read_pixels(
+                            gl::RenderTarget::Screen,
+                            Rect::new(point2(0, 2), size2(24, 1)),
+                        )
+                        .unwrap(),
+                )
+            }
+        }
+
+        #[test]
+        fn tiles_dont_pick_up_their_atlas_neighbours_when_scaled() {
+            let row = match render_seam(TILE_UV_INSET) {
+                Some(row) => row,
+                None => return,
+            };
+            // pixel centers up to 5.5 are on the red tile, up to 10.5 on the blue one
+            let expected: Vec<u8> = (0..24)
+                .flat_map(|x| match x {
+                    0..=5 => [255, 0, 0, 255],
+                    6..=10 => [0, 0, 255, 255],
+                    _ => [0, 0, 0, 255],
+                })
+                .collect();
+            assert_eq!(row, expected);
+            // without the inset the red tile's last pixel blends in its atlas neighbour
+            assert_ne!(render_seam(0.).unwrap()[5 * 4..6 * 4], [255, 0, 0, 255]);
+        }
+    }
+}
@@ -1,5 +1,10 @@
-use std::rc::Rc;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
 
+use euclid::default::{Point2D, Rect};
 use glow::HasContext;
 use thiserror::Error;
 use zerocopy::AsBytes;
@@ -13,29 +18,410 @@ type ProgramId = <glow::Context as glow::HasContext>::Program;
 type ShaderId = <glow::Context as glow::HasContext>::Shader;
 type TextureId = <glow::Context as glow::HasContext>::Texture;
 type FramebufferId = <glow::Context as glow::HasContext>::Framebuffer;
+type RenderbufferId = <glow::Context as glow::HasContext>::Renderbuffer;
 
-pub struct Shader(Rc<ShaderId>);
+// Logs the errors raised by the GL calls made for `$operation` along with where it is checked.
+// Expands to nothing without the debug-gl feature.
+macro_rules! check_gl_errors {
+    ($context:expr, $operation:expr) => {
+        #[cfg(feature = "debug-gl")]
+        log_gl_errors(&$context, $operation, concat!(file!(), ":", line!()));
+    };
+}
+
+#[cfg(feature = "debug-gl")]
+unsafe fn log_gl_errors(context: &glow::Context, operation: &str, location: &str) {
+    // a lost context can keep reporting errors, so don't wait for the queue to empty
+    for _ in 0..8 {
+        let error = context.get_error();
+        let name = match error {
+            glow::NO_ERROR => return,
+            glow::INVALID_ENUM => "INVALID_ENUM",
+            glow::INVALID_VALUE => "INVALID_VALUE",
+            glow::INVALID_OPERATION => "INVALID_OPERATION",
+            glow::INVALID_FRAMEBUFFER_OPERATION => "INVALID_FRAMEBUFFER_OPERATION",
+            glow::OUT_OF_MEMORY => "OUT_OF_MEMORY",
+            glow::STACK_UNDERFLOW => "STACK_UNDERFLOW",
+            glow::STACK_OVERFLOW => "STACK_OVERFLOW",
+            glow::CONTEXT_LOST => "CONTEXT_LOST",
+            _ => "unknown error",
+        };
+        log::error!(
+            "GL error {} ({:#x}) in {} at {}",
+            name,
+            error,
+            operation,
+            location
+        );
+    }
+}
+
+// Forwards the driver's own messages to the log where KHR_debug is available
+#[cfg(all(feature = "debug-gl", not(target_arch = "wasm32")))]
+unsafe fn enable_debug_messages(context: &glow::Context) {
+    if !context.supports_debug() {
+        return;
+    }
+    context.enable(glow::DEBUG_OUTPUT);
+    // report messages from inside the call that caused them, so they line up with the error checks
+    context.enable(glow::DEBUG_OUTPUT_SYNCHRONOUS);
+    context.debug_message_callback(|_source, ty, id, severity, message| {
+        let level = match severity {
+            glow::DEBUG_SEVERITY_HIGH => log::Level::Error,
+            glow::DEBUG_SEVERITY_MEDIUM => log::Level::Warn,
+            glow::DEBUG_SEVERITY_LOW => log::Level::Info,
+            _ => log::Level::Debug,
+        };
+        let level = if ty == glow::DEBUG_TYPE_ERROR {
+            log::Level::Error
+        } else {
+            level
+        };
+        log::log!(level, "GL debug message {}: {}", id, message);
+    });
+}
+
+// Names objects and groups commands for graphics debuggers through KHR_debug. Only with
+// debug-gl, and WebGL doesn't have it, so everywhere else these do nothing.
+#[cfg(all(feature = "debug-gl", not(target_arch = "wasm32")))]
+unsafe fn object_label(context: &glow::Context, identifier: u32, name: u32, label: &str) {
+    if context.supports_debug() {
+        context.object_label(identifier, name, Some(label));
+    }
+}
+
+#[cfg(not(all(feature = "debug-gl", not(target_arch = "wasm32"))))]
+unsafe fn object_label<T>(_context: &glow::Context, _identifier: u32, _name: T, _label: &str) {}
+
+#[cfg(all(feature = "debug-gl", not(target_arch = "wasm32")))]
+unsafe fn push_debug_group(context: &glow::Context, name: &str) {
+    if context.supports_debug() {
+        context.push_debug_group(glow::DEBUG_SOURCE_APPLICATION, 0, name);
+    }
+}
+
+#[cfg(not(all(feature = "debug-gl", not(target_arch = "wasm32"))))]
+unsafe fn push_debug_group(_context: &glow::Context, _name: &str) {}
+
+#[cfg(all(feature = "debug-gl", not(target_arch = "wasm32")))]
+unsafe fn pop_debug_group(context: &glow::Context) {
+    if context.supports_debug() {
+        context.pop_debug_group();
+    }
+}
+
+#[cfg(not(all(feature = "debug-gl", not(target_arch = "wasm32"))))]
+unsafe fn pop_debug_group(_context: &glow::Context) {}
+
+pub struct Shader(Rc<Handle<ShaderId>>);
 pub struct Texture {
     context: Rc<glow::Context>,
-    texture_id: Rc<TextureId>,
+    texture_id: Rc<Handle<TextureId>>,
     size: (i32, i32),
     format: TextureFormat,
+    // What the storage is allocated as, which for some formats depends on the GL version
+    internal_format: u32,
+    filter: TextureFilter,
 }
 pub struct VertexBuffer {
     context: Rc<glow::Context>,
-    vertex_array: Rc<VertexArrayId>,
-    buffer: Rc<BufferId>,
+    vertex_array: Rc<Handle<VertexArrayId>>,
+    buffer: Rc<Handle<BufferId>>,
+    usage: BufferUsage,
+    len: usize,
+    // Allocated size in bytes
+    capacity: usize,
+}
+/// Per-instance data for `Program::render_instanced`, read by the attributes with a divisor.
+pub struct InstanceBuffer {
+    context: Rc<glow::Context>,
+    buffer: Rc<Handle<BufferId>>,
+    usage: BufferUsage,
+    len: usize,
+}
+
+pub struct IndexBuffer {
+    context: Rc<glow::Context>,
+    buffer: Rc<Handle<BufferId>>,
+    usage: BufferUsage,
     len: usize,
 }
 
 pub struct Context {
     context: Rc<glow::Context>,
-    shaders: Vec<Rc<ShaderId>>,
-    programs: Vec<Rc<ProgramId>>,
-    vertex_arrays: Vec<Rc<VertexArrayId>>,
-    buffers: Vec<Rc<BufferId>>,
-    textures: Vec<Rc<TextureId>>,
-    frame_buffers: Vec<Rc<FramebufferId>>,
+    state: Rc<ContextState>,
+    // Whether attributes can be passed to the shader as integers, which GLES 2 and WebGL 1 can't
+    integer_attributes: bool,
+    // Whether GL_FRAMEBUFFER_SRGB exists, which is desktop GL only
+    srgb_framebuffer: bool,
+    srgb: bool,
+    // Whether RGBA32F textures can be sampled and rendered to
+    float_textures: bool,
+    float_render_targets: bool,
+    // glPolygonMode only exists in desktop GL
+    polygon_modes: bool,
+    polygon_mode: PolygonMode,
+    limits: Limits,
+    deletion_queue: DeletionQueue,
+    supersampling: Option<Supersampling>,
+    texture_copier: Option<TextureCopier>,
+    // Set with `set_scissor`, restored after clears of a given rectangle
+    scissor: Option<Rect<i32>>,
+    // Depth and stencil buffer of the framebuffer set with `set_screen_framebuffer`
+    screen_depth_stencil: Option<Rc<Handle<RenderbufferId>>>,
+}
+
+// Offscreen target that screen draws go to while rendering at a scale, and what is needed to
+// draw it to the screen
+struct Supersampling {
+    target: RenderTexture,
+    program: Program,
+    quad: VertexBuffer,
+}
+
+// Reusable parts for `Context::copy_texture_region`
+struct TextureCopier {
+    program: Program,
+    quad: VertexBuffer,
+    framebuffer: Rc<Handle<FramebufferId>>,
+}
+
+// Vertices for a quad covering the viewport that shows the given min x, min y, max x, max y of
+// the texture, for the blit program
+fn blit_quad(uv: [f32; 4]) -> [[f32; 4]; 6] {
+    [
+        [-1.0, -1.0, uv[0], uv[1]],
+        [1.0, -1.0, uv[2], uv[1]],
+        [-1.0, 1.0, uv[0], uv[3]],
+        [1.0, -1.0, uv[2], uv[1]],
+        [1.0, 1.0, uv[2], uv[3]],
+        [-1.0, 1.0, uv[0], uv[3]],
+    ]
+}
+
+const BLIT_VERTEX_SHADER: &str = "#version 100
+attribute highp vec2 a_pos;
+attribute highp vec2 a_uv;
+varying highp vec2 v_uv;
+
+void main()
+{
+    v_uv = a_uv;
+    gl_Position = vec4(a_pos, 0.0, 1.0);
+}";
+
+const BLIT_FRAGMENT_SHADER: &str = "#version 100
+varying highp vec2 v_uv;
+uniform sampler2D u_texture;
+
+void main()
+{
+    gl_FragColor = texture2D(u_texture, v_uv);
+}";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GlObject {
+    Program(ProgramId),
+    Shader(ShaderId),
+    VertexArray(VertexArrayId),
+    Buffer(BufferId),
+    Texture(TextureId),
+    Framebuffer(FramebufferId),
+    Renderbuffer(RenderbufferId),
+}
+
+// Objects whose last handle was dropped, deleted by `Context::maintain`
+#[derive(Clone, Default)]
+struct DeletionQueue(Rc<RefCell<Vec<GlObject>>>);
+
+impl DeletionQueue {
+    fn push(&self, object: GlObject) {
+        self.0.borrow_mut().push(object);
+    }
+
+    // Empties the queue, so it only ever holds what was dropped since the last call
+    fn take(&self) -> Vec<GlObject> {
+        std::mem::take(&mut *self.0.borrow_mut())
+    }
+}
+
+// The id of a GL object, shared through an `Rc` by everything using the object. Queues the object
+// for deletion when the last one is dropped.
+struct Handle<T> {
+    id: T,
+    object: GlObject,
+    deletion_queue: DeletionQueue,
+}
+
+impl<T> std::ops::Deref for Handle<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.id
+    }
+}
+
+impl<T: PartialEq> PartialEq for Handle<T> {
+    fn eq(&self, other: &Handle<T>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Drop for Handle<T> {
+    fn drop(&mut self) {
+        self.deletion_queue.push(self.object);
+    }
+}
+
+// State shared between the context and the programs created from it
+struct ContextState {
+    // Size of the default framebuffer in pixels
+    screen_size: Cell<(i32, i32)>,
+    // Overrides the viewport that covers the whole render target
+    viewport: Cell<Option<(i32, i32, i32, i32)>>,
+    // While rendering at a scale, screen draws go to this framebuffer of the given size instead
+    screen_redirect: Cell<Option<(FramebufferId, (i32, i32))>>,
+    // Set with redirect_screen, takes precedence over screen_redirect
+    screen_override: Cell<Option<(FramebufferId, (i32, i32))>>,
+    // Stands in for the default framebuffer, see set_screen_framebuffer
+    screen_framebuffer: Cell<Option<FramebufferId>>,
+    render_scale: Cell<f32>,
+    // Whether instanced draws and attribute divisors are available, core in GLES 3 and WebGL 2
+    // and an extension before that
+    instancing: Cell<bool>,
+    // Program last bound with use_program, so drawing with the same one again skips the rebind
+    current_program: Cell<Option<ProgramId>>,
+    // GL calls issued by draws since the count was last taken
+    #[cfg(feature = "debug-gl")]
+    gl_calls: Cell<u32>,
+}
+
+// Framebuffer and viewport a draw goes to, and the blend mode a render pass overrides the
+// program's with
+#[derive(Clone, Copy)]
+struct DrawTarget {
+    framebuffer: Option<FramebufferId>,
+    viewport: (i32, i32, i32, i32),
+    blend_mode: Option<BlendMode>,
+}
+
+impl ContextState {
+    fn draw_target(&self, target: RenderTarget) -> DrawTarget {
+        self.draw_target_with_viewport(target, self.viewport.get())
+    }
+
+    fn draw_target_with_viewport(
+        &self,
+        target: RenderTarget,
+        viewport: Option<(i32, i32, i32, i32)>,
+    ) -> DrawTarget {
+        let (framebuffer, size) = self.target_framebuffer(target);
+        let is_screen = match target {
+            RenderTarget::Screen => true,
+            RenderTarget::Texture(_) => false,
+        };
+        let viewport = match viewport {
+            // explicit viewports are given in screen pixels
+            Some((x, y, width, height)) if is_screen && self.screen_redirect.get().is_some() => {
+                let scale = self.render_scale.get();
+                let scale = |v: i32| (v as f32 * scale).round() as i32;
+                (scale(x), scale(y), scale(width), scale(height))
+            }
+            Some(viewport) => viewport,
+            None => (0, 0, size.0, size.1),
+        };
+        DrawTarget {
+            framebuffer,
+            viewport,
+            blend_mode: None,
+        }
+    }
+
+    // Framebuffer that draws to `target` go to and its size
+    fn target_framebuffer(&self, target: RenderTarget) -> (Option<FramebufferId>, (i32, i32)) {
+        match target {
+            RenderTarget::Screen => match self
+                .screen_override
+                .get()
+                .or_else(|| self.screen_redirect.get())
+            {
+                Some((framebuffer, size)) => (Some(framebuffer), size),
+                None => (self.screen_framebuffer.get(), self.screen_size.get()),
+            },
+            RenderTarget::Texture(render_texture) => (
+                Some(**render_texture.framebuffer),
+                render_texture.texture.size,
+            ),
+        }
+    }
+
+    // Binds the framebuffer for `target` and returns its size
+    unsafe fn bind_target(&self, context: &glow::Context, target: RenderTarget) -> (i32, i32) {
+        let (framebuffer, size) = self.target_framebuffer(target);
+        context.bind_framebuffer(glow::FRAMEBUFFER, framebuffer);
+        size
+    }
+
+    #[allow(unused_variables)]
+    fn count_gl_calls(&self, calls: u32) {
+        #[cfg(feature = "debug-gl")]
+        self.gl_calls.set(self.gl_calls.get() + calls);
+    }
+}
+
+// Inserts `#define`s into shader source after its header, followed by a `#line` that puts line
+// numbers back in step with the original source
+fn with_defines(src: &str, defines: &[(&str, &str)]) -> String {
+    let lines: Vec<&str> = src.lines().collect();
+    let header_len = lines
+        .iter()
+        .take_while(|line| {
+            let line = line.trim();
+            line.is_empty() || line.starts_with("#version") || line.starts_with("precision")
+        })
+        .count();
+    let version = lines
+        .first()
+        .and_then(|line| line.trim().strip_prefix("#version"))
+        .and_then(|version| version.split_whitespace().next())
+        .and_then(|version| version.parse::<u32>().ok())
+        .unwrap_or(100);
+
+    let mut out = String::with_capacity(src.len() + defines.len() * 32);
+    for line in &lines[..header_len] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    for (name, value) in defines {
+        out.push_str(&format!("#define {} {}\n", name, value));
+    }
+    // GLSL ES 1.00 and GLSL up to 1.20 number the line after `#line n` as n + 1, later versions
+    // as n
+    let next_line = header_len + 1;
+    let line = if version <= 120 {
+        next_line - 1
+    } else {
+        next_line
+    };
+    out.push_str(&format!("#line {}\n", line));
+    for line in &lines[header_len..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// What the GL implementation supports, queried when the context is created.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Largest width and height of a texture
+    pub max_texture_size: u32,
+    pub max_vertex_attribs: u32,
+    /// Number of textures a fragment shader can sample at once
+    pub max_texture_units: u32,
+    pub max_renderbuffer_size: u32,
+    pub version: String,
+    pub renderer: String,
 }
 
 #[derive(Debug, Error)]
@@ -44,190 +430,1762 @@ pub struct GLError(String);
 
 impl Context {
     pub fn from_glow_context(context: glow::Context) -> Context {
+        let version = unsafe { context.get_parameter_string(glow::VERSION) };
+        let integer_attributes =
+            !(version.starts_with("OpenGL ES 2") || version.starts_with("WebGL 1"));
+        // Desktop GL has sRGB framebuffers, polygon modes and float textures built in
+        let desktop_gl = cfg!(not(target_arch = "wasm32"))
+            && !(version.starts_with("OpenGL ES") || version.starts_with("WebGL"));
+        // Float textures are core in desktop GL. On GLES they are sampleable from GLES 3 or with
+        // an extension, rendering to them always needs one. WebGL extensions have to be enabled,
+        // so the web platform reports them through `enable_float_texture_extensions`.
+        let (float_textures, float_render_targets) = if desktop_gl {
+            (true, true)
+        } else if cfg!(not(target_arch = "wasm32")) {
+            let extensions = unsafe { context.get_parameter_string(glow::EXTENSIONS) };
+            let has_extension = |name: &str| extensions.split(' ').any(|ext| ext == name);
+            (
+                integer_attributes || has_extension("GL_OES_texture_float"),
+                has_extension("GL_EXT_color_buffer_float"),
+            )
+        } else {
+            (false, false)
+        };
+        #[cfg(all(feature = "debug-gl", not(target_arch = "wasm32")))]
+        unsafe {
+            enable_debug_messages(&context)
+        };
+        let limits = unsafe {
+            Limits {
+                max_texture_size: context.get_parameter_i32(glow::MAX_TEXTURE_SIZE) as u32,
+                max_vertex_attribs: context.get_parameter_i32(glow::MAX_VERTEX_ATTRIBS) as u32,
+                max_texture_units: context.get_parameter_i32(glow::MAX_TEXTURE_IMAGE_UNITS) as u32,
+                max_renderbuffer_size: context.get_parameter_i32(glow::MAX_RENDERBUFFER_SIZE)
+                    as u32,
+                version,
+                renderer: context.get_parameter_string(glow::RENDERER),
+            }
+        };
+        log::info!("GL limits: {:?}", limits);
         Context {
+            integer_attributes,
+            srgb_framebuffer: desktop_gl,
+            srgb: false,
+            float_textures,
+            float_render_targets,
+            polygon_modes: desktop_gl,
+            polygon_mode: PolygonMode::Fill,
+            limits,
             context: Rc::new(context),
-            shaders: Vec::new(),
-            programs: Vec::new(),
-            vertex_arrays: Vec::new(),
-            buffers: Vec::new(),
-            textures: Vec::new(),
-            frame_buffers: Vec::new(),
+            state: Rc::new(ContextState {
+                screen_size: Cell::new((SCREEN_SIZE.0 as i32, SCREEN_SIZE.1 as i32)),
+                viewport: Cell::new(None),
+                screen_redirect: Cell::new(None),
+                screen_override: Cell::new(None),
+                screen_framebuffer: Cell::new(None),
+                render_scale: Cell::new(1.),
+                instancing: Cell::new(integer_attributes),
+                current_program: Cell::new(None),
+                #[cfg(feature = "debug-gl")]
+                gl_calls: Cell::new(0),
+            }),
+            deletion_queue: DeletionQueue::default(),
+            supersampling: None,
+            texture_copier: None,
+            scissor: None,
+            screen_depth_stencil: None,
         }
     }
 
-    pub unsafe fn create_shader(
-        &mut self,
-        shader_type: ShaderType,
-        src: &str,
-    ) -> Result<Shader, GLError> {
-        let shader_id = self
-            .context
-            .create_shader(shader_type as u32)
-            .map_err(GLError)?;
-        self.context.shader_source(shader_id, src);
-        self.context.compile_shader(shader_id);
-        if !self.context.get_shader_compile_status(shader_id) {
-            Err(GLError(self.context.get_shader_info_log(shader_id)))
-        } else {
-            let shader = Shader(Rc::new(shader_id));
-            self.shaders.push(shader.0.clone());
-            Ok(shader)
+    /// Called by the platform layer whenever the window's framebuffer changes size.
+    pub unsafe fn set_screen_size(&mut self, width: u32, height: u32) {
+        self.state.screen_size.set((width as i32, height as i32));
+        if let Err(e) = self.update_supersampling() {
+            log::error!("Could not resize the supersampling target: {}", e);
+            self.disable_supersampling();
         }
     }
 
-    pub unsafe fn create_program(&mut self, desc: &ProgramDescriptor) -> Result<Program, GLError> {
-        let program_id = self.context.create_program().map_err(GLError)?;
-        self.context
-            .attach_shader(program_id, *desc.vertex_shader.0);
-        self.context
-            .attach_shader(program_id, *desc.fragment_shader.0);
-        self.context.link_program(program_id);
-        if !self.context.get_program_link_status(program_id) {
-            return Err(GLError(self.context.get_program_info_log(program_id)));
+    /// Called by the platform layer when instancing is available through an extension, e.g.
+    /// `ANGLE_instanced_arrays` on WebGL 1.
+    pub fn enable_instancing_extension(&mut self) {
+        self.state.instancing.set(true);
+    }
+
+    /// Names the texture in graphics debugger captures. Like the other labels, only does
+    /// something with the debug-gl feature on drivers with KHR_debug.
+    pub unsafe fn label_texture(&self, texture: &Texture, label: &str) {
+        object_label(&self.context, glow::TEXTURE, **texture.texture_id, label);
+    }
+
+    pub unsafe fn label_buffer(&self, buffer: &VertexBuffer, label: &str) {
+        object_label(&self.context, glow::BUFFER, **buffer.buffer, label);
+        object_label(
+            &self.context,
+            glow::VERTEX_ARRAY,
+            **buffer.vertex_array,
+            label,
+        );
+    }
+
+    pub unsafe fn label_index_buffer(&self, buffer: &IndexBuffer, label: &str) {
+        object_label(&self.context, glow::BUFFER, **buffer.buffer, label);
+    }
+
+    pub unsafe fn label_program(&self, program: &Program, label: &str) {
+        object_label(&self.context, glow::PROGRAM, **program.program_id, label);
+    }
+
+    /// Groups the commands until the matching `pop_debug_group` under `name` in graphics
+    /// debugger captures.
+    pub unsafe fn push_debug_group(&self, name: &str) {
+        push_debug_group(&self.context, name);
+    }
+
+    pub unsafe fn pop_debug_group(&self) {
+        pop_debug_group(&self.context);
+    }
+
+    /// Called by the web platform with which float texture extensions it could enable:
+    /// `OES_texture_float` for sampling and `WEBGL_color_buffer_float` or
+    /// `EXT_color_buffer_float` for rendering.
+    pub fn enable_float_texture_extensions(&mut self, sampling: bool, rendering: bool) {
+        self.float_textures |= sampling;
+        self.float_render_targets |= sampling && rendering;
+    }
+
+    /// Whether `create_texture` can create textures of the format.
+    pub fn supports_format(&self, format: TextureFormat) -> bool {
+        match format {
+            // sRGB and half float textures came with GLES 3 and WebGL 2, same as integer
+            // attributes. GLES 2 has half floats as an extension, but they can't be uploaded as
+            // 32 bit floats.
+            TextureFormat::SRGBA | TextureFormat::RGBA16F => self.integer_attributes,
+            TextureFormat::RGBA32F => self.float_textures,
+            _ => true,
         }
+    }
 
-        let mut set_uniforms = Vec::new();
-        for entry in desc.uniforms {
-            let location = self
-                .context
-                .get_uniform_location(program_id, entry.name)
-                .ok_or_else(|| {
-                    GLError(format!("could not get location for uniform {}", entry.name))
-                })?;
-            set_uniforms.push((location, None));
+    /// Whether `create_render_texture` can create render textures of the format.
+    pub fn supports_render_format(&self, format: TextureFormat) -> bool {
+        match format {
+            TextureFormat::RGBA16F | TextureFormat::RGBA32F => {
+                self.supports_format(format) && self.float_render_targets
+            }
+            _ => self.supports_format(format),
         }
+    }
 
-        let vertex_format = VertexFormatInner {
-            stride: desc.vertex_format.stride as i32,
-            attributes: desc
-                .vertex_format
-                .attributes
-                .iter()
-                .map(|attr_desc| {
-                    let location = self
-                        .context
-                        .get_attrib_location(program_id, attr_desc.name)
-                        .ok_or_else(|| {
-                            GLError(format!(
-                                "could not get location of attribute {}",
-                                attr_desc.name
-                            ))
-                        })?;
-                    let attribute = VertexAttributeInner {
-                        ty: attr_desc.ty,
-                        size: attr_desc.size,
-                        offset: attr_desc.offset,
-                    };
-                    Ok((location, attribute))
-                })
-                .collect::<Result<Vec<_>, GLError>>()?,
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Whether `Program::render_instanced` can be used. Without it, the same thing can be drawn
+    /// by setting the per-instance values as uniforms and calling `render_vertices` for each
+    /// instance, or by writing out every instance's vertices.
+    pub fn supports_instancing(&self) -> bool {
+        self.state.instancing.get()
+    }
+
+    /// Width in pixels of lines drawn with `PrimitiveType::Lines` and `LineStrip`. WebGL and
+    /// core profile GL generally only support 1, in which case other widths are clamped.
+    pub unsafe fn set_line_width(&mut self, width: f32) {
+        self.context.line_width(width);
+        check_gl_errors!(self.context, "set_line_width");
+    }
+
+    /// Renders everything drawn to the screen at `scale` times the screen's resolution, and
+    /// filters it down to the screen in `present`. Viewports set with `set_viewport` are scaled
+    /// along, scissor rectangles and `read_pixels` work in the scaled pixels. The scaled target
+    /// has no depth or stencil buffer.
+    pub unsafe fn set_render_scale(&mut self, scale: f32) -> Result<(), GLError> {
+        if scale == self.state.render_scale.get() {
+            return Ok(());
+        }
+        self.state.render_scale.set(scale);
+        let result = self.update_supersampling();
+        if result.is_err() {
+            self.disable_supersampling();
+        }
+        result
+    }
+
+    /// Opts into gamma correct rendering. Off by default, which keeps colors blending as the raw
+    /// sRGB values they are given as.
+    ///
+    /// Where `srgb_framebuffer` is true this enables `GL_FRAMEBUFFER_SRGB`, so that writes to
+    /// `TextureFormat::SRGBA` render textures and to an sRGB capable default framebuffer are
+    /// converted from linear. GLES 2 and WebGL 1 have no such switch; there programs have to
+    /// check `srgb` and do the conversions in the shader instead.
+    pub unsafe fn set_srgb(&mut self, enabled: bool) {
+        self.srgb = enabled;
+        if self.srgb_framebuffer {
+            if enabled {
+                self.context.enable(glow::FRAMEBUFFER_SRGB);
+            } else {
+                self.context.disable(glow::FRAMEBUFFER_SRGB);
+            }
+            check_gl_errors!(self.context, "set_srgb");
+        }
+    }
+
+    pub fn srgb(&self) -> bool {
+        self.srgb
+    }
+
+    /// Whether `set_srgb` makes GL convert written colors, see there.
+    pub fn srgb_framebuffer(&self) -> bool {
+        self.srgb_framebuffer
+    }
+
+    pub fn render_scale(&self) -> f32 {
+        self.state.render_scale.get()
+    }
+
+    /// Draws triangles filled or as outlines, for checking geometry. Only desktop GL can outline,
+    /// elsewhere switching to `PolygonMode::Line` logs a warning and keeps filling. Stays in
+    /// effect for all following draws until set back, apart from `present`, which always fills.
+    pub unsafe fn set_polygon_mode(&mut self, mode: PolygonMode) {
+        if mode == self.polygon_mode {
+            return;
+        }
+        if !self.polygon_modes {
+            log::warn!("Polygon mode {:?} is only supported on desktop GL", mode);
+            return;
+        }
+        self.polygon_mode = mode;
+        self.apply_polygon_mode(mode);
+    }
+
+    pub fn polygon_mode(&self) -> PolygonMode {
+        self.polygon_mode
+    }
+
+    pub fn supports_polygon_mode(&self) -> bool {
+        self.polygon_modes
+    }
+
+    unsafe fn apply_polygon_mode(&self, mode: PolygonMode) {
+        self.context.polygon_mode(glow::FRONT_AND_BACK, mode as u32);
+        check_gl_errors!(self.context, "set_polygon_mode");
+    }
+
+    /// Draws what was rendered to the screen at a scale onto the actual screen. Does nothing at
+    /// a scale of 1. The platform layer calls this before swapping buffers.
+    pub unsafe fn present(&mut self) -> Result<(), GLError> {
+        let supersampling = match &self.supersampling {
+            Some(supersampling) => supersampling,
+            None => return Ok(()),
         };
+        self.state.screen_redirect.set(None);
+        let screen_override = self.state.screen_override.take();
+        let viewport = self.state.viewport.take();
+        if self.polygon_mode != PolygonMode::Fill {
+            self.apply_polygon_mode(PolygonMode::Fill);
+        }
+        let result = supersampling.program.render_vertices(
+            &supersampling.quad,
+            PrimitiveType::Triangles,
+            RenderTarget::Screen,
+        );
+        if self.polygon_mode != PolygonMode::Fill {
+            self.apply_polygon_mode(self.polygon_mode);
+        }
+        self.state.viewport.set(viewport);
+        self.state.screen_override.set(screen_override);
+        self.state.screen_redirect.set(Some((
+            **supersampling.target.framebuffer,
+            supersampling.target.texture.size,
+        )));
+        result
+    }
 
-        let program_id = Rc::new(program_id);
-        self.programs.push(program_id.clone());
-        Ok(Program {
-            context: self.context.clone(),
-            program_id: program_id,
-            vertex_shader: desc.vertex_shader.0.clone(),
-            fragment_shader: desc.fragment_shader.0.clone(),
-            uniform_entry_types: desc.uniforms.iter().map(|e| e.ty).collect(),
-            set_uniforms,
-            vertex_format,
+    // Creates, resizes or removes the offscreen target to match the render scale
+    unsafe fn update_supersampling(&mut self) -> Result<(), GLError> {
+        let scale = self.state.render_scale.get();
+        if scale == 1. {
+            self.disable_supersampling();
+            return Ok(());
+        }
+        let (width, height) = self.state.screen_size.get();
+        let size = (
+            ((width as f32 * scale).round() as u32).max(1),
+            ((height as f32 * scale).round() as u32).max(1),
+        );
+
+        match &mut self.supersampling {
+            Some(supersampling) => {
+                if supersampling.target.size() != size {
+                    supersampling.target.resize(size.0, size.1)?;
+                }
+            }
+            None => {
+                let supersampling = self.create_supersampling(size)?;
+                self.supersampling = Some(supersampling);
+            }
+        }
+        let target = &self.supersampling.as_ref().unwrap().target;
+        self.state
+            .screen_redirect
+            .set(Some((**target.framebuffer, target.texture.size)));
+        Ok(())
+    }
+
+    unsafe fn disable_supersampling(&mut self) {
+        self.state.screen_redirect.set(None);
+        self.state.render_scale.set(1.);
+        self.supersampling = None;
+    }
+
+    unsafe fn create_supersampling(&mut self, size: (u32, u32)) -> Result<Supersampling, GLError> {
+        let mut target = self.create_render_texture(TextureFormat::RGBA8, size.0, size.1)?;
+        target.texture_mut().set_filter(TextureFilter::LINEAR);
+
+        let mut program = self.create_blit_program()?;
+        program.set_uniform(0, Uniform::Texture(target.texture()))?;
+
+        let mut quad = self.create_vertex_buffer(BufferUsage::Static)?;
+        quad.write(&blit_quad([0., 0., 1., 1.]));
+
+        Ok(Supersampling {
+            target,
+            program,
+            quad,
         })
     }
 
-    pub unsafe fn create_vertex_buffer(&mut self) -> Result<VertexBuffer, GLError> {
-        let vertex_array_id = Rc::new(self.context.create_vertex_array().map_err(GLError)?);
-        self.vertex_arrays.push(vertex_array_id.clone());
-        let buffer_id = Rc::new(self.context.create_buffer().map_err(GLError)?);
-        self.buffers.push(buffer_id.clone());
+    // Program that draws its u_texture onto quads made by `blit_quad` without blending
+    unsafe fn create_blit_program(&mut self) -> Result<Program, GLError> {
+        let vertex_shader = self.create_shader(ShaderType::Vertex, BLIT_VERTEX_SHADER)?;
+        let fragment_shader = self.create_shader(ShaderType::Fragment, BLIT_FRAGMENT_SHADER)?;
+        let mut program = self.create_program(&ProgramDescriptor {
+            vertex_shader: &vertex_shader,
+            fragment_shader: &fragment_shader,
+            uniforms: &[UniformEntry {
+                name: "u_texture",
+                ty: UniformType::Texture,
+                array_len: 0,
+                default: None,
+            }],
+            vertex_format: VertexFormat {
+                stride: 4 * 4,
+                instance_stride: 0,
+                attributes: &[
+                    VertexAttribute {
+                        name: "a_pos",
+                        ty: VertexAttributeType::Float,
+                        size: 2,
+                        offset: 0,
+                        normalized: false,
+                        integer: false,
+                        divisor: 0,
+                    },
+                    VertexAttribute {
+                        name: "a_uv",
+                        ty: VertexAttributeType::Float,
+                        size: 2,
+                        offset: 2 * 4,
+                        normalized: false,
+                        integer: false,
+                        divisor: 0,
+                    },
+                ],
+            },
+        })?;
+        program.set_blend_mode(BlendMode::None);
+        Ok(program)
+    }
+
+    /// Copies `src_rect` of `src` to `dst` at `dst_pos`, both in the texel coordinates used by
+    /// `Texture::write`. glow has no copy_tex_sub_image, so this draws `src` into `dst`. The copy
+    /// is clipped to the scissor rectangle while one is set.
+    pub unsafe fn copy_texture_region(
+        &mut self,
+        src: &Texture,
+        src_rect: Rect<u32>,
+        dst: &mut Texture,
+        dst_pos: Point2D<u32>,
+    ) -> Result<(), GLError> {
+        if src_rect.max_x() > src.size.0 as u32 || src_rect.max_y() > src.size.1 as u32 {
+            return Err(GLError(format!(
+                "Cannot copy {:?} from a texture of size {:?}",
+                src_rect, src.size
+            )));
+        }
+        let dst_rect = Rect::new(dst_pos, src_rect.size);
+        if dst_rect.max_x() > dst.size.0 as u32 || dst_rect.max_y() > dst.size.1 as u32 {
+            return Err(GLError(format!(
+                "Cannot copy to {:?} in a texture of size {:?}",
+                dst_rect, dst.size
+            )));
+        }
+        if src_rect.is_empty() {
+            return Ok(());
+        }
+
+        if self.texture_copier.is_none() {
+            let program = self.create_blit_program()?;
+            let quad = self.create_vertex_buffer(BufferUsage::Stream)?;
+            let framebuffer = self.handle(
+                self.context.create_framebuffer().map_err(GLError)?,
+                GlObject::Framebuffer,
+            );
+            self.texture_copier = Some(TextureCopier {
+                program,
+                quad,
+                framebuffer,
+            });
+        }
+        let copier = self.texture_copier.as_mut().unwrap();
+
+        self.context
+            .bind_framebuffer(glow::FRAMEBUFFER, Some(**copier.framebuffer));
+        self.context.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(**dst.texture_id),
+            0,
+        );
+        let status = self.context.check_framebuffer_status(glow::FRAMEBUFFER);
+        if status != glow::FRAMEBUFFER_COMPLETE {
+            return Err(GLError(format!(
+                "Cannot copy into a {:?} texture, framebuffer status {:#x}",
+                dst.format, status
+            )));
+        }
+
+        copier.quad.write(&blit_quad([
+            src_rect.min_x() as f32 / src.size.0 as f32,
+            src_rect.min_y() as f32 / src.size.1 as f32,
+            src_rect.max_x() as f32 / src.size.0 as f32,
+            src_rect.max_y() as f32 / src.size.1 as f32,
+        ]));
+        copier.program.set_uniform(0, Uniform::Texture(src))?;
+        copier.program.prepare_draw(
+            &copier.quad,
+            None,
+            DrawTarget {
+                framebuffer: Some(**copier.framebuffer),
+                viewport: (
+                    dst_rect.origin.x as i32,
+                    dst_rect.origin.y as i32,
+                    dst_rect.size.width as i32,
+                    dst_rect.size.height as i32,
+                ),
+                blend_mode: None,
+            },
+        )?;
+        self.context.draw_arrays(glow::TRIANGLES, 0, 6);
+        check_gl_errors!(self.context, "copy_texture_region");
+
+        if dst.filter.min.uses_mipmaps() {
+            dst.generate_mipmaps();
+        }
+        Ok(())
+    }
+
+    pub fn screen_size(&self) -> (u32, u32) {
+        let (width, height) = self.state.screen_size.get();
+        (width as u32, height as u32)
+    }
+
+    /// Size in pixels of what draws to the screen go to, the screen size times the render scale.
+    pub fn screen_target_size(&self) -> (u32, u32) {
+        match &self.supersampling {
+            Some(supersampling) => supersampling.target.size(),
+            None => self.screen_size(),
+        }
+    }
+
+    /// Sends draws to `RenderTarget::Screen` into `target` instead, until called again with
+    /// `None`. Viewports are still scaled by the render scale, so `target` should be
+    /// `screen_target_size` big. `present` is unaffected.
+    pub fn redirect_screen(&mut self, target: Option<&RenderTexture>) {
+        self.state
+            .screen_override
+            .set(target.map(|target| (**target.framebuffer, target.texture.size)));
+    }
+
+    /// Makes `target` the screen, for contexts without a default framebuffer like headless ones,
+    /// and the screen size its size. Unlike with `redirect_screen`, `present` draws to it too.
+    /// `target` gets depth and stencil buffers like the screen has, which needs GLES 3 or
+    /// `OES_packed_depth_stencil`, and has to outlive the context's use of the screen.
+    pub unsafe fn set_screen_framebuffer(&mut self, target: &RenderTexture) -> Result<(), GLError> {
+        let (width, height) = target.size();
+        // drivers tend to only take depth and stencil packed into one buffer, which GLES 2 has no
+        // attachment point for, so it's attached as both
+        let renderbuffer = self.handle(
+            self.context.create_renderbuffer().map_err(GLError)?,
+            GlObject::Renderbuffer,
+        );
+        self.context
+            .bind_renderbuffer(glow::RENDERBUFFER, Some(**renderbuffer));
+        self.context.renderbuffer_storage(
+            glow::RENDERBUFFER,
+            glow::DEPTH24_STENCIL8,
+            width as i32,
+            height as i32,
+        );
+        self.context
+            .bind_framebuffer(glow::FRAMEBUFFER, Some(**target.framebuffer));
+        for &attachment in &[glow::DEPTH_ATTACHMENT, glow::STENCIL_ATTACHMENT] {
+            self.context.framebuffer_renderbuffer(
+                glow::FRAMEBUFFER,
+                attachment,
+                glow::RENDERBUFFER,
+                Some(**renderbuffer),
+            );
+        }
+        check_gl_errors!(self.context, "set_screen_framebuffer");
+        target.check_complete()?;
+        self.screen_depth_stencil = Some(renderbuffer);
+
+        self.state
+            .screen_framebuffer
+            .set(Some(**target.framebuffer));
+        self.set_screen_size(width, height);
+        Ok(())
+    }
+
+    /// Draws into the given part of the render target from now on, instead of the whole of
+    /// whichever target is drawn to. In pixels from the bottom left.
+    pub fn set_viewport(&mut self, x: i32, y: i32, width: i32, height: i32) {
+        self.state.viewport.set(Some((x, y, width, height)));
+    }
+
+    /// Goes back to drawing to the whole of the screen or texture being rendered to.
+    pub fn reset_viewport(&mut self) {
+        self.state.viewport.set(None);
+    }
+
+    /// Starts a render pass drawing into `target`. The pass's settings only apply to draws made
+    /// through it.
+    pub fn pass<'a>(&'a mut self, target: RenderTarget<'a>) -> RenderPass<'a> {
+        RenderPass {
+            context: self,
+            target,
+            viewport: None,
+            blend_mode: None,
+            scissor: None,
+        }
+    }
+
+    /// Number of GL calls issued by draws since the last time this was called, to check how many
+    /// redundant ones are being skipped. Call once a frame.
+    #[cfg(feature = "debug-gl")]
+    pub fn take_gl_call_count(&mut self) -> u32 {
+        self.state.gl_calls.replace(0)
+    }
+
+    // Wraps a new object's id so it's queued for deletion once the last clone is dropped
+    fn handle<T: Copy>(&self, id: T, object: fn(T) -> GlObject) -> Rc<Handle<T>> {
+        Rc::new(Handle {
+            id,
+            object: object(id),
+            deletion_queue: self.deletion_queue.clone(),
+        })
+    }
+
+    pub unsafe fn create_shader(
+        &mut self,
+        shader_type: ShaderType,
+        src: &str,
+    ) -> Result<Shader, GLError> {
+        self.create_shader_with_defines(shader_type, src, &[])
+    }
+
+    /// Compiles `src` with a `#define NAME VALUE` line for each of `defines`, so one source can
+    /// have variants selected with `#ifdef`. The defines go after the `#version` and `precision`
+    /// lines at the top, and line numbers in compile errors still match `src`.
+    pub unsafe fn create_shader_with_defines(
+        &mut self,
+        shader_type: ShaderType,
+        src: &str,
+        defines: &[(&str, &str)],
+    ) -> Result<Shader, GLError> {
+        let src = if defines.is_empty() {
+            src.to_owned()
+        } else {
+            with_defines(src, defines)
+        };
+        let shader_id = self
+            .context
+            .create_shader(shader_type as u32)
+            .map_err(GLError)?;
+        self.context.shader_source(shader_id, &src);
+        self.context.compile_shader(shader_id);
+        check_gl_errors!(self.context, "create_shader");
+        if !self.context.get_shader_compile_status(shader_id) {
+            Err(GLError(self.context.get_shader_info_log(shader_id)))
+        } else {
+            let shader = Shader(self.handle(shader_id, GlObject::Shader));
+            Ok(shader)
+        }
+    }
+
+    pub unsafe fn create_program(&mut self, desc: &ProgramDescriptor) -> Result<Program, GLError> {
+        let program_id = self.context.create_program().map_err(GLError)?;
+        self.context
+            .attach_shader(program_id, **desc.vertex_shader.0);
+        self.context
+            .attach_shader(program_id, **desc.fragment_shader.0);
+        self.context.link_program(program_id);
+        if !self.context.get_program_link_status(program_id) {
+            return Err(GLError(self.context.get_program_info_log(program_id)));
+        }
+
+        let texture_count = desc
+            .uniforms
+            .iter()
+            .filter(|entry| entry.ty == UniformType::Texture)
+            .count();
+        if texture_count > self.limits.max_texture_units as usize {
+            return Err(GLError(format!(
+                "program samples {} textures, but only {} texture units are available",
+                texture_count, self.limits.max_texture_units
+            )));
+        }
+
+        let mut set_uniforms = Vec::new();
+        let mut element_locations = Vec::new();
+        for entry in desc.uniforms {
+            let location = self
+                .context
+                .get_uniform_location(program_id, entry.name)
+                .ok_or_else(|| {
+                    GLError(format!("could not get location for uniform {}", entry.name))
+                })?;
+            set_uniforms.push((location, None));
+
+            let mut elements = Vec::new();
+            if entry.ty.is_array() {
+                if entry.array_len == 0 {
+                    return Err(GLError(format!(
+                        "uniform {} is an array but has an array_len of 0",
+                        entry.name
+                    )));
+                }
+                // glow only uploads one element at a time, so every element gets its own
+                // location. The compiler may drop elements past the last one the shader uses.
+                for i in 0..entry.array_len {
+                    match self
+                        .context
+                        .get_uniform_location(program_id, &format!("{}[{}]", entry.name, i))
+                    {
+                        Some(location) => elements.push(location),
+                        None => break,
+                    }
+                }
+            }
+            element_locations.push(elements);
+        }
+
+        let vertex_format = VertexFormatInner {
+            stride: desc.vertex_format.stride as i32,
+            instance_stride: desc.vertex_format.instance_stride as i32,
+            attributes: desc
+                .vertex_format
+                .attributes
+                .iter()
+                .map(|attr_desc| {
+                    let location = self
+                        .context
+                        .get_attrib_location(program_id, attr_desc.name)
+                        .ok_or_else(|| {
+                            GLError(format!(
+                                "could not get location of attribute {}",
+                                attr_desc.name
+                            ))
+                        })?;
+                    if attr_desc.integer && !self.integer_attributes {
+                        return Err(GLError(format!(
+                            "attribute {} is an integer attribute, which needs GLES 3 or WebGL 2",
+                            attr_desc.name
+                        )));
+                    }
+                    if attr_desc.integer && attr_desc.ty == VertexAttributeType::Float {
+                        return Err(GLError(format!(
+                            "attribute {} is an integer attribute but has type Float",
+                            attr_desc.name
+                        )));
+                    }
+                    if attr_desc.divisor > 0 && !self.state.instancing.get() {
+                        return Err(GLError(format!(
+                            "attribute {} has a divisor, but instancing is not supported",
+                            attr_desc.name
+                        )));
+                    }
+                    let attribute = VertexAttributeInner {
+                        ty: attr_desc.ty,
+                        size: attr_desc.size,
+                        offset: attr_desc.offset,
+                        normalized: attr_desc.normalized,
+                        integer: attr_desc.integer,
+                        divisor: attr_desc.divisor,
+                    };
+                    Ok((location, attribute))
+                })
+                .collect::<Result<Vec<_>, GLError>>()?,
+        };
+
+        check_gl_errors!(self.context, "create_program");
+        let program_id = self.handle(program_id, GlObject::Program);
+        let mut program = Program {
+            context: self.context.clone(),
+            state: self.state.clone(),
+            program_id: program_id,
+            vertex_shader: desc.vertex_shader.0.clone(),
+            fragment_shader: desc.fragment_shader.0.clone(),
+            uniform_names: desc.uniforms.iter().map(|e| e.name.to_owned()).collect(),
+            uniform_indices: desc
+                .uniforms
+                .iter()
+                .enumerate()
+                .map(|(i, e)| (e.name.to_owned(), i))
+                .collect(),
+            uniform_entry_types: desc.uniforms.iter().map(|e| e.ty).collect(),
+            uploaded_uniforms: RefCell::new(vec![None; desc.uniforms.len()]),
+            set_uniforms,
+            element_locations,
+            uniform_array_lens: desc.uniforms.iter().map(|e| e.array_len).collect(),
+            vertex_format,
+            depth_test: None,
+            stencil: None,
+            color_write: true,
+            blend_mode: BlendMode::default(),
+        };
+        for (i, entry) in desc.uniforms.iter().enumerate() {
+            if let Some(default) = entry.default {
+                program
+                    .set_uniform(i, default)
+                    .map_err(|e| GLError(format!("default for uniform {}: {}", entry.name, e.0)))?;
+            }
+        }
+        Ok(program)
+    }
+
+    pub unsafe fn create_vertex_buffer(
+        &mut self,
+        usage: BufferUsage,
+    ) -> Result<VertexBuffer, GLError> {
+        let vertex_array_id = self.handle(
+            self.context.create_vertex_array().map_err(GLError)?,
+            GlObject::VertexArray,
+        );
+        let buffer_id = self.handle(
+            self.context.create_buffer().map_err(GLError)?,
+            GlObject::Buffer,
+        );
+
+        Ok(VertexBuffer {
+            context: self.context.clone(),
+            vertex_array: vertex_array_id,
+            buffer: buffer_id,
+            usage,
+            len: 0,
+            capacity: 0,
+        })
+    }
+
+    pub unsafe fn create_instance_buffer(
+        &mut self,
+        usage: BufferUsage,
+    ) -> Result<InstanceBuffer, GLError> {
+        let buffer_id = self.handle(
+            self.context.create_buffer().map_err(GLError)?,
+            GlObject::Buffer,
+        );
+
+        Ok(InstanceBuffer {
+            context: self.context.clone(),
+            buffer: buffer_id,
+            usage,
+            len: 0,
+        })
+    }
+
+    pub unsafe fn create_index_buffer(
+        &mut self,
+        usage: BufferUsage,
+    ) -> Result<IndexBuffer, GLError> {
+        let buffer_id = self.handle(
+            self.context.create_buffer().map_err(GLError)?,
+            GlObject::Buffer,
+        );
+
+        Ok(IndexBuffer {
+            context: self.context.clone(),
+            buffer: buffer_id,
+            usage,
+            len: 0,
+        })
+    }
+
+    /// A texture that can be rendered into with `RenderTarget::Texture`.
+    pub unsafe fn create_render_texture(
+        &mut self,
+        format: TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<RenderTexture, GLError> {
+        if !self.supports_render_format(format) {
+            return Err(GLError(format!(
+                "Rendering to {:?} textures is not supported by {}",
+                format, self.limits.version
+            )));
+        }
+        let texture = self.create_texture(format, width, height, TextureFilter::default())?;
+        let framebuffer = self.handle(
+            self.context.create_framebuffer().map_err(GLError)?,
+            GlObject::Framebuffer,
+        );
+
+        self.context
+            .bind_framebuffer(glow::FRAMEBUFFER, Some(**framebuffer));
+        self.context.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(**texture.texture_id),
+            0,
+        );
+        check_gl_errors!(self.context, "create_render_texture");
+
+        let render_texture = RenderTexture {
+            texture,
+            framebuffer,
+        };
+        render_texture.check_complete()?;
+        Ok(render_texture)
+    }
+
+    pub unsafe fn create_texture(
+        &mut self,
+        format: TextureFormat,
+        width: u32,
+        height: u32,
+        filter: TextureFilter,
+    ) -> Result<Texture, GLError> {
+        if !self.supports_format(format) {
+            return Err(GLError(format!(
+                "{:?} textures are not supported by {}",
+                format, self.limits.version
+            )));
+        }
+        let texture_id = self.context.create_texture().map_err(GLError)?;
+        self.context
+            .bind_texture(glow::TEXTURE_2D, Some(texture_id));
+        self.context.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            filter.min as i32,
+        );
+        self.context.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            filter.mag as i32,
+        );
+        self.context.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_S,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        self.context.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_T,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        check_gl_errors!(self.context, "create_texture");
+
+        let texture_id = self.handle(texture_id, GlObject::Texture);
+        let mut texture = Texture {
+            context: self.context.clone(),
+            texture_id,
+            size: (0, 0),
+            format,
+            internal_format: self.internal_format(format),
+            filter,
+        };
+        texture.allocate(width, height);
+        Ok(texture)
+    }
+
+    fn internal_format(&self, format: TextureFormat) -> u32 {
+        match format {
+            TextureFormat::R8 | TextureFormat::RInt => glow::RED,
+            TextureFormat::RG8 | TextureFormat::RGInt => glow::RG,
+            TextureFormat::RGB8 | TextureFormat::RGBInt => glow::RGB,
+            TextureFormat::BGR8 | TextureFormat::BGRInt => glow::BGR,
+            TextureFormat::RGBA8 | TextureFormat::RGBAInt => glow::RGBA,
+            TextureFormat::BGRA8 | TextureFormat::BGRAInt => glow::BGRA,
+            TextureFormat::SRGBA => glow::SRGB8_ALPHA8,
+            TextureFormat::RGBA16F => glow::RGBA16F,
+            // GLES 2 and WebGL 1 only take unsized formats, the type makes it float
+            TextureFormat::RGBA32F if !self.integer_attributes => glow::RGBA,
+            TextureFormat::RGBA32F => glow::RGBA32F,
+        }
+    }
+
+    /// Deletes the GL objects whose last handle was dropped since the last call. Only does work
+    /// for what was dropped, call it once a frame.
+    pub unsafe fn maintain(&mut self) {
+        let dropped = self.deletion_queue.take();
+        for object in dropped {
+            match object {
+                GlObject::Program(program) => {
+                    if self.state.current_program.get() == Some(program) {
+                        self.state.current_program.set(None);
+                    }
+                    self.context.delete_program(program);
+                }
+                GlObject::Shader(shader) => self.context.delete_shader(shader),
+                GlObject::VertexArray(vertex_array) => {
+                    self.context.delete_vertex_array(vertex_array)
+                }
+                GlObject::Buffer(buffer) => self.context.delete_buffer(buffer),
+                GlObject::Texture(texture) => self.context.delete_texture(texture),
+                GlObject::Framebuffer(framebuffer) => self.context.delete_framebuffer(framebuffer),
+                GlObject::Renderbuffer(renderbuffer) => {
+                    self.context.delete_renderbuffer(renderbuffer)
+                }
+            }
+        }
+        check_gl_errors!(self.context, "maintain");
+    }
+
+    /// Reads back `rect` of the target as tightly packed RGBA8 rows, top row first like an
+    /// image, even though `rect` itself is in GL's bottom left origin pixel coordinates.
+    pub unsafe fn read_pixels(
+        &mut self,
+        target: RenderTarget,
+        rect: Rect<u32>,
+    ) -> Result<Vec<u8>, GLError> {
+        let target_size = self.state.bind_target(&self.context, target);
+        if rect.max_x() > target_size.0 as u32 || rect.max_y() > target_size.1 as u32 {
+            return Err(GLError(format!(
+                "Cannot read {:?} from a render target of size {:?}",
+                rect, target_size
+            )));
+        }
+
+        let row_len = rect.size.width as usize * 4;
+        let mut pixels = vec![0; row_len * rect.size.height as usize];
+        // rows are already tightly packed, RGBA8 rows are always 4 byte aligned
+        self.context.read_pixels(
+            rect.origin.x as i32,
+            rect.origin.y as i32,
+            rect.size.width as i32,
+            rect.size.height as i32,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            &mut pixels,
+        );
+        check_gl_errors!(self.context, "read_pixels");
+
+        // GL returns the bottom row first
+        let mut flipped = Vec::with_capacity(pixels.len());
+        for row in pixels.chunks(row_len.max(1)).rev() {
+            flipped.extend_from_slice(row);
+        }
+        Ok(flipped)
+    }
+
+    /// Reads back all of `texture` as tightly packed RGBA8 rows in the order `Texture::write`
+    /// takes them, for debugging. Only for 8 bit RGBA formats; sRGB ones come back as stored.
+    pub unsafe fn read_texture(&mut self, texture: &Texture) -> Result<Vec<u8>, GLError> {
+        let framebuffer = self.handle(
+            self.context.create_framebuffer().map_err(GLError)?,
+            GlObject::Framebuffer,
+        );
+        self.context
+            .bind_framebuffer(glow::FRAMEBUFFER, Some(**framebuffer));
+        self.context.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(**texture.texture_id),
+            0,
+        );
+        let status = self.context.check_framebuffer_status(glow::FRAMEBUFFER);
+        if status != glow::FRAMEBUFFER_COMPLETE {
+            return Err(GLError(format!(
+                "Cannot read back a {:?} texture, framebuffer status {:#x}",
+                texture.format, status
+            )));
+        }
+
+        let mut pixels = vec![0; texture.width() as usize * texture.height() as usize * 4];
+        self.context.read_pixels(
+            0,
+            0,
+            texture.size.0,
+            texture.size.1,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            &mut pixels,
+        );
+        check_gl_errors!(self.context, "read_texture");
+        Ok(pixels)
+    }
+
+    /// Restricts drawing and clearing to `rect`, in pixels of the render target with the origin at
+    /// the bottom left as usual for GL. `None` turns clipping off again. Stays in effect across
+    /// targets and draws until changed.
+    pub unsafe fn set_scissor(&mut self, rect: Option<Rect<i32>>) {
+        self.scissor = rect;
+        self.apply_scissor(rect);
+    }
+
+    unsafe fn apply_scissor(&self, rect: Option<Rect<i32>>) {
+        match rect {
+            Some(rect) => {
+                self.context.enable(glow::SCISSOR_TEST);
+                self.context.scissor(
+                    rect.origin.x,
+                    rect.origin.y,
+                    rect.size.width.max(0),
+                    rect.size.height.max(0),
+                );
+            }
+            None => self.context.disable(glow::SCISSOR_TEST),
+        }
+        check_gl_errors!(self.context, "set_scissor");
+    }
+
+    /// Clears the buffers of `target` that `options` gives values for. Only clears inside the
+    /// scissor rectangle while one is set, or inside `options.rect` instead if given. Only the
+    /// screen has depth and stencil buffers.
+    pub unsafe fn clear(&mut self, target: RenderTarget, options: ClearOptions) {
+        let mut mask = 0;
+        if let Some(color) = options.color {
+            // clears only touch what the color mask lets through
+            self.context.color_mask(true, true, true, true);
+            self.context
+                .clear_color(color[0], color[1], color[2], color[3]);
+            mask |= glow::COLOR_BUFFER_BIT;
+        }
+        if let Some(depth) = options.depth {
+            self.context.depth_mask(true);
+            self.context.clear_depth_f32(depth);
+            mask |= glow::DEPTH_BUFFER_BIT;
+        }
+        if let Some(stencil) = options.stencil {
+            self.context.stencil_mask(0xff);
+            self.context.clear_stencil(stencil as i32);
+            mask |= glow::STENCIL_BUFFER_BIT;
+        }
+        if mask == 0 {
+            return;
+        }
+
+        self.state.bind_target(&self.context, target);
+        if options.rect.is_some() {
+            self.apply_scissor(options.rect);
+        }
+        self.context.clear(mask);
+        if options.rect.is_some() {
+            self.apply_scissor(self.scissor);
+        }
+        check_gl_errors!(self.context, "clear");
+    }
+}
+
+/// Draws into one target with the viewport, scissor rectangle and blend mode it was built with.
+/// All of the pass's state is applied on every draw and the context's scissor rectangle restored
+/// after, so nothing set up for one pass leaks into draws made by another pass or directly with
+/// `Program`.
+pub struct RenderPass<'a> {
+    context: &'a mut Context,
+    target: RenderTarget<'a>,
+    viewport: Option<(i32, i32, i32, i32)>,
+    blend_mode: Option<BlendMode>,
+    scissor: Option<Rect<i32>>,
+}
+
+impl<'a> RenderPass<'a> {
+    /// Clears the target's color right away, inside the scissor rectangle if one was already
+    /// given.
+    pub unsafe fn clear(self, color: [f32; 4]) -> Self {
+        self.context.clear(
+            self.target,
+            ClearOptions {
+                color: Some(color),
+                rect: self.scissor,
+                ..ClearOptions::default()
+            },
+        );
+        self
+    }
+
+    /// Like `Context::set_viewport`. The whole target by default, regardless of the context's
+    /// viewport.
+    pub fn viewport(mut self, x: i32, y: i32, width: i32, height: i32) -> Self {
+        self.viewport = Some((x, y, width, height));
+        self
+    }
+
+    /// Overrides the blend mode of the programs drawn with.
+    pub fn blend(mut self, blend_mode: BlendMode) -> Self {
+        self.blend_mode = Some(blend_mode);
+        self
+    }
+
+    /// Like `Context::set_scissor`. No scissor rectangle by default, regardless of the context's.
+    pub fn scissor(mut self, rect: Rect<i32>) -> Self {
+        self.scissor = Some(rect);
+        self
+    }
+
+    /// Draws the triangles in `vertex_buffer` with `program`.
+    pub unsafe fn draw(
+        &mut self,
+        program: &Program,
+        vertex_buffer: &VertexBuffer,
+    ) -> Result<(), GLError> {
+        self.draw_primitives(program, vertex_buffer, PrimitiveType::Triangles)
+    }
+
+    /// Like `Program::render_vertices`.
+    pub unsafe fn draw_primitives(
+        &mut self,
+        program: &Program,
+        vertex_buffer: &VertexBuffer,
+        primitive: PrimitiveType,
+    ) -> Result<(), GLError> {
+        let target = self.begin_draw();
+        let result = program.draw_vertices(vertex_buffer, primitive, target);
+        self.end_draw();
+        result
+    }
+
+    /// Like `Program::render_indexed`.
+    pub unsafe fn draw_indexed(
+        &mut self,
+        program: &Program,
+        vertex_buffer: &VertexBuffer,
+        index_buffer: &IndexBuffer,
+    ) -> Result<(), GLError> {
+        let target = self.begin_draw();
+        let result = program.draw_indexed(vertex_buffer, index_buffer, target);
+        self.end_draw();
+        result
+    }
+
+    /// Like `Program::render_indexed_range`.
+    pub unsafe fn draw_indexed_range(
+        &mut self,
+        program: &Program,
+        vertex_buffer: &VertexBuffer,
+        index_buffer: &IndexBuffer,
+        first: usize,
+        count: usize,
+    ) -> Result<(), GLError> {
+        let target = self.begin_draw();
+        let result = program.draw_indexed_range(vertex_buffer, index_buffer, first, count, target);
+        self.end_draw();
+        result
+    }
+
+    unsafe fn begin_draw(&self) -> DrawTarget {
+        self.context.apply_scissor(self.scissor);
+        DrawTarget {
+            blend_mode: self.blend_mode,
+            ..self
+                .context
+                .state
+                .draw_target_with_viewport(self.target, self.viewport)
+        }
+    }
+
+    unsafe fn end_draw(&self) {
+        self.context.apply_scissor(self.context.scissor);
+    }
+}
+
+/// What `Context::clear` clears, and to what.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClearOptions {
+    pub color: Option<[f32; 4]>,
+    pub depth: Option<f32>,
+    pub stencil: Option<u8>,
+    /// Pixels to clear, in the same coordinates as `Context::set_scissor`
+    pub rect: Option<Rect<i32>>,
+}
+
+impl ClearOptions {
+    /// Clears only the color buffer.
+    pub fn color(color: [f32; 4]) -> ClearOptions {
+        ClearOptions {
+            color: Some(color),
+            ..ClearOptions::default()
+        }
+    }
+}
+
+/// Formats ending in 8 store 8 bit unsigned components that shaders read as floats from 0 to 1.
+/// `Int` formats store the same bytes but are read as integers.
+#[derive(Debug, Clone, Copy)]
+pub enum TextureFormat {
+    R8,
+    RInt,
+    RG8,
+    RGInt,
+    RGB8,
+    RGBInt,
+    BGR8,
+    BGRInt,
+    RGBA8,
+    RGBAInt,
+    BGRA8,
+    BGRAInt,
+    /// 8 bit RGBA stored as sRGB, converted to linear when sampled. Needs GLES 3, WebGL 2 or
+    /// desktop GL.
+    SRGBA,
+    /// 16 bit float RGBA, written with `Texture::write_f32`. Needs GLES 3, WebGL 2 or desktop GL,
+    /// and an extension to be rendered to on GLES and WebGL.
+    RGBA16F,
+    /// 32 bit float RGBA, written with `Texture::write_f32`. Needs an extension on GLES 2 and
+    /// WebGL 1, and to be rendered to on GLES and WebGL. Filtering it linearly needs another
+    /// extension on GLES and WebGL.
+    RGBA32F,
+}
+
+impl TextureFormat {
+    pub fn is_float(self) -> bool {
+        match self {
+            TextureFormat::RGBA16F | TextureFormat::RGBA32F => true,
+            _ => false,
+        }
+    }
+
+    // Type of the components as uploaded
+    fn pixel_type(self) -> u32 {
+        if self.is_float() {
+            glow::FLOAT
+        } else {
+            glow::UNSIGNED_BYTE
+        }
+    }
+
+    /// Size of a pixel as passed to `Texture::write`, or `write_f32` for float formats
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            TextureFormat::R8 | TextureFormat::RInt => 1,
+            TextureFormat::RG8 | TextureFormat::RGInt => 2,
+            TextureFormat::RGB8
+            | TextureFormat::RGBInt
+            | TextureFormat::BGR8
+            | TextureFormat::BGRInt => 3,
+            TextureFormat::RGBA8
+            | TextureFormat::RGBAInt
+            | TextureFormat::BGRA8
+            | TextureFormat::BGRAInt
+            | TextureFormat::SRGBA => 4,
+            TextureFormat::RGBA16F | TextureFormat::RGBA32F => 16,
+        }
+    }
+}
+
+/// How often a buffer's contents are expected to be rewritten, as a hint to the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum BufferUsage {
+    /// Written once and drawn many times
+    Static = glow::STATIC_DRAW,
+    /// Rewritten occasionally
+    Dynamic = glow::DYNAMIC_DRAW,
+    /// Rewritten every frame
+    Stream = glow::STREAM_DRAW,
+}
+
+impl Default for BufferUsage {
+    fn default() -> Self {
+        BufferUsage::Static
+    }
+}
+
+impl VertexBuffer {
+    /// Number of vertices written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Allocated size in bytes, which `write` only grows.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Replaces the contents. Reuses the allocation when the vertices fit, which rewriting every
+    /// frame usually does, and otherwise grows it to at least twice its size.
+    pub unsafe fn write<V: AsBytes>(&mut self, vertices: &[V]) {
+        let bytes = vertices.as_bytes();
+        self.len = vertices.len();
+        self.context.bind_vertex_array(Some(**self.vertex_array));
+        self.context
+            .bind_buffer(glow::ARRAY_BUFFER, Some(**self.buffer));
+        if bytes.len() > self.capacity {
+            self.capacity = bytes.len().max(self.capacity * 2);
+            #[cfg(feature = "debug-gl")]
+            log::debug!("Vertex buffer grew to {} bytes", self.capacity);
+        }
+        // allocating anew orphans the old storage, so the driver doesn't have to wait for draws
+        // still reading it
+        self.context
+            .buffer_data_size(glow::ARRAY_BUFFER, self.capacity as i32, self.usage as u32);
+        self.context
+            .buffer_sub_data_u8_slice(glow::ARRAY_BUFFER, 0, bytes);
+        check_gl_errors!(self.context, "VertexBuffer::write");
+    }
+
+    /// Allocates room for `len` vertices of type `V` so they can be filled in with `write_at`.
+    /// Discards the current contents.
+    pub unsafe fn reserve<V: AsBytes>(&mut self, len: usize) {
+        self.len = 0;
+        self.capacity = len * std::mem::size_of::<V>();
+        self.context.bind_vertex_array(Some(**self.vertex_array));
+        self.context
+            .bind_buffer(glow::ARRAY_BUFFER, Some(**self.buffer));
+        self.context
+            .buffer_data_size(glow::ARRAY_BUFFER, self.capacity as i32, self.usage as u32);
+        check_gl_errors!(self.context, "VertexBuffer::reserve");
+    }
+
+    /// Overwrites vertices starting at `vertex_offset` without reallocating. The range has to fit
+    /// in what was allocated by the last `write` or `reserve`. Vertices past the end of the last
+    /// `write` become part of what is drawn.
+    pub unsafe fn write_at<V: AsBytes>(
+        &mut self,
+        vertex_offset: usize,
+        vertices: &[V],
+    ) -> Result<(), GLError> {
+        let vertex_size = std::mem::size_of::<V>();
+        let start = vertex_offset * vertex_size;
+        let end = start + vertices.as_bytes().len();
+        if end > self.capacity {
+            return Err(GLError(format!(
+                "Writing vertices {}..{} is out of range of a buffer with room for {}",
+                vertex_offset,
+                vertex_offset + vertices.len(),
+                self.capacity / vertex_size.max(1)
+            )));
+        }
+
+        self.len = self.len.max(vertex_offset + vertices.len());
+        self.context.bind_vertex_array(Some(**self.vertex_array));
+        self.context
+            .bind_buffer(glow::ARRAY_BUFFER, Some(**self.buffer));
+        self.context.buffer_sub_data_u8_slice(
+            glow::ARRAY_BUFFER,
+            start as i32,
+            vertices.as_bytes(),
+        );
+        check_gl_errors!(self.context, "VertexBuffer::write_at");
+        Ok(())
+    }
+}
+
+/// How `Program::render_vertices` puts vertices together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PrimitiveType {
+    /// Every 3 vertices are a triangle
+    Triangles = glow::TRIANGLES,
+    /// Every vertex after the first two makes a triangle with the two before it
+    TriangleStrip = glow::TRIANGLE_STRIP,
+    /// Every 2 vertices are a line, `Context::set_line_width` wide
+    Lines = glow::LINES,
+    /// A line through all vertices
+    LineStrip = glow::LINE_STRIP,
+    /// Every vertex is a square point. Its size in pixels is whatever the vertex shader writes to
+    /// `gl_PointSize`, which is undefined if the shader doesn't write it.
+    Points = glow::POINTS,
+}
+
+impl Default for PrimitiveType {
+    fn default() -> PrimitiveType {
+        PrimitiveType::Triangles
+    }
+}
+
+/// Filtering for textures scaled down (`min`) and up (`mag`). Only the min filter can use
+/// mipmaps, which then need to be generated with `Texture::generate_mipmaps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextureFilter {
+    pub min: MinFilter,
+    pub mag: MagFilter,
+}
+
+impl TextureFilter {
+    /// Crisp pixel art, the default
+    pub const NEAREST: TextureFilter = TextureFilter {
+        min: MinFilter::Nearest,
+        mag: MagFilter::Nearest,
+    };
+    pub const LINEAR: TextureFilter = TextureFilter {
+        min: MinFilter::Linear,
+        mag: MagFilter::Linear,
+    };
+    /// Smooth scaling down to any size without shimmering
+    pub const TRILINEAR: TextureFilter = TextureFilter {
+        min: MinFilter::LinearMipmapLinear,
+        mag: MagFilter::Linear,
+    };
+}
+
+impl Default for TextureFilter {
+    fn default() -> Self {
+        TextureFilter::NEAREST
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MinFilter {
+    Nearest = glow::NEAREST,
+    Linear = glow::LINEAR,
+    NearestMipmapNearest = glow::NEAREST_MIPMAP_NEAREST,
+    LinearMipmapNearest = glow::LINEAR_MIPMAP_NEAREST,
+    NearestMipmapLinear = glow::NEAREST_MIPMAP_LINEAR,
+    LinearMipmapLinear = glow::LINEAR_MIPMAP_LINEAR,
+}
+
+impl MinFilter {
+    pub fn uses_mipmaps(self) -> bool {
+        match self {
+            MinFilter::Nearest | MinFilter::Linear => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MagFilter {
+    Nearest = glow::NEAREST,
+    Linear = glow::LINEAR,
+}
+
+/// How drawn fragments combine with what is already in the render target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Replaces the target, for opaque draws
+    None,
+    /// Blends by the source alpha, for colors that aren't premultiplied
+    Alpha,
+    /// Adds the source color scaled by its alpha, for glows and flashes
+    Additive,
+    /// Blends colors that are already multiplied by their alpha, which is what the game's shader
+    /// outputs. The default.
+    PremultipliedAlpha,
+}
+
+impl BlendMode {
+    // Source and destination blend factors, or None if blending is disabled
+    fn factors(self) -> Option<(u32, u32)> {
+        match self {
+            BlendMode::None => None,
+            BlendMode::Alpha => Some((glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA)),
+            BlendMode::Additive => Some((glow::SRC_ALPHA, glow::ONE)),
+            BlendMode::PremultipliedAlpha => Some((glow::ONE, glow::ONE_MINUS_SRC_ALPHA)),
+        }
+    }
+}
+
+impl Default for BlendMode {
+    fn default() -> Self {
+        BlendMode::PremultipliedAlpha
+    }
+}
+
+/// How triangles are rasterized, see `Context::set_polygon_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PolygonMode {
+    Fill = glow::FILL,
+    /// Only the edges, as lines of the current line width
+    Line = glow::LINE,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DepthFunc {
+    Never = glow::NEVER,
+    Less = glow::LESS,
+    Equal = glow::EQUAL,
+    LessOrEqual = glow::LEQUAL,
+    Greater = glow::GREATER,
+    NotEqual = glow::NOTEQUAL,
+    GreaterOrEqual = glow::GEQUAL,
+    Always = glow::ALWAYS,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum StencilFunc {
+    Never = glow::NEVER,
+    Less = glow::LESS,
+    Equal = glow::EQUAL,
+    LessOrEqual = glow::LEQUAL,
+    Greater = glow::GREATER,
+    NotEqual = glow::NOTEQUAL,
+    GreaterOrEqual = glow::GEQUAL,
+    Always = glow::ALWAYS,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum StencilOp {
+    Keep = glow::KEEP,
+    Zero = glow::ZERO,
+    Replace = glow::REPLACE,
+    Increment = glow::INCR,
+    IncrementWrap = glow::INCR_WRAP,
+    Decrement = glow::DECR,
+    DecrementWrap = glow::DECR_WRAP,
+    Invert = glow::INVERT,
+}
+
+/// Stencil test and update for a program's draws, as in `glStencilFunc`, `glStencilOp` and
+/// `glStencilMask`. Fragments pass when `func` holds between `reference` and the stencil value,
+/// both masked by `read_mask`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StencilState {
+    pub func: StencilFunc,
+    pub reference: u8,
+    pub read_mask: u8,
+    /// Applied when the stencil test fails
+    pub fail: StencilOp,
+    /// Applied when the stencil test passes but the depth test fails
+    pub depth_fail: StencilOp,
+    /// Applied when both tests pass
+    pub pass: StencilOp,
+    /// Bits of the stencil value that the ops can change
+    pub write_mask: u8,
+}
+
+impl StencilState {
+    /// Sets the stencil value to `reference` everywhere the draw covers. Usually drawn with color
+    /// writes off to build a mask.
+    pub fn write(reference: u8) -> StencilState {
+        StencilState {
+            func: StencilFunc::Always,
+            reference,
+            read_mask: 0xff,
+            fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Replace,
+            write_mask: 0xff,
+        }
+    }
+
+    /// Only draws where the stencil value is `reference`, leaving the stencil buffer as it is.
+    pub fn test_equal(reference: u8) -> StencilState {
+        StencilState {
+            func: StencilFunc::Equal,
+            reference,
+            read_mask: 0xff,
+            fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep,
+            write_mask: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TextureWrap {
+    ClampToEdge = glow::CLAMP_TO_EDGE,
+    Repeat = glow::REPEAT,
+    MirroredRepeat = glow::MIRRORED_REPEAT,
+}
+
+impl InstanceBuffer {
+    pub unsafe fn write<I: AsBytes>(&mut self, instances: &[I]) {
+        self.len = instances.len();
+        // unbind any vertex array so this doesn't disturb its state
+        self.context.bind_vertex_array(None);
+        self.context
+            .bind_buffer(glow::ARRAY_BUFFER, Some(**self.buffer));
+        self.context.buffer_data_u8_slice(
+            glow::ARRAY_BUFFER,
+            instances.as_bytes(),
+            self.usage as u32,
+        );
+        check_gl_errors!(self.context, "InstanceBuffer::write");
+    }
+
+    /// Number of instances written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl IndexBuffer {
+    pub unsafe fn write(&mut self, indices: &[u16]) {
+        self.len = indices.len();
+        self.context
+            .bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(**self.buffer));
+        self.context.buffer_data_u8_slice(
+            glow::ELEMENT_ARRAY_BUFFER,
+            indices.as_bytes(),
+            self.usage as u32,
+        );
+        check_gl_errors!(self.context, "IndexBuffer::write");
+    }
+}
+
+impl std::fmt::Debug for Texture {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Texture")
+            .field("size", &self.size)
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+impl Texture {
+    pub fn width(&self) -> u32 {
+        self.size.0 as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.size.1 as u32
+    }
 
-        Ok(VertexBuffer {
-            context: self.context.clone(),
-            vertex_array: vertex_array_id,
-            buffer: buffer_id,
-            len: 0,
-        })
+    pub fn format(&self) -> TextureFormat {
+        self.format
     }
 
-    pub unsafe fn create_texture_render_target(
+    /// Writes a `width` by `height` block of tightly packed pixels at `x`, `y`. Fails if the block
+    /// doesn't fit in the texture or `data` isn't exactly the block's size in this texture's
+    /// format.
+    pub unsafe fn write(
         &mut self,
-        texture: &Texture,
-    ) -> TextureRenderTarget {
-        let framebuffer = Rc::new(self.context.create_framebuffer().unwrap());
-        self.frame_buffers.push(Rc::clone(&framebuffer));
-
-        self.context
-            .bind_framebuffer(glow::FRAMEBUFFER, Some(*framebuffer));
-        self.context
-            .bind_texture(glow::TEXTURE_2D, Some(*texture.texture_id));
-
-        self.context.framebuffer_texture_2d(
-            glow::FRAMEBUFFER,
-            glow::COLOR_ATTACHMENT0,
-            glow::TEXTURE_2D,
-            Some(*texture.texture_id),
-            0,
-        );
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: &[u8],
+    ) -> Result<(), GLError> {
+        if self.format.is_float() {
+            return Err(GLError(format!(
+                "{:?} textures are written with write_f32",
+                self.format
+            )));
+        }
+        self.write_bytes(x, y, width, height, data)
+    }
 
-        TextureRenderTarget {
-            texture: Rc::clone(&texture.texture_id),
-            framebuffer,
-            size: texture.size,
+    /// `write` for float formats, taking 4 floats per pixel.
+    pub unsafe fn write_f32(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: &[f32],
+    ) -> Result<(), GLError> {
+        if !self.format.is_float() {
+            return Err(GLError(format!(
+                "{:?} textures are written with write",
+                self.format
+            )));
         }
+        self.write_bytes(x, y, width, height, data.as_bytes())
     }
 
-    pub unsafe fn create_texture(
+    unsafe fn write_bytes(
         &mut self,
-        format: TextureFormat,
+        x: u32,
+        y: u32,
         width: u32,
         height: u32,
-    ) -> Result<Texture, GLError> {
-        let texture_id = self.context.create_texture().map_err(GLError)?;
+        data: &[u8],
+    ) -> Result<(), GLError> {
+        if x as u64 + width as u64 > self.width() as u64
+            || y as u64 + height as u64 > self.height() as u64
+        {
+            return Err(GLError(format!(
+                "Writing {}x{} pixels at ({}, {}) is out of bounds of a {}x{} texture",
+                width,
+                height,
+                x,
+                y,
+                self.width(),
+                self.height()
+            )));
+        }
+        let expected_len = width as usize * height as usize * self.format.bytes_per_pixel();
+        if data.len() != expected_len {
+            return Err(GLError(format!(
+                "Writing {}x{} pixels of {:?} takes {} bytes, got {}",
+                width,
+                height,
+                self.format,
+                expected_len,
+                data.len()
+            )));
+        }
+
         self.context
-            .bind_texture(glow::TEXTURE_2D, Some(texture_id));
-        self.context.tex_parameter_i32(
-            glow::TEXTURE_2D,
-            glow::TEXTURE_MIN_FILTER,
-            glow::NEAREST as i32,
-        );
-        self.context.tex_parameter_i32(
-            glow::TEXTURE_2D,
-            glow::TEXTURE_MAG_FILTER,
-            glow::NEAREST as i32,
-        );
-        self.context.tex_parameter_i32(
-            glow::TEXTURE_2D,
-            glow::TEXTURE_WRAP_S,
-            glow::CLAMP_TO_EDGE as i32,
-        );
-        self.context.tex_parameter_i32(
+            .bind_texture(glow::TEXTURE_2D, Some(**self.texture_id));
+        // rows are tightly packed rather than padded to 4 bytes
+        self.context.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
+        self.context.tex_sub_image_2d_u8_slice(
             glow::TEXTURE_2D,
-            glow::TEXTURE_WRAP_T,
-            glow::CLAMP_TO_EDGE as i32,
+            0,
+            x as i32,
+            y as i32,
+            width as i32,
+            height as i32,
+            match self.format {
+                TextureFormat::R8 | TextureFormat::RInt => glow::RED,
+                TextureFormat::RG8 | TextureFormat::RGInt => glow::RG,
+                TextureFormat::RGB8 | TextureFormat::RGBInt => glow::RGB,
+                TextureFormat::BGR8 | TextureFormat::BGRInt => glow::BGR,
+                TextureFormat::RGBA8
+                | TextureFormat::RGBAInt
+                | TextureFormat::SRGBA
+                | TextureFormat::RGBA16F
+                | TextureFormat::RGBA32F => glow::RGBA,
+                TextureFormat::BGRA8 | TextureFormat::BGRAInt => glow::BGRA,
+            },
+            self.format.pixel_type(),
+            Some(data),
         );
+        // keep the smaller levels in sync with what was just written
+        if self.filter.min.uses_mipmaps() {
+            self.context.generate_mipmap(glow::TEXTURE_2D);
+        }
+        check_gl_errors!(self.context, "Texture::write");
+        Ok(())
+    }
 
+    // (Re)creates the texture's storage at the given size, leaving the contents undefined
+    unsafe fn allocate(&mut self, width: u32, height: u32) {
+        self.size = (width as i32, height as i32);
+        self.context
+            .bind_texture(glow::TEXTURE_2D, Some(**self.texture_id));
         self.context.tex_image_2d(
             glow::TEXTURE_2D,
             0,
-            match format {
-                TextureFormat::RFloat | TextureFormat::RInt => glow::RED,
-                TextureFormat::RGFloat | TextureFormat::RGInt => glow::RG,
-                TextureFormat::RGBFloat | TextureFormat::RGBInt => glow::RGB,
-                TextureFormat::BGRFloat | TextureFormat::BGRInt => glow::BGR,
-                TextureFormat::RGBAFloat | TextureFormat::RGBAInt => glow::RGBA,
-                TextureFormat::BGRAFloat | TextureFormat::BGRAInt => glow::BGRA,
-            } as i32,
+            self.internal_format as i32,
             width as i32,
             height as i32,
             0,
-            match format {
-                TextureFormat::RFloat => glow::RED,
-                TextureFormat::RGFloat => glow::RG,
-                TextureFormat::RGBFloat => glow::RGB,
-                TextureFormat::BGRFloat => glow::BGR,
-                TextureFormat::RGBAFloat => glow::RGBA,
-                TextureFormat::BGRAFloat => glow::BGRA,
+            match self.format {
+                TextureFormat::R8 => glow::RED,
+                TextureFormat::RG8 => glow::RG,
+                TextureFormat::RGB8 => glow::RGB,
+                TextureFormat::BGR8 => glow::BGR,
+                TextureFormat::RGBA8
+                | TextureFormat::SRGBA
+                | TextureFormat::RGBA16F
+                | TextureFormat::RGBA32F => glow::RGBA,
+                TextureFormat::BGRA8 => glow::BGRA,
                 TextureFormat::RInt => glow::RED_INTEGER,
                 TextureFormat::RGInt => glow::RG_INTEGER,
                 TextureFormat::RGBInt => glow::RGB_INTEGER,
@@ -235,324 +2193,738 @@ impl Context {
                 TextureFormat::RGBAInt => glow::RGBA_INTEGER,
                 TextureFormat::BGRAInt => glow::BGRA_INTEGER,
             },
-            glow::UNSIGNED_BYTE,
+            self.format.pixel_type(),
             None,
         );
+        check_gl_errors!(self.context, "Texture::allocate");
+    }
 
-        let texture_id = Rc::new(texture_id);
-        self.textures.push(texture_id.clone());
-        Ok(Texture {
-            context: self.context.clone(),
-            texture_id,
-            size: (width as i32, height as i32),
-            format,
-        })
+    pub fn filter(&self) -> TextureFilter {
+        self.filter
     }
 
-    pub unsafe fn maintain(&mut self) {
-        for i in (0..self.programs.len()).rev() {
-            if Rc::strong_count(&self.programs[i]) == 1 {
-                let program = self.programs.swap_remove(i);
-                self.context.delete_program(*program);
-            }
-        }
-        for i in (0..self.shaders.len()).rev() {
-            if Rc::strong_count(&self.shaders[i]) == 1 {
-                let shader = self.shaders.swap_remove(i);
-                self.context.delete_shader(*shader);
-            }
+    pub unsafe fn set_filter(&mut self, filter: TextureFilter) {
+        self.filter = filter;
+        self.context
+            .bind_texture(glow::TEXTURE_2D, Some(**self.texture_id));
+        self.context.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            filter.min as i32,
+        );
+        self.context.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            filter.mag as i32,
+        );
+        check_gl_errors!(self.context, "Texture::set_filter");
+    }
+
+    /// Textures are created clamping to the edge in both directions. The web backend runs on
+    /// WebGL1, which can only repeat textures with power of two dimensions.
+    pub unsafe fn set_wrap(&mut self, s: TextureWrap, t: TextureWrap) -> Result<(), GLError> {
+        let power_of_two = |size: i32| size > 0 && size & (size - 1) == 0;
+        if cfg!(target_arch = "wasm32")
+            && (s != TextureWrap::ClampToEdge || t != TextureWrap::ClampToEdge)
+            && !(power_of_two(self.size.0) && power_of_two(self.size.1))
+        {
+            return Err(GLError(format!(
+                "WebGL1 can only repeat power of two textures, this one is {}x{}",
+                self.size.0, self.size.1
+            )));
         }
-        for i in (0..self.vertex_arrays.len()).rev() {
-            if Rc::strong_count(&self.vertex_arrays[i]) == 1 {
-                let vertex_array = self.vertex_arrays.swap_remove(i);
-                self.context.delete_vertex_array(*vertex_array);
-            }
+
+        self.context
+            .bind_texture(glow::TEXTURE_2D, Some(**self.texture_id));
+        self.context
+            .tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_S, s as i32);
+        self.context
+            .tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_T, t as i32);
+        check_gl_errors!(self.context, "Texture::set_wrap");
+        Ok(())
+    }
+
+    /// Builds the smaller mip levels from the full size image. WebGL1 can only do this for
+    /// textures with power of two dimensions. Done automatically by `write` when the texture's
+    /// min filter uses mipmaps, but not after rendering into the texture.
+    pub unsafe fn generate_mipmaps(&mut self) {
+        self.context
+            .bind_texture(glow::TEXTURE_2D, Some(**self.texture_id));
+        self.context.generate_mipmap(glow::TEXTURE_2D);
+        check_gl_errors!(self.context, "Texture::generate_mipmaps");
+    }
+
+    /// Resizes the texture to `width` by `height`, keeping its format and filter. The contents
+    /// are lost.
+    pub unsafe fn reallocate(&mut self, width: u32, height: u32) {
+        self.allocate(width, height);
+    }
+}
+
+#[repr(u32)]
+pub enum ShaderType {
+    Vertex = glow::VERTEX_SHADER,
+    Fragment = glow::FRAGMENT_SHADER,
+}
+
+struct VertexFormatInner {
+    stride: i32,
+    instance_stride: i32,
+    attributes: Vec<(VertexAttributeLocation, VertexAttributeInner)>,
+}
+
+struct VertexAttributeInner {
+    pub ty: VertexAttributeType,
+    pub size: u32,
+    pub offset: u32,
+    pub normalized: bool,
+    pub integer: bool,
+    pub divisor: u32,
+}
+
+pub struct Program {
+    context: Rc<glow::Context>,
+    state: Rc<ContextState>,
+    program_id: Rc<Handle<ProgramId>>,
+    vertex_shader: Rc<Handle<ShaderId>>,
+    fragment_shader: Rc<Handle<ShaderId>>,
+    uniform_names: Vec<String>,
+    uniform_indices: HashMap<String, usize>,
+    uniform_entry_types: Vec<UniformType>,
+    set_uniforms: Vec<(UniformLocationId, Option<SetUniformValue>)>,
+    // Locations of each element of array uniforms, empty for the others
+    element_locations: Vec<Vec<UniformLocationId>>,
+    uniform_array_lens: Vec<usize>,
+    // What each uniform was last uploaded as, uniform values are kept by the GL program so
+    // unchanged ones don't need to be sent again
+    uploaded_uniforms: RefCell<Vec<Option<SetUniformValue>>>,
+    vertex_format: VertexFormatInner,
+    depth_test: Option<DepthFunc>,
+    stencil: Option<StencilState>,
+    color_write: bool,
+    blend_mode: BlendMode,
+}
+
+impl Program {
+    /// Used by every draw with this program until changed.
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+
+    /// Depth tests fragments against the depth buffer and writes their depth when enabled, using
+    /// the z of a 3 component position attribute. Only the screen has a depth buffer. Blended
+    /// draws still need to be submitted back to front, since a transparent fragment that passes
+    /// the test hides whatever is drawn behind it later.
+    pub fn set_depth_test(&mut self, depth_test: Option<DepthFunc>) {
+        self.depth_test = depth_test;
+    }
+
+    /// Tests and updates the stencil buffer with every draw while set. Only the screen has a
+    /// stencil buffer. Masking is done by drawing the mask with `StencilState::write` and color
+    /// writes off, then the masked draws with `StencilState::test_equal`.
+    pub fn set_stencil(&mut self, stencil: Option<StencilState>) {
+        self.stencil = stencil;
+    }
+
+    /// Whether draws change the render target's colors. On by default.
+    pub fn set_color_write(&mut self, color_write: bool) {
+        self.color_write = color_write;
+    }
+
+    /// Sets the uniform declared as `name` in the program's descriptor. This is the one to use
+    /// unless the name lookup shows up in a profile.
+    pub fn set_uniform_by_name(&mut self, name: &str, value: Uniform<'_>) -> Result<(), GLError> {
+        let index = *self.uniform_indices.get(name).ok_or_else(|| {
+            GLError(format!(
+                "No uniform named {}, the program has: {}",
+                name,
+                self.uniform_names.join(", ")
+            ))
+        })?;
+        self.set_uniform(index, value)
+    }
+
+    /// Names of the uniforms that have neither been set nor have a default, which drawing fails
+    /// on.
+    pub fn unset_uniforms(&self) -> Vec<&str> {
+        self.set_uniforms
+            .iter()
+            .zip(&self.uniform_names)
+            .filter(|((_, value), _)| value.is_none())
+            .map(|(_, name)| name.as_str())
+            .collect()
+    }
+
+    /// Sets the uniform at `index` in the program descriptor's uniform list.
+    pub fn set_uniform(&mut self, index: usize, value: Uniform<'_>) -> Result<(), GLError> {
+        if index >= self.set_uniforms.len() {
+            return Err(GLError(format!("Uniform index {} is out of range", index)));
         }
-        for i in (0..self.buffers.len()).rev() {
-            if Rc::strong_count(&self.buffers[i]) == 1 {
-                let buffer = self.buffers.swap_remove(i);
-                self.context.delete_buffer(*buffer);
-            }
+        if value.uniform_type() != self.uniform_entry_types[index] {
+            return Err(GLError(format!(
+                "Wrong uniform type. Expected: {:?} Got uniform of type: {:?}",
+                self.uniform_entry_types[index],
+                value.uniform_type()
+            )));
         }
-        for i in (0..self.textures.len()).rev() {
-            if Rc::strong_count(&self.textures[i]) == 1 {
-                let texture = self.textures.swap_remove(i);
-                self.context.delete_texture(*texture);
+        if let Some((elements, element_size)) = value.array_elements() {
+            if elements.len() % element_size != 0 {
+                return Err(GLError(format!(
+                    "{} floats don't make up whole elements of {}",
+                    elements.len(),
+                    element_size
+                )));
             }
-        }
-        for i in (0..self.frame_buffers.len()).rev() {
-            if Rc::strong_count(&self.frame_buffers[i]) == 1 {
-                let framebuffer = self.frame_buffers.swap_remove(i);
-                self.context.delete_framebuffer(*framebuffer);
+            let len = elements.len() / element_size;
+            if len > self.uniform_array_lens[index] {
+                return Err(GLError(format!(
+                    "{} elements given for uniform {}, which has {}",
+                    len, self.uniform_names[index], self.uniform_array_lens[index]
+                )));
             }
         }
+        self.set_uniforms[index].1 = match value {
+            Uniform::Texture(texture) => Some(SetUniformValue::Texture(texture.texture_id.clone())),
+            Uniform::Int(x) => Some(SetUniformValue::Int(x)),
+            Uniform::Int2(x, y) => Some(SetUniformValue::Int2(x, y)),
+            Uniform::Int3(x, y, z) => Some(SetUniformValue::Int3(x, y, z)),
+            Uniform::Int4(x, y, z, w) => Some(SetUniformValue::Int4(x, y, z, w)),
+            Uniform::Float(x) => Some(SetUniformValue::Float(x)),
+            Uniform::Float2(x, y) => Some(SetUniformValue::Float2(x, y)),
+            Uniform::Float3(x, y, z) => Some(SetUniformValue::Float3(x, y, z)),
+            Uniform::Float4(x, y, z, w) => Some(SetUniformValue::Float4(x, y, z, w)),
+            Uniform::Mat2(m) => Some(SetUniformValue::Mat2(m)),
+            Uniform::Mat3(m) => Some(SetUniformValue::Mat3(m)),
+            Uniform::Mat4(m) => Some(SetUniformValue::Mat4(m)),
+            Uniform::FloatArray(v) => Some(SetUniformValue::FloatArray(v.to_vec())),
+            Uniform::Float4Array(v) => Some(SetUniformValue::Float4Array(v.to_vec())),
+            Uniform::Mat3Array(v) => Some(SetUniformValue::Mat3Array(v.to_vec())),
+        };
+
+        Ok(())
     }
 
-    pub unsafe fn clear(&mut self, target: RenderTarget, color: [f32; 4]) {
-        match target {
-            RenderTarget::Screen => {
-                self.context.bind_framebuffer(glow::FRAMEBUFFER, None);
-            }
-            RenderTarget::Texture(framebuffer) => {
-                self.context
-                    .bind_framebuffer(glow::FRAMEBUFFER, Some(*framebuffer.framebuffer));
-            }
-        }
-        self.context
-            .clear_color(color[0], color[1], color[2], color[3]);
-        self.context.clear(glow::COLOR_BUFFER_BIT);
+    /// Draws the vertices in `vertex_buffer` in order, put together as `primitive`.
+    pub unsafe fn render_vertices(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        primitive: PrimitiveType,
+        target: RenderTarget,
+    ) -> Result<(), GLError> {
+        self.draw_vertices(vertex_buffer, primitive, self.state.draw_target(target))
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub enum TextureFormat {
-    RFloat,
-    RInt,
-    RGFloat,
-    RGInt,
-    RGBFloat,
-    RGBInt,
-    BGRFloat,
-    BGRInt,
-    RGBAFloat,
-    RGBAInt,
-    BGRAFloat,
-    BGRAInt,
-}
+    unsafe fn draw_vertices(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        primitive: PrimitiveType,
+        target: DrawTarget,
+    ) -> Result<(), GLError> {
+        self.prepare_draw(vertex_buffer, None, target)?;
+        self.context
+            .draw_arrays(primitive as u32, 0, vertex_buffer.len as i32);
+        check_gl_errors!(self.context, "render_vertices");
+        self.state.count_gl_calls(1);
 
-impl VertexBuffer {
-    pub unsafe fn write<V: AsBytes>(&mut self, vertices: &[V]) {
-        self.len = vertices.len();
-        self.context.bind_vertex_array(Some(*self.vertex_array));
+        Ok(())
+    }
+
+    /// Like `render_vertices`, but only draws the `count` vertices starting at `first`.
+    pub unsafe fn render_vertices_range(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        first: usize,
+        count: usize,
+        primitive: PrimitiveType,
+        target: RenderTarget,
+    ) -> Result<(), GLError> {
+        if first + count > vertex_buffer.len {
+            return Err(GLError(format!(
+                "Vertex range {}..{} is out of range of a buffer with {} vertices",
+                first,
+                first + count,
+                vertex_buffer.len
+            )));
+        }
+        self.prepare_draw(vertex_buffer, None, self.state.draw_target(target))?;
         self.context
-            .bind_buffer(glow::ARRAY_BUFFER, Some(*self.buffer));
-        self.context.buffer_data_u8_slice(
-            glow::ARRAY_BUFFER,
-            vertices.as_bytes(),
-            glow::STATIC_DRAW,
+            .draw_arrays(primitive as u32, first as i32, count as i32);
+        check_gl_errors!(self.context, "render_vertices_range");
+        self.state.count_gl_calls(1);
+
+        Ok(())
+    }
+
+    /// Draws the triangles in `vertex_buffer` `instance_count` times. Attributes with a divisor
+    /// are read from `instance_buffer`. Needs `Context::supports_instancing`.
+    pub unsafe fn render_instanced(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        instance_buffer: &InstanceBuffer,
+        instance_count: usize,
+        target: RenderTarget,
+    ) -> Result<(), GLError> {
+        self.prepare_draw(
+            vertex_buffer,
+            Some((instance_buffer, instance_count)),
+            self.state.draw_target(target),
+        )?;
+        self.context.draw_arrays_instanced(
+            glow::TRIANGLES,
+            0,
+            vertex_buffer.len as i32,
+            instance_count as i32,
         );
+        check_gl_errors!(self.context, "render_instanced");
+        self.state.count_gl_calls(1);
+
+        Ok(())
     }
-}
 
-impl Texture {
-    pub unsafe fn write(&mut self, x: u32, y: u32, width: u32, height: u32, data: &[u8]) {
+    /// `render_instanced` with the vertices in the order given by `index_buffer`, e.g. to draw
+    /// many quads from one 4 vertex mesh.
+    pub unsafe fn render_indexed_instanced(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        index_buffer: &IndexBuffer,
+        instance_buffer: &InstanceBuffer,
+        instance_count: usize,
+        target: RenderTarget,
+    ) -> Result<(), GLError> {
+        self.prepare_draw(
+            vertex_buffer,
+            Some((instance_buffer, instance_count)),
+            self.state.draw_target(target),
+        )?;
         self.context
-            .bind_texture(glow::TEXTURE_2D, Some(*self.texture_id));
-        self.context.tex_sub_image_2d_u8_slice(
-            glow::TEXTURE_2D,
+            .bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(**index_buffer.buffer));
+        self.context.draw_elements_instanced(
+            glow::TRIANGLES,
+            index_buffer.len as i32,
+            glow::UNSIGNED_SHORT,
             0,
-            x as i32,
-            y as i32,
-            width as i32,
-            height as i32,
-            match self.format {
-                TextureFormat::RFloat | TextureFormat::RInt => glow::RED,
-                TextureFormat::RGFloat | TextureFormat::RGInt => glow::RG,
-                TextureFormat::RGBFloat | TextureFormat::RGBInt => glow::RGB,
-                TextureFormat::BGRFloat | TextureFormat::BGRInt => glow::BGR,
-                TextureFormat::RGBAFloat | TextureFormat::RGBAInt => glow::RGBA,
-                TextureFormat::BGRAFloat | TextureFormat::BGRAInt => glow::BGRA,
-            },
-            glow::UNSIGNED_BYTE,
-            Some(data),
+            instance_count as i32,
         );
+        check_gl_errors!(self.context, "render_indexed_instanced");
+        self.state.count_gl_calls(2);
+
+        Ok(())
     }
-}
 
-#[repr(u32)]
-pub enum ShaderType {
-    Vertex = glow::VERTEX_SHADER,
-    Fragment = glow::FRAGMENT_SHADER,
-}
+    /// Draws triangles from `vertex_buffer` in the order given by `index_buffer`.
+    pub unsafe fn render_indexed(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        index_buffer: &IndexBuffer,
+        target: RenderTarget,
+    ) -> Result<(), GLError> {
+        self.draw_indexed(vertex_buffer, index_buffer, self.state.draw_target(target))
+    }
 
-struct VertexFormatInner {
-    stride: i32,
-    attributes: Vec<(VertexAttributeLocation, VertexAttributeInner)>,
-}
+    unsafe fn draw_indexed(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        index_buffer: &IndexBuffer,
+        target: DrawTarget,
+    ) -> Result<(), GLError> {
+        self.prepare_draw(vertex_buffer, None, target)?;
+        // the element array binding is part of the vertex array state, so this has to happen
+        // after the vertex array is bound
+        self.context
+            .bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(**index_buffer.buffer));
+        self.context.draw_elements(
+            glow::TRIANGLES,
+            index_buffer.len as i32,
+            glow::UNSIGNED_SHORT,
+            0,
+        );
+        check_gl_errors!(self.context, "render_indexed");
+        self.state.count_gl_calls(2);
 
-struct VertexAttributeInner {
-    pub ty: VertexAttributeType,
-    pub size: u32,
-    pub offset: u32,
-}
+        Ok(())
+    }
 
-pub struct Program {
-    context: Rc<glow::Context>,
-    program_id: Rc<ProgramId>,
-    vertex_shader: Rc<ShaderId>,
-    fragment_shader: Rc<ShaderId>,
-    uniform_entry_types: Vec<UniformType>,
-    set_uniforms: Vec<(UniformLocationId, Option<SetUniformValue>)>,
-    vertex_format: VertexFormatInner,
-}
+    /// Like `render_indexed`, but only draws the `count` indices starting at `first`.
+    pub unsafe fn render_indexed_range(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        index_buffer: &IndexBuffer,
+        first: usize,
+        count: usize,
+        target: RenderTarget,
+    ) -> Result<(), GLError> {
+        self.draw_indexed_range(
+            vertex_buffer,
+            index_buffer,
+            first,
+            count,
+            self.state.draw_target(target),
+        )
+    }
 
-impl Program {
-    pub fn set_uniform(&mut self, index: usize, value: Uniform<'_>) -> Result<(), GLError> {
-        if index > self.set_uniforms.len() {
-            return Err(GLError(format!("Uniform index {} is out of range", index)));
-        }
-        if value.uniform_type() != self.uniform_entry_types[index] {
+    unsafe fn draw_indexed_range(
+        &self,
+        vertex_buffer: &VertexBuffer,
+        index_buffer: &IndexBuffer,
+        first: usize,
+        count: usize,
+        target: DrawTarget,
+    ) -> Result<(), GLError> {
+        if first + count > index_buffer.len {
             return Err(GLError(format!(
-                "Wrong uniform type. Expected: {:?} Got uniform of type: {:?}",
-                self.uniform_entry_types[index],
-                value.uniform_type()
+                "Index range {}..{} is out of range of a buffer with {} indices",
+                first,
+                first + count,
+                index_buffer.len
             )));
         }
-        self.set_uniforms[index].1 = match value {
-            Uniform::Texture(texture) => Some(SetUniformValue::Texture(texture.texture_id.clone())),
-            Uniform::Int(x) => Some(SetUniformValue::Int(x)),
-            Uniform::Int2(x, y) => Some(SetUniformValue::Int2(x, y)),
-            Uniform::Int3(x, y, z) => Some(SetUniformValue::Int3(x, y, z)),
-            Uniform::Int4(x, y, z, w) => Some(SetUniformValue::Int4(x, y, z, w)),
-            Uniform::Float(x) => Some(SetUniformValue::Float(x)),
-            Uniform::Float2(x, y) => Some(SetUniformValue::Float2(x, y)),
-            Uniform::Float3(x, y, z) => Some(SetUniformValue::Float3(x, y, z)),
-            Uniform::Float4(x, y, z, w) => Some(SetUniformValue::Float4(x, y, z, w)),
-            Uniform::Mat2(m) => Some(SetUniformValue::Mat2(m)),
-            Uniform::Mat3(m) => Some(SetUniformValue::Mat3(m)),
-            Uniform::Mat4(m) => Some(SetUniformValue::Mat4(m)),
-        };
+        self.prepare_draw(vertex_buffer, None, target)?;
+        self.context
+            .bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(**index_buffer.buffer));
+        self.context.draw_elements(
+            glow::TRIANGLES,
+            count as i32,
+            glow::UNSIGNED_SHORT,
+            (first * std::mem::size_of::<u16>()) as i32,
+        );
+        check_gl_errors!(self.context, "render_indexed_range");
+        self.state.count_gl_calls(2);
 
         Ok(())
     }
 
-    pub unsafe fn render_vertices(
+    // Binds everything needed to draw from `vertex_buffer` and the given number of instances from
+    // an instance buffer into `target`
+    unsafe fn prepare_draw(
         &self,
         vertex_buffer: &VertexBuffer,
-        target: RenderTarget,
+        instances: Option<(&InstanceBuffer, usize)>,
+        target: DrawTarget,
     ) -> Result<(), GLError> {
-        self.context
-            .blend_func(glow::ONE, glow::ONE_MINUS_SRC_ALPHA);
-        self.context.enable(glow::BLEND);
+        let instanced_attributes = self
+            .vertex_format
+            .attributes
+            .iter()
+            .any(|(_, attribute)| attribute.divisor > 0);
+        match instances {
+            Some(_) if !self.state.instancing.get() => {
+                return Err(GLError("Instancing is not supported".to_owned()));
+            }
+            Some((instance_buffer, count)) if count > instance_buffer.len => {
+                return Err(GLError(format!(
+                    "Drawing {} instances from an instance buffer with {}",
+                    count, instance_buffer.len
+                )));
+            }
+            None if instanced_attributes => {
+                return Err(GLError(
+                    "The program has per-instance attributes, draw it with render_instanced"
+                        .to_owned(),
+                ));
+            }
+            _ => {}
+        }
+
+        match target.blend_mode.unwrap_or(self.blend_mode).factors() {
+            Some((src, dst)) => {
+                self.context.enable(glow::BLEND);
+                self.context.blend_equation(glow::FUNC_ADD);
+                self.context.blend_func(src, dst);
+                self.state.count_gl_calls(3);
+            }
+            None => {
+                self.context.disable(glow::BLEND);
+                self.state.count_gl_calls(1);
+            }
+        }
+        match self.depth_test {
+            Some(func) => {
+                self.context.enable(glow::DEPTH_TEST);
+                self.context.depth_func(func as u32);
+                self.context.depth_mask(true);
+                self.state.count_gl_calls(3);
+            }
+            None => {
+                self.context.disable(glow::DEPTH_TEST);
+                self.state.count_gl_calls(1);
+            }
+        }
+        match self.stencil {
+            Some(stencil) => {
+                self.context.enable(glow::STENCIL_TEST);
+                self.context.stencil_func(
+                    stencil.func as u32,
+                    stencil.reference as i32,
+                    stencil.read_mask as u32,
+                );
+                self.context.stencil_op(
+                    stencil.fail as u32,
+                    stencil.depth_fail as u32,
+                    stencil.pass as u32,
+                );
+                self.context.stencil_mask(stencil.write_mask as u32);
+                self.state.count_gl_calls(4);
+            }
+            None => {
+                self.context.disable(glow::STENCIL_TEST);
+                self.state.count_gl_calls(1);
+            }
+        }
+        self.context.color_mask(
+            self.color_write,
+            self.color_write,
+            self.color_write,
+            self.color_write,
+        );
+        self.state.count_gl_calls(1);
 
         self.context
-            .bind_vertex_array(Some(*vertex_buffer.vertex_array));
+            .bind_vertex_array(Some(**vertex_buffer.vertex_array));
         self.context
-            .bind_buffer(glow::ARRAY_BUFFER, Some(*vertex_buffer.buffer));
+            .bind_buffer(glow::ARRAY_BUFFER, Some(**vertex_buffer.buffer));
+        self.state.count_gl_calls(2);
+
+        if self.state.current_program.get() != Some(**self.program_id) {
+            self.context.use_program(Some(**self.program_id));
+            self.state.current_program.set(Some(**self.program_id));
+            self.state.count_gl_calls(1);
+        }
 
-        self.context.use_program(Some(*self.program_id));
+        self.context
+            .bind_framebuffer(glow::FRAMEBUFFER, target.framebuffer);
+        let (x, y, width, height) = target.viewport;
+        self.context.viewport(x, y, width, height);
+        self.state.count_gl_calls(2);
+        check_gl_errors!(self.context, "binding state for a draw");
 
-        match target {
-            RenderTarget::Screen => {
+        let mut uploaded_uniforms = self.uploaded_uniforms.borrow_mut();
+        let mut texture_index = 0;
+        for (i, (location, uniform_value)) in self.set_uniforms.iter().enumerate() {
+            let value = match uniform_value {
+                Some(value) => value,
+                None => {
+                    return Err(GLError(format!(
+                        "uniform {} is not set",
+                        self.uniform_names[i]
+                    )))
+                }
+            };
+            // texture bindings aren't part of the program, so they are always made
+            if let SetUniformValue::Texture(texture) = value {
+                self.context.active_texture(glow::TEXTURE0 + texture_index);
                 self.context
-                    .viewport(0, 0, SCREEN_SIZE.0 as i32, SCREEN_SIZE.1 as i32);
-                self.context.bind_framebuffer(glow::FRAMEBUFFER, None);
+                    .bind_texture(glow::TEXTURE_2D, Some(***texture));
+                self.state.count_gl_calls(2);
             }
-            RenderTarget::Texture(framebuffer) => {
-                self.context
-                    .viewport(0, 0, framebuffer.size.0, framebuffer.size.1);
+            if uploaded_uniforms[i].as_ref() != Some(value) {
+                self.upload_uniform(location, &self.element_locations[i], value, texture_index);
+                uploaded_uniforms[i] = Some(value.clone());
+                self.state.count_gl_calls(1);
+            }
+            if let SetUniformValue::Texture(_) = value {
+                texture_index += 1;
+            }
+        }
+        check_gl_errors!(self.context, "uploading uniforms for a draw");
+
+        // attribute pointers read from the buffer bound when they are set, so the per-vertex
+        // ones are set while the vertex buffer is bound and the per-instance ones after
+        self.set_attribute_pointers(false);
+        if let Some((instance_buffer, _)) = instances {
+            self.context
+                .bind_buffer(glow::ARRAY_BUFFER, Some(**instance_buffer.buffer));
+            self.state.count_gl_calls(1);
+            self.set_attribute_pointers(true);
+        }
+        check_gl_errors!(self.context, "setting attributes for a draw");
+
+        Ok(())
+    }
+
+    // Points either the per-vertex or the per-instance attributes at the bound array buffer
+    unsafe fn set_attribute_pointers(&self, per_instance: bool) {
+        let stride = if per_instance {
+            self.vertex_format.instance_stride
+        } else {
+            self.vertex_format.stride
+        };
+        for (location, attribute) in self.vertex_format.attributes.iter() {
+            if (attribute.divisor > 0) != per_instance {
+                continue;
+            }
+            self.context.enable_vertex_attrib_array(*location);
+            if attribute.integer {
+                self.context.vertex_attrib_pointer_i32(
+                    *location,
+                    attribute.size as i32,
+                    attribute.ty as u32,
+                    stride,
+                    attribute.offset as i32,
+                );
+            } else {
+                self.context.vertex_attrib_pointer_f32(
+                    *location,
+                    attribute.size as i32,
+                    attribute.ty as u32,
+                    attribute.normalized,
+                    stride,
+                    attribute.offset as i32,
+                );
+            }
+            self.state.count_gl_calls(2);
+            // the divisor is vertex array state, so it's set every time in case another program
+            // used this location of the vertex array with a different one
+            if self.state.instancing.get() {
                 self.context
-                    .bind_framebuffer(glow::FRAMEBUFFER, Some(*framebuffer.framebuffer));
+                    .vertex_attrib_divisor(*location, attribute.divisor);
+                self.state.count_gl_calls(1);
             }
         }
+    }
 
-        let mut texture_index = 0;
-        for (i, (location, uniform_value)) in self.set_uniforms.iter().enumerate() {
-            if uniform_value.is_none() {
-                return Err(GLError(format!("uniform {} is not set", i)));
+    unsafe fn upload_uniform(
+        &self,
+        location: &UniformLocationId,
+        element_locations: &[UniformLocationId],
+        value: &SetUniformValue,
+        texture_index: u32,
+    ) {
+        match value {
+            SetUniformValue::Texture(_) => {
+                self.context
+                    .uniform_1_i32(Some(location.clone()), texture_index as i32);
             }
-            match uniform_value.as_ref().unwrap() {
-                SetUniformValue::Texture(texture) => {
-                    self.context.active_texture(glow::TEXTURE0 + texture_index);
-                    self.context.bind_texture(glow::TEXTURE_2D, Some(**texture));
-                    self.context
-                        .uniform_1_i32(Some(location.clone()), texture_index as i32);
-                    texture_index += 1;
-                }
-                SetUniformValue::Int(x) => {
-                    self.context.uniform_1_i32(Some(location.clone()), *x);
-                }
-                SetUniformValue::Int2(x, y) => {
-                    self.context.uniform_2_i32(Some(location.clone()), *x, *y);
-                }
-                SetUniformValue::Int3(x, y, z) => {
-                    self.context
-                        .uniform_3_i32(Some(location.clone()), *x, *y, *z);
-                }
-                SetUniformValue::Int4(x, y, z, w) => {
-                    self.context
-                        .uniform_4_i32(Some(location.clone()), *x, *y, *z, *w);
-                }
-                SetUniformValue::Float(x) => {
+            SetUniformValue::Int(x) => {
+                self.context.uniform_1_i32(Some(location.clone()), *x);
+            }
+            SetUniformValue::Int2(x, y) => {
+                self.context.uniform_2_i32(Some(location.clone()), *x, *y);
+            }
+            SetUniformValue::Int3(x, y, z) => {
+                self.context
+                    .uniform_3_i32(Some(location.clone()), *x, *y, *z);
+            }
+            SetUniformValue::Int4(x, y, z, w) => {
+                self.context
+                    .uniform_4_i32(Some(location.clone()), *x, *y, *z, *w);
+            }
+            SetUniformValue::Float(x) => {
+                self.context.uniform_1_f32(Some(location.clone()), *x);
+            }
+            SetUniformValue::Float2(x, y) => {
+                self.context.uniform_2_f32(Some(location.clone()), *x, *y);
+            }
+            SetUniformValue::Float3(x, y, z) => {
+                self.context
+                    .uniform_3_f32(Some(location.clone()), *x, *y, *z);
+            }
+            SetUniformValue::Float4(x, y, z, w) => {
+                self.context
+                    .uniform_4_f32(Some(location.clone()), *x, *y, *z, *w);
+            }
+            SetUniformValue::Mat2(m) => {
+                self.context.uniform_matrix_2_f32_slice(
+                    Some(location.clone()),
+                    false,
+                    &[m[0][0], m[0][1], m[1][0], m[1][1]],
+                );
+            }
+            SetUniformValue::Mat3(m) => {
+                self.context.uniform_matrix_3_f32_slice(
+                    Some(location.clone()),
+                    false,
+                    &[
+                        m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1],
+                        m[2][2],
+                    ],
+                );
+            }
+            SetUniformValue::Mat4(m) => {
+                self.context.uniform_matrix_4_f32_slice(
+                    Some(location.clone()),
+                    false,
+                    &[
+                        m[0][0], m[0][1], m[0][2], m[0][3], m[1][0], m[1][1], m[1][2], m[1][3],
+                        m[2][0], m[2][1], m[2][2], m[2][3], m[3][0], m[3][1], m[3][2], m[3][3],
+                    ],
+                );
+            }
+            // elements the shader doesn't use have no location and are skipped
+            SetUniformValue::FloatArray(v) => {
+                for (location, x) in element_locations.iter().zip(v) {
                     self.context.uniform_1_f32(Some(location.clone()), *x);
                 }
-                SetUniformValue::Float2(x, y) => {
-                    self.context.uniform_2_f32(Some(location.clone()), *x, *y);
-                }
-                SetUniformValue::Float3(x, y, z) => {
+            }
+            SetUniformValue::Float4Array(v) => {
+                for (location, x) in element_locations.iter().zip(v.chunks_exact(4)) {
                     self.context
-                        .uniform_3_f32(Some(location.clone()), *x, *y, *z);
+                        .uniform_4_f32(Some(location.clone()), x[0], x[1], x[2], x[3]);
                 }
-                SetUniformValue::Float4(x, y, z, w) => {
+            }
+            SetUniformValue::Mat3Array(v) => {
+                for (location, m) in element_locations.iter().zip(v.chunks_exact(9)) {
+                    let mut matrix = [0.; 9];
+                    matrix.copy_from_slice(m);
                     self.context
-                        .uniform_4_f32(Some(location.clone()), *x, *y, *z, *w);
-                }
-                SetUniformValue::Mat2(m) => {
-                    self.context.uniform_matrix_2_f32_slice(
-                        Some(location.clone()),
-                        false,
-                        &[m[0][0], m[0][1], m[1][0], m[1][1]],
-                    );
-                }
-                SetUniformValue::Mat3(m) => {
-                    self.context.uniform_matrix_3_f32_slice(
-                        Some(location.clone()),
-                        false,
-                        &[
-                            m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1],
-                            m[2][2],
-                        ],
-                    );
-                }
-                SetUniformValue::Mat4(m) => {
-                    self.context.uniform_matrix_4_f32_slice(
-                        Some(location.clone()),
-                        false,
-                        &[
-                            m[0][0], m[0][1], m[0][2], m[0][3], m[1][0], m[1][1], m[1][2], m[1][3],
-                            m[2][0], m[2][1], m[2][2], m[2][3], m[3][0], m[3][1], m[3][2], m[3][3],
-                        ],
-                    );
+                        .uniform_matrix_3_f32_slice(Some(location.clone()), false, &matrix);
                 }
             }
         }
+    }
+}
 
-        for (location, attribute) in self.vertex_format.attributes.iter() {
-            self.context.enable_vertex_attrib_array(*location);
-            self.context.vertex_attrib_pointer_f32(
-                *location,
-                attribute.size as i32,
-                match attribute.ty {
-                    VertexAttributeType::Float => glow::FLOAT,
-                    VertexAttributeType::Int => glow::BYTE,
-                    VertexAttributeType::Uint => glow::UNSIGNED_BYTE,
-                },
-                false,
-                self.vertex_format.stride,
-                attribute.offset as i32,
-            );
-        }
+/// A texture with a framebuffer attached for rendering into it.
+pub struct RenderTexture {
+    texture: Texture,
+    framebuffer: Rc<Handle<FramebufferId>>,
+}
 
-        self.context
-            .draw_arrays(glow::TRIANGLES, 0, vertex_buffer.len as i32);
+impl RenderTexture {
+    /// For drawing with what was rendered, or changing the texture's filtering.
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
 
-        Ok(())
+    pub fn texture_mut(&mut self) -> &mut Texture {
+        &mut self.texture
     }
-}
 
-pub struct TextureRenderTarget {
-    framebuffer: Rc<<glow::Context as glow::HasContext>::Framebuffer>,
-    texture: Rc<TextureId>,
-    size: (i32, i32),
+    pub fn size(&self) -> (u32, u32) {
+        (self.texture.size.0 as u32, self.texture.size.1 as u32)
+    }
+
+    /// Reallocates the texture at a new size. The contents are undefined afterwards, so clear or
+    /// render the whole texture again.
+    pub unsafe fn resize(&mut self, width: u32, height: u32) -> Result<(), GLError> {
+        self.texture.allocate(width, height);
+        self.check_complete()
+    }
+
+    // Leaves the framebuffer bound
+    unsafe fn check_complete(&self) -> Result<(), GLError> {
+        let context = &self.texture.context;
+        context.bind_framebuffer(glow::FRAMEBUFFER, Some(**self.framebuffer));
+        let status = context.check_framebuffer_status(glow::FRAMEBUFFER);
+        check_gl_errors!(context, "checking framebuffer completeness");
+        if status != glow::FRAMEBUFFER_COMPLETE {
+            return Err(GLError(format!(
+                "Framebuffer for a {}x{} {:?} texture is incomplete, status {:#x}",
+                self.texture.size.0, self.texture.size.1, self.texture.format, status
+            )));
+        }
+        Ok(())
+    }
 }
 
+#[derive(Clone, Copy)]
 pub enum RenderTarget<'a> {
     Screen,
-    Texture(&'a TextureRenderTarget),
+    Texture(&'a RenderTexture),
 }
 
+#[derive(Clone, PartialEq)]
 enum SetUniformValue {
-    Texture(Rc<TextureId>),
+    Texture(Rc<Handle<TextureId>>),
     Int(i32),
     Int2(i32, i32),
     Int3(i32, i32, i32),
@@ -564,6 +2936,9 @@ enum SetUniformValue {
     Mat2([[f32; 2]; 2]),
     Mat3([[f32; 3]; 3]),
     Mat4([[f32; 4]; 4]),
+    FloatArray(Vec<f32>),
+    Float4Array(Vec<f32>),
+    Mat3Array(Vec<f32>),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -580,8 +2955,25 @@ pub enum UniformType {
     Mat2,
     Mat3,
     Mat4,
+    FloatArray,
+    Float4Array,
+    Mat3Array,
+}
+
+impl UniformType {
+    fn is_array(self) -> bool {
+        match self {
+            UniformType::FloatArray | UniformType::Float4Array | UniformType::Mat3Array => true,
+            _ => false,
+        }
+    }
 }
 
+/// The array variants take the elements' floats one after the other, e.g. 4 per element of a
+/// `vec4` array, and column major for matrices. Fewer elements than the array has can be given
+/// to only update the first ones. WebGL 1 shaders can only index uniform arrays with constant
+/// expressions or loop indices, not with arbitrary values computed in the shader.
+#[derive(Clone, Copy, Debug)]
 pub enum Uniform<'a> {
     Texture(&'a Texture),
     Int(i32),
@@ -595,6 +2987,9 @@ pub enum Uniform<'a> {
     Mat2([[f32; 2]; 2]),
     Mat3([[f32; 3]; 3]),
     Mat4([[f32; 4]; 4]),
+    FloatArray(&'a [f32]),
+    Float4Array(&'a [f32]),
+    Mat3Array(&'a [f32]),
 }
 
 impl<'a> Uniform<'a> {
@@ -612,6 +3007,19 @@ impl<'a> Uniform<'a> {
             Uniform::Mat2(_) => UniformType::Mat2,
             Uniform::Mat3(_) => UniformType::Mat3,
             Uniform::Mat4(_) => UniformType::Mat4,
+            Uniform::FloatArray(_) => UniformType::FloatArray,
+            Uniform::Float4Array(_) => UniformType::Float4Array,
+            Uniform::Mat3Array(_) => UniformType::Mat3Array,
+        }
+    }
+
+    // The floats of an array uniform and how many make up each element
+    fn array_elements(&self) -> Option<(&'a [f32], usize)> {
+        match self {
+            Uniform::FloatArray(v) => Some((v, 1)),
+            Uniform::Float4Array(v) => Some((v, 4)),
+            Uniform::Mat3Array(v) => Some((v, 9)),
+            _ => None,
         }
     }
 }
@@ -620,27 +3028,51 @@ impl<'a> Uniform<'a> {
 pub struct UniformEntry<'a> {
     pub name: &'a str,
     pub ty: UniformType,
+    /// Number of elements the array types are declared with in the shader, ignored for the others
+    pub array_len: usize,
+    /// Set when the program is created, so the uniform doesn't have to be set before drawing
+    pub default: Option<Uniform<'a>>,
 }
 
-#[derive(Clone, Copy)]
+/// Type of each component of an attribute in the vertex data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
 pub enum VertexAttributeType {
-    Int,
-    Uint,
-    Float,
+    Float = glow::FLOAT,
+    I32 = glow::INT,
+    U32 = glow::UNSIGNED_INT,
+    I16 = glow::SHORT,
+    U16 = glow::UNSIGNED_SHORT,
+    I8 = glow::BYTE,
+    U8 = glow::UNSIGNED_BYTE,
 }
 
 #[derive(Clone)]
 pub struct VertexAttribute<'a> {
     pub name: &'a str,
     pub ty: VertexAttributeType,
+    /// Number of components
     pub size: u32,
+    /// In bytes from the start of the vertex
     pub offset: u32,
+    /// Integer components are converted to floats in 0..1 (or -1..1 if signed) instead of keeping
+    /// their value, e.g. for colors packed as 4 `U8`s
+    pub normalized: bool,
+    /// Integer components are passed to an `int`/`ivec` shader input instead of converted to
+    /// floats. Needs GLES 3 or WebGL 2.
+    pub integer: bool,
+    /// 0 for attributes read from the vertex buffer for every vertex. Otherwise the attribute is
+    /// read from the instance buffer, advancing once every `divisor` instances. Needs
+    /// `Context::supports_instancing`.
+    pub divisor: u32,
 }
 
 type VertexAttributeLocation = u32;
 
 pub struct VertexFormat<'a> {
     pub stride: usize,
+    /// Size in bytes of each instance in the instance buffer, 0 if no attribute has a divisor
+    pub instance_stride: usize,
     pub attributes: &'a [VertexAttribute<'a>],
 }
 
@@ -650,3 +3082,760 @@ pub struct ProgramDescriptor<'a> {
     pub uniforms: &'a [UniformEntry<'a>],
     pub vertex_format: VertexFormat<'a>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_handle(id: BufferId, deletion_queue: &DeletionQueue) -> Rc<Handle<BufferId>> {
+        Rc::new(Handle {
+            id,
+            object: GlObject::Buffer(id),
+            deletion_queue: deletion_queue.clone(),
+        })
+    }
+
+    #[test]
+    fn objects_are_queued_for_deletion_when_the_last_handle_drops() {
+        let deletion_queue = DeletionQueue::default();
+        let handle = buffer_handle(1, &deletion_queue);
+        let other_handle = Rc::clone(&handle);
+        drop(handle);
+        assert!(deletion_queue.take().is_empty());
+        drop(other_handle);
+        drop(buffer_handle(2, &deletion_queue));
+        assert_eq!(
+            deletion_queue.take(),
+            [GlObject::Buffer(1), GlObject::Buffer(2)]
+        );
+        assert!(deletion_queue.take().is_empty());
+    }
+
+    #[test]
+    fn the_deletion_queue_holds_only_what_dropped_since_it_was_taken() {
+        let deletion_queue = DeletionQueue::default();
+        let mut deleted = 0;
+        // 10k objects made and dropped over 100 frames
+        for frame in 0..100 {
+            let handles: Vec<_> = (0..100)
+                .map(|i| buffer_handle(frame * 100 + i, &deletion_queue))
+                .collect();
+            drop(handles);
+            assert_eq!(deletion_queue.0.borrow().len(), 100);
+            deleted += deletion_queue.take().len();
+            assert_eq!(deletion_queue.0.borrow().capacity(), 0);
+        }
+        assert_eq!(deleted, 10_000);
+    }
+
+    // Drawing on a real GL context, which tests skip where there is none
+    #[cfg(feature = "headless")]
+    mod headless {
+        use euclid::{point2, size2, Rect};
+
+        use super::super::*;
+        use crate::{
+            graphics::{load_images_batch, BatchImage},
+            platform::headless_gl_for_test,
+            texture_atlas::TextureAtlas,
+        };
+
+        const COLOR_VERTEX_SHADER: &str = "#version 100
+attribute highp vec2 a_pos;
+attribute highp vec4 a_color;
+varying highp vec4 v_color;
+void main() {
+    v_color = a_color;
+    gl_Position = vec4(a_pos, 0.0, 1.0);
+}";
+        const COLOR_FRAGMENT_SHADER: &str = "#version 100
+varying highp vec4 v_color;
+void main() {
+    gl_FragColor = v_color;
+}";
+
+        // Draws triangles of vertices with a position in clip space and an RGBA color
+        unsafe fn color_program(context: &mut Context) -> Program {
+            let vertex_shader = context
+                .create_shader(ShaderType::Vertex, COLOR_VERTEX_SHADER)
+                .unwrap();
+            let fragment_shader = context
+                .create_shader(ShaderType::Fragment, COLOR_FRAGMENT_SHADER)
+                .unwrap();
+            let attribute = |name, size, offset| VertexAttribute {
+                name,
+                ty: VertexAttributeType::Float,
+                size,
+                offset,
+                normalized: false,
+                integer: false,
+                divisor: 0,
+            };
+            context
+                .create_program(&ProgramDescriptor {
+                    vertex_shader: &vertex_shader,
+                    fragment_shader: &fragment_shader,
+                    uniforms: &[],
+                    vertex_format: VertexFormat {
+                        stride: 6 * 4,
+                        instance_stride: 0,
+                        attributes: &[attribute("a_pos", 2, 0), attribute("a_color", 4, 2 * 4)],
+                    },
+                })
+                .unwrap()
+        }
+
+        // Two triangles covering clip space from `min` to `max`
+        fn rect(min: [f32; 2], max: [f32; 2], color: [f32; 4]) -> Vec<[f32; 6]> {
+            let vertex = |x, y| [x, y, color[0], color[1], color[2], color[3]];
+            vec![
+                vertex(min[0], min[1]),
+                vertex(max[0], min[1]),
+                vertex(min[0], max[1]),
+                vertex(max[0], min[1]),
+                vertex(max[0], max[1]),
+                vertex(min[0], max[1]),
+            ]
+        }
+
+        // `rect` from `min_x` to `max_x` across the whole height
+        fn column(min_x: f32, max_x: f32, color: [f32; 4]) -> Vec<[f32; 6]> {
+            rect([min_x, -1.], [max_x, 1.], color)
+        }
+
+        #[test]
+        fn read_pixels_comes_back_top_row_first() {
+            let mut gl = match headless_gl_for_test(4, 4) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let target = RenderTarget::Screen;
+            let pixels = unsafe {
+                gl.context
+                    .clear(target, ClearOptions::color([0., 0., 1., 1.]));
+                // the bottom left quarter, in GL's coordinates
+                gl.context.clear(
+                    target,
+                    ClearOptions {
+                        rect: Some(Rect::new(point2(0, 0), size2(2, 2))),
+                        ..ClearOptions::color([1., 0., 0., 1.])
+                    },
+                );
+                gl.context
+                    .read_pixels(target, Rect::new(point2(0, 0), size2(4, 4)))
+                    .unwrap()
+            };
+            let red = [255, 0, 0, 255];
+            let blue = [0, 0, 255, 255];
+            let pixel = |x: usize, y: usize| &pixels[(y * 4 + x) * 4..(y * 4 + x) * 4 + 4];
+            assert_eq!(pixel(0, 0), blue);
+            assert_eq!(pixel(3, 3), blue);
+            assert_eq!(pixel(0, 3), red);
+            assert_eq!(pixel(1, 2), red);
+            assert_eq!(pixel(2, 2), blue);
+
+            let corner = unsafe {
+                gl.context
+                    .read_pixels(target, Rect::new(point2(1, 1), size2(2, 2)))
+                    .unwrap()
+            };
+            assert_eq!(corner, [blue, blue, red, blue].concat());
+
+            let outside = unsafe {
+                gl.context
+                    .read_pixels(target, Rect::new(point2(2, 2), size2(4, 4)))
+            };
+            assert!(outside.is_err());
+        }
+
+        #[test]
+        fn write_at_updates_part_of_a_buffer() {
+            let mut gl = match headless_gl_for_test(2, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let red = [1., 0., 0., 1.];
+            let green = [0., 1., 0., 1.];
+            let pixels = unsafe {
+                let program = color_program(&mut gl.context);
+                let mut buffer = gl
+                    .context
+                    .create_vertex_buffer(BufferUsage::Static)
+                    .unwrap();
+                buffer.reserve::<[f32; 6]>(12);
+                assert_eq!(buffer.len(), 0);
+                // the right column before the left one, into the space reserve made
+                buffer.write_at(6, &column(0., 1., red)).unwrap();
+                buffer.write_at(0, &column(-1., 0., red)).unwrap();
+                assert_eq!(buffer.len(), 12);
+                buffer.write_at(6, &column(0., 1., green)).unwrap();
+                assert!(buffer.write_at(7, &column(0., 1., green)).is_err());
+                assert_eq!(buffer.len(), 12);
+
+                let target = RenderTarget::Screen;
+                gl.context
+                    .pass(target)
+                    .clear([0., 0., 0., 1.])
+                    .draw(&program, &buffer)
+                    .unwrap();
+                gl.context
+                    .read_pixels(target, Rect::new(point2(0, 0), size2(2, 1)))
+                    .unwrap()
+            };
+            assert_eq!(pixels, [255, 0, 0, 255, 0, 255, 0, 255]);
+        }
+
+        #[test]
+        fn copy_texture_region_copies_only_the_region() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            // every texel of the source is different, the destination starts out clear
+            let texel = |x: u32, y: u32| [x as u8 * 30, y as u8 * 30, 255, 255];
+            let pattern: Vec<u8> = (0..8)
+                .flat_map(|y| (0..8).flat_map(move |x| texel(x, y)))
+                .collect();
+            let copied = unsafe {
+                let mut src = context
+                    .create_texture(TextureFormat::RGBA8, 8, 8, TextureFilter::NEAREST)
+                    .unwrap();
+                src.write(0, 0, 8, 8, &pattern).unwrap();
+                let mut dst = context
+                    .create_texture(TextureFormat::RGBA8, 8, 8, TextureFilter::NEAREST)
+                    .unwrap();
+                dst.write(0, 0, 8, 8, &[0; 8 * 8 * 4]).unwrap();
+                context
+                    .copy_texture_region(
+                        &src,
+                        Rect::new(point2(2, 1), size2(3, 4)),
+                        &mut dst,
+                        point2(4, 3),
+                    )
+                    .unwrap();
+                assert!(context
+                    .copy_texture_region(
+                        &src,
+                        Rect::new(point2(6, 0), size2(3, 1)),
+                        &mut dst,
+                        point2(0, 0)
+                    )
+                    .is_err());
+                assert!(context
+                    .copy_texture_region(
+                        &src,
+                        Rect::new(point2(0, 0), size2(3, 1)),
+                        &mut dst,
+                        point2(6, 0)
+                    )
+                    .is_err());
+                context.read_texture(&dst).unwrap()
+            };
+            for y in 0..8 {
+                for x in 0..8 {
+                    let expected = if (4..7).contains(&x) && (3..7).contains(&y) {
+                        texel(x - 2, y - 2)
+                    } else {
+                        [0; 4]
+                    };
+                    let i = (y * 8 + x) as usize * 4;
+                    assert_eq!(copied[i..i + 4], expected, "at {}, {}", x, y);
+                }
+            }
+        }
+
+        // One pixel quads in a grid filling a 40x25 screen, one per instance
+        const GRID_SIZE: (u32, u32) = (40, 25);
+        const QUAD_VERTEX_SHADER: &str = "#version 100
+attribute highp vec2 a_corner;
+#ifdef INSTANCED
+attribute highp vec2 i_offset;
+attribute highp vec4 i_color;
+#else
+uniform highp vec2 i_offset;
+uniform highp vec4 i_color;
+#endif
+varying highp vec4 v_color;
+void main() {
+    v_color = i_color;
+    gl_Position = vec4((a_corner + i_offset) / vec2(40.0, 25.0) * 2.0 - 1.0, 0.0, 1.0);
+}";
+
+        // Draws the quad mesh with the offset and color of each quad from the instance buffer, or
+        // from uniforms for the fallback without instancing
+        unsafe fn quad_program(context: &mut Context, instanced: bool) -> Program {
+            let defines: &[(&str, &str)] = if instanced {
+                &[("INSTANCED", "1")]
+            } else {
+                &[]
+            };
+            let vertex_shader = context
+                .create_shader_with_defines(ShaderType::Vertex, QUAD_VERTEX_SHADER, defines)
+                .unwrap();
+            let fragment_shader = context
+                .create_shader(ShaderType::Fragment, COLOR_FRAGMENT_SHADER)
+                .unwrap();
+            let attribute = |name, size, offset, divisor| VertexAttribute {
+                name,
+                ty: VertexAttributeType::Float,
+                size,
+                offset,
+                normalized: false,
+                integer: false,
+                divisor,
+            };
+            let uniform = |name, ty| UniformEntry {
+                name,
+                ty,
+                array_len: 0,
+                default: None,
+            };
+            let (attributes, uniforms) = if instanced {
+                (
+                    vec![
+                        attribute("a_corner", 2, 0, 0),
+                        attribute("i_offset", 2, 0, 1),
+                        attribute("i_color", 4, 2 * 4, 1),
+                    ],
+                    vec![],
+                )
+            } else {
+                (
+                    vec![attribute("a_corner", 2, 0, 0)],
+                    vec![
+                        uniform("i_offset", UniformType::Float2),
+                        uniform("i_color", UniformType::Float4),
+                    ],
+                )
+            };
+            context
+                .create_program(&ProgramDescriptor {
+                    vertex_shader: &vertex_shader,
+                    fragment_shader: &fragment_shader,
+                    uniforms: &uniforms,
+                    vertex_format: VertexFormat {
+                        stride: 2 * 4,
+                        instance_stride: if instanced { 6 * 4 } else { 0 },
+                        attributes: &attributes,
+                    },
+                })
+                .unwrap()
+        }
+
+        fn read_screen(context: &mut Context) -> Vec<u8> {
+            let (width, height) = context.screen_size();
+            unsafe {
+                context
+                    .read_pixels(
+                        RenderTarget::Screen,
+                        Rect::new(point2(0, 0), size2(width, height)),
+                    )
+                    .unwrap()
+            }
+        }
+
+        #[test]
+        fn texture_writes_must_fit_the_texture_and_the_data() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            unsafe {
+                let mut texture = context
+                    .create_texture(TextureFormat::RGBA8, 4, 3, TextureFilter::NEAREST)
+                    .unwrap();
+                // a rect ending one past the right or bottom edge
+                assert!(texture.write(1, 0, 4, 1, &[0; 4 * 4]).is_err());
+                assert!(texture.write(0, 1, 1, 3, &[0; 3 * 4]).is_err());
+                // a slice one pixel short, or one pixel long
+                assert!(texture.write(0, 0, 2, 2, &[0; 3 * 4]).is_err());
+                assert!(texture.write(0, 0, 2, 2, &[0; 5 * 4]).is_err());
+                assert!(texture.write(0, 0, 4, 3, &[7; 4 * 3 * 4]).is_ok());
+                assert_eq!(context.read_texture(&texture).unwrap(), [7; 4 * 3 * 4]);
+
+                if !context.supports_format(TextureFormat::RGBA32F) {
+                    return;
+                }
+                let mut texture = context
+                    .create_texture(TextureFormat::RGBA32F, 4, 3, TextureFilter::NEAREST)
+                    .unwrap();
+                assert!(texture.write_f32(1, 0, 4, 1, &[0.; 4 * 4]).is_err());
+                assert!(texture.write_f32(0, 1, 1, 3, &[0.; 3 * 4]).is_err());
+                assert!(texture.write_f32(0, 0, 2, 2, &[0.; 3 * 4]).is_err());
+                assert!(texture.write_f32(0, 0, 4, 3, &[0.5; 4 * 3 * 4]).is_ok());
+            }
+        }
+
+        #[test]
+        fn float_textures_are_written_with_write_f32() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            if !context.supports_format(TextureFormat::RGBA32F) {
+                return;
+            }
+            let pixels = unsafe {
+                let mut float = context
+                    .create_texture(TextureFormat::RGBA32F, 2, 1, TextureFilter::NEAREST)
+                    .unwrap();
+                assert!(float.write(0, 0, 2, 1, &[0; 2 * 16]).is_err());
+                float
+                    .write_f32(0, 0, 2, 1, &[1., 0., 0., 1., 0.5, 0.25, 1., 1.])
+                    .unwrap();
+                let mut bytes = context
+                    .create_texture(TextureFormat::RGBA8, 2, 1, TextureFilter::NEAREST)
+                    .unwrap();
+                assert!(bytes.write_f32(0, 0, 2, 1, &[0.; 2 * 4]).is_err());
+                // drawn into an 8 bit texture to read it back
+                context
+                    .copy_texture_region(
+                        &float,
+                        Rect::new(point2(0, 0), size2(2, 1)),
+                        &mut bytes,
+                        point2(0, 0),
+                    )
+                    .unwrap();
+                context.read_texture(&bytes).unwrap()
+            };
+            let expected = [255, 0, 0, 255, 128, 64, 255, 255];
+            for (i, (&actual, &expected)) in pixels.iter().zip(&expected).enumerate() {
+                assert!(
+                    (actual as i32 - expected).abs() <= 1,
+                    "byte {} is {}, not {}",
+                    i,
+                    actual,
+                    expected
+                );
+            }
+        }
+
+        #[test]
+        fn unsupported_formats_are_errors() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            // like GLES 2 without the float extensions
+            context.float_textures = false;
+            context.float_render_targets = false;
+            let version = context.limits().version.clone();
+            unsafe {
+                assert_eq!(
+                    context
+                        .create_texture(TextureFormat::RGBA32F, 1, 1, TextureFilter::NEAREST)
+                        .unwrap_err()
+                        .to_string(),
+                    format!(
+                        "OpenGL error: RGBA32F textures are not supported by {}",
+                        version
+                    )
+                );
+                assert_eq!(
+                    context
+                        .create_render_texture(TextureFormat::RGBA16F, 1, 1)
+                        .err()
+                        .unwrap()
+                        .to_string(),
+                    format!(
+                        "OpenGL error: Rendering to RGBA16F textures is not supported by {}",
+                        version
+                    )
+                );
+                assert!(context
+                    .create_render_texture(TextureFormat::RGBA8, 1, 1)
+                    .is_ok());
+            }
+        }
+
+        #[test]
+        fn raw_images_keep_their_orientation_in_the_atlas() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            // 2 wide and 4 tall, with the row and column in red and green
+            let texel = |x: u8, y: u8| [x * 100, y * 60, 255, 255];
+            let pixels: Vec<u8> = (0..4)
+                .flat_map(|y| (0..2).flat_map(move |x| texel(x, y)))
+                .collect();
+            let (rect, atlas_pixels) = unsafe {
+                let mut texture = context
+                    .create_texture(TextureFormat::RGBA8, 8, 8, TextureFilter::NEAREST)
+                    .unwrap();
+                let mut texture_atlas = TextureAtlas::new((8, 8), 1);
+                let rects = load_images_batch(
+                    &[BatchImage::Raw(&pixels, size2(2, 4))],
+                    &mut texture_atlas,
+                    &mut texture,
+                )
+                .unwrap();
+                (rects[0], context.read_texture(&texture).unwrap())
+            };
+            assert_eq!(rect.texels[2] - rect.texels[0], 2);
+            assert_eq!(rect.texels[3] - rect.texels[1], 4);
+            for y in 0..4 {
+                for x in 0..2 {
+                    let i = ((rect.texels[1] + y) * 8 + rect.texels[0] + x) as usize * 4;
+                    assert_eq!(
+                        atlas_pixels[i..i + 4],
+                        texel(x as u8, y as u8),
+                        "at {}, {}",
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn creating_and_dropping_ten_thousand_buffers_deletes_them_as_it_goes() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            for _frame in 0..100 {
+                for _ in 0..100 {
+                    unsafe {
+                        let mut buffer = context.create_vertex_buffer(BufferUsage::Static).unwrap();
+                        buffer.write(&column(-1., 1., [1., 1., 1., 1.]));
+                    }
+                }
+                // a vertex array and a buffer for each
+                assert_eq!(context.deletion_queue.0.borrow().len(), 200);
+                unsafe { context.maintain() };
+                assert!(context.deletion_queue.0.borrow().is_empty());
+            }
+            assert_eq!(unsafe { context.context.get_error() }, glow::NO_ERROR);
+        }
+
+        #[test]
+        fn render_passes_dont_leak_state_into_other_draws() {
+            let mut gl = match headless_gl_for_test(4, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            let target = RenderTarget::Screen;
+            let black = [0., 0., 0., 1.];
+            let red = [1., 0., 0., 1.];
+            let green = [0., 1., 0., 1.];
+            let blue = [0., 0., 1., 1.];
+            let pixels = |colors: &[[f32; 4]]| -> Vec<u8> {
+                colors.iter().flatten().map(|c| (c * 255.) as u8).collect()
+            };
+            let first_pixel = Rect::new(point2(0, 0), size2(1, 1));
+            let last_pixel = Rect::new(point2(3, 0), size2(1, 1));
+            unsafe {
+                let program = color_program(context);
+                let mut buffer = context.create_vertex_buffer(BufferUsage::Static).unwrap();
+
+                // the left half, clipped to its first pixel and added to what's there
+                buffer.write(&column(-1., 1., red));
+                context
+                    .pass(target)
+                    .clear(black)
+                    .viewport(0, 0, 2, 1)
+                    .scissor(first_pixel)
+                    .blend(BlendMode::Additive)
+                    .draw(&program, &buffer)
+                    .unwrap();
+                assert_eq!(read_screen(context), pixels(&[red, black, black, black]));
+
+                // the next pass covers the whole screen, replacing the red instead of adding to it
+                buffer.write(&column(-1., 1., green));
+                context.pass(target).draw(&program, &buffer).unwrap();
+                assert_eq!(read_screen(context), pixels(&[green; 4]));
+
+                // passes ignore the context's scissor rectangle and put it back after
+                context.set_scissor(Some(last_pixel));
+                buffer.write(&column(-1., 1., blue));
+                context
+                    .pass(target)
+                    .scissor(first_pixel)
+                    .draw(&program, &buffer)
+                    .unwrap();
+                buffer.write(&column(-1., 1., red));
+                program
+                    .render_vertices(&buffer, PrimitiveType::Triangles, target)
+                    .unwrap();
+                context.set_scissor(None);
+            }
+            assert_eq!(read_screen(context), pixels(&[blue, green, green, red]));
+        }
+
+        #[test]
+        fn stencil_masks_draws_to_where_the_mask_was_drawn() {
+            let mut gl = match headless_gl_for_test(4, 4) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            let target = RenderTarget::Screen;
+            let blue = [0., 0., 1., 1.];
+            let red = [1., 0., 0., 1.];
+            let (masked_program, fill) = unsafe {
+                let mut mask_program = color_program(context);
+                mask_program.set_color_write(false);
+                mask_program.set_stencil(Some(StencilState::write(1)));
+                let mut masked_program = color_program(context);
+                masked_program.set_stencil(Some(StencilState::test_equal(1)));
+
+                // the middle 2x2 pixels, like the room block the next room shows through
+                let mut mask = context.create_vertex_buffer(BufferUsage::Static).unwrap();
+                mask.write(&rect([-0.5, -0.5], [0.5, 0.5], [1., 1., 1., 1.]));
+                let mut fill = context.create_vertex_buffer(BufferUsage::Static).unwrap();
+                fill.write(&column(-1., 1., red));
+
+                context.clear(
+                    target,
+                    ClearOptions {
+                        stencil: Some(0),
+                        ..ClearOptions::color(blue)
+                    },
+                );
+                mask_program
+                    .render_vertices(&mask, PrimitiveType::Triangles, target)
+                    .unwrap();
+                masked_program
+                    .render_vertices(&fill, PrimitiveType::Triangles, target)
+                    .unwrap();
+                (masked_program, fill)
+            };
+            let pixels = read_screen(context);
+            let expected: Vec<u8> = (0..16)
+                .flat_map(|i| {
+                    let (x, y) = (i % 4, i / 4);
+                    if (1..3).contains(&x) && (1..3).contains(&y) {
+                        [255, 0, 0, 255]
+                    } else {
+                        [0, 0, 255, 255]
+                    }
+                })
+                .collect();
+            assert_eq!(pixels, expected);
+
+            // nothing passes once the mask is cleared
+            let pixels = unsafe {
+                context.clear(
+                    target,
+                    ClearOptions {
+                        stencil: Some(0),
+                        ..ClearOptions::color(blue)
+                    },
+                );
+                masked_program
+                    .render_vertices(&fill, PrimitiveType::Triangles, target)
+                    .unwrap();
+                read_screen(context)
+            };
+            assert_eq!(pixels, [0, 0, 255, 255].repeat(16));
+        }
+
+        #[test]
+        fn instancing_draws_a_thousand_quads_from_one_mesh() {
+            let mut gl = match headless_gl_for_test(GRID_SIZE.0, GRID_SIZE.1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let context = &mut gl.context;
+            // offset and color of each quad, in steps that survive the trip through 8 bits
+            let instances: Vec<[f32; 6]> = (0..GRID_SIZE.0 * GRID_SIZE.1)
+                .map(|i| {
+                    let (x, y) = (i % GRID_SIZE.0, i / GRID_SIZE.0);
+                    let channel = |value: u32| value as f32 / 255.;
+                    [
+                        x as f32,
+                        y as f32,
+                        channel(x * 6),
+                        channel(y * 10),
+                        channel(i % 7 * 40),
+                        1.,
+                    ]
+                })
+                .collect();
+            assert_eq!(instances.len(), 1000);
+
+            let (mesh, indices) = unsafe {
+                let mut mesh = context.create_vertex_buffer(BufferUsage::Static).unwrap();
+                mesh.write(&[[0f32, 0.], [1., 0.], [0., 1.], [1., 1.]]);
+                let mut indices = context.create_index_buffer(BufferUsage::Static).unwrap();
+                indices.write(&[0, 1, 2, 2, 1, 3]);
+                (mesh, indices)
+            };
+
+            // the way Context::supports_instancing suggests drawing without it
+            let fallback = unsafe {
+                let mut program = quad_program(context, false);
+                context.pass(RenderTarget::Screen).clear([0., 0., 0., 0.]);
+                for instance in &instances {
+                    program
+                        .set_uniform_by_name("i_offset", Uniform::Float2(instance[0], instance[1]))
+                        .unwrap();
+                    program
+                        .set_uniform_by_name(
+                            "i_color",
+                            Uniform::Float4(instance[2], instance[3], instance[4], instance[5]),
+                        )
+                        .unwrap();
+                    program
+                        .render_indexed(&mesh, &indices, RenderTarget::Screen)
+                        .unwrap();
+                }
+                read_screen(context)
+            };
+            // read back top row first
+            for (i, instance) in instances.iter().enumerate() {
+                let (x, y) = (instance[0] as usize, instance[1] as usize);
+                let row = GRID_SIZE.1 as usize - 1 - y;
+                let start = (row * GRID_SIZE.0 as usize + x) * 4;
+                let expected: Vec<u8> = instance[2..]
+                    .iter()
+                    .map(|channel| (channel * 255.).round() as u8)
+                    .collect();
+                assert_eq!(fallback[start..start + 4], expected[..], "quad {}", i);
+            }
+
+            if !context.supports_instancing() {
+                eprintln!(
+                    "Skipping the instanced draw, {} has no instancing",
+                    context.limits().version
+                );
+                return;
+            }
+            unsafe {
+                let program = quad_program(context, true);
+                let mut instance_buffer =
+                    context.create_instance_buffer(BufferUsage::Static).unwrap();
+                instance_buffer.write(&instances);
+                context.pass(RenderTarget::Screen).clear([0., 0., 0., 0.]);
+                program
+                    .render_indexed_instanced(
+                        &mesh,
+                        &indices,
+                        &instance_buffer,
+                        instances.len(),
+                        RenderTarget::Screen,
+                    )
+                    .unwrap();
+                assert_eq!(read_screen(context), fallback);
+                // asking for more instances than the buffer has is caught
+                assert!(program
+                    .render_indexed_instanced(
+                        &mesh,
+                        &indices,
+                        &instance_buffer,
+                        instances.len() + 1,
+                        RenderTarget::Screen,
+                    )
+                    .is_err());
+            }
+        }
+    }
+}
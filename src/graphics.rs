@@ -1,13 +1,28 @@
-use anyhow::Error;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::HashMap,
+    ops::{Mul, Range},
+    rc::Rc,
+};
+
+use anyhow::{bail, format_err, Context, Error};
 use euclid::{
-    default::{Box2D, Point2D, Rect, Size2D, Transform2D},
-    point2, size2,
+    default::{Point2D, Rect, Transform2D, Vector2D},
+    point2, size2, Angle, Box2D, Size2D,
 };
+use palette::{encoding::srgb::Srgb, Hsv, LinSrgb};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
 use zerocopy::AsBytes;
 
 use crate::{
-    gl,
-    texture_atlas::{TextureAtlas, TextureRect},
+    constants::SCREEN_SIZE,
+    gl, json,
+    spaces::{
+        self, AtlasSpace, ClipSpace, RoomPoint, ScreenPoint, ScreenSpace, UiPoint, UiRect, UiSize,
+        UiSpace,
+    },
+    texture_atlas::{self, ContentKey, TextureAtlas, TextureRect},
 };
 
 #[repr(C)]
@@ -16,198 +31,3076 @@ pub struct Vertex {
     pub position: [f32; 2],
     pub uv: [f32; 2],
     pub color: [f32; 4],
+    /// Color the texel is mixed toward before `color` is applied, by this color's alpha. Unlike
+    /// `color` this can brighten, for white flashes.
+    pub flash: [f32; 4],
+}
+
+/// A linear RGBA color with components from 0 to 1, not premultiplied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl Color {
+    pub const WHITE: Color = Color::rgba(1., 1., 1., 1.);
+    pub const BLACK: Color = Color::rgba(0., 0., 0., 1.);
+    pub const TRANSPARENT: Color = Color::rgba(0., 0., 0., 0.);
+
+    pub const fn rgba(r: f32, g: f32, b: f32, a: f32) -> Color {
+        Color { r, g, b, a }
+    }
+
+    pub fn rgb8(r: u8, g: u8, b: u8) -> Color {
+        Color::rgba(r as f32 / 255., g as f32 / 255., b as f32 / 255., 1.)
+    }
+
+    /// `hue` is in degrees, `saturation` and `value` from 0 to 1. The HSV color is taken as sRGB
+    /// and converted to linear, which is what the room colors are picked for.
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> Color {
+        let (r, g, b) = LinSrgb::from(Hsv::<Srgb, f32>::from_components((hue, saturation, value)))
+            .into_components();
+        Color::rgba(r, g, b, 1.)
+    }
+
+    /// Parses `#rrggbb` or `#rrggbbaa`.
+    pub fn from_hex(hex: &str) -> Result<Color, Error> {
+        let digits = hex
+            .strip_prefix('#')
+            .filter(|digits| (digits.len() == 6 || digits.len() == 8) && digits.is_ascii())
+            .ok_or_else(|| format_err!("Color '{}' is not #rrggbb or #rrggbbaa", hex))?;
+        let mut channels = [255; 4];
+        for (i, channel) in channels.iter_mut().take(digits.len() / 2).enumerate() {
+            *channel = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)
+                .with_context(|| format!("Color '{}' has invalid hex digits", hex))?;
+        }
+        Ok(
+            Color::rgb8(channels[0], channels[1], channels[2])
+                .with_alpha(channels[3] as f32 / 255.),
+        )
+    }
+
+    pub fn with_alpha(self, a: f32) -> Color {
+        Color { a, ..self }
+    }
+
+    /// This color at `t` = 0 to `other` at `t` = 1.
+    pub fn lerp(self, other: Color, t: f32) -> Color {
+        Color::rgba(
+            self.r + (other.r - self.r) * t,
+            self.g + (other.g - self.g) * t,
+            self.b + (other.b - self.b) * t,
+            self.a + (other.a - self.a) * t,
+        )
+    }
+
+    /// The color channels multiplied by alpha, which the game's blend modes expect.
+    pub fn premultiplied(self) -> Color {
+        Color::rgba(self.r * self.a, self.g * self.a, self.b * self.a, self.a)
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+
+    /// The color channels rounded to bytes, dropping alpha.
+    pub fn to_rgb8(self) -> (u8, u8, u8) {
+        let byte = |c: f32| (c.max(0.).min(1.) * 255.).round() as u8;
+        (byte(self.r), byte(self.g), byte(self.b))
+    }
+}
+
+impl From<Color> for [f32; 4] {
+    fn from(color: Color) -> [f32; 4] {
+        color.to_array()
+    }
+}
+
+/// Multiplies each channel, for tints.
+impl Mul for Color {
+    type Output = Color;
+
+    fn mul(self, other: Color) -> Color {
+        Color::rgba(
+            self.r * other.r,
+            self.g * other.g,
+            self.b * other.b,
+            self.a * other.a,
+        )
+    }
+}
+
+/// Entries in a `PaletteSwap`, and the length of the palette program's `u_palette`.
+pub const PALETTE_SIZE: usize = 4;
+
+/// Colors for the palette program, which replaces each texel with the entry its red channel
+/// picks, from the first at black to the last at full red. The texel's alpha is kept, and the
+/// vertex color and flash still apply on top.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaletteSwap {
+    colors: [Color; PALETTE_SIZE],
+}
+
+impl PaletteSwap {
+    pub fn new(colors: [Color; PALETTE_SIZE]) -> PaletteSwap {
+        PaletteSwap { colors }
+    }
+
+    /// Sets `u_palette` on a program created with the palette variant of the sprite shader.
+    pub fn apply(&self, program: &mut gl::Program) -> Result<(), gl::GLError> {
+        let mut elements = [0.; PALETTE_SIZE * 4];
+        for (i, color) in self.colors.iter().enumerate() {
+            elements[i * 4..i * 4 + 4].copy_from_slice(&color.to_array());
+        }
+        program.set_uniform_by_name("u_palette", gl::Uniform::Float4Array(&elements))
+    }
+}
+
+/// Quads as 4 vertices each plus indices for their two triangles, for drawing with
+/// `Program::render_indexed`.
+#[derive(Default)]
+pub struct Mesh {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u16>,
+}
+
+impl Mesh {
+    pub fn with_quad_capacity(quads: usize) -> Mesh {
+        Mesh {
+            vertices: Vec::with_capacity(quads * 4),
+            indices: Vec::with_capacity(quads * 6),
+        }
+    }
+
+    /// Corners are bottom left, bottom right, top left, top right.
+    pub fn push_quad(&mut self, corners: [Vertex; 4]) {
+        let first = self.vertices.len();
+        assert!(
+            first + 4 <= u16::max_value() as usize + 1,
+            "too many vertices for 16 bit indices"
+        );
+        let first = first as u16;
+        self.vertices.extend_from_slice(&corners);
+        self.indices.extend_from_slice(&[
+            first,
+            first + 1,
+            first + 2,
+            first + 1,
+            first + 3,
+            first + 2,
+        ]);
+    }
+
+    pub unsafe fn write(
+        &self,
+        vertex_buffer: &mut gl::VertexBuffer,
+        index_buffer: &mut gl::IndexBuffer,
+    ) {
+        vertex_buffer.write(&self.vertices);
+        index_buffer.write(&self.indices);
+    }
+}
+
+/// Something `render_sprite` and `render_quad` can add quads to.
+pub trait QuadSink {
+    /// Corners are bottom left, bottom right, top left, top right.
+    fn push_corners(&mut self, corners: [Vertex; 4]);
+}
+
+impl QuadSink for Mesh {
+    fn push_corners(&mut self, corners: [Vertex; 4]) {
+        self.push_quad(corners);
+    }
+}
+
+impl QuadSink for Vec<Vertex> {
+    fn push_corners(&mut self, corners: [Vertex; 4]) {
+        self.extend_from_slice(&corners);
+    }
+}
+
+/// Quads pushed with a sort key, written out lowest key first. Pushes with the same key keep the
+/// order they were pushed in.
+#[derive(Default)]
+pub struct RenderQueue {
+    vertices: Vec<Vertex>,
+    // Sort key and the range of `vertices` pushed with it
+    items: Vec<(f32, Range<usize>)>,
+}
+
+impl RenderQueue {
+    pub fn new() -> RenderQueue {
+        RenderQueue::default()
+    }
+
+    /// Queues whatever `render` adds to the vertices it is given, which should be whole quads.
+    pub fn push<F: FnOnce(&mut Vec<Vertex>)>(&mut self, sort_key: f32, render: F) {
+        let start = self.vertices.len();
+        render(&mut self.vertices);
+        debug_assert!((self.vertices.len() - start) % 4 == 0);
+        self.items.push((sort_key, start..self.vertices.len()));
+    }
+
+    /// Writes everything queued into `out` in key order and empties the queue.
+    pub fn sort_and_flush<S: QuadSink>(&mut self, out: &mut S) {
+        self.sort_and_flush_through(std::f32::INFINITY, out);
+    }
+
+    /// Writes what is queued with keys up to and including `max_key` into `out` in key order,
+    /// keeping the rest queued. For when some layers have to be drawn with another program.
+    pub fn sort_and_flush_through<S: QuadSink>(&mut self, max_key: f32, out: &mut S) {
+        self.items
+            .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        let count = self
+            .items
+            .iter()
+            .take_while(|(key, _)| *key <= max_key)
+            .count();
+        for (_, range) in self.items.drain(..count) {
+            for corners in self.vertices[range].chunks_exact(4) {
+                out.push_corners([corners[0], corners[1], corners[2], corners[3]]);
+            }
+        }
+        if self.items.is_empty() {
+            self.vertices.clear();
+        }
+    }
+}
+
+// As many quads as 16 bit indices can address
+const MAX_BATCH_QUADS: usize = (u16::max_value() as usize + 1) / 4;
+
+/// Collects quads and draws them with one indexed draw per `flush`, or one per 16384 quads. Every
+/// batch uses the same indices, so they are kept in one index buffer that is only extended when a
+/// batch has more quads than any before it.
+pub struct QuadBatcher {
+    vertices: Vec<Vertex>,
+    vertex_buffer: gl::VertexBuffer,
+    index_buffer: gl::IndexBuffer,
+    // Quads the index buffer has indices for
+    indexed_quads: usize,
+}
+
+impl QuadBatcher {
+    pub unsafe fn new(
+        gl_context: &mut gl::Context,
+        usage: gl::BufferUsage,
+    ) -> Result<QuadBatcher, gl::GLError> {
+        Ok(QuadBatcher {
+            vertices: Vec::new(),
+            vertex_buffer: gl_context.create_vertex_buffer(usage)?,
+            index_buffer: gl_context.create_index_buffer(gl::BufferUsage::Static)?,
+            indexed_quads: 0,
+        })
+    }
+
+    /// Names the batcher's buffers in graphics debuggers, see `gl::Context::label_buffer`.
+    pub unsafe fn label(&self, gl_context: &gl::Context, label: &str) {
+        gl_context.label_buffer(&self.vertex_buffer, &format!("{} vertices", label));
+        gl_context.label_index_buffer(&self.index_buffer, &format!("{} indices", label));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+
+    /// Draws the quads pushed since the last flush into `pass` and starts a new batch.
+    pub unsafe fn flush(
+        &mut self,
+        program: &gl::Program,
+        pass: &mut gl::RenderPass,
+    ) -> Result<(), gl::GLError> {
+        let mut result = Ok(());
+        for chunk in self.vertices.chunks(MAX_BATCH_QUADS * 4) {
+            let quads = chunk.len() / 4;
+            if quads > self.indexed_quads {
+                self.indexed_quads = quads.max(self.indexed_quads * 2).min(MAX_BATCH_QUADS);
+                let indices: Vec<u16> = (0..self.indexed_quads as u32)
+                    .flat_map(|quad| {
+                        let first = (quad * 4) as u16;
+                        vec![first, first + 1, first + 2, first + 2, first + 1, first + 3]
+                    })
+                    .collect();
+                self.index_buffer.write(&indices);
+            }
+            self.vertex_buffer.write(chunk);
+            result = pass.draw_indexed_range(
+                program,
+                &self.vertex_buffer,
+                &self.index_buffer,
+                0,
+                quads * 6,
+            );
+            if result.is_err() {
+                break;
+            }
+        }
+        self.vertices.clear();
+        result
+    }
+}
+
+impl QuadSink for QuadBatcher {
+    fn push_corners(&mut self, corners: [Vertex; 4]) {
+        self.vertices.extend_from_slice(&corners);
+    }
+}
+
+/// Sprites and quads from one texture, drawn together by `flush`. The vertices are kept between
+/// frames, so after the first few frames batching doesn't allocate.
+pub struct SpriteBatch {
+    quads: QuadBatcher,
+    // Sprites drawn while a palette swap was set, drawn after the rest with the palette program
+    palette_quads: QuadBatcher,
+    texture: Rc<gl::Texture>,
+    palette_swap: Option<PaletteSwap>,
+    // The palette the sprites in palette_quads were drawn with
+    pending_palette: Option<PaletteSwap>,
+}
+
+impl SpriteBatch {
+    pub unsafe fn new(
+        gl_context: &mut gl::Context,
+        texture: Rc<gl::Texture>,
+    ) -> Result<SpriteBatch, gl::GLError> {
+        Ok(SpriteBatch {
+            quads: QuadBatcher::new(gl_context, gl::BufferUsage::Stream)?,
+            palette_quads: QuadBatcher::new(gl_context, gl::BufferUsage::Stream)?,
+            texture,
+            palette_swap: None,
+            pending_palette: None,
+        })
+    }
+
+    /// Recolors the sprites drawn from now on with `palette`, see `PaletteSwap`. They are kept
+    /// apart from everything else and drawn after it by `flush_with_palette`, and all of them
+    /// share one palette per flush. Other quads are left as they are.
+    pub fn set_palette_swap(&mut self, palette: Option<PaletteSwap>) {
+        self.palette_swap = palette;
+    }
+
+    pub unsafe fn label(&self, gl_context: &gl::Context, label: &str) {
+        self.quads.label(gl_context, label);
+        self.palette_quads
+            .label(gl_context, &format!("{} palette swapped", label));
+    }
+
+    /// Drops anything drawn since the last flush.
+    pub fn begin(&mut self) {
+        self.quads.vertices.clear();
+        self.palette_quads.vertices.clear();
+        self.pending_palette = None;
+    }
+
+    // Where sprites go, depending on the palette swap
+    fn sprite_quads(&mut self) -> &mut QuadBatcher {
+        match self.palette_swap {
+            Some(palette) => {
+                debug_assert!(
+                    self.pending_palette.unwrap_or(palette) == palette,
+                    "sprites with different palettes in one flush"
+                );
+                self.pending_palette = Some(palette);
+                &mut self.palette_quads
+            }
+            None => &mut self.quads,
+        }
+    }
+
+    /// See `render_sprite_with`.
+    pub fn draw_sprite_with(
+        &mut self,
+        sprite: &Sprite,
+        frame: usize,
+        position: SpritePosition,
+        transform: &Transform2D<f32>,
+        tint: Color,
+    ) {
+        render_sprite_with(
+            sprite,
+            frame,
+            position,
+            transform,
+            tint,
+            self.sprite_quads(),
+        );
+    }
+
+    pub fn draw_quad<U>(&mut self, rect: Box2D<f32, U>, tex_coords: TextureRect, color: Color) {
+        render_quad(rect, tex_coords, color, &mut self.quads);
+    }
+
+    /// Draws everything since the last flush with `program`, which gets the batch's texture as
+    /// its `u_texture`. Use `flush_with_palette` if any sprites were palette swapped.
+    pub unsafe fn flush(
+        &mut self,
+        program: &mut gl::Program,
+        pass: &mut gl::RenderPass,
+    ) -> Result<(), gl::GLError> {
+        debug_assert!(
+            self.palette_quads.is_empty(),
+            "palette swapped sprites need flush_with_palette"
+        );
+        self.palette_quads.vertices.clear();
+        program.set_uniform_by_name("u_texture", gl::Uniform::Texture(&self.texture))?;
+        self.quads.flush(program, pass)
+    }
+
+    /// `flush`, then the palette swapped sprites with `palette_program`, which gets the same
+    /// uniforms as `program` should have been given plus the palette in `u_palette`.
+    pub unsafe fn flush_with_palette(
+        &mut self,
+        program: &mut gl::Program,
+        palette_program: &mut gl::Program,
+        pass: &mut gl::RenderPass,
+    ) -> Result<(), gl::GLError> {
+        program.set_uniform_by_name("u_texture", gl::Uniform::Texture(&self.texture))?;
+        self.quads.flush(program, pass)?;
+        if let Some(palette) = self.pending_palette.take() {
+            palette.apply(palette_program)?;
+            palette_program
+                .set_uniform_by_name("u_texture", gl::Uniform::Texture(&self.texture))?;
+            self.palette_quads.flush(palette_program, pass)?;
+        }
+        Ok(())
+    }
+}
+
+impl QuadSink for SpriteBatch {
+    fn push_corners(&mut self, corners: [Vertex; 4]) {
+        self.quads.push_corners(corners);
+    }
+}
+
+/// Which point of the screen `UiLayer::anchored` measures from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
+    Center,
+}
+
+/// Sprites and panels drawn in UI pixels over the whole screen, see `spaces::UiSpace`.
+pub struct UiLayer {
+    batch: SpriteBatch,
+}
+
+impl UiLayer {
+    pub unsafe fn new(
+        gl_context: &mut gl::Context,
+        texture: Rc<gl::Texture>,
+    ) -> Result<UiLayer, gl::GLError> {
+        let batch = SpriteBatch::new(gl_context, texture)?;
+        batch.label(gl_context, "ui");
+        Ok(UiLayer { batch })
+    }
+
+    /// A rect of `size` placed `margin` pixels in from the screen's edges at `anchor`, or
+    /// centered and moved right and down by `margin` for `Anchor::Center`.
+    pub fn anchored(
+        anchor: Anchor,
+        margin: euclid::Vector2D<f32, UiSpace>,
+        size: UiSize,
+    ) -> UiRect {
+        let screen: UiSize = size2(SCREEN_SIZE.0 as f32, SCREEN_SIZE.1 as f32);
+        let origin = match anchor {
+            Anchor::TopLeft => point2(margin.x, screen.height - margin.y - size.height),
+            Anchor::Center => point2(
+                (screen.width - size.width) / 2. + margin.x,
+                (screen.height - size.height) / 2. - margin.y,
+            ),
+        };
+        UiRect::new(origin, size)
+    }
+
+    /// Whether the mouse at `mouse_pos`, as reported by the platform, is over `rect`.
+    pub fn hit(rect: UiRect, mouse_pos: ScreenPoint) -> bool {
+        rect.contains(spaces::screen_to_ui(mouse_pos))
+    }
+
+    /// Drops anything drawn since the last flush.
+    pub fn begin(&mut self) {
+        self.batch.begin();
+    }
+
+    /// See `SpriteBatch::set_palette_swap`.
+    pub fn set_palette_swap(&mut self, palette: Option<PaletteSwap>) {
+        self.batch.set_palette_swap(palette);
+    }
+
+    /// See `render_sprite_with`.
+    pub fn draw_sprite_with(
+        &mut self,
+        sprite: &Sprite,
+        frame: usize,
+        position: UiPoint,
+        transform: &Transform2D<f32>,
+        tint: Color,
+    ) {
+        self.batch
+            .draw_sprite_with(sprite, frame, SpritePosition::Ui(position), transform, tint);
+    }
+
+    /// Text with the top left of its first line at `top_left`, see `draw_text_layout`.
+    pub fn draw_text(
+        &mut self,
+        layout: &TextLayout,
+        font: &Font,
+        text: &str,
+        top_left: UiPoint,
+        color: Color,
+    ) {
+        draw_text_layout(layout, font, text, top_left, color, &mut self.batch);
+    }
+
+    /// Stretches `tex_coords` over `rect`, keeping its corners `border` texels wide at `scale`
+    /// UI pixels per texel so that panels of any size get the same edges.
+    pub fn draw_nine_slice(
+        &mut self,
+        rect: UiRect,
+        tex_coords: TextureRect,
+        border: u32,
+        scale: f32,
+        color: Color,
+    ) {
+        let edge = (border as f32 * scale)
+            .min(rect.width() / 2.)
+            .min(rect.height() / 2.);
+        let xs = [
+            rect.min_x(),
+            rect.min_x() + edge,
+            rect.max_x() - edge,
+            rect.max_x(),
+        ];
+        let ys = [
+            rect.min_y(),
+            rect.min_y() + edge,
+            rect.max_y() - edge,
+            rect.max_y(),
+        ];
+        let (width, height) = (tex_coords.width(), tex_coords.height());
+        let texel_xs = [0, border, width - border, width];
+        // texel rows go down from the top while UI y goes up
+        let texel_ys = [height, height - border, border, 0];
+        for row in 0..3 {
+            for column in 0..3 {
+                let quad: Box2D<f32, UiSpace> = Box2D::new(
+                    point2(xs[column], ys[row]),
+                    point2(xs[column + 1], ys[row + 1]),
+                );
+                let slice = tex_coords.sub_rect(
+                    texel_xs[column],
+                    texel_ys[row + 1],
+                    texel_xs[column + 1] - texel_xs[column],
+                    texel_ys[row] - texel_ys[row + 1],
+                );
+                self.batch.draw_quad(quad, slice, color);
+            }
+        }
+    }
+
+    /// Draws everything since the last flush over the whole of `pass`'s target, the palette
+    /// swapped sprites with `palette_program`. Sets both programs' `u_transform` and `u_texture`.
+    pub unsafe fn flush(
+        &mut self,
+        program: &mut gl::Program,
+        palette_program: &mut gl::Program,
+        pass: &mut gl::RenderPass,
+    ) -> Result<(), gl::GLError> {
+        let transform = gl::Uniform::Mat3(spaces::to_mat3(&spaces::ui_to_clip()));
+        program.set_uniform_by_name("u_transform", transform)?;
+        palette_program.set_uniform_by_name("u_transform", transform)?;
+        self.batch
+            .flush_with_palette(program, palette_program, pass)
+    }
+}
+
+/// The post shader `PostProcess` uses unless given another, which changes nothing.
+pub const PASSTHROUGH_POST_SHADER: &str = include_str!("shaders/post.frag");
+
+/// Renders the frame offscreen and draws it to the screen through a fragment shader, for
+/// effects over the whole screen. Everything drawn to `RenderTarget::Screen` between `begin` and
+/// `finish` goes through it.
+pub struct PostProcess {
+    target: gl::RenderTexture,
+    quad: gl::VertexBuffer,
+    program: gl::Program,
+    // Whether the shader declares u_time, which it may leave out
+    uses_time: bool,
+}
+
+impl PostProcess {
+    /// `frag_source` gets `v_uv` and the frame as `u_texture`, see `PASSTHROUGH_POST_SHADER`.
+    /// The offscreen target starts at `screen_size` and follows the screen from then on.
+    pub unsafe fn new(
+        gl_context: &mut gl::Context,
+        screen_size: Size2D<u32, ScreenSpace>,
+        frag_source: &str,
+    ) -> Result<PostProcess, gl::GLError> {
+        // stored as sRGB where GL converts writes to it, like the screen itself
+        let format = if gl_context.srgb() && gl_context.srgb_framebuffer() {
+            gl::TextureFormat::SRGBA
+        } else {
+            gl::TextureFormat::RGBA8
+        };
+        let mut target =
+            gl_context.create_render_texture(format, screen_size.width, screen_size.height)?;
+        target.texture_mut().set_filter(gl::TextureFilter::NEAREST);
+        gl_context.label_texture(target.texture(), "post process target");
+
+        let mut quad = gl_context.create_vertex_buffer(gl::BufferUsage::Static)?;
+        quad.write(&[
+            [-1.0f32, -1.0, 0.0, 0.0],
+            [1.0, -1.0, 1.0, 0.0],
+            [-1.0, 1.0, 0.0, 1.0],
+            [1.0, -1.0, 1.0, 0.0],
+            [1.0, 1.0, 1.0, 1.0],
+            [-1.0, 1.0, 0.0, 1.0],
+        ]);
+
+        let vertex_shader =
+            gl_context.create_shader(gl::ShaderType::Vertex, include_str!("shaders/post.vert"))?;
+        let fragment_shader = gl_context.create_shader(gl::ShaderType::Fragment, frag_source)?;
+        let uses_time = declares_uniform(frag_source, "u_time");
+        let mut uniforms = vec![gl::UniformEntry {
+            name: "u_texture",
+            ty: gl::UniformType::Texture,
+            array_len: 0,
+            default: Some(gl::Uniform::Texture(target.texture())),
+        }];
+        if uses_time {
+            uniforms.push(gl::UniformEntry {
+                name: "u_time",
+                ty: gl::UniformType::Float,
+                array_len: 0,
+                default: Some(gl::Uniform::Float(0.)),
+            });
+        }
+        let mut program = gl_context.create_program(&gl::ProgramDescriptor {
+            vertex_shader: &vertex_shader,
+            fragment_shader: &fragment_shader,
+            uniforms: &uniforms,
+            vertex_format: gl::VertexFormat {
+                stride: 4 * 4,
+                instance_stride: 0,
+                attributes: &[
+                    gl::VertexAttribute {
+                        name: "a_pos",
+                        ty: gl::VertexAttributeType::Float,
+                        size: 2,
+                        offset: 0,
+                        normalized: false,
+                        integer: false,
+                        divisor: 0,
+                    },
+                    gl::VertexAttribute {
+                        name: "a_uv",
+                        ty: gl::VertexAttributeType::Float,
+                        size: 2,
+                        offset: 2 * 4,
+                        normalized: false,
+                        integer: false,
+                        divisor: 0,
+                    },
+                ],
+            },
+        })?;
+        program.set_blend_mode(gl::BlendMode::None);
+        gl_context.label_program(&program, "post process program");
+
+        Ok(PostProcess {
+            target,
+            quad,
+            program,
+            uses_time,
+        })
+    }
+
+    /// Seconds passed to the shader as `u_time`, if it has one.
+    pub fn set_time(&mut self, time: f32) -> Result<(), gl::GLError> {
+        if self.uses_time {
+            self.program
+                .set_uniform_by_name("u_time", gl::Uniform::Float(time))?;
+        }
+        Ok(())
+    }
+
+    /// Sends the screen draws that follow to the offscreen target, resized to what the screen
+    /// draws to first if that changed. Call after setting the render scale for the frame.
+    pub unsafe fn begin(&mut self, context: &mut gl::Context) -> Result<(), gl::GLError> {
+        let size = context.screen_target_size();
+        if self.target.size() != size {
+            self.target.resize(size.0, size.1)?;
+        }
+        context.redirect_screen(Some(&self.target));
+        Ok(())
+    }
+
+    /// Draws the offscreen target to the screen through the post shader. Always fills, like
+    /// `gl::Context::present`.
+    pub unsafe fn finish(&mut self, context: &mut gl::Context) -> Result<(), gl::GLError> {
+        context.redirect_screen(None);
+        let polygon_mode = context.polygon_mode();
+        context.set_polygon_mode(gl::PolygonMode::Fill);
+        let result = context
+            .pass(gl::RenderTarget::Screen)
+            .draw(&self.program, &self.quad);
+        context.set_polygon_mode(polygon_mode);
+        result
+    }
+}
+
+// Whether GLSL `source` declares the uniform `name`, not counting line comments like the one in
+// the passthrough shader that mentions u_time
+fn declares_uniform(source: &str, name: &str) -> bool {
+    source.lines().any(|line| {
+        let code = line.split("//").next().unwrap().trim_start();
+        code.starts_with("uniform ")
+            && code
+                .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .any(|word| word == name)
+    })
+}
+
+/// What `ParticleSystem::emit` spawns. Each particle picks its values uniformly from the ranges.
+#[derive(Clone, Copy, Debug)]
+pub struct EmitterParams {
+    pub position: Point2D<f32>,
+    pub count: usize,
+    /// Lowest and highest velocity on each axis
+    pub velocity_range: (Vector2D<f32>, Vector2D<f32>),
+    pub lifetime_range: (f32, f32),
+    /// Width and height of the particles' quads
+    pub size_range: (f32, f32),
+    /// Color at the start and the end of a particle's life, not premultiplied
+    pub color_over_life: (Color, Color),
+    pub gravity: Vector2D<f32>,
+    /// Stretched over every particle. A white pixel makes solid particles.
+    pub tex_coords: TextureRect,
+}
+
+struct Particle {
+    position: Point2D<f32>,
+    velocity: Vector2D<f32>,
+    gravity: Vector2D<f32>,
+    age: f32,
+    lifetime: f32,
+    size: f32,
+    color_over_life: (Color, Color),
+    tex_coords: TextureRect,
+}
+
+/// Particles in a space of the caller's choosing. Holds at most `capacity` particles, emitting
+/// more replaces the oldest ones.
+pub struct ParticleSystem {
+    particles: Vec<Particle>,
+    capacity: usize,
+    rng: SmallRng,
+}
+
+impl ParticleSystem {
+    pub fn new(capacity: usize, seed: u64) -> ParticleSystem {
+        ParticleSystem {
+            particles: Vec::with_capacity(capacity),
+            capacity,
+            rng: SmallRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn emit(&mut self, params: EmitterParams) {
+        let rng = &mut self.rng;
+        let mut range = |(min, max): (f32, f32)| {
+            if min < max {
+                rng.gen_range(min, max)
+            } else {
+                min
+            }
+        };
+        for _ in 0..params.count {
+            let (min_velocity, max_velocity) = params.velocity_range;
+            let particle = Particle {
+                position: params.position,
+                velocity: Vector2D::new(
+                    range((min_velocity.x, max_velocity.x)),
+                    range((min_velocity.y, max_velocity.y)),
+                ),
+                gravity: params.gravity,
+                age: 0.,
+                lifetime: range(params.lifetime_range),
+                size: range(params.size_range),
+                color_over_life: params.color_over_life,
+                tex_coords: params.tex_coords,
+            };
+            if self.particles.len() < self.capacity {
+                self.particles.push(particle);
+            } else if let Some(oldest) = self
+                .particles
+                .iter_mut()
+                .max_by(|a, b| a.age.partial_cmp(&b.age).unwrap())
+            {
+                *oldest = particle;
+            }
+        }
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        for i in (0..self.particles.len()).rev() {
+            let particle = &mut self.particles[i];
+            particle.velocity += particle.gravity * dt;
+            particle.position += particle.velocity * dt;
+            particle.age += dt;
+            if particle.age >= particle.lifetime {
+                self.particles.swap_remove(i);
+            }
+        }
+    }
+
+    pub fn render<S: QuadSink>(&self, out: &mut S) {
+        for particle in &self.particles {
+            let t = particle.age / particle.lifetime;
+            let (start, end) = particle.color_over_life;
+            let color = start.lerp(end, t).premultiplied();
+            let half_size = Vector2D::new(particle.size, particle.size) / 2.;
+            let rect: Box2D<f32, euclid::UnknownUnit> =
+                Box2D::new(particle.position - half_size, particle.position + half_size);
+            render_quad(rect, particle.tex_coords, color, out);
+        }
+    }
+}
+
+/// One character of a `Font`, in font pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct Glyph {
+    /// None for characters that only move the pen, like spaces
+    pub tex_coords: Option<TextureRect>,
+    /// From the pen to the glyph image's top left, x right and y down
+    pub offset: Vector2D<f32>,
+    pub advance: f32,
+}
+
+/// A bitmap font with glyph images from the atlas. Characters without a glyph advance the pen by
+/// `default_advance` and draw nothing.
+#[derive(Clone, Debug)]
+pub struct Font {
+    glyphs: HashMap<char, Glyph>,
+    line_height: f32,
+    default_advance: f32,
+}
+
+impl Font {
+    pub fn new(line_height: f32, default_advance: f32) -> Font {
+        Font {
+            glyphs: HashMap::new(),
+            line_height,
+            default_advance,
+        }
+    }
+
+    /// A font from a sheet of same sized glyphs for `chars`, left to right and then top to
+    /// bottom, each advancing by its width.
+    pub fn monospace(sheet: TextureRect, chars: &str, glyph_size: Size2D<u32, AtlasSpace>) -> Font {
+        let mut font = Font::new(glyph_size.height as f32, glyph_size.width as f32);
+        let columns = (sheet.width() / glyph_size.width).max(1);
+        for (i, c) in chars.chars().enumerate() {
+            let (column, row) = (i as u32 % columns, i as u32 / columns);
+            font.add_glyph(
+                c,
+                Glyph {
+                    tex_coords: Some(sheet.sub_rect(
+                        column * glyph_size.width,
+                        row * glyph_size.height,
+                        glyph_size.width,
+                        glyph_size.height,
+                    )),
+                    offset: Vector2D::zero(),
+                    advance: glyph_size.width as f32,
+                },
+            );
+        }
+        font
+    }
+
+    pub fn add_glyph(&mut self, c: char, glyph: Glyph) {
+        self.glyphs.insert(c, glyph);
+    }
+
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs.get(&c)
+    }
+
+    pub fn line_height(&self) -> f32 {
+        self.line_height
+    }
+
+    pub fn advance(&self, c: char) -> f32 {
+        self.glyph(c)
+            .map_or(self.default_advance, |glyph| glyph.advance)
+    }
+
+    /// Width of `text` on one line, in font pixels.
+    pub fn text_width(&self, text: &str) -> f32 {
+        text.chars().map(|c| self.advance(c)).sum()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextAlign {
+    Left,
+    Center,
+}
+
+/// How `draw_text_layout` places text. Lines break at newlines, and at spaces to stay within
+/// `max_width` if there is one, with words too long for a line of their own split wherever
+/// they reach it. Spaces at the end of a line don't count towards its width.
+#[derive(Clone, Copy, Debug)]
+pub struct TextLayout {
+    pub align: TextAlign,
+    /// In the units text is drawn in, after `scale`
+    pub max_width: Option<f32>,
+    /// Distance between lines as a multiple of the font's line height
+    pub line_spacing: f32,
+    /// Units per font pixel
+    pub scale: f32,
+}
+
+impl Default for TextLayout {
+    fn default() -> TextLayout {
+        TextLayout {
+            align: TextAlign::Left,
+            max_width: None,
+            line_spacing: 1.,
+            scale: 1.,
+        }
+    }
+}
+
+impl TextLayout {
+    /// Width of the widest line and height from the top of the first line to the bottom of the
+    /// last.
+    pub fn measure(&self, font: &Font, text: &str) -> Size2D<f32, euclid::UnknownUnit> {
+        let lines = self.lines(font, text);
+        let width = lines
+            .iter()
+            .map(|line| font.text_width(line.trim_end_matches(' ')))
+            .fold(0., f32::max);
+        let height =
+            font.line_height() + (lines.len() - 1) as f32 * font.line_height() * self.line_spacing;
+        size2(width, height) * self.scale
+    }
+
+    /// `text` split into the lines it is drawn as, with the spaces lines were broken at left out.
+    pub fn lines<'a>(&self, font: &Font, text: &'a str) -> Vec<&'a str> {
+        let mut lines = Vec::new();
+        for paragraph in text.split('\n') {
+            let max_width = match self.max_width {
+                Some(max_width) => max_width / self.scale,
+                None => {
+                    lines.push(paragraph);
+                    continue;
+                }
+            };
+            let fits = |line: &str| font.text_width(line.trim_end_matches(' ')) <= max_width;
+
+            let mut line_start = 0;
+            // End of the last word that fit on the current line
+            let mut fit_end = None;
+            let mut word_start = 0;
+            for word in paragraph.split(' ') {
+                let word_end = word_start + word.len();
+                if !fits(&paragraph[line_start..word_end]) {
+                    if let Some(end) = fit_end {
+                        lines.push(&paragraph[line_start..end]);
+                        line_start = word_start;
+                    }
+                    while !fits(&paragraph[line_start..word_end]) {
+                        let end = hard_break(font, &paragraph[line_start..word_end], max_width);
+                        // a last character wider than a line gets one of its own like the rest
+                        if line_start + end == word_end {
+                            break;
+                        }
+                        lines.push(&paragraph[line_start..line_start + end]);
+                        line_start += end;
+                    }
+                }
+                fit_end = Some(word_end);
+                word_start = word_end + 1;
+            }
+            lines.push(&paragraph[line_start..]);
+        }
+        lines
+    }
+}
+
+// Bytes of `word` that fit in `max_width`, at least one character so that breaking always makes
+// progress
+fn hard_break(font: &Font, word: &str, max_width: f32) -> usize {
+    let mut width = 0.;
+    for (i, c) in word.char_indices() {
+        width += font.advance(c);
+        if width > max_width {
+            return if i == 0 { c.len_utf8() } else { i };
+        }
+    }
+    word.len()
+}
+
+/// Draws `text` laid out by `layout` with the top left of its first line at `top_left`, y up.
+/// Centered and right aligned lines are aligned within `max_width`, or within the widest line if
+/// there is no maximum.
+pub fn draw_text_layout<U, S: QuadSink>(
+    layout: &TextLayout,
+    font: &Font,
+    text: &str,
+    top_left: euclid::Point2D<f32, U>,
+    color: Color,
+    out: &mut S,
+) {
+    let lines = layout.lines(font, text);
+    let line_widths: Vec<f32> = lines
+        .iter()
+        .map(|line| font.text_width(line.trim_end_matches(' ')) * layout.scale)
+        .collect();
+    let block_width = layout
+        .max_width
+        .unwrap_or_else(|| line_widths.iter().cloned().fold(0., f32::max));
+    let line_advance = font.line_height() * layout.line_spacing * layout.scale;
+
+    for (i, (line, line_width)) in lines.iter().zip(&line_widths).enumerate() {
+        let mut pen_x = top_left.x
+            + match layout.align {
+                TextAlign::Left => 0.,
+                TextAlign::Center => (block_width - line_width) / 2.,
+            };
+        let line_top = top_left.y - i as f32 * line_advance;
+        for c in line.chars() {
+            if let Some(Glyph {
+                tex_coords: Some(tex_coords),
+                offset,
+                ..
+            }) = font.glyph(c)
+            {
+                let min_x = pen_x + offset.x * layout.scale;
+                let max_y = line_top - offset.y * layout.scale;
+                let rect: Box2D<f32, U> = Box2D::new(
+                    point2(min_x, max_y - tex_coords.height() as f32 * layout.scale),
+                    point2(min_x + tex_coords.width() as f32 * layout.scale, max_y),
+                );
+                render_quad(rect, *tex_coords, color, out);
+            }
+            pen_x += font.advance(c) * layout.scale;
+        }
+    }
+}
+
+/// Where the pieces of an autotiling sheet are. The sheet is a row of five tiles: outer corners,
+/// horizontal edges, vertical edges, inner corners and solid. Each tile is drawn as four quarters,
+/// split `split` texels from its top left, and each quarter picks its piece from its neighbours.
+#[derive(Clone, Debug)]
+pub struct AutotileSheet {
+    // Quarters top left, top right, bottom left, bottom right, each with the five pieces in
+    // sheet order
+    pieces: [[TextureRect; 5]; 4],
+    // Where the quarters meet as a fraction of the tile from its bottom left
+    split: Vector2D<f32>,
+}
+
+const OUTER_CORNER: usize = 0;
+const HORIZONTAL: usize = 1;
+const VERTICAL: usize = 2;
+const INNER_CORNER: usize = 3;
+const SOLID: usize = 4;
+
+impl AutotileSheet {
+    pub fn new(
+        sheet: TextureRect,
+        tile_size: Size2D<u32, AtlasSpace>,
+        split: euclid::Vector2D<u32, AtlasSpace>,
+    ) -> AutotileSheet {
+        let quarters = [
+            [0, 0, split.x, split.y],
+            [split.x, 0, tile_size.width, split.y],
+            [0, split.y, split.x, tile_size.height],
+            [split.x, split.y, tile_size.width, tile_size.height],
+        ];
+        let mut pieces = [[sheet; 5]; 4];
+        for (quarter, rect) in quarters.iter().enumerate() {
+            for (piece, tex_coords) in pieces[quarter].iter_mut().enumerate() {
+                *tex_coords = sheet.sub_rect(
+                    piece as u32 * tile_size.width + rect[0],
+                    rect[1],
+                    rect[2] - rect[0],
+                    rect[3] - rect[1],
+                );
+            }
+        }
+        AutotileSheet {
+            pieces,
+            split: Vector2D::new(
+                split.x as f32 / tile_size.width as f32,
+                (tile_size.height - split.y) as f32 / tile_size.height as f32,
+            ),
+        }
+    }
+}
+
+/// A grid of tiles drawn with an `AutotileSheet`, one unit per tile with the first tile at the
+/// bottom left. Tiles that `is_solid` picks are drawn and join up with their solid neighbours,
+/// the outside of the grid counts as solid. The quads are kept per tile and only rebuilt after
+/// the color or UV inset changes.
+pub struct Tilemap<T> {
+    size: Size2D<u32, euclid::UnknownUnit>,
+    tiles: Vec<T>,
+    sheet: AutotileSheet,
+    is_solid: Box<dyn Fn(&T) -> bool>,
+    color: Color,
+    uv_inset: f32,
+    cell_vertices: Vec<Vec<Vertex>>,
+    dirty: Vec<bool>,
+}
+
+impl<T> Tilemap<T> {
+    /// `tiles` are row by row from the bottom.
+    pub fn new<F: Fn(&T) -> bool + 'static>(
+        size: Size2D<u32, euclid::UnknownUnit>,
+        tiles: Vec<T>,
+        sheet: AutotileSheet,
+        is_solid: F,
+    ) -> Tilemap<T> {
+        let cells = size.area() as usize;
+        assert_eq!(tiles.len(), cells, "tilemap needs one tile per cell");
+        Tilemap {
+            size,
+            tiles,
+            sheet,
+            is_solid: Box::new(is_solid),
+            color: Color::WHITE,
+            uv_inset: 0.,
+            cell_vertices: vec![Vec::new(); cells],
+            dirty: vec![true; cells],
+        }
+    }
+
+    /// Multiplied into every tile's vertex colors.
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+        self.mark_all_dirty();
+    }
+
+    /// Texels to pull the pieces' UVs in by on each side, see `atlas_uv_rect`.
+    pub fn set_uv_inset(&mut self, inset: f32) {
+        self.uv_inset = inset;
+        self.mark_all_dirty();
+    }
+
+    /// Rebuilds the changed tiles and adds the quads of every solid tile to `out`.
+    pub fn render<S: QuadSink>(&mut self, out: &mut S) {
+        for index in 0..self.tiles.len() {
+            if self.dirty[index] {
+                self.rebuild_cell(index);
+                self.dirty[index] = false;
+            }
+            for corners in self.cell_vertices[index].chunks_exact(4) {
+                out.push_corners([corners[0], corners[1], corners[2], corners[3]]);
+            }
+        }
+    }
+
+    fn mark_all_dirty(&mut self) {
+        for dirty in &mut self.dirty {
+            *dirty = true;
+        }
+    }
+
+    fn solid_at(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 || x >= self.size.width as i32 || y >= self.size.height as i32 {
+            true
+        } else {
+            (self.is_solid)(&self.tiles[(y as u32 * self.size.width + x as u32) as usize])
+        }
+    }
+
+    fn rebuild_cell(&mut self, index: usize) {
+        let mut vertices = std::mem::take(&mut self.cell_vertices[index]);
+        vertices.clear();
+        if (self.is_solid)(&self.tiles[index]) {
+            let x = (index as u32 % self.size.width) as i32;
+            let y = (index as u32 / self.size.width) as i32;
+            let (tl, t, tr, l, r, bl, b, br) = (
+                self.solid_at(x - 1, y + 1),
+                self.solid_at(x, y + 1),
+                self.solid_at(x + 1, y + 1),
+                self.solid_at(x - 1, y),
+                self.solid_at(x + 1, y),
+                self.solid_at(x - 1, y - 1),
+                self.solid_at(x, y - 1),
+                self.solid_at(x + 1, y - 1),
+            );
+            // Each quarter looks at the neighbours on its sides and the one diagonally off its
+            // corner
+            let piece = |horizontal: bool, vertical: bool, diagonal: bool| {
+                if !diagonal && horizontal && vertical {
+                    INNER_CORNER
+                } else if !horizontal && !vertical {
+                    OUTER_CORNER
+                } else if horizontal && !vertical {
+                    HORIZONTAL
+                } else if !horizontal && vertical {
+                    VERTICAL
+                } else {
+                    SOLID
+                }
+            };
+
+            let min: Point2D<f32> = point2(x as f32, y as f32);
+            let max = min + Vector2D::new(1., 1.);
+            let mid = min + self.sheet.split;
+            let quarters = [
+                (
+                    Box2D::new(point2(min.x, mid.y), point2(mid.x, max.y)),
+                    piece(l, t, tl),
+                ),
+                (Box2D::new(mid, max), piece(r, t, tr)),
+                (Box2D::new(min, mid), piece(l, b, bl)),
+                (
+                    Box2D::new(point2(mid.x, min.y), point2(max.x, mid.y)),
+                    piece(r, b, br),
+                ),
+            ];
+            for (quarter, (rect, piece)) in quarters.iter().enumerate() {
+                render_quad_inset(
+                    *rect,
+                    self.sheet.pieces[quarter][*piece],
+                    self.color,
+                    self.uv_inset,
+                    &mut vertices,
+                );
+            }
+        }
+        self.cell_vertices[index] = vertices;
+    }
+}
+
+/// A view of the world in units `U`: `center` is in the middle of the viewport, which is
+/// `viewport` screen pixels in size with `zoom` pixels per world unit. World y goes up, screen y
+/// down.
+pub struct Camera2D<U> {
+    pub center: euclid::Point2D<f32, U>,
+    pub zoom: f32,
+    pub viewport: Size2D<f32, ScreenSpace>,
+}
+
+impl<U> Clone for Camera2D<U> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<U> Copy for Camera2D<U> {}
+
+impl<U> Camera2D<U> {
+    pub fn new(
+        center: euclid::Point2D<f32, U>,
+        zoom: f32,
+        viewport: Size2D<f32, ScreenSpace>,
+    ) -> Camera2D<U> {
+        Camera2D {
+            center,
+            zoom,
+            viewport,
+        }
+    }
+
+    pub fn view_transform(&self) -> euclid::Transform2D<f32, U, ClipSpace> {
+        euclid::Transform2D::translation(-self.center.x, -self.center.y).then_scale(
+            self.zoom * 2. / self.viewport.width,
+            self.zoom * 2. / self.viewport.height,
+        )
+    }
+
+    /// `view_transform` as a `u_transform` uniform.
+    pub fn view_matrix(&self) -> [[f32; 3]; 3] {
+        spaces::to_mat3(&self.view_transform())
+    }
+
+    /// Where a point in the viewport, with y down from its top left, is in the world.
+    pub fn screen_to_world(&self, p: ScreenPoint) -> euclid::Point2D<f32, U> {
+        point2(
+            (p.x - self.viewport.width / 2.) / self.zoom + self.center.x,
+            (self.viewport.height / 2. - p.y) / self.zoom + self.center.y,
+        )
+    }
+
+    /// Moves from this camera at `t` = 0 to `to` at 1. The visible area changes size linearly,
+    /// so the view's edges move at constant speeds while zooming.
+    pub fn lerp(&self, to: &Camera2D<U>, t: f32) -> Camera2D<U> {
+        let inverse_zoom = (1. / self.zoom) + (1. / to.zoom - 1. / self.zoom) * t;
+        Camera2D {
+            center: self.center.lerp(to.center, t),
+            zoom: 1. / inverse_zoom,
+            viewport: self.viewport.lerp(to.viewport, t),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Sprite {
     frames: Vec<TextureRect>,
-    frame_count: u32,
+    // Where each frame's bottom left corner goes in sprite pixels, for frames trimmed of their
+    // transparent edges
+    frame_offsets: Vec<Vector2D<f32>>,
     origin: Point2D<f32>,
     transform: Transform2D<f32>,
+    uv_inset: f32,
+    color: Color,
+    // rgb is the color to flash toward and alpha how far
+    flash: Color,
+    flip_x: bool,
 }
 
 impl Sprite {
     pub fn new(image: TextureRect, frame_count: u32, origin: Point2D<f32>) -> Self {
-        let width = image[2] - image[0];
-        let frame_width = width / frame_count;
+        let frame_width = image.width() / frame_count;
         let frames = (0..frame_count)
-            .map(|i| {
-                [
-                    image[0] + i * frame_width,
-                    image[1],
-                    image[0] + (i + 1) * frame_width,
-                    image[3],
-                ]
-            })
+            .map(|i| image.sub_rect(i * frame_width, 0, frame_width, image.height()))
             .collect();
+        Sprite::from_frames(frames, vec![Vector2D::zero(); frame_count as usize], origin)
+    }
+
+    /// A sprite with frames of any size, each drawn at its offset from the sprite's bottom left.
+    pub fn from_frames(
+        frames: Vec<TextureRect>,
+        frame_offsets: Vec<Vector2D<f32>>,
+        origin: Point2D<f32>,
+    ) -> Self {
+        assert_eq!(
+            frames.len(),
+            frame_offsets.len(),
+            "sprite needs one offset per frame"
+        );
         Self {
             frames,
-            frame_count,
+            frame_offsets,
             origin,
             transform: Transform2D::translation(-origin.x, -origin.y),
+            uv_inset: 0.,
+            color: Color::WHITE,
+            flash: Color::TRANSPARENT,
+            flip_x: false,
+        }
+    }
+
+    /// Tint multiplied into the sprite's vertex colors, white by default.
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+    }
+
+    /// Mixes the sprite's texels toward `color` by `amount` from 0 to 1, before the tint is
+    /// multiplied in. An amount of 0 turns the flash off.
+    pub fn set_flash(&mut self, color: Color, amount: f32) {
+        self.flash = color.with_alpha(amount.max(0.).min(1.));
+    }
+
+    /// Mirrors the image left to right within each frame's quad. Only the UVs are swapped, the
+    /// quad itself stays where the transform puts it.
+    pub fn set_flip_x(&mut self, flip: bool) {
+        self.flip_x = flip;
+    }
+
+    /// Texels to pull the UVs in by on each side, see `atlas_uv_rect`.
+    pub fn set_uv_inset(&mut self, inset: f32) {
+        self.uv_inset = inset;
+    }
+
+    /// The default transform of every draw, applied around the origin. For a one off transform
+    /// use `render_sprite_with` instead of changing this.
+    pub fn set_transform(&mut self, t: Transform2D<f32>) {
+        self.transform = Transform2D::translation(-self.origin.x, -self.origin.y).then(&t);
+    }
+
+    pub fn transform(&self) -> &Transform2D<f32> {
+        &self.transform
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlayMode {
+    Loop,
+    /// Stops on the last frame
+    Once,
+    /// Runs back and forth, without repeating the first and last frames at the turns
+    PingPong,
+}
+
+/// Steps through sprite frames, showing each for its own duration.
+#[derive(Clone, Debug)]
+pub struct Animation {
+    frames: Vec<usize>,
+    durations: Vec<f32>,
+    mode: PlayMode,
+    // Position in `frames`, and how long it has been shown
+    index: usize,
+    timer: f32,
+    reversing: bool,
+    finished: bool,
+}
+
+impl Animation {
+    /// `durations` are in seconds, one per frame, and have to be positive.
+    pub fn new(frames: Vec<usize>, durations: Vec<f32>, mode: PlayMode) -> Animation {
+        assert!(!frames.is_empty(), "animation has no frames");
+        assert_eq!(
+            frames.len(),
+            durations.len(),
+            "animation needs one duration per frame"
+        );
+        assert!(
+            durations.iter().all(|duration| *duration > 0.),
+            "animation frame durations have to be positive"
+        );
+        Animation {
+            frames,
+            durations,
+            mode,
+            index: 0,
+            timer: 0.,
+            reversing: false,
+            finished: false,
+        }
+    }
+
+    /// Shows every frame for `frame_duration`.
+    pub fn uniform(frames: Vec<usize>, frame_duration: f32, mode: PlayMode) -> Animation {
+        let durations = vec![frame_duration; frames.len()];
+        Animation::new(frames, durations, mode)
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        if self.finished {
+            return;
+        }
+        self.timer += dt;
+        while self.timer >= self.durations[self.index] {
+            self.timer -= self.durations[self.index];
+            self.advance();
+            if self.finished {
+                self.timer = 0.;
+                return;
+            }
+        }
+    }
+
+    /// The sprite frame to draw.
+    pub fn current_frame(&self) -> usize {
+        self.frames[self.index]
+    }
+
+    /// Whether a `PlayMode::Once` animation has reached the end of its last frame. Never true in
+    /// the other modes.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    fn advance(&mut self) {
+        let last = self.frames.len() - 1;
+        match self.mode {
+            PlayMode::Loop => {
+                self.index = if self.index == last {
+                    0
+                } else {
+                    self.index + 1
+                }
+            }
+            PlayMode::Once => {
+                if self.index == last {
+                    self.finished = true;
+                } else {
+                    self.index += 1;
+                }
+            }
+            PlayMode::PingPong => {
+                if last == 0 {
+                    return;
+                }
+                if self.reversing && self.index == 0 {
+                    self.reversing = false;
+                } else if !self.reversing && self.index == last {
+                    self.reversing = true;
+                }
+                if self.reversing {
+                    self.index -= 1;
+                } else {
+                    self.index += 1;
+                }
+            }
+        }
+    }
+}
+
+/// A sprite and its named animations, see `load_aseprite`.
+pub struct SpriteSheet {
+    sprite: Sprite,
+    animations: HashMap<String, Animation>,
+}
+
+impl SpriteSheet {
+    /// Has a frame for every frame of the sheet, in export order.
+    pub fn sprite(&self) -> &Sprite {
+        &self.sprite
+    }
+
+    /// The animation for the frame tag `tag`, started from the beginning.
+    pub fn animation(&self, tag: &str) -> Option<Animation> {
+        self.animations.get(tag).cloned()
+    }
+}
+
+/// Reads the data of a sprite sheet exported from Aseprite with the "Array" JSON data option,
+/// for the sheet image at `sheet` in the atlas. Frame tags become animations of the frames they
+/// span with the frames' durations. Their direction picks the play mode, and tags set to repeat
+/// once play `PlayMode::Once`. `origin` is in pixels from the bottom left of the untrimmed
+/// frames.
+pub fn load_aseprite(
+    json_bytes: &[u8],
+    sheet: TextureRect,
+    origin: Point2D<f32>,
+) -> Result<SpriteSheet, Error> {
+    let doc = json::parse(std::str::from_utf8(json_bytes)?)?;
+    let number = |value: &json::Value, key: &str| -> Result<f64, Error> {
+        value
+            .get(key)
+            .and_then(json::Value::as_f64)
+            .ok_or_else(|| anyhow::anyhow!("Aseprite data is missing number '{}'", key))
+    };
+
+    let frame_list = doc
+        .get("frames")
+        .and_then(json::Value::as_array)
+        .ok_or_else(|| {
+            anyhow::anyhow!("Aseprite data has no frame array, export it with the Array option")
+        })?;
+    if frame_list.is_empty() {
+        anyhow::bail!("Aseprite data has no frames");
+    }
+
+    let mut frames = Vec::with_capacity(frame_list.len());
+    let mut frame_offsets = Vec::with_capacity(frame_list.len());
+    let mut durations = Vec::with_capacity(frame_list.len());
+    for entry in frame_list {
+        if entry.get("rotated").and_then(json::Value::as_bool) == Some(true) {
+            anyhow::bail!("Rotated Aseprite frames are not supported");
+        }
+        let rect = entry
+            .get("frame")
+            .ok_or_else(|| anyhow::anyhow!("Aseprite frame has no rect"))?;
+        let (x, y) = (number(rect, "x")? as u32, number(rect, "y")? as u32);
+        let (w, h) = (number(rect, "w")? as u32, number(rect, "h")? as u32);
+        if x + w > sheet.width() || y + h > sheet.height() {
+            anyhow::bail!("Aseprite frame at {}, {} is outside of the sheet", x, y);
         }
+        frames.push(sheet.sub_rect(x, y, w, h));
+
+        // trimmed frames are placed where they were in the full frame, whose rows go down
+        let offset = match (entry.get("spriteSourceSize"), entry.get("sourceSize")) {
+            (Some(trimmed), Some(source)) => Vector2D::new(
+                number(trimmed, "x")? as f32,
+                (number(source, "h")? - number(trimmed, "y")? - h as f64) as f32,
+            ),
+            _ => Vector2D::zero(),
+        };
+        frame_offsets.push(offset);
+
+        // Aseprite counts in milliseconds
+        durations.push(
+            (entry
+                .get("duration")
+                .and_then(json::Value::as_f64)
+                .unwrap_or(100.)
+                / 1000.) as f32,
+        );
     }
 
-    pub fn set_transform(&mut self, t: Transform2D<f32>) {
-        self.transform = Transform2D::translation(-self.origin.x, -self.origin.y).then(&t);
+    let mut animations = HashMap::new();
+    let tags = doc
+        .get("meta")
+        .and_then(|meta| meta.get("frameTags"))
+        .and_then(json::Value::as_array)
+        .unwrap_or(&[]);
+    for tag in tags {
+        let name = tag
+            .get("name")
+            .and_then(json::Value::as_str)
+            .ok_or_else(|| anyhow::anyhow!("Aseprite frame tag has no name"))?;
+        let (from, to) = (number(tag, "from")? as usize, number(tag, "to")? as usize);
+        if from > to || to >= frames.len() {
+            anyhow::bail!("Aseprite frame tag '{}' is out of range", name);
+        }
+        let mut tag_frames: Vec<usize> = (from..=to).collect();
+        let direction = tag.get("direction").and_then(json::Value::as_str);
+        if direction == Some("reverse") {
+            tag_frames.reverse();
+        }
+        // a repeat count is only written for tags that don't loop forever, and is a string
+        let repeat = tag
+            .get("repeat")
+            .and_then(json::Value::as_str)
+            .and_then(|repeat| repeat.parse::<u32>().ok());
+        let mode = match (repeat, direction) {
+            (Some(1), _) => PlayMode::Once,
+            (_, Some("pingpong")) => PlayMode::PingPong,
+            _ => PlayMode::Loop,
+        };
+        let tag_durations = tag_frames.iter().map(|frame| durations[*frame]).collect();
+        animations.insert(
+            name.to_owned(),
+            Animation::new(tag_frames, tag_durations, mode),
+        );
     }
 
-    pub fn transform(&self) -> &Transform2D<f32> {
-        &self.transform
+    Ok(SpriteSheet {
+        sprite: Sprite::from_frames(frames, frame_offsets, origin),
+        animations,
+    })
+}
+
+fn check_raw_image(bytes: &[u8], size: Size2D<u32, AtlasSpace>) -> Result<(), Error> {
+    let expected_len = size.area() as usize * 4;
+    if bytes.len() != expected_len {
+        bail!(
+            "Raw {}x{} image should be {} bytes, got {}",
+            size.width,
+            size.height,
+            expected_len,
+            bytes.len()
+        );
     }
+    Ok(())
+}
+
+/// An image for `load_images_batch`.
+#[derive(Clone, Copy)]
+// the prebaked atlas has every encoded image packed already
+#[cfg_attr(feature = "prebaked-atlas", allow(dead_code))]
+pub enum BatchImage<'a> {
+    /// Bytes of a PNG or anything else the `image` crate decodes
+    Encoded(&'a [u8]),
+    /// `Encoded` that the atlas may store on its side, see `TextureAtlas::add_texture_rotatable`.
+    /// Sprites and quads drawn from the rect come out upright either way.
+    EncodedRotatable(&'a [u8]),
+    /// RGBA rows of the given size, top row first like decoded images
+    Raw(&'a [u8], Size2D<u32, AtlasSpace>),
 }
 
-pub unsafe fn load_image(
-    image_bytes: &[u8],
+/// Decodes all of `images` and adds them tallest first, see `TextureAtlas::pack_batch`. The
+/// rects come back in the order of `images`. If one doesn't fit, the ones added before it are
+/// removed again.
+pub unsafe fn load_images_batch(
+    images: &[BatchImage],
     texture_atlas: &mut TextureAtlas,
     texture: &mut gl::Texture,
-) -> Result<TextureRect, Error> {
-    let image = image::load_from_memory(image_bytes).unwrap().to_rgba();
-    let texture_coords = texture_atlas
-        .add_texture((image.width(), image.height()))
-        .unwrap();
-    texture.write(
-        texture_coords[0],
-        texture_coords[1],
-        texture_coords[2] - texture_coords[0],
-        texture_coords[3] - texture_coords[1],
-        &image.into_raw(),
-    );
-    Ok(texture_coords)
+) -> Result<Vec<TextureRect>, Error> {
+    let mut decoded = Vec::with_capacity(images.len());
+    for (i, image) in images.iter().enumerate() {
+        let (bytes, size, rotatable) = match image {
+            BatchImage::Encoded(bytes) | BatchImage::EncodedRotatable(bytes) => {
+                let rotatable = matches!(image, BatchImage::EncodedRotatable(_));
+                let image = image::load_from_memory(bytes)
+                    .with_context(|| format!("Could not decode image {} of the batch", i))?
+                    .to_rgba();
+                let size = image.dimensions();
+                (Cow::Owned(image.into_raw()), size, rotatable)
+            }
+            BatchImage::Raw(bytes, size) => {
+                check_raw_image(bytes, *size)?;
+                (Cow::Borrowed(*bytes), size.to_tuple(), false)
+            }
+        };
+        decoded.push((bytes, size, rotatable));
+    }
+
+    let sizes: Vec<(u32, u32)> = decoded.iter().map(|(_, size, _)| *size).collect();
+    // by handle, as rects added before the atlas grows partway through move
+    let mut handles = vec![None; images.len()];
+    for i in texture_atlas::packing_order(&sizes) {
+        let (bytes, size, rotatable) = &decoded[i];
+        match write_to_atlas(bytes, *size, 0, *rotatable, texture_atlas, texture) {
+            Ok(rect) => handles[i] = texture_atlas.handle(rect),
+            Err(e) => {
+                for handle in handles.into_iter().flatten() {
+                    if let Some(rect) = texture_atlas.rect(handle) {
+                        texture_atlas.remove(rect)?;
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    handles
+        .into_iter()
+        .map(|handle| {
+            handle
+                .and_then(|handle| texture_atlas.rect(handle))
+                .ok_or_else(|| format_err!("A texture of the batch went missing from the atlas"))
+        })
+        .collect()
 }
 
-pub unsafe fn load_raw_image(
+// Adds RGBA rows of `size` texels to the atlas and writes them along with their gutter, turned
+// if the atlas chose to rotate them. Images the atlas already has are reused.
+unsafe fn write_to_atlas(
     bytes: &[u8],
-    height: u32,
-    width: u32,
+    size: (u32, u32),
+    gutter: u32,
+    rotatable: bool,
     texture_atlas: &mut TextureAtlas,
     texture: &mut gl::Texture,
 ) -> Result<TextureRect, Error> {
-    let texture_coords = texture_atlas.add_texture((width, height)).unwrap();
+    let content = ContentKey::new(bytes, size, gutter);
+    if let Some(rect) = texture_atlas.reuse(content) {
+        return Ok(rect);
+    }
+    let texture_coords = loop {
+        let atlas_size = texture_atlas.size();
+        let added = if rotatable {
+            texture_atlas.add_texture_rotatable(size, gutter)
+        } else {
+            texture_atlas.add_texture_with_gutter(size, gutter)
+        };
+        let fit_context = || {
+            format!(
+                "Could not fit a {}x{} image in the {}x{} texture atlas",
+                size.0, size.1, atlas_size.0, atlas_size.1
+            )
+        };
+        match added {
+            Ok(texture_coords) => break texture_coords,
+            Err(e) if texture_atlas.auto_grow().is_none() => return Err(e.context(fit_context())),
+            Err(_) => texture_atlas
+                .grow_retained(texture)
+                .with_context(fit_context)?,
+        }
+    };
+    // an empty image has no edges to repeat
+    let gutter = if size.0 == 0 || size.1 == 0 {
+        0
+    } else {
+        gutter
+    };
+    let mut pixels = if gutter == 0 {
+        Cow::Borrowed(bytes)
+    } else {
+        Cow::Owned(extrude_edges(bytes, size, gutter))
+    };
+    let mut outer_size = (size.0 + gutter * 2, size.1 + gutter * 2);
+    if texture_coords.rotated {
+        pixels = Cow::Owned(rotate_clockwise(&pixels, outer_size));
+        outer_size = (outer_size.1, outer_size.0);
+    }
     texture.write(
-        texture_coords[0],
-        texture_coords[1],
-        texture_coords[2] - texture_coords[0],
-        texture_coords[3] - texture_coords[1],
-        bytes,
-    );
+        texture_coords.texels[0] - gutter,
+        texture_coords.texels[1] - gutter,
+        outer_size.0,
+        outer_size.1,
+        &pixels,
+    )?;
+    texture_atlas.record_content(texture_coords, content);
+    if texture_atlas.auto_grow().is_some() {
+        texture_atlas.retain_pixels(texture_coords, pixels.into_owned());
+    }
     Ok(texture_coords)
 }
 
-pub fn render_sprite(
+// RGBA rows of `size` texels turned 90° clockwise, so the top row becomes the right column
+fn rotate_clockwise(bytes: &[u8], size: (u32, u32)) -> Vec<u8> {
+    let (width, height) = size;
+    let mut out = Vec::with_capacity(bytes.len());
+    for y in 0..width {
+        for x in 0..height {
+            let i = ((height - 1 - x) * width + y) as usize * 4;
+            out.extend_from_slice(&bytes[i..i + 4]);
+        }
+    }
+    out
+}
+
+// RGBA rows of `size` texels with every edge texel repeated `gutter` texels outwards, corners
+// included
+fn extrude_edges(bytes: &[u8], size: (u32, u32), gutter: u32) -> Vec<u8> {
+    let (width, height) = (size.0 + gutter * 2, size.1 + gutter * 2);
+    let mut out = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height {
+        let src_y = y.saturating_sub(gutter).min(size.1.saturating_sub(1));
+        for x in 0..width {
+            let src_x = x.saturating_sub(gutter).min(size.0.saturating_sub(1));
+            let i = (src_y * size.0 + src_x) as usize * 4;
+            out.extend_from_slice(&bytes[i..i + 4]);
+        }
+    }
+    out
+}
+
+/// Inset for `atlas_uv_rect` that keeps sampling inside the texture rect.
+pub const HALF_TEXEL: f32 = 0.5;
+
+/// UVs of `tex_coords` in its atlas, pulled in by `inset` texels on each side. Half a texel keeps
+/// scaled and filtered sampling from reaching into the neighbouring atlas entries, at the cost
+/// of stretching the image by one texel overall. At 1:1 with nearest sampling every texel is
+/// still hit exactly once.
+pub fn atlas_uv_rect(tex_coords: TextureRect, inset: f32) -> Rect<f32> {
+    let (atlas_width, atlas_height) = tex_coords.atlas_size;
+    let uv_pos = point2(
+        (tex_coords.texels[0] as f32 + inset) / atlas_width as f32,
+        (tex_coords.texels[1] as f32 + inset) / atlas_height as f32,
+    );
+    let texel_width = tex_coords.texels[2] - tex_coords.texels[0];
+    let texel_height = tex_coords.texels[3] - tex_coords.texels[1];
+    let uv_size = size2(
+        (texel_width as f32 - inset * 2.) / atlas_width as f32,
+        (texel_height as f32 - inset * 2.) / atlas_height as f32,
+    );
+    Rect::new(uv_pos, uv_size)
+}
+
+// UVs for a quad's corners in the order bottom left, bottom right, top left, top right, with the
+// image mirrored left to right if `flip_x`. Rotated rects have their axes swapped back here.
+fn corner_uvs(tex_coords: TextureRect, inset: f32, flip_x: bool) -> [[f32; 2]; 4] {
+    let uv_rect = atlas_uv_rect(tex_coords, inset);
+    let (left, right) = if flip_x { (1., 0.) } else { (0., 1.) };
+    // V runs down the image, so the bottom edge of the quad gets the larger V
+    let (bottom, top) = (1., 0.);
+    let uv = |x: f32, y: f32| {
+        // the image's top row runs down the rect's right edge
+        let (x, y) = if tex_coords.rotated {
+            (1. - y, x)
+        } else {
+            (x, y)
+        };
+        [
+            uv_rect.min_x() + x * uv_rect.width(),
+            uv_rect.min_y() + y * uv_rect.height(),
+        ]
+    };
+    [
+        uv(left, bottom),
+        uv(right, bottom),
+        uv(left, top),
+        uv(right, top),
+    ]
+}
+
+/// Where a sprite is drawn, which also decides the units its transform maps sprite pixels to.
+#[derive(Clone, Copy, Debug)]
+pub enum SpritePosition {
+    /// In a room, where sprite transforms map pixels to tiles.
+    Room(RoomPoint),
+    /// On the UI, where sprite transforms map pixels to UI pixels.
+    Ui(UiPoint),
+}
+
+impl SpritePosition {
+    fn to_untyped(self) -> Point2D<f32> {
+        match self {
+            SpritePosition::Room(position) => position.to_untyped(),
+            SpritePosition::Ui(position) => position.to_untyped(),
+        }
+    }
+}
+
+pub fn render_sprite<S: QuadSink>(
     sprite: &Sprite,
     frame: usize,
-    position: Point2D<f32>,
-    color: [f32; 4],
-    out: &mut Vec<Vertex>,
+    position: SpritePosition,
+    out: &mut S,
 ) {
-    let size = size2(
-        (sprite.frames[frame][2] - sprite.frames[frame][0]) as f32,
-        (sprite.frames[frame][3] - sprite.frames[frame][1]) as f32,
+    render_sprite_tinted(sprite, frame, position, Color::WHITE, out);
+}
+
+/// `render_sprite` with `tint` multiplied into the sprite's own color for this draw only.
+pub fn render_sprite_tinted<S: QuadSink>(
+    sprite: &Sprite,
+    frame: usize,
+    position: SpritePosition,
+    tint: Color,
+    out: &mut S,
+) {
+    render_sprite_transformed(
+        sprite,
+        frame,
+        position.to_untyped(),
+        sprite.transform(),
+        tint,
+        None,
+        out,
     );
-    let vertex_rect = Rect::new(point2(0., 0.), size);
+}
 
-    let uv_pos = point2(
-        sprite.frames[frame][0] as f32 / TEXTURE_ATLAS_SIZE.width as f32,
-        sprite.frames[frame][1] as f32 / TEXTURE_ATLAS_SIZE.height as f32,
+/// `render_sprite` with `transform` in place of the sprite's own transform for this draw only. `transform` is applied around the sprite's origin like `Sprite::set_transform`,
+/// and `tint` is multiplied into the sprite's color.
+pub fn render_sprite_with<S: QuadSink>(
+    sprite: &Sprite,
+    frame: usize,
+    position: SpritePosition,
+    transform: &Transform2D<f32>,
+    tint: Color,
+    out: &mut S,
+) {
+    let transform = Transform2D::translation(-sprite.origin.x, -sprite.origin.y).then(transform);
+    render_sprite_transformed(
+        sprite,
+        frame,
+        position.to_untyped(),
+        &transform,
+        tint,
+        None,
+        out,
     );
-    let uv_size = size2(
-        (sprite.frames[frame][2] - sprite.frames[frame][0]) as f32
-            / TEXTURE_ATLAS_SIZE.width as f32,
-        (sprite.frames[frame][3] - sprite.frames[frame][1]) as f32
-            / TEXTURE_ATLAS_SIZE.height as f32,
+}
+
+/// `render_sprite` moved so that the quad's corners land on a grid of `pixels_per_unit` per unit
+/// of `position`'s space, where they would otherwise land between pixels. Keeps slow moving
+/// sprites from shimmering without rounding the position they are drawn for.
+pub fn render_sprite_snapped<S: QuadSink>(
+    sprite: &Sprite,
+    frame: usize,
+    position: SpritePosition,
+    pixels_per_unit: f32,
+    out: &mut S,
+) {
+    render_sprite_transformed(
+        sprite,
+        frame,
+        position.to_untyped(),
+        sprite.transform(),
+        Color::WHITE,
+        Some(pixels_per_unit),
+        out,
+    );
+}
+
+/// `p` rounded to the nearest point of a grid of `pixels_per_unit` per unit.
+pub fn snap_to_pixel_grid<U>(
+    p: euclid::Point2D<f32, U>,
+    pixels_per_unit: f32,
+) -> euclid::Point2D<f32, U> {
+    (p * pixels_per_unit).round() / pixels_per_unit
+}
+
+/// `render_sprite` with the sprite rotated counterclockwise by `angle` around its origin, after
+/// its own transform. A flipping transform doesn't flip the rotation along with it.
+pub fn render_sprite_rotated<S: QuadSink>(
+    sprite: &Sprite,
+    frame: usize,
+    position: SpritePosition,
+    angle: Angle<f32>,
+    out: &mut S,
+) {
+    let transform = sprite.transform().then_rotate(angle);
+    let position = position.to_untyped();
+    render_sprite_transformed(sprite, frame, position, &transform, Color::WHITE, None, out);
+}
+
+// All four corners go through the full transform, so rotated quads keep their UVs on the same
+// corners and their winding. Snapping moves the whole quad by however far its bottom left corner
+// is from the grid.
+fn render_sprite_transformed<S: QuadSink>(
+    sprite: &Sprite,
+    frame: usize,
+    position: Point2D<f32>,
+    transform: &Transform2D<f32>,
+    tint: Color,
+    pixel_snap: Option<f32>,
+    out: &mut S,
+) {
+    let color = (sprite.color * tint).to_array();
+    let flash = sprite.flash.to_array();
+    let size = size2(
+        sprite.frames[frame].width() as f32,
+        sprite.frames[frame].height() as f32,
     );
-    let uv_rect = Rect::new(uv_pos, uv_size);
+    let vertex_rect = Rect::new(sprite.frame_offsets[frame].to_point(), size);
+
+    let uvs = corner_uvs(sprite.frames[frame], sprite.uv_inset, sprite.flip_x);
 
+    let position = match pixel_snap {
+        Some(pixels_per_unit) => {
+            let corner = position + transform.transform_point(vertex_rect.min()).to_vector();
+            position + (snap_to_pixel_grid(corner, pixels_per_unit) - corner)
+        }
+        None => position,
+    };
     let transform = |p: Point2D<f32>| -> [f32; 2] {
-        (position + sprite.transform().transform_point(p).to_vector()).to_array()
+        (position + transform.transform_point(p).to_vector()).to_array()
     };
-    out.extend_from_slice(&[
+    out.push_corners([
         Vertex {
             position: transform(vertex_rect.min()),
-            uv: [uv_rect.min_x(), uv_rect.max_y()],
+            uv: uvs[0],
             color,
+            flash,
         },
         Vertex {
             position: transform(point2(vertex_rect.max_x(), vertex_rect.min_y())),
-            uv: [uv_rect.max_x(), uv_rect.max_y()],
+            uv: uvs[1],
             color,
+            flash,
         },
         Vertex {
             position: transform(point2(vertex_rect.min_x(), vertex_rect.max_y())),
-            uv: [uv_rect.min_x(), uv_rect.min_y()],
-            color,
-        },
-        Vertex {
-            position: transform(point2(vertex_rect.max_x(), vertex_rect.min_y())),
-            uv: [uv_rect.max_x(), uv_rect.max_y()],
+            uv: uvs[2],
             color,
+            flash,
         },
         Vertex {
             position: transform(vertex_rect.max()),
-            uv: [uv_rect.max_x(), uv_rect.min_y()],
-            color,
-        },
-        Vertex {
-            position: transform(point2(vertex_rect.min_x(), vertex_rect.max_y())),
-            uv: [uv_rect.min_x(), uv_rect.min_y()],
+            uv: uvs[3],
             color,
+            flash,
         },
     ]);
 }
 
-pub fn render_quad(
-    rect: Box2D<f32>,
+pub fn render_quad<U, S: QuadSink>(
+    rect: Box2D<f32, U>,
     tex_coords: TextureRect,
-    color: [f32; 4],
-    out: &mut Vec<Vertex>,
+    color: Color,
+    out: &mut S,
 ) {
-    let uv_pos = point2(
-        tex_coords[0] as f32 / TEXTURE_ATLAS_SIZE.width as f32,
-        tex_coords[1] as f32 / TEXTURE_ATLAS_SIZE.height as f32,
-    );
-    let uv_size = size2(
-        (tex_coords[2] - tex_coords[0]) as f32 / TEXTURE_ATLAS_SIZE.width as f32,
-        (tex_coords[3] - tex_coords[1]) as f32 / TEXTURE_ATLAS_SIZE.height as f32,
-    );
-    let uv_rect = Rect::new(uv_pos, uv_size);
+    render_quad_inset(rect, tex_coords, color, 0., out);
+}
+
+/// `render_quad` with the UVs pulled in by `inset` texels on each side, see `atlas_uv_rect`.
+pub fn render_quad_inset<U, S: QuadSink>(
+    rect: Box2D<f32, U>,
+    tex_coords: TextureRect,
+    color: Color,
+    inset: f32,
+    out: &mut S,
+) {
+    render_quad_gradient_inset(rect, tex_coords, [color; 4], inset, out);
+}
+
+fn render_quad_gradient_inset<U, S: QuadSink>(
+    rect: Box2D<f32, U>,
+    tex_coords: TextureRect,
+    corner_colors: [Color; 4],
+    inset: f32,
+    out: &mut S,
+) {
+    let uvs = corner_uvs(tex_coords, inset, false);
 
-    out.extend_from_slice(&[
+    out.push_corners([
         Vertex {
             position: rect.min.to_array(),
-            uv: [uv_rect.min_x(), uv_rect.max_y()],
-            color,
+            uv: uvs[0],
+            color: corner_colors[0].to_array(),
+            flash: [0.; 4],
         },
         Vertex {
             position: [rect.max.x, rect.min.y],
-            uv: [uv_rect.max_x(), uv_rect.max_y()],
-            color,
+            uv: uvs[1],
+            color: corner_colors[1].to_array(),
+            flash: [0.; 4],
         },
         Vertex {
             position: [rect.min.x, rect.max.y],
-            uv: [uv_rect.min_x(), uv_rect.min_y()],
-            color,
-        },
-        Vertex {
-            position: [rect.max.x, rect.min.y],
-            uv: [uv_rect.max_x(), uv_rect.max_y()],
-            color,
+            uv: uvs[2],
+            color: corner_colors[2].to_array(),
+            flash: [0.; 4],
         },
         Vertex {
             position: rect.max.to_array(),
-            uv: [uv_rect.max_x(), uv_rect.min_y()],
-            color,
-        },
-        Vertex {
-            position: [rect.min.x, rect.max.y],
-            uv: [uv_rect.min_x(), uv_rect.min_y()],
-            color,
+            uv: uvs[3],
+            color: corner_colors[3].to_array(),
+            flash: [0.; 4],
         },
     ]);
 }
 
-pub const TEXTURE_ATLAS_SIZE: Size2D<u32> = Size2D {
+/// The atlas texture's contents as a PNG, with every rect outlined on the padding around it,
+/// for checking the layout and UVs.
+pub unsafe fn atlas_png(
+    gl_context: &mut gl::Context,
+    texture: &gl::Texture,
+    texture_atlas: &TextureAtlas,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = texture_atlas.size();
+    if (texture.width(), texture.height()) != (width, height) {
+        bail!(
+            "{}x{} texture doesn't match the {}x{} atlas",
+            texture.width(),
+            texture.height(),
+            width,
+            height
+        );
+    }
+    let mut pixels = gl_context.read_texture(texture)?;
+    let mut outline = |x: i64, y: i64| {
+        if x >= 0 && y >= 0 && x < width as i64 && y < height as i64 {
+            let i = (y as usize * width as usize + x as usize) * 4;
+            pixels[i..i + 4].copy_from_slice(&ATLAS_DUMP_OUTLINE);
+        }
+    };
+    for rect in texture_atlas.rects() {
+        let [min_x, min_y, max_x, max_y] = rect.texels;
+        let (min_x, min_y, max_x, max_y) = (
+            min_x as i64 - 1,
+            min_y as i64 - 1,
+            max_x as i64,
+            max_y as i64,
+        );
+        for x in min_x..=max_x {
+            outline(x, min_y);
+            outline(x, max_y);
+        }
+        for y in min_y..=max_y {
+            outline(min_x, y);
+            outline(max_x, y);
+        }
+    }
+
+    let mut png = Vec::new();
+    image::png::PNGEncoder::new(&mut png)
+        .encode(&pixels, width, height, image::ColorType::Rgba8)
+        .context("Could not encode the atlas")?;
+    Ok(png)
+}
+
+const ATLAS_DUMP_OUTLINE: [u8; 4] = [255, 0, 255, 255];
+
+/// `rect` in a solid color. `white` is the atlas rect of a white texel, which is all the solid
+/// shapes are drawn from.
+pub fn render_rect_filled<U, S: QuadSink>(
+    rect: Box2D<f32, U>,
+    white: TextureRect,
+    color: Color,
+    out: &mut S,
+) {
+    // an inset of half a texel samples the white texel's center only
+    render_quad_inset(rect, white, color, HALF_TEXEL, out);
+}
+
+/// `rect` with a solid color per corner, in the order bottom left, bottom right, top left, top
+/// right. Colors are interpolated over the two triangles, which always split the quad from bottom
+/// right to top left. Gradients along one axis are seamless, a different color in just one corner
+/// shows that diagonal.
+pub fn render_rect_gradient<U, S: QuadSink>(
+    rect: Box2D<f32, U>,
+    white: TextureRect,
+    corner_colors: [Color; 4],
+    out: &mut S,
+) {
+    render_quad_gradient_inset(rect, white, corner_colors, HALF_TEXEL, out);
+}
+
+/// The edges of `rect` as solid bands `thickness` wide, on the inside of the rect.
+pub fn render_rect_outline<U, S: QuadSink>(
+    rect: Box2D<f32, U>,
+    thickness: f32,
+    white: TextureRect,
+    color: Color,
+    out: &mut S,
+) {
+    let thickness = thickness
+        .min(rect.width() / 2.)
+        .min(rect.height() / 2.)
+        .max(0.);
+    let (min, max) = (rect.min, rect.max);
+    for band in &[
+        Box2D::new(min, point2(max.x, min.y + thickness)),
+        Box2D::new(point2(min.x, max.y - thickness), max),
+        Box2D::new(
+            point2(min.x, min.y + thickness),
+            point2(min.x + thickness, max.y - thickness),
+        ),
+        Box2D::new(
+            point2(max.x - thickness, min.y + thickness),
+            point2(max.x, max.y - thickness),
+        ),
+    ] {
+        render_rect_filled(*band, white, color, out);
+    }
+}
+
+/// A solid line `thickness` wide centered on `from` to `to`. A zero length line is drawn as a
+/// square `thickness` wide.
+pub fn render_line<U, S: QuadSink>(
+    from: euclid::Point2D<f32, U>,
+    to: euclid::Point2D<f32, U>,
+    thickness: f32,
+    white: TextureRect,
+    color: Color,
+    out: &mut S,
+) {
+    let half = thickness / 2.;
+    let direction = to - from;
+    let length = direction.length();
+    let (along, across) = if length > 0. {
+        let along = direction / length * half;
+        (along, euclid::Vector2D::new(-along.y, along.x))
+    } else {
+        (
+            euclid::Vector2D::new(half, 0.),
+            euclid::Vector2D::new(0., half),
+        )
+    };
+    let (from, to) = if length > 0. {
+        (from, to)
+    } else {
+        (from - along, from + along)
+    };
+    let uv = atlas_uv_rect(white, HALF_TEXEL).center();
+    let color = color.to_array();
+    let vertex = |position: euclid::Point2D<f32, U>| Vertex {
+        position: position.to_array(),
+        uv: uv.to_array(),
+        color,
+        flash: [0.; 4],
+    };
+    out.push_corners([
+        vertex(from - across),
+        vertex(to - across),
+        vertex(from + across),
+        vertex(to + across),
+    ]);
+}
+
+pub const TEXTURE_ATLAS_SIZE: Size2D<u32, AtlasSpace> = Size2D {
     width: 1024,
     height: 1024,
-    _unit: std::marker::PhantomData::<euclid::UnknownUnit>,
+    _unit: std::marker::PhantomData::<AtlasSpace>,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A rect of `width` by `height` texels at `x`, `y` in a 64x64 atlas
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> TextureRect {
+        TextureRect {
+            texels: [x, y, x + width, y + height],
+            atlas_size: (64, 64),
+            rotated: false,
+        }
+    }
+
+    #[test]
+    fn post_shaders_declare_u_time_only_in_code() {
+        assert!(!declares_uniform(PASSTHROUGH_POST_SHADER, "u_time"));
+        assert!(declares_uniform(PASSTHROUGH_POST_SHADER, "u_texture"));
+        assert!(declares_uniform("uniform highp float u_time;", "u_time"));
+        assert!(declares_uniform(
+            "  uniform float u_time; // seconds",
+            "u_time"
+        ));
+        assert!(!declares_uniform("// uniform float u_time;", "u_time"));
+        assert!(!declares_uniform("uniform float u_timer;", "u_time"));
+        assert!(!declares_uniform("float wave = sin(u_time);", "u_time"));
+    }
+
+    #[test]
+    fn aseprite_tags_map_to_frames() {
+        let sheet = rect(8, 16, 12, 4);
+        let dust =
+            load_aseprite(include_bytes!("../assets/dust.json"), sheet, point2(2., 2.)).unwrap();
+        assert_eq!(
+            dust.sprite().frames,
+            [
+                sheet.sub_rect(0, 0, 4, 4),
+                sheet.sub_rect(4, 0, 4, 4),
+                sheet.sub_rect(8, 0, 4, 4)
+            ]
+        );
+        let mut puff = dust.animation("puff").unwrap();
+        assert_eq!(puff.frames, [0, 1, 2]);
+        assert_eq!(puff.durations, [0.067, 0.067, 0.066]);
+        assert_eq!(puff.mode, PlayMode::Once);
+        puff.update(0.2);
+        assert!(puff.finished());
+        assert!(dust.animation("walk").is_none());
+    }
+
+    #[test]
+    fn aseprite_directions_pick_play_modes() {
+        let json = r#"{
+            "frames": [
+                { "frame": { "x": 0, "y": 0, "w": 2, "h": 2 }, "duration": 100 },
+                { "frame": { "x": 2, "y": 0, "w": 2, "h": 2 }, "duration": 200 },
+                { "frame": { "x": 4, "y": 0, "w": 2, "h": 2 }, "duration": 300 },
+                { "frame": { "x": 6, "y": 0, "w": 2, "h": 2 } }
+            ],
+            "meta": { "frameTags": [
+                { "name": "forward", "from": 1, "to": 3, "direction": "forward" },
+                { "name": "back", "from": 0, "to": 2, "direction": "reverse" },
+                { "name": "bounce", "from": 0, "to": 3, "direction": "pingpong" },
+                { "name": "twice", "from": 2, "to": 2, "direction": "forward", "repeat": "2" }
+            ] }
+        }"#;
+        let sheet = load_aseprite(json.as_bytes(), rect(0, 0, 8, 2), point2(0., 0.)).unwrap();
+        let forward = sheet.animation("forward").unwrap();
+        assert_eq!(forward.frames, [1, 2, 3]);
+        // frames without a duration get Aseprite's default
+        assert_eq!(forward.durations, [0.2, 0.3, 0.1]);
+        assert_eq!(forward.mode, PlayMode::Loop);
+        let back = sheet.animation("back").unwrap();
+        assert_eq!(back.frames, [2, 1, 0]);
+        assert_eq!(back.durations, [0.3, 0.2, 0.1]);
+        assert_eq!(sheet.animation("bounce").unwrap().mode, PlayMode::PingPong);
+        // repeating more than once can't be told apart from looping
+        assert_eq!(sheet.animation("twice").unwrap().mode, PlayMode::Loop);
+    }
+
+    #[test]
+    fn trimmed_aseprite_frames_keep_their_place() {
+        // an 8x8 frame trimmed to the 3x2 at 4, 1 from its top left
+        let json = r#"{
+            "frames": [ {
+                "frame": { "x": 0, "y": 0, "w": 3, "h": 2 },
+                "trimmed": true,
+                "spriteSourceSize": { "x": 4, "y": 1, "w": 3, "h": 2 },
+                "sourceSize": { "w": 8, "h": 8 }
+            } ]
+        }"#;
+        let sheet = load_aseprite(json.as_bytes(), rect(0, 0, 3, 2), point2(0., 0.)).unwrap();
+        assert_eq!(sheet.sprite().frame_offsets, [Vector2D::new(4., 5.)]);
+    }
+
+    #[test]
+    fn bad_aseprite_data_is_an_error() {
+        let sheet = rect(0, 0, 4, 4);
+        for json in &[
+            r#"{ "frames": {} }"#,
+            r#"{ "frames": [] }"#,
+            r#"{ "frames": [ { "frame": { "x": 2, "y": 0, "w": 4, "h": 4 } } ] }"#,
+            r#"{ "frames": [ { "frame": { "x": 0, "y": 0, "w": 4, "h": 4 }, "rotated": true } ] }"#,
+            r#"{ "frames": [ { "frame": { "x": 0, "y": 0, "w": 4, "h": 4 } } ],
+                 "meta": { "frameTags": [ { "name": "run", "from": 0, "to": 1 } ] } }"#,
+        ] {
+            assert!(
+                load_aseprite(json.as_bytes(), sheet, point2(0., 0.)).is_err(),
+                "{} loaded",
+                json
+            );
+        }
+    }
+
+    // The frames `animation` shows at each of `steps` updates of `dt`
+    fn frames_over(animation: &mut Animation, dt: f32, steps: usize) -> Vec<usize> {
+        (0..steps)
+            .map(|_| {
+                animation.update(dt);
+                animation.current_frame()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loop_animations_wrap_around() {
+        let mut animation = Animation::uniform(vec![4, 5, 6], 1., PlayMode::Loop);
+        assert_eq!(animation.current_frame(), 4);
+        assert_eq!(frames_over(&mut animation, 1., 7), [5, 6, 4, 5, 6, 4, 5]);
+        assert!(!animation.finished());
+    }
+
+    #[test]
+    fn frames_keep_their_own_durations() {
+        let mut animation = Animation::new(vec![0, 1, 2], vec![0.5, 1., 0.25], PlayMode::Loop);
+        assert_eq!(
+            frames_over(&mut animation, 0.25, 8),
+            [0, 1, 1, 1, 1, 2, 0, 0]
+        );
+        // a long step skips frames rather than lagging behind
+        let mut animation = Animation::new(vec![0, 1, 2], vec![0.5, 1., 0.25], PlayMode::Loop);
+        animation.update(1.6);
+        assert_eq!(animation.current_frame(), 2);
+        animation.update(0.2);
+        assert_eq!(animation.current_frame(), 0);
+    }
+
+    #[test]
+    fn once_animations_stop_on_the_last_frame() {
+        let mut animation = Animation::uniform(vec![0, 1, 2], 1., PlayMode::Once);
+        assert_eq!(frames_over(&mut animation, 1., 2), [1, 2]);
+        // still showing the last frame until it has had its time
+        assert!(!animation.finished());
+        animation.update(0.5);
+        assert!(!animation.finished());
+        animation.update(0.5);
+        assert!(animation.finished());
+        assert_eq!(frames_over(&mut animation, 1., 3), [2, 2, 2]);
+    }
+
+    #[test]
+    fn ping_pong_animations_turn_without_repeating_the_ends() {
+        let mut animation = Animation::uniform(vec![0, 1, 2, 3], 1., PlayMode::PingPong);
+        assert_eq!(
+            frames_over(&mut animation, 1., 9),
+            [1, 2, 3, 2, 1, 0, 1, 2, 3]
+        );
+        assert!(!animation.finished());
+
+        let mut two = Animation::uniform(vec![0, 1], 1., PlayMode::PingPong);
+        assert_eq!(frames_over(&mut two, 1., 4), [1, 0, 1, 0]);
+        let mut one = Animation::uniform(vec![7], 1., PlayMode::PingPong);
+        assert_eq!(frames_over(&mut one, 1., 3), [7, 7, 7]);
+    }
+
+    #[test]
+    fn screen_to_world_undoes_the_view_transform() {
+        let camera = Camera2D::<spaces::RoomSpace>::new(point2(7.5, 4.), 3., size2(320., 240.));
+        let to_screen = |p: RoomPoint| {
+            let clip = camera.view_transform().transform_point(p);
+            ScreenPoint::new((clip.x + 1.) / 2. * 320., (1. - clip.y) / 2. * 240.)
+        };
+        assert_eq!(to_screen(point2(7.5, 4.)), point2(160., 120.));
+        // y is up in the world and down on the screen
+        assert_eq!(to_screen(point2(8.5, 5.)), point2(163., 117.));
+        for p in &[
+            point2(0., 0.),
+            point2(7.5, 4.),
+            point2(-20., 33.25),
+            point2(60., -1.),
+        ] {
+            let back = camera.screen_to_world(to_screen(*p));
+            assert!(
+                (back - *p).length() < 1e-4,
+                "{:?} came back as {:?}",
+                p,
+                back
+            );
+        }
+
+        // zooming in shows less of the world around the same center
+        let zoomed = Camera2D::<spaces::RoomSpace>::new(point2(7.5, 4.), 6., size2(320., 240.));
+        assert_eq!(
+            zoomed.screen_to_world(point2(0., 0.)),
+            point2(7.5 - 160. / 6., 4. + 20.)
+        );
+    }
+
+    #[test]
+    fn flipped_sprites_mirror_their_uvs_in_place() {
+        let mut sprite = Sprite::new(rect(8, 16, 12, 4), 3, point2(2., 0.));
+        let mut plain = Vec::new();
+        render_sprite(&sprite, 1, SpritePosition::Room(point2(5., 5.)), &mut plain);
+        sprite.set_flip_x(true);
+        let mut flipped = Vec::new();
+        render_sprite(
+            &sprite,
+            1,
+            SpritePosition::Room(point2(5., 5.)),
+            &mut flipped,
+        );
+
+        let positions = |vertices: &[Vertex]| -> Vec<[f32; 2]> {
+            vertices.iter().map(|v| v.position).collect()
+        };
+        let uvs =
+            |vertices: &[Vertex]| -> Vec<[f32; 2]> { vertices.iter().map(|v| v.uv).collect() };
+        assert_eq!(positions(&flipped), positions(&plain));
+        assert_eq!(positions(&plain), [[3., 5.], [7., 5.], [3., 9.], [7., 9.]]);
+        // the second frame spans texels 12 to 16, with V down the image
+        let (left, right, top, bottom) = (12. / 64., 16. / 64., 16. / 64., 20. / 64.);
+        assert_eq!(
+            uvs(&plain),
+            [[left, bottom], [right, bottom], [left, top], [right, top]]
+        );
+        assert_eq!(
+            uvs(&flipped),
+            [[right, bottom], [left, bottom], [right, top], [left, top]]
+        );
+    }
+
+    // The room builder `Tilemap` replaced, kept to check that it draws the same: the `solid`
+    // cells of a grid `width` wide, with a 15 texel tile sheet at `sheet` split 8 texels from
+    // its top left, one quad per quarter in the order top left, top right, bottom left, bottom
+    // right
+    fn old_room_quads(solid: &[bool], width: i32, sheet: TextureRect, color: Color) -> Vec<Vertex> {
+        let height = solid.len() as i32 / width;
+        let solid_at = |x: i32, y: i32| {
+            x < 0 || y < 0 || x >= width || y >= height || solid[(y * width + x) as usize]
+        };
+        let mut out = Vec::new();
+        for (cell, _) in solid.iter().enumerate().filter(|(_, solid)| **solid) {
+            let (x, y) = (cell as i32 % width, cell as i32 / width);
+            let (tl, t, tr, l, r, bl, b, br) = (
+                solid_at(x - 1, y + 1),
+                solid_at(x, y + 1),
+                solid_at(x + 1, y + 1),
+                solid_at(x - 1, y),
+                solid_at(x + 1, y),
+                solid_at(x - 1, y - 1),
+                solid_at(x, y - 1),
+                solid_at(x + 1, y - 1),
+            );
+            let min = point2(x as f32, y as f32);
+            let max = point2((x + 1) as f32, (y + 1) as f32);
+            let mid = point2(x as f32 + 8. / 15., y as f32 + 7. / 15.);
+            // outer corner, horizontal, vertical, inner corner, solid
+            let piece = |side: bool, vertical: bool, diagonal: bool| -> u32 {
+                if !diagonal && side && vertical {
+                    3
+                } else if !side && !vertical {
+                    0
+                } else if side && !vertical {
+                    1
+                } else if !side && vertical {
+                    2
+                } else {
+                    4
+                }
+            };
+            let quarters: [(Box2D<f32, spaces::RoomSpace>, [u32; 4], u32); 4] = [
+                (
+                    Box2D::new(point2(min.x, mid.y), point2(mid.x, max.y)),
+                    [0, 0, 8, 8],
+                    piece(l, t, tl),
+                ),
+                (Box2D::new(mid, max), [8, 0, 7, 8], piece(r, t, tr)),
+                (Box2D::new(min, mid), [0, 8, 8, 7], piece(l, b, bl)),
+                (
+                    Box2D::new(point2(mid.x, min.y), point2(max.x, mid.y)),
+                    [8, 8, 7, 7],
+                    piece(r, b, br),
+                ),
+            ];
+            for (rect, [px, py, pw, ph], piece) in &quarters {
+                let image = sheet.sub_rect(piece * 15 + px, *py, *pw, *ph);
+                render_quad_inset(*rect, image, color, HALF_TEXEL, &mut out);
+            }
+        }
+        out
+    }
+
+    fn tilemap_quads(solid: &[bool], width: u32, sheet: TextureRect, color: Color) -> Vec<Vertex> {
+        let height = solid.len() as u32 / width;
+        let sheet = AutotileSheet::new(sheet, size2(15, 15), euclid::vec2(8, 8));
+        let mut tilemap = Tilemap::new(size2(width, height), solid.to_vec(), sheet, |solid| *solid);
+        tilemap.set_color(color);
+        tilemap.set_uv_inset(HALF_TEXEL);
+        let mut out = Vec::new();
+        tilemap.render(&mut out);
+        out
+    }
+
+    #[test]
+    fn tilemaps_draw_what_the_old_room_builder_drew() {
+        let sheet = TextureRect {
+            texels: [3, 40, 78, 55],
+            atlas_size: (128, 128),
+            rotated: false,
+        };
+        let color = Color::rgba(0.5, 0.25, 1., 1.);
+        let mut rng = SmallRng::seed_from_u64(1846);
+        let mut grids = vec![vec![false; 225], vec![true; 225]];
+        for density in &[0.2, 0.5, 0.8] {
+            grids.push((0..225).map(|_| rng.gen_bool(*density)).collect());
+        }
+        for solid in &grids {
+            let old = old_room_quads(solid, 15, sheet, color);
+            let new = tilemap_quads(solid, 15, sheet, color);
+            let solid_count = solid.iter().filter(|solid| **solid).count();
+            assert_eq!(old.len(), solid_count * 16);
+            assert_eq!(new.len(), old.len());
+            assert_eq!(new.as_bytes(), old.as_bytes());
+        }
+    }
+
+    // Quads as two triangles of their own, how rooms were drawn before index buffers
+    struct TriangleList(Vec<Vertex>);
+
+    impl QuadSink for TriangleList {
+        fn push_corners(&mut self, corners: [Vertex; 4]) {
+            for &i in &[0, 1, 2, 1, 3, 2] {
+                self.0.push(corners[i]);
+            }
+        }
+    }
+
+    // Every tile solid, so 15x15 tiles of 4 quarters each
+    fn full_room_tilemap() -> Tilemap<bool> {
+        let sheet = AutotileSheet::new(rect(0, 0, 75, 15), size2(15, 15), euclid::vec2(8, 8));
+        Tilemap::new(size2(15, 15), vec![true; 225], sheet, |solid| *solid)
+    }
+
+    #[test]
+    fn indexed_rooms_take_a_third_less_vertex_data() {
+        let mut tilemap = full_room_tilemap();
+        let mut triangles = TriangleList(Vec::new());
+        tilemap.render(&mut triangles);
+        let mut mesh = Mesh::default();
+        tilemap.render(&mut mesh);
+
+        assert_eq!(triangles.0.len(), 15 * 15 * 4 * 6);
+        assert_eq!(mesh.indices.len(), triangles.0.len());
+        assert_eq!(mesh.vertices.len() * 3, triangles.0.len() * 2);
+        // still smaller with the indices counted
+        let mesh_bytes = mesh.vertices.as_bytes().len() + mesh.indices.as_bytes().len();
+        assert!(mesh_bytes < triangles.0.as_bytes().len() * 3 / 4);
+        // the same triangles either way
+        for (triangle, indices) in triangles.0.chunks(3).zip(mesh.indices.chunks(3)) {
+            for (vertex, &index) in triangle.iter().zip(indices) {
+                assert_eq!(vertex.as_bytes(), mesh.vertices[index as usize].as_bytes());
+            }
+        }
+    }
+
+    // Only prints the timings, run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn time_indexed_and_triangle_list_rooms() {
+        const RUNS: u32 = 100;
+        let mut tilemap = full_room_tilemap();
+
+        let start = std::time::Instant::now();
+        let mut triangles = TriangleList(Vec::new());
+        for _ in 0..RUNS {
+            triangles.0.clear();
+            tilemap.render(&mut triangles);
+        }
+        let triangle_time = start.elapsed() / RUNS;
+
+        let start = std::time::Instant::now();
+        let mut mesh = Mesh::default();
+        for _ in 0..RUNS {
+            mesh.vertices.clear();
+            mesh.indices.clear();
+            tilemap.render(&mut mesh);
+        }
+        let mesh_time = start.elapsed() / RUNS;
+        println!(
+            "15x15x4 room: {:?} as a triangle list, {:?} indexed",
+            triangle_time, mesh_time
+        );
+    }
+
+    #[test]
+    fn tilemap_pieces_follow_the_neighbours() {
+        let sheet = rect(0, 0, 75, 15);
+        let piece_uvs = |piece: u32, quarter: [u32; 4]| {
+            let image = sheet.sub_rect(piece * 15 + quarter[0], quarter[1], quarter[2], quarter[3]);
+            corner_uvs(image, HALF_TEXEL, false)
+        };
+        let uvs =
+            |vertices: &[Vertex]| -> Vec<[f32; 2]> { vertices.iter().map(|v| v.uv).collect() };
+        let quarters = [[0, 0, 8, 8], [8, 0, 7, 8], [0, 8, 8, 7], [8, 8, 7, 7]];
+
+        // a lone tile in the middle of empty space is all outer corners
+        let mut lone = vec![false; 9];
+        lone[4] = true;
+        let quads = tilemap_quads(&lone, 3, sheet, Color::WHITE);
+        assert_eq!(quads.len(), 16);
+        for (quarter, corners) in quads.chunks(4).enumerate() {
+            assert_eq!(uvs(corners), piece_uvs(0, quarters[quarter]));
+        }
+
+        // the middle of a full grid is all solid
+        let quads = tilemap_quads(&[true; 9], 3, sheet, Color::WHITE);
+        assert_eq!(quads.len(), 9 * 16);
+        for (quarter, corners) in quads[4 * 16..5 * 16].chunks(4).enumerate() {
+            assert_eq!(uvs(corners), piece_uvs(4, quarters[quarter]));
+        }
+        // its top left quarter starts where the quarters meet
+        assert_eq!(quads[4 * 16].position, [1., 1. + 7. / 15.]);
+    }
+
+    #[test]
+    fn tints_multiply_into_vertex_colors() {
+        let mut sprite = Sprite::new(rect(0, 0, 4, 4), 1, point2(0., 0.));
+        let colors = |sprite: &Sprite, tint: Color| -> Vec<([f32; 4], [f32; 4])> {
+            let mut out = Vec::new();
+            render_sprite_tinted(
+                sprite,
+                0,
+                SpritePosition::Room(point2(0., 0.)),
+                tint,
+                &mut out,
+            );
+            out.iter().map(|v| (v.color, v.flash)).collect()
+        };
+        assert_eq!(
+            colors(&sprite, Color::WHITE),
+            [([1., 1., 1., 1.], [0., 0., 0., 0.]); 4]
+        );
+
+        sprite.set_color(Color::rgba(0.5, 1., 0.25, 1.));
+        assert_eq!(
+            colors(&sprite, Color::WHITE),
+            [([0.5, 1., 0.25, 1.], [0., 0., 0., 0.]); 4]
+        );
+        assert_eq!(
+            colors(&sprite, Color::rgba(0.5, 0.5, 1., 0.5)),
+            [([0.25, 0.5, 0.25, 0.5], [0., 0., 0., 0.]); 4]
+        );
+
+        // the flash stays as it is whatever the tint, with the amount clamped
+        sprite.set_flash(Color::WHITE, 0.25);
+        assert_eq!(
+            colors(&sprite, Color::rgba(0.5, 0.5, 1., 0.5)),
+            [([0.25, 0.5, 0.25, 0.5], [1., 1., 1., 0.25]); 4]
+        );
+        sprite.set_flash(Color::rgba(1., 0., 0., 1.), 3.);
+        assert_eq!(colors(&sprite, Color::WHITE)[0].1, [1., 0., 0., 1.]);
+    }
+
+    #[test]
+    fn rotated_sprites_keep_their_uvs_and_winding() {
+        let sprite = Sprite::new(rect(8, 16, 4, 4), 1, point2(2., 2.));
+        let mut plain = Mesh::with_quad_capacity(1);
+        render_sprite(
+            &sprite,
+            0,
+            SpritePosition::Room(point2(10., 10.)),
+            &mut plain,
+        );
+        let uvs: Vec<[f32; 2]> = plain.vertices.iter().map(|v| v.uv).collect();
+
+        for degrees in &[0., 37., 90., 180., 270.] {
+            let mut mesh = Mesh::with_quad_capacity(1);
+            let angle = Angle::degrees(*degrees);
+            render_sprite_rotated(
+                &sprite,
+                0,
+                SpritePosition::Room(point2(10., 10.)),
+                angle,
+                &mut mesh,
+            );
+            // every corner moves around the origin and takes its UV along
+            for (rotated, plain) in mesh.vertices.iter().zip(&plain.vertices) {
+                let expected = point2(10., 10.)
+                    + Transform2D::rotation(angle)
+                        .transform_vector(Point2D::from(plain.position) - point2(10., 10.));
+                let position = Point2D::from(rotated.position);
+                assert!((position - expected).length() < 1e-5, "{:?}", position);
+            }
+            let rotated_uvs: Vec<[f32; 2]> = mesh.vertices.iter().map(|v| v.uv).collect();
+            assert_eq!(rotated_uvs, uvs);
+            // both triangles stay counterclockwise
+            for triangle in mesh.indices.chunks(3) {
+                let corner = |i: usize| Point2D::from(mesh.vertices[triangle[i] as usize].position);
+                let area = (corner(1) - corner(0)).cross(corner(2) - corner(0));
+                assert!(area > 0., "clockwise at {} degrees", degrees);
+            }
+        }
+
+        // a quarter turn puts the bottom left corner at the bottom right
+        let mut mesh = Mesh::with_quad_capacity(1);
+        render_sprite_rotated(
+            &sprite,
+            0,
+            SpritePosition::Room(point2(10., 10.)),
+            Angle::degrees(90.),
+            &mut mesh,
+        );
+        let bottom_left = Point2D::from(mesh.vertices[0].position);
+        assert!((bottom_left - point2(12., 8.)).length() < 1e-5);
+    }
+
+    #[test]
+    fn lines_are_quads_around_their_ends() {
+        let white = rect(4, 4, 1, 1);
+        let positions = |from: RoomPoint, to: RoomPoint| -> Vec<[f32; 2]> {
+            let mut out = Vec::new();
+            render_line(from, to, 2., white, Color::WHITE, &mut out);
+            out.iter().map(|v| v.position).collect()
+        };
+        assert_eq!(
+            positions(point2(1., 1.), point2(5., 1.)),
+            [[1., 0.], [5., 0.], [1., 2.], [5., 2.]]
+        );
+        // the ends stay where they are, the width goes across
+        assert_eq!(
+            positions(point2(1., 1.), point2(1., 5.)),
+            [[2., 1.], [2., 5.], [0., 1.], [0., 5.]]
+        );
+        assert_eq!(
+            positions(point2(3., 3.), point2(3., 3.)),
+            [[2., 2.], [4., 2.], [2., 4.], [4., 4.]]
+        );
+    }
+
+    // Every character 4 pixels wide on 8 pixel lines, with no glyphs to draw
+    fn fixed_font() -> Font {
+        Font::new(8., 4.)
+    }
+
+    fn wrapped(max_width: f32) -> TextLayout {
+        TextLayout {
+            max_width: Some(max_width),
+            ..TextLayout::default()
+        }
+    }
+
+    #[test]
+    fn text_wraps_at_the_last_space_that_fits() {
+        let font = fixed_font();
+        let text = "the quick brown fox jumps";
+        assert_eq!(
+            wrapped(40.).lines(&font, text),
+            ["the quick", "brown fox", "jumps"]
+        );
+        // a line exactly as wide as the maximum fits
+        assert_eq!(
+            wrapped(36.).lines(&font, text),
+            ["the quick", "brown fox", "jumps"]
+        );
+        assert_eq!(
+            wrapped(35.).lines(&font, text),
+            ["the", "quick", "brown", "fox", "jumps"]
+        );
+        assert_eq!(TextLayout::default().lines(&font, text), [text]);
+        // the maximum is in drawn units, after the scale
+        let scaled = TextLayout {
+            scale: 2.,
+            ..wrapped(80.)
+        };
+        assert_eq!(
+            scaled.lines(&font, text),
+            ["the quick", "brown fox", "jumps"]
+        );
+    }
+
+    #[test]
+    fn newlines_always_break() {
+        let font = fixed_font();
+        assert_eq!(
+            TextLayout::default().lines(&font, "one\n\ntwo"),
+            ["one", "", "two"]
+        );
+        assert_eq!(
+            wrapped(40.).lines(&font, "a b\nc d e f g h i j k"),
+            ["a b", "c d e f g", "h i j k"]
+        );
+    }
+
+    #[test]
+    fn long_words_break_where_they_reach_the_edge() {
+        let font = fixed_font();
+        assert_eq!(
+            wrapped(16.).lines(&font, "abcdefghij"),
+            ["abcd", "efgh", "ij"]
+        );
+        assert_eq!(
+            wrapped(16.).lines(&font, "hi abcdefghij ok"),
+            ["hi", "abcd", "efgh", "ij", "ok"]
+        );
+        // at least one character a line, even when none fits
+        assert_eq!(wrapped(2.).lines(&font, "abc"), ["a", "b", "c"]);
+        // only between characters
+        assert_eq!(hard_break(&font, "éèa", 6.), "é".len());
+        assert_eq!(hard_break(&font, "éèa", 12.), "éèa".len());
+    }
+
+    #[test]
+    fn trailing_spaces_dont_count() {
+        let font = fixed_font();
+        let layout = wrapped(8.);
+        assert_eq!(layout.lines(&font, "ab  cd"), ["ab ", "cd"]);
+        assert_eq!(layout.measure(&font, "ab  cd"), size2(8., 16.));
+        assert_eq!(TextLayout::default().measure(&font, "ab   "), size2(8., 8.));
+    }
+
+    #[test]
+    fn measure_takes_the_widest_line_and_the_spacing() {
+        let font = fixed_font();
+        let layout = TextLayout {
+            line_spacing: 1.5,
+            ..TextLayout::default()
+        };
+        assert_eq!(layout.measure(&font, "ab\nabcd\nabc"), size2(16., 32.));
+        assert_eq!(layout.measure(&font, ""), size2(0., 8.));
+        let scaled = TextLayout {
+            scale: 2.,
+            ..layout
+        };
+        assert_eq!(scaled.measure(&font, "ab\nabcd\nabc"), size2(32., 64.));
+    }
+
+    #[test]
+    fn aligned_lines_sit_within_the_block() {
+        let font = Font::monospace(rect(0, 0, 16, 8), "abcd", size2(4, 8));
+        let line_lefts = |align: TextAlign, max_width: Option<f32>| -> Vec<[f32; 2]> {
+            let layout = TextLayout {
+                align,
+                max_width,
+                ..TextLayout::default()
+            };
+            let mut out = Vec::new();
+            draw_text_layout(
+                &layout,
+                &font,
+                "ab\nc",
+                UiPoint::new(10., 50.),
+                Color::WHITE,
+                &mut out,
+            );
+            // the bottom left corner of the first glyph on each line
+            vec![out[0].position, out[8].position]
+        };
+        assert_eq!(line_lefts(TextAlign::Left, None), [[10., 42.], [10., 34.]]);
+        assert_eq!(
+            line_lefts(TextAlign::Center, None),
+            [[10., 42.], [12., 34.]]
+        );
+        assert_eq!(
+            line_lefts(TextAlign::Center, Some(20.)),
+            [[16., 42.], [18., 34.]]
+        );
+    }
+
+    #[test]
+    fn extruding_repeats_the_edges_and_corners() {
+        let texels = [
+            [1, 0, 0, 255],
+            [2, 0, 0, 255],
+            [3, 0, 0, 255],
+            [4, 0, 0, 255],
+        ];
+        let out = extrude_edges(&texels.concat(), (2, 2), 2);
+        assert_eq!(out.len(), 6 * 6 * 4);
+        // each texel fills the 3x3 corner of the 6x6 output it sits in
+        let expected = [
+            [1, 1, 1, 2, 2, 2],
+            [1, 1, 1, 2, 2, 2],
+            [1, 1, 1, 2, 2, 2],
+            [3, 3, 3, 4, 4, 4],
+            [3, 3, 3, 4, 4, 4],
+            [3, 3, 3, 4, 4, 4],
+        ];
+        for (y, row) in expected.iter().enumerate() {
+            for (x, &red) in row.iter().enumerate() {
+                let i = (y * 6 + x) * 4;
+                assert_eq!(out[i..i + 4], [red, 0, 0, 255], "at {}, {}", x, y);
+            }
+        }
+        assert_eq!(extrude_edges(&texels.concat(), (2, 2), 0), texels.concat());
+    }
+
+    #[test]
+    fn raw_images_need_four_bytes_per_texel() {
+        let size = size2(2, 4);
+        assert!(check_raw_image(&[0; 2 * 4 * 4], size).is_ok());
+        assert_eq!(
+            check_raw_image(&[0; 2 * 4 * 4 - 1], size)
+                .unwrap_err()
+                .to_string(),
+            "Raw 2x4 image should be 32 bytes, got 31"
+        );
+        assert!(check_raw_image(&[0; 4 * 4 * 4], size).is_err());
+        assert!(check_raw_image(&[], size2(0, 3)).is_ok());
+    }
+
+    // Loading into a real texture, which tests skip where there is no GL context
+    #[cfg(feature = "headless")]
+    mod headless {
+        use super::super::*;
+        use crate::platform::headless_gl_for_test;
+
+        // An empty atlas of `size` and the texture for it
+        unsafe fn atlas(
+            context: &mut gl::Context,
+            size: (u32, u32),
+        ) -> (TextureAtlas, gl::Texture) {
+            let texture = context
+                .create_texture(
+                    gl::TextureFormat::RGBA8,
+                    size.0,
+                    size.1,
+                    gl::TextureFilter::NEAREST,
+                )
+                .unwrap();
+            (TextureAtlas::new(size, 1), texture)
+        }
+
+        #[test]
+        fn gutters_repeat_the_edges_in_the_texture() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let texels = [
+                [1, 0, 0, 255],
+                [2, 0, 0, 255],
+                [3, 0, 0, 255],
+                [4, 0, 0, 255],
+            ];
+            let (rect, pixels) = unsafe {
+                let (mut texture_atlas, mut texture) = atlas(&mut gl.context, (16, 16));
+                let rect = write_to_atlas(
+                    &texels.concat(),
+                    (2, 2),
+                    2,
+                    false,
+                    &mut texture_atlas,
+                    &mut texture,
+                )
+                .unwrap();
+                (rect, gl.context.read_texture(&texture).unwrap())
+            };
+            assert_eq!(rect.texels[2] - rect.texels[0], 2);
+            assert_eq!(rect.texels[3] - rect.texels[1], 2);
+            // the 6x6 block around the rect is the image with its edges extruded
+            for y in 0..6 {
+                for x in 0..6 {
+                    let red = 1 + (x >= 3) as u8 + (y >= 3) as u8 * 2;
+                    let (atlas_x, atlas_y) = (rect.texels[0] - 2 + x, rect.texels[1] - 2 + y);
+                    let i = (atlas_y * 16 + atlas_x) as usize * 4;
+                    assert_eq!(pixels[i..i + 4], [red, 0, 0, 255], "at {}, {}", x, y);
+                }
+            }
+        }
+
+        #[test]
+        fn truncated_images_are_decode_errors() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let block = include_bytes!("../assets/block.png");
+            let (mut texture_atlas, mut texture) = unsafe { atlas(&mut gl.context, (128, 128)) };
+            let images = [
+                BatchImage::Encoded(block),
+                BatchImage::Encoded(&block[..block.len() / 2]),
+            ];
+            let error = unsafe { load_images_batch(&images, &mut texture_atlas, &mut texture) }
+                .unwrap_err();
+            assert_eq!(error.to_string(), "Could not decode image 1 of the batch");
+            // the whole one is fine by itself
+            assert!(
+                unsafe { load_images_batch(&images[..1], &mut texture_atlas, &mut texture) }
+                    .is_ok()
+            );
+        }
+
+        #[test]
+        fn images_larger_than_the_atlas_are_errors() {
+            let mut gl = match headless_gl_for_test(1, 1) {
+                Some(gl) => gl,
+                None => return,
+            };
+            let (mut texture_atlas, mut texture) = unsafe { atlas(&mut gl.context, (16, 16)) };
+            let small = vec![255; 4 * 4 * 4];
+            let wide = vec![255; 32 * 8 * 4];
+            let images = [
+                BatchImage::Raw(&small, size2(4, 4)),
+                BatchImage::Raw(&wide, size2(32, 8)),
+            ];
+            let error = unsafe { load_images_batch(&images, &mut texture_atlas, &mut texture) }
+                .unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Could not fit a 32x8 image in the 16x16 texture atlas"
+            );
+            // the small one went back out with it, so a 14x14 image still fits beside the padding
+            let big = vec![255; 14 * 14 * 4];
+            assert!(unsafe {
+                load_images_batch(
+                    &[BatchImage::Raw(&big, size2(14, 14))],
+                    &mut texture_atlas,
+                    &mut texture,
+                )
+            }
+            .is_ok());
+        }
+    }
+}
@@ -1,4 +1,11 @@
-use euclid::default::{Point2D, Vector2D};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use euclid::default::Vector2D;
+
+use crate::spaces::ScreenPoint;
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum Key {
@@ -40,6 +47,84 @@ pub enum Key {
     Up,
     Right,
     Down,
+    F3,
+    F10,
+    LShift,
+    RShift,
+    LCtrl,
+    RCtrl,
+    LAlt,
+    RAlt,
+}
+
+#[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+impl Key {
+    /// The key with the variant name `name`, like `Space` or `F3`, as scenarios name them.
+    pub fn from_name(name: &str) -> Option<Key> {
+        KEYS.iter()
+            .copied()
+            .find(|key| format!("{:?}", key) == name)
+    }
+}
+
+// Every key, for looking them up by name
+#[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+const KEYS: [Key; 46] = [
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+    Key::Space,
+    Key::Backspace,
+    Key::Return,
+    Key::Escape,
+    Key::Slash,
+    Key::Home,
+    Key::Delete,
+    Key::End,
+    Key::Left,
+    Key::Up,
+    Key::Right,
+    Key::Down,
+    Key::F3,
+    Key::F10,
+    Key::LShift,
+    Key::RShift,
+    Key::LCtrl,
+    Key::RCtrl,
+    Key::LAlt,
+    Key::RAlt,
+];
+
+/// Which modifier keys were held when a key or mouse event happened, the left and right ones
+/// alike.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
@@ -50,12 +135,393 @@ pub enum MouseButton {
     Other(u8),
 }
 
+/// Gamepad buttons by where they are on the pad, `South` being A on Xbox pads and Cross on
+/// PlayStation ones.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftShoulder,
+    RightShoulder,
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    Home,
+    LeftStick,
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Axis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum InputEvent {
-    KeyDown(Key),
-    KeyUp(Key),
-    MouseDown(MouseButton),
-    MouseUp(MouseButton),
-    MouseMove(Point2D<f32>),
+    /// Modifier keys come as keys of their own too. Platforms send a `KeyUp` for every held key
+    /// when the game loses focus, so none stay stuck after alt-tabbing away.
+    KeyDown(Key, Modifiers),
+    KeyUp(Key, Modifiers),
+    MouseDown(MouseButton, Modifiers),
+    MouseUp(MouseButton, Modifiers),
+    MouseMove(ScreenPoint),
     MouseWheel(Vector2D<f32>),
+    Focused(bool),
+    /// Gamepad events, from whichever pad is connected. A pad disconnecting releases everything
+    /// it held and centers its sticks.
+    ButtonDown(GamepadButton),
+    ButtonUp(GamepadButton),
+    /// From -1 to 1, right and up being positive. Sticks don't rest at exactly 0, so they need a
+    /// dead zone.
+    AxisMoved(Axis, f32),
+}
+
+/// What's held down and what changed within a tick, built from the events that came since the
+/// last one, so code can ask for presses and releases instead of tracking them from events.
+///
+/// Call `begin_tick` at the start of every tick and then `handle_event` with the tick's events.
+/// Presses and releases last until the next `begin_tick`, so a key that went down and up again
+/// within one tick still reports one press, and ticks that get no events report none. Key
+/// repeats don't count as presses.
+pub struct InputState {
+    keys: ButtonStates<Key>,
+    mouse_buttons: ButtonStates<MouseButton>,
+    gamepad_buttons: ButtonStates<GamepadButton>,
+    axes: HashMap<Axis, f32>,
+    mouse_pos: ScreenPoint,
+    wheel_delta: Vector2D<f32>,
+    modifiers: Modifiers,
+    focused: bool,
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        InputState {
+            keys: ButtonStates::default(),
+            mouse_buttons: ButtonStates::default(),
+            gamepad_buttons: ButtonStates::default(),
+            axes: HashMap::new(),
+            mouse_pos: ScreenPoint::zero(),
+            wheel_delta: Vector2D::zero(),
+            modifiers: Modifiers::default(),
+            focused: true,
+        }
+    }
+}
+
+impl InputState {
+    /// Forgets the last tick's presses, releases and wheel movement.
+    pub fn begin_tick(&mut self) {
+        self.keys.begin_tick();
+        self.mouse_buttons.begin_tick();
+        self.gamepad_buttons.begin_tick();
+        self.wheel_delta = Vector2D::zero();
+    }
+
+    pub fn handle_event(&mut self, event: &InputEvent) {
+        match *event {
+            InputEvent::KeyDown(key, modifiers) => {
+                self.keys.press(key);
+                self.modifiers = modifiers;
+            }
+            InputEvent::KeyUp(key, modifiers) => {
+                self.keys.release(key);
+                self.modifiers = modifiers;
+            }
+            InputEvent::MouseDown(button, modifiers) => {
+                self.mouse_buttons.press(button);
+                self.modifiers = modifiers;
+            }
+            InputEvent::MouseUp(button, modifiers) => {
+                self.mouse_buttons.release(button);
+                self.modifiers = modifiers;
+            }
+            InputEvent::MouseMove(position) => self.mouse_pos = position,
+            InputEvent::MouseWheel(delta) => self.wheel_delta += delta,
+            InputEvent::Focused(focused) => {
+                self.focused = focused;
+                // releases are missed while in the background
+                if !focused {
+                    self.keys.release_all();
+                    self.mouse_buttons.release_all();
+                    self.gamepad_buttons.release_all();
+                    self.axes.clear();
+                    self.modifiers = Modifiers::default();
+                }
+            }
+            InputEvent::ButtonDown(button) => self.gamepad_buttons.press(button),
+            InputEvent::ButtonUp(button) => self.gamepad_buttons.release(button),
+            InputEvent::AxisMoved(axis, value) => {
+                self.axes.insert(axis, value);
+            }
+        }
+    }
+
+    pub fn key_held(&self, key: Key) -> bool {
+        self.keys.held.contains(&key)
+    }
+
+    pub fn key_pressed(&self, key: Key) -> bool {
+        self.keys.pressed.contains(&key)
+    }
+
+    pub fn key_released(&self, key: Key) -> bool {
+        self.keys.released.contains(&key)
+    }
+
+    /// The modifiers of the latest key or mouse event, for shortcuts like Ctrl+S.
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    pub fn mouse_pos(&self) -> ScreenPoint {
+        self.mouse_pos
+    }
+
+    pub fn mouse_pressed(&self, button: MouseButton) -> bool {
+        self.mouse_buttons.pressed.contains(&button)
+    }
+
+    /// How far the wheel moved this tick.
+    pub fn wheel_delta(&self) -> Vector2D<f32> {
+        self.wheel_delta
+    }
+
+    pub fn button_held(&self, button: GamepadButton) -> bool {
+        self.gamepad_buttons.held.contains(&button)
+    }
+
+    pub fn button_pressed(&self, button: GamepadButton) -> bool {
+        self.gamepad_buttons.pressed.contains(&button)
+    }
+
+    pub fn button_released(&self, button: GamepadButton) -> bool {
+        self.gamepad_buttons.released.contains(&button)
+    }
+
+    /// Whether any key, mouse button or gamepad button went down this tick, for "press anything
+    /// to continue". Moving the mouse, the wheel or a stick doesn't count.
+    pub fn any_pressed(&self) -> bool {
+        !self.keys.pressed.is_empty()
+            || !self.mouse_buttons.pressed.is_empty()
+            || !self.gamepad_buttons.pressed.is_empty()
+    }
+
+    /// Where the axis was last moved to, 0 if it never was.
+    pub fn axis(&self, axis: Axis) -> f32 {
+        self.axes.get(&axis).copied().unwrap_or(0.)
+    }
+
+    /// Whether the game is in the foreground, which it starts out as.
+    pub fn focused(&self) -> bool {
+        self.focused
+    }
+}
+
+// Which buttons of one kind are held, and which went down or up since `begin_tick`
+struct ButtonStates<T> {
+    held: HashSet<T>,
+    pressed: HashSet<T>,
+    released: HashSet<T>,
+}
+
+impl<T> Default for ButtonStates<T> {
+    fn default() -> Self {
+        ButtonStates {
+            held: HashSet::new(),
+            pressed: HashSet::new(),
+            released: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> ButtonStates<T> {
+    fn begin_tick(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+    }
+
+    // Down events for a button that's already held are repeats
+    fn press(&mut self, button: T) {
+        if self.held.insert(button) {
+            self.pressed.insert(button);
+        }
+    }
+
+    fn release(&mut self, button: T) {
+        if self.held.remove(&button) {
+            self.released.insert(button);
+        }
+    }
+
+    fn release_all(&mut self) {
+        self.released.extend(self.held.drain());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use euclid::{point2, vec2};
+
+    use super::*;
+
+    fn tick(state: &mut InputState, events: &[InputEvent]) {
+        state.begin_tick();
+        for event in events {
+            state.handle_event(event);
+        }
+    }
+
+    fn key_down(key: Key) -> InputEvent {
+        InputEvent::KeyDown(key, Modifiers::default())
+    }
+
+    fn key_up(key: Key) -> InputEvent {
+        InputEvent::KeyUp(key, Modifiers::default())
+    }
+
+    #[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+    #[test]
+    fn keys_are_found_by_name() {
+        assert_eq!(Key::from_name("D"), Some(Key::D));
+        assert_eq!(Key::from_name("Space"), Some(Key::Space));
+        assert_eq!(Key::from_name("F10"), Some(Key::F10));
+        assert_eq!(Key::from_name("space"), None);
+        assert_eq!(Key::from_name("F1"), None);
+        let names: HashSet<String> = KEYS.iter().map(|key| format!("{:?}", key)).collect();
+        assert_eq!(names.len(), KEYS.len());
+    }
+
+    #[test]
+    fn press_and_release_within_one_tick() {
+        let mut state = InputState::default();
+        tick(&mut state, &[key_down(Key::W), key_up(Key::W)]);
+        assert!(state.key_pressed(Key::W));
+        assert!(state.key_released(Key::W));
+        assert!(!state.key_held(Key::W));
+
+        // the fixed update loop runs several ticks with no events when frames are slow
+        tick(&mut state, &[]);
+        assert!(!state.key_pressed(Key::W));
+        assert!(!state.key_released(Key::W));
+    }
+
+    #[test]
+    fn repeats_are_not_presses() {
+        let mut state = InputState::default();
+        tick(&mut state, &[key_down(Key::A)]);
+        assert!(state.key_pressed(Key::A));
+        tick(&mut state, &[key_down(Key::A), key_down(Key::A)]);
+        assert!(!state.key_pressed(Key::A));
+        assert!(state.key_held(Key::A));
+    }
+
+    #[test]
+    fn focus_loss_releases_everything() {
+        let mut state = InputState::default();
+        tick(
+            &mut state,
+            &[
+                key_down(Key::A),
+                InputEvent::ButtonDown(GamepadButton::South),
+                InputEvent::AxisMoved(Axis::LeftStickX, 0.8),
+            ],
+        );
+        tick(&mut state, &[InputEvent::Focused(false)]);
+        assert!(!state.focused());
+        assert!(state.key_released(Key::A));
+        assert!(!state.key_held(Key::A));
+        assert!(state.button_released(GamepadButton::South));
+        assert!(!state.button_held(GamepadButton::South));
+        assert_eq!(state.axis(Axis::LeftStickX), 0.);
+
+        tick(&mut state, &[InputEvent::Focused(true)]);
+        assert!(state.focused());
+    }
+
+    #[test]
+    fn modifiers_of_the_latest_event() {
+        let shift = Modifiers {
+            shift: true,
+            ..Modifiers::default()
+        };
+        let mut state = InputState::default();
+        tick(
+            &mut state,
+            &[
+                InputEvent::KeyDown(Key::LShift, shift),
+                InputEvent::KeyDown(Key::S, shift),
+            ],
+        );
+        assert!(state.modifiers().shift);
+        assert!(state.key_held(Key::LShift));
+
+        tick(
+            &mut state,
+            &[InputEvent::KeyUp(Key::LShift, Modifiers::default())],
+        );
+        assert_eq!(state.modifiers(), Modifiers::default());
+
+        tick(&mut state, &[InputEvent::KeyDown(Key::LShift, shift)]);
+        tick(&mut state, &[InputEvent::Focused(false)]);
+        assert_eq!(state.modifiers(), Modifiers::default());
+        assert!(state.key_released(Key::LShift));
+    }
+
+    #[test]
+    fn mouse_and_wheel() {
+        let mut state = InputState::default();
+        tick(
+            &mut state,
+            &[
+                InputEvent::MouseMove(point2(3., 4.)),
+                InputEvent::MouseDown(MouseButton::Left, Modifiers::default()),
+                InputEvent::MouseWheel(vec2(0., 1.)),
+                InputEvent::MouseWheel(vec2(0., 2.)),
+            ],
+        );
+        assert!(state.mouse_pressed(MouseButton::Left));
+        assert_eq!(state.mouse_pos(), point2(3., 4.));
+        assert_eq!(state.wheel_delta(), vec2(0., 3.));
+
+        tick(&mut state, &[]);
+        assert!(!state.mouse_pressed(MouseButton::Left));
+        assert_eq!(state.mouse_pos(), point2(3., 4.));
+        assert_eq!(state.wheel_delta(), vec2(0., 0.));
+    }
+
+    #[test]
+    fn any_press_counts_but_movement_doesnt() {
+        let mut state = InputState::default();
+        tick(
+            &mut state,
+            &[
+                InputEvent::MouseMove(point2(3., 4.)),
+                InputEvent::MouseWheel(vec2(0., 1.)),
+                InputEvent::AxisMoved(Axis::LeftStickX, 1.),
+            ],
+        );
+        assert!(!state.any_pressed());
+        for event in &[
+            key_down(Key::Q),
+            InputEvent::MouseDown(MouseButton::Right, Modifiers::default()),
+            InputEvent::ButtonDown(GamepadButton::Start),
+        ] {
+            tick(&mut state, &[*event]);
+            assert!(state.any_pressed(), "{:?}", event);
+            // held from before isn't a new press
+            tick(&mut state, &[]);
+            assert!(!state.any_pressed());
+        }
+    }
 }
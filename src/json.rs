@@ -0,0 +1,315 @@
+use anyhow::{anyhow, bail, Error};
+
+/// A parsed JSON document. Just enough for reading tool exports, object keys keep their order
+/// and duplicates.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The first entry named `key` if this is an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        source: source.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.source.len() {
+        bail!("Trailing characters in JSON at byte {}", parser.pos);
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    source: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Value, Error> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(c) => bail!(
+                "Unexpected '{}' in JSON at byte {}",
+                char::from(c),
+                self.pos
+            ),
+            None => bail!("Unexpected end of JSON"),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, Error> {
+        self.expect(b'{')?;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            entries.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b'}') => return Ok(Value::Object(entries)),
+                _ => bail!("Expected ',' or '}}' in JSON at byte {}", self.pos - 1),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, Error> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b']') => return Ok(Value::Array(values)),
+                _ => bail!("Expected ',' or ']' in JSON at byte {}", self.pos - 1),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next() {
+                Some(b'"') => break,
+                Some(b'\\') => match self.next() {
+                    Some(b'"') => bytes.push(b'"'),
+                    Some(b'\\') => bytes.push(b'\\'),
+                    Some(b'/') => bytes.push(b'/'),
+                    Some(b'b') => bytes.push(8),
+                    Some(b'f') => bytes.push(12),
+                    Some(b'n') => bytes.push(b'\n'),
+                    Some(b'r') => bytes.push(b'\r'),
+                    Some(b't') => bytes.push(b'\t'),
+                    Some(b'u') => {
+                        let c = self.unicode_escape()?;
+                        let mut buf = [0; 4];
+                        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    }
+                    _ => bail!("Invalid escape in JSON string at byte {}", self.pos - 1),
+                },
+                Some(c) => bytes.push(c),
+                None => bail!("Unterminated JSON string"),
+            }
+        }
+        // the source is a str and escapes are pushed as whole characters, so this is valid
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    // The code after a `\u`, combined with a following low surrogate escape if it is a high one
+    fn unicode_escape(&mut self) -> Result<char, Error> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if self.next() != Some(b'\\') || self.next() != Some(b'u') {
+                bail!("Unpaired surrogate in JSON string at byte {}", self.pos);
+            }
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            high
+        };
+        std::char::from_u32(code)
+            .ok_or_else(|| anyhow!("Invalid unicode escape in JSON at byte {}", self.pos))
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = self
+            .source
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| anyhow!("Invalid unicode escape in JSON at byte {}", self.pos))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+        | Some(b'0'..=b'9') = self.peek()
+        {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.source[start..self.pos]).unwrap();
+        text.parse()
+            .map(Value::Number)
+            .map_err(|_| anyhow!("Invalid number '{}' in JSON at byte {}", text, start))
+    }
+
+    fn literal(&mut self, text: &str, value: Value) -> Result<Value, Error> {
+        if self.source[self.pos..].starts_with(text.as_bytes()) {
+            self.pos += text.len();
+            Ok(value)
+        } else {
+            bail!("Unexpected token in JSON at byte {}", self.pos)
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), Error> {
+        if self.next() == Some(c) {
+            Ok(())
+        } else {
+            bail!(
+                "Expected '{}' in JSON at byte {}",
+                char::from(c),
+                self.pos - 1
+            )
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_values() {
+        let doc =
+            parse(r#" { "a": [1, -2.5, 3e2], "b": { "c": true, "d": null }, "e": "f" } "#).unwrap();
+        assert_eq!(
+            doc.get("a").and_then(Value::as_array),
+            Some(&[Value::Number(1.), Value::Number(-2.5), Value::Number(300.)][..])
+        );
+        let b = doc.get("b").unwrap();
+        assert_eq!(b.get("c").and_then(Value::as_bool), Some(true));
+        assert_eq!(b.get("d"), Some(&Value::Null));
+        assert_eq!(doc.get("e").and_then(Value::as_str), Some("f"));
+        assert_eq!(doc.get("missing"), None);
+        assert_eq!(parse("[]").unwrap(), Value::Array(Vec::new()));
+        assert_eq!(parse("{}").unwrap(), Value::Object(Vec::new()));
+    }
+
+    #[test]
+    fn keys_keep_their_order_and_duplicates() {
+        let doc = parse(r#"{"b": 1, "a": 2, "b": 3}"#).unwrap();
+        assert_eq!(
+            doc,
+            Value::Object(vec![
+                ("b".to_owned(), Value::Number(1.)),
+                ("a".to_owned(), Value::Number(2.)),
+                ("b".to_owned(), Value::Number(3.)),
+            ])
+        );
+        // the first one wins
+        assert_eq!(doc.get("b").and_then(Value::as_f64), Some(1.));
+    }
+
+    #[test]
+    fn strings_unescape() {
+        assert_eq!(
+            parse(r#""a\"b\\c\/d\n\té😀""#).unwrap(),
+            Value::String("a\"b\\c/d\n\té😀".to_owned())
+        );
+        // multibyte characters pass through as they are
+        assert_eq!(
+            parse("\"blöck\"").unwrap(),
+            Value::String("blöck".to_owned())
+        );
+    }
+
+    #[test]
+    fn malformed_documents_are_errors() {
+        for source in &[
+            "",
+            "{",
+            "[1,]",
+            "[1 2]",
+            r#"{"a" 1}"#,
+            r#"{"a": 1,}"#,
+            "\"unterminated",
+            r#""\x""#,
+            r#""\ud83d""#,
+            "tru",
+            "1 2",
+            "--1",
+        ] {
+            assert!(parse(source).is_err(), "{:?} parsed", source);
+        }
+        assert_eq!(
+            parse("[1] x").unwrap_err().to_string(),
+            "Trailing characters in JSON at byte 4"
+        );
+    }
+}
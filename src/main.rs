@@ -1,46 +1,232 @@
+#[cfg(not(target_arch = "wasm32"))]
+mod asset_manifest;
+#[cfg(not(target_arch = "wasm32"))]
+mod asset_paths;
+mod atlas_packer;
 mod constants;
 mod game;
 #[allow(unused)]
 mod gl;
 mod graphics;
 mod input;
+mod json;
 mod mixer;
 mod platform;
+#[cfg(feature = "prebaked-atlas")]
+mod prebaked_atlas;
+mod presence;
+mod resilience;
+mod spaces;
+mod strings;
 mod texture_atlas;
+mod texture_registry;
+#[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+mod visual_test;
 
 use std::sync::Arc;
 
 use constants::{SCREEN_SIZE, TICK_DT};
-use game::Game;
+use game::{ErrorChoice, ErrorScreen, Game, GameSnapshot, LevelSource, ProgressMode};
 use input::InputEvent;
+use mixer::Mixer;
+use resilience::{Crash, Resilience};
+
+// Small enough that sound effects line up with what's on screen. The web backend schedules
+// buffers from timers, so it needs more headroom.
+#[cfg(not(target_arch = "wasm32"))]
+const AUDIO_BUFFER_FRAMES: u32 = 256;
+#[cfg(target_arch = "wasm32")]
+const AUDIO_BUFFER_FRAMES: u32 = 1024;
+
+// Where `--resilient` mode saves the flight recorder's dump after a panic
+const CRASH_DUMP_FILE: &str = "ld48-crash.txt";
 
 fn main() {
+    // Plays a scenario from visual_tests/ without a window and compares it with its references
+    // instead of running the game, see src/visual_test.rs
+    #[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+    {
+        let args: Vec<String> = std::env::args().collect();
+        if let Some(i) = args.iter().position(|arg| arg == "--visual-test") {
+            std::process::exit(visual_test::main(&args[i + 1..]));
+        }
+    }
+    #[cfg(not(all(feature = "headless", not(target_arch = "wasm32"))))]
+    if std::env::args().any(|arg| arg == "--visual-test") {
+        eprintln!("--visual-test needs a native build with the headless feature");
+        std::process::exit(2);
+    }
+
+    // Unwinding isn't available on wasm, so resilience is native only
+    let resilient =
+        cfg!(not(target_arch = "wasm32")) && std::env::args().any(|arg| arg == "--resilient");
+    // Gamma correct blending, off by default to keep the look the art was made with
+    let srgb = std::env::args().any(|arg| arg == "--srgb");
+    // Room levels from the assets folder instead of the built in ones, so they can be edited
+    // without rebuilding. The web has no folder to read them from.
+    let loose_levels =
+        cfg!(not(target_arch = "wasm32")) && std::env::args().any(|arg| arg == "--loose-levels");
+
     platform::run(
         "Ludum Dare 48",
         SCREEN_SIZE,
-        |gl_context: &mut gl::Context| {
+        move |gl_context: &mut gl::Context| {
+            unsafe { gl_context.set_srgb(srgb) };
             let mixer = Arc::new(mixer::Mixer::default());
             let mixer_inner = Arc::clone(&mixer);
-            platform::start_audio_playback(move |out: &mut [i16]| mixer_inner.poll(out));
+            let audio_config = platform::AudioConfig {
+                buffer_frames: Some(AUDIO_BUFFER_FRAMES),
+                sample_rate: None,
+            };
+            // held by the frame callback for the rest of the program
+            let audio_output =
+                platform::start_audio_playback_with(audio_config, move |out: &mut [i16]| {
+                    mixer_inner.poll(out)
+                });
 
-            let mut game = Game::new(gl_context, mixer);
+            let mut game = Some(new_game(gl_context, &mixer, loose_levels));
+            // replaces the game after a panic in resilient mode
+            let mut error_screen: Option<ErrorScreen> = None;
+            let mut resilience: Resilience<GameSnapshot> = Resilience::new();
             let mut input_vec = Vec::new();
             let mut last_update: f32 = 0.;
+            let mut time: f32 = 0.;
             move |dt: f32, inputs: &[InputEvent], gl_context: &mut gl::Context| {
+                time += dt;
+
+                // the stream may open late or be reopened on a different device
+                if let Some(info) = audio_output.stream_info() {
+                    mixer.set_output_format(info.sample_rate, info.channels as usize);
+                }
+
+                // silence audio while in the background regardless of what the game does, and
+                // stop the output stream so it doesn't keep the audio thread busy
+                for input in inputs {
+                    match input {
+                        InputEvent::Focused(true) => {
+                            audio_output.resume();
+                            mixer.resume();
+                        }
+                        InputEvent::Focused(false) => {
+                            mixer.suspend();
+                            audio_output.pause();
+                        }
+                        _ => {}
+                    }
+                }
+
+                let choice = error_screen
+                    .as_mut()
+                    .and_then(|error_screen| error_screen.update(inputs));
+                match choice {
+                    Some(ErrorChoice::Restore) => {
+                        error_screen = None;
+                        unsafe { gl_context.maintain() };
+                        let snapshot = resilience.restore(time);
+                        let restored = resilience.run(time, |_| {
+                            let mut game = new_game(gl_context, &mixer, loose_levels);
+                            if let Some(snapshot) = &snapshot {
+                                game.restore(snapshot);
+                            }
+                            game
+                        });
+                        match restored {
+                            Ok(restored) => {
+                                log::info!("Restored the game from {:?}", snapshot);
+                                game = Some(restored);
+                            }
+                            Err(crash) => error_screen = Some(crashed(gl_context, &crash)),
+                        }
+                        input_vec.clear();
+                        last_update = 0.;
+                    }
+                    Some(ErrorChoice::Quit) => {
+                        log::info!("Quitting after a panic");
+                        std::process::exit(1);
+                    }
+                    None => {}
+                }
+                if let Some(error_screen) = &mut error_screen {
+                    error_screen.draw(gl_context);
+                    return;
+                }
+                let playing = match &mut game {
+                    Some(game) => game,
+                    None => return,
+                };
+
                 // accumulate input over several frames
                 input_vec.extend_from_slice(inputs);
 
-                // jank ass fixed update loop, skip max 5 frames
-                last_update = (last_update + dt).min(TICK_DT * 5.);
-                while last_update > TICK_DT {
-                    game.update(&input_vec);
+                let mut frame = |recorder: &mut resilience::FlightRecorder<GameSnapshot>| {
+                    // jank ass fixed update loop, skip max 5 frames
+                    last_update = (last_update + dt).min(TICK_DT * 5.);
+                    while last_update > TICK_DT {
+                        playing.update(&input_vec);
+                        recorder.record_tick(&input_vec, || playing.snapshot());
 
-                    last_update -= TICK_DT;
-                    input_vec.clear();
+                        last_update -= TICK_DT;
+                        input_vec.clear();
+                    }
+
+                    playing.draw(gl_context);
+                    #[cfg(feature = "debug-gl")]
+                    log::debug!("GL calls this frame: {}", gl_context.take_gl_call_count());
+                };
+                if !resilient {
+                    frame(&mut resilience.recorder);
+                    return;
                 }
 
-                game.draw(gl_context);
+                // The game may be left half-updated by a panic, so it is never reused afterwards.
+                // The only state shared with the rest of the program is the mixer, which recovers
+                // from a poisoned lock on its own.
+                if let Err(crash) = resilience.run(time, frame) {
+                    mixer.stop_all();
+                    game = None;
+                    unsafe { gl_context.maintain() };
+                    error_screen = Some(crashed(gl_context, &crash));
+                    input_vec.clear();
+                }
             }
         },
     )
 }
+
+fn new_game(gl_context: &mut gl::Context, mixer: &Arc<Mixer>, loose_levels: bool) -> Game {
+    Game::new(
+        gl_context,
+        Arc::clone(mixer),
+        platform::create_presence(),
+        level_source(loose_levels),
+        ProgressMode::Saved,
+    )
+}
+
+// Saves the flight recorder's dump for `crash` and makes the screen that tells the player
+fn crashed(gl_context: &mut gl::Context, crash: &Crash) -> ErrorScreen {
+    log::error!("Caught a panic: {}", crash.message);
+    let dump_file = match platform::save_file(CRASH_DUMP_FILE, crash.dump.as_bytes()) {
+        Ok(()) => {
+            log::info!("Saved the flight recorder to {}", CRASH_DUMP_FILE);
+            Some(CRASH_DUMP_FILE)
+        }
+        Err(e) => {
+            log::error!("Could not save the flight recorder: {:?}", e);
+            None
+        }
+    };
+    ErrorScreen::new(gl_context, crash, dump_file)
+}
+
+// Without the assets folder the levels are the built in ones, after a warning
+#[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+fn level_source(loose_levels: bool) -> LevelSource {
+    #[cfg(not(target_arch = "wasm32"))]
+    if loose_levels {
+        if let Some(asset_root) = asset_paths::AssetRoot::locate_or_embedded() {
+            return LevelSource::Loose(asset_root);
+        }
+    }
+    LevelSource::Embedded
+}
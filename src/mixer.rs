@@ -1,103 +1,614 @@
 use std::{
-    collections::HashMap,
+    io::Cursor,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
     },
 };
 
 use anyhow::Error;
 use lewton::inside_ogg::OggStreamReader;
 
+use crate::spaces::RoomPoint;
+
+// What the output is assumed to play at until it says otherwise, the rate of most of the sounds
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// Frames mixed at a time, so that sounds triggered while a large buffer is being mixed start at
+// the next chunk instead of the next poll
+const MIX_CHUNK_FRAMES: usize = 128;
+// Enough room that playing sounds never reallocates the instance slots in practice
+const INITIAL_INSTANCE_CAPACITY: usize = 32;
+// Positional sounds play at full volume within this many tiles of the listener
+const FULL_VOLUME_DISTANCE: f32 = 2.;
+// and fade linearly down to this volume at a room width away
+const ATTENUATION_FLOOR: f32 = 0.2;
+// Vorbis packets decoded per `poll_loading` call on the web, where there is no thread to decode
+// on. A packet is at most a couple thousand frames.
+#[cfg(target_arch = "wasm32")]
+const DECODE_PACKETS_PER_POLL: usize = 64;
+
 pub struct Mixer {
-    playing: Arc<Mutex<HashMap<usize, AudioInstance>>>,
-    next_id: AtomicUsize,
+    playing: Arc<Mutex<Instances>>,
+    suspended: AtomicBool,
+    output_channels: AtomicUsize,
+    output_sample_rate: AtomicU32,
+    // Number of samples mixed so far
+    clock: AtomicUsize,
+    // Most samples between a sound being triggered and the start of the chunk it was first
+    // mixed in since `take_trigger_latency`
+    trigger_delay: AtomicUsize,
+    // f32 bits of the left and right peak levels of the last polled output
+    peak_left: AtomicU32,
+    peak_right: AtomicU32,
 }
 
 impl Default for Mixer {
     fn default() -> Self {
         Self {
-            playing: Arc::new(Mutex::new(HashMap::new())),
-            next_id: AtomicUsize::new(0),
+            playing: Arc::new(Mutex::new(Instances::with_capacity(
+                INITIAL_INSTANCE_CAPACITY,
+            ))),
+            suspended: AtomicBool::new(false),
+            output_channels: AtomicUsize::new(2),
+            output_sample_rate: AtomicU32::new(DEFAULT_SAMPLE_RATE),
+            clock: AtomicUsize::new(0),
+            trigger_delay: AtomicUsize::new(0),
+            peak_left: AtomicU32::new(0),
+            peak_right: AtomicU32::new(0),
         }
     }
 }
 
 impl Mixer {
     pub fn load_ogg(&self, bytes: &[u8]) -> Result<Audio, Error> {
-        let mut reader = OggStreamReader::new(std::io::Cursor::new(bytes))?;
-        let mut buffer = Vec::new();
-        while let Some(pck_samples) = reader.read_dec_packet_itl()? {
-            for sample in pck_samples {
-                buffer.push(sample);
+        let mut decoder = OggDecoder::new(bytes)?;
+        while !decoder.decode_packets(usize::max_value())? {}
+        Ok(decoder.finish())
+    }
+
+    /// Starts decoding without blocking. Natively this happens on a worker thread, on the web it
+    /// happens a chunk at a time in `poll_loading`.
+    pub fn load_ogg_async(&self, bytes: &'static [u8]) -> AudioFuture {
+        #[cfg(not(target_arch = "wasm32"))]
+        let state = {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                let result = OggDecoder::new(bytes).and_then(|mut decoder| {
+                    while !decoder.decode_packets(usize::max_value())? {}
+                    Ok(decoder.finish())
+                });
+                let _ = sender.send(result);
+            });
+            LoadState::Decoding(receiver)
+        };
+        #[cfg(target_arch = "wasm32")]
+        let state = match OggDecoder::new(bytes) {
+            Ok(decoder) => LoadState::Decoding(decoder),
+            Err(e) => LoadState::Failed(Some(e)),
+        };
+
+        AudioFuture {
+            state,
+            queued: Vec::new(),
+        }
+    }
+
+    /// Advances loading and returns the audio once it is ready, starting anything queued with
+    /// `play_when_ready`. Call this every frame until then. A decoding error is returned once, and
+    /// the queued instances are stopped.
+    pub fn poll_loading(&self, future: &mut AudioFuture) -> Result<Option<Audio>, Error> {
+        let result = match &mut future.state {
+            LoadState::Loaded(audio) => return Ok(Some(audio.clone())),
+            LoadState::Failed(error) => return error.take().map_or(Ok(None), Err),
+            #[cfg(not(target_arch = "wasm32"))]
+            LoadState::Decoding(receiver) => match receiver.try_recv() {
+                Ok(result) => result,
+                Err(std::sync::mpsc::TryRecvError::Empty) => return Ok(None),
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    Err(anyhow::anyhow!("Audio decoding thread panicked"))
+                }
+            },
+            #[cfg(target_arch = "wasm32")]
+            LoadState::Decoding(decoder) => match decoder.decode_packets(DECODE_PACKETS_PER_POLL) {
+                Ok(false) => return Ok(None),
+                Ok(true) => match std::mem::replace(&mut future.state, LoadState::Failed(None)) {
+                    LoadState::Decoding(decoder) => Ok(decoder.finish()),
+                    _ => unreachable!(),
+                },
+                Err(e) => Err(e),
+            },
+        };
+
+        let mut instances = self.instances();
+        match result {
+            Ok(audio) => {
+                let now = self.clock.load(Ordering::Relaxed);
+                let triggered_at = self.trigger_clock(&instances);
+                for handle in future.queued.drain(..) {
+                    if let Some(instance) = instances.get_mut(&handle) {
+                        instance.audio = audio.clone();
+                        instance.started_at = now;
+                        instance.triggered_at = triggered_at;
+                        instance.waiting = false;
+                    }
+                }
+                future.state = LoadState::Loaded(audio.clone());
+                Ok(Some(audio))
+            }
+            Err(e) => {
+                for handle in future.queued.drain(..) {
+                    if instances.get_mut(&handle).is_some() {
+                        instances.remove(handle.index);
+                    }
+                }
+                future.state = LoadState::Failed(None);
+                Err(e)
             }
         }
+    }
 
-        Ok(Audio {
-            buffer: Arc::new(buffer),
-        })
+    /// Like `play`, for audio that may still be loading. Until it has loaded the instance is
+    /// silent, but its handle can be used as usual and changes to it apply once it starts.
+    pub fn play_when_ready(
+        &self,
+        future: &mut AudioFuture,
+        volume: f32,
+        do_loop: bool,
+    ) -> AudioInstanceHandle {
+        if let LoadState::Loaded(audio) = &future.state {
+            return self.play(audio, volume, do_loop);
+        }
+        let mut instances = self.instances();
+        let triggered_at = self.trigger_clock(&instances);
+        let handle = instances.insert(AudioInstance {
+            audio: Audio::empty(),
+            index: 0,
+            volume,
+            do_loop,
+            started_at: self.clock.load(Ordering::Relaxed),
+            triggered_at,
+            position: None,
+            waiting: true,
+            mixed: false,
+        });
+        future.queued.push(AudioInstanceHandle {
+            index: handle.index,
+            generation: handle.generation,
+        });
+        handle
     }
 
     pub fn play(&self, audio: &Audio, volume: f32, do_loop: bool) -> AudioInstanceHandle {
-        let instance = AudioInstance {
-            audio: Audio {
-                buffer: audio.buffer.clone(),
-            },
+        let mut instances = self.instances();
+        self.start_instance(&mut instances, audio, volume, do_loop)
+    }
+
+    /// Like `play`, but if an instance of the same clip was started less than `min_interval_secs`
+    /// ago (measured in mixed samples at the output's rate) no new instance is started and the
+    /// existing one's handle is returned instead.
+    pub fn play_debounced(
+        &self,
+        audio: &Audio,
+        volume: f32,
+        do_loop: bool,
+        min_interval_secs: f32,
+    ) -> AudioInstanceHandle {
+        let min_interval = (min_interval_secs * self.samples_per_sec() as f32) as usize;
+        let mut instances = self.instances();
+        let now = self.clock.load(Ordering::Relaxed);
+        let recent = instances.find(|instance| {
+            instance.audio.same_clip(audio) && now - instance.started_at < min_interval
+        });
+        if let Some(handle) = recent {
+            return handle;
+        }
+        self.start_instance(&mut instances, audio, volume, do_loop)
+    }
+
+    /// Plays a sound panned and attenuated relative to the listener set with `set_listener`.
+    /// Sounds in a different area than the listener are silent.
+    pub fn play_at(
+        &self,
+        audio: &Audio,
+        volume: f32,
+        do_loop: bool,
+        position: SoundPosition,
+    ) -> AudioInstanceHandle {
+        let mut instances = self.instances();
+        let handle = self.start_instance(&mut instances, audio, volume, do_loop);
+        if let Some(instance) = instances.get_mut(&handle) {
+            instance.position = Some(position);
+        }
+        handle
+    }
+
+    fn start_instance(
+        &self,
+        instances: &mut Instances,
+        audio: &Audio,
+        volume: f32,
+        do_loop: bool,
+    ) -> AudioInstanceHandle {
+        let triggered_at = self.trigger_clock(instances);
+        instances.insert(AudioInstance {
+            audio: audio.clone(),
             index: 0,
             volume,
             do_loop,
-        };
-        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
-        self.playing.lock().unwrap().insert(id, instance);
-        AudioInstanceHandle(id)
+            started_at: self.clock.load(Ordering::Relaxed),
+            triggered_at,
+            position: None,
+            waiting: false,
+            mixed: false,
+        })
+    }
+
+    /// Moves a sound started with `play_at`. Does nothing for non-positional sounds.
+    pub fn set_position(&self, handle: &AudioInstanceHandle, position: SoundPosition) {
+        let mut instances = self.instances();
+        if let Some(instance) = instances.get_mut(handle) {
+            if instance.position.is_some() {
+                instance.position = Some(position);
+            }
+        }
+    }
+
+    /// Positional sounds are heard from `position`. `room_width` is the distance in tiles from
+    /// hard left to hard right.
+    pub fn set_listener(&self, position: SoundPosition, room_width: f32) {
+        self.instances().listener = Some(Listener {
+            position,
+            room_width,
+        });
+    }
+
+    /// The format `poll` is asked to fill, which the output stream may only know once it opened.
+    /// Panning is only applied to stereo output, other channel counts only get attenuation.
+    pub fn set_output_format(&self, sample_rate: u32, channels: usize) {
+        self.output_sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+        self.output_channels.store(channels, Ordering::Relaxed);
+    }
+
+    /// Stops the instance immediately. Does nothing if it already finished.
+    pub fn stop(&self, handle: &AudioInstanceHandle) {
+        let mut instances = self.instances();
+        if instances.get_mut(handle).is_some() {
+            instances.remove(handle.index);
+        }
     }
 
     pub fn set_volume(&self, handle: &AudioInstanceHandle, volume: f32) {
-        let mut instances = self.playing.lock().unwrap();
-        if let Some(instance) = instances.get_mut(&handle.0) {
+        let mut instances = self.instances();
+        if let Some(instance) = instances.get_mut(handle) {
             instance.volume = volume;
         };
     }
 
     pub fn set_looping(&self, handle: &AudioInstanceHandle, do_loop: bool) {
-        let mut instances = self.playing.lock().unwrap();
-        if let Some(instance) = instances.get_mut(&handle.0) {
+        let mut instances = self.instances();
+        if let Some(instance) = instances.get_mut(handle) {
             instance.do_loop = do_loop;
         };
     }
 
+    pub fn stop_all(&self) {
+        let mut instances = self.instances();
+        for index in 0..instances.slots.len() {
+            instances.remove(index);
+        }
+    }
+
+    /// Outputs silence and holds every playing instance at its current sample until `resume`.
+    pub fn suspend(&self) {
+        self.suspended.store(true, Ordering::Relaxed);
+        // the output may stop polling entirely while suspended
+        self.peak_left.store(0f32.to_bits(), Ordering::Relaxed);
+        self.peak_right.store(0f32.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.suspended.store(false, Ordering::Relaxed);
+    }
+
+    // A panic while holding the lock can't leave the instances in an inconsistent state, so a
+    // poisoned lock is safe to keep using
+    fn instances(&self) -> MutexGuard<'_, Instances> {
+        self.playing.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Peak absolute level in 0..1 of the left and right channels of the most recently mixed
+    /// output, assuming interleaved stereo output.
+    pub fn peak_levels(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.peak_left.load(Ordering::Relaxed)),
+            f32::from_bits(self.peak_right.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// The longest time in seconds from a sound being triggered to the start of the chunk it was
+    /// first mixed in, since the last call. The output's own buffering comes on top of this.
+    pub fn take_trigger_latency(&self) -> f32 {
+        self.trigger_delay.swap(0, Ordering::Relaxed) as f32 / self.samples_per_sec().max(1) as f32
+    }
+
+    fn samples_per_sec(&self) -> usize {
+        self.output_sample_rate.load(Ordering::Relaxed) as usize
+            * self.output_channels.load(Ordering::Relaxed)
+    }
+
+    // The sample clock of a trigger now, as far past the start of the last poll as the time
+    // since then. It assumes each poll's output starts playing as it is mixed, so a trigger's
+    // latency counts the wait for the chunk it starts in. There is no clock to go by on the web,
+    // where it is the end of what was mixed so far.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    fn trigger_clock(&self, instances: &Instances) -> usize {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some((poll_start, polled_at)) = instances.last_poll {
+            let elapsed = polled_at.elapsed().as_secs_f32() * self.samples_per_sec() as f32;
+            return poll_start + elapsed as usize;
+        }
+        self.clock.load(Ordering::Relaxed)
+    }
+
     pub fn poll(&self, out: &mut [i16]) {
-        let mut instances = self.playing.lock().unwrap();
+        let chunk_len = MIX_CHUNK_FRAMES * self.output_channels.load(Ordering::Relaxed).max(1);
+        self.poll_in_chunks(out, chunk_len, || {});
+    }
 
-        let mut finished = Vec::new();
-        for (id, instance) in instances.iter_mut() {
-            let requested_samples = out.len();
-            let remaining_samples = if instance.do_loop {
-                requested_samples
-            } else {
-                instance.audio.buffer.len() - instance.index
-            };
-            for i in 0..requested_samples.min(remaining_samples) {
-                let instance_i = (instance.index + i) % instance.audio.buffer.len();
-                out[i] += ((instance.audio.buffer[instance_i] as f32 / i16::max_value() as f32)
-                    * instance.volume
-                    * i16::max_value() as f32)
-                    .floor() as i16;
+    // Mixes `out` `chunk_len` samples at a time, letting go of the instances in between so that
+    // sounds triggered meanwhile start at the next chunk. `between_chunks` runs after each chunk.
+    fn poll_in_chunks(&self, out: &mut [i16], chunk_len: usize, mut between_chunks: impl FnMut()) {
+        if self.suspended.load(Ordering::Relaxed) {
+            self.peak_left.store(0f32.to_bits(), Ordering::Relaxed);
+            self.peak_right.store(0f32.to_bits(), Ordering::Relaxed);
+            return;
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.instances().last_poll = Some((
+                self.clock.load(Ordering::Relaxed),
+                std::time::Instant::now(),
+            ));
+        }
+        let output_channels = self.output_channels.load(Ordering::Relaxed);
+        for chunk in out.chunks_mut(chunk_len) {
+            // Finished instances free their slot in place so that steady state polling never
+            // allocates on the audio thread
+            let mut instances = self.instances();
+            let now = self.clock.fetch_add(chunk.len(), Ordering::Relaxed);
+            let listener = instances.listener;
+            for index in 0..instances.slots.len() {
+                let playing = match &mut instances.slots[index].instance {
+                    Some(instance) if instance.waiting => continue,
+                    Some(instance) => {
+                        if !instance.mixed {
+                            instance.mixed = true;
+                            self.trigger_delay.fetch_max(
+                                now.saturating_sub(instance.triggered_at),
+                                Ordering::Relaxed,
+                            );
+                        }
+                        let gains = instance.channel_gains(listener.as_ref(), output_channels);
+                        instance.mix(chunk, gains)
+                    }
+                    None => continue,
+                };
+                if !playing {
+                    instances.remove(index);
+                }
             }
-            if requested_samples >= remaining_samples && !instance.do_loop {
-                finished.push(*id);
-            } else {
-                instance.index = (instance.index + requested_samples) % instance.audio.buffer.len();
+            drop(instances);
+            between_chunks();
+        }
+
+        let (mut peak_left, mut peak_right) = (0i32, 0i32);
+        for frame in out.chunks(2) {
+            peak_left = peak_left.max((frame[0] as i32).abs());
+            peak_right = peak_right.max((frame[frame.len() - 1] as i32).abs());
+        }
+        let to_level = |peak: i32| (peak as f32 / i16::max_value() as f32).min(1.0);
+        self.peak_left
+            .store(to_level(peak_left).to_bits(), Ordering::Relaxed);
+        self.peak_right
+            .store(to_level(peak_right).to_bits(), Ordering::Relaxed);
+    }
+}
+
+// Slot storage for playing instances. A slot's generation is bumped whenever its instance is
+// removed, so handles to a finished instance never refer to whatever reuses the slot.
+struct Instances {
+    slots: Vec<InstanceSlot>,
+    free: Vec<usize>,
+    listener: Option<Listener>,
+    // The sample clock at the start of the last poll and when that was
+    #[cfg(not(target_arch = "wasm32"))]
+    last_poll: Option<(usize, std::time::Instant)>,
+}
+
+struct InstanceSlot {
+    generation: u32,
+    instance: Option<AudioInstance>,
+}
+
+impl Instances {
+    fn with_capacity(capacity: usize) -> Instances {
+        Instances {
+            slots: Vec::with_capacity(capacity),
+            free: Vec::with_capacity(capacity),
+            listener: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            last_poll: None,
+        }
+    }
+
+    fn insert(&mut self, instance: AudioInstance) -> AudioInstanceHandle {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(InstanceSlot {
+                    generation: 0,
+                    instance: None,
+                });
+                // make sure freeing every slot from the audio thread never allocates
+                self.free.reserve(self.slots.len());
+                self.slots.len() - 1
             }
+        };
+        let slot = &mut self.slots[index];
+        slot.instance = Some(instance);
+        AudioInstanceHandle {
+            index,
+            generation: slot.generation,
         }
-        for id in finished.into_iter().rev() {
-            instances.remove(&id);
+    }
+
+    fn remove(&mut self, index: usize) {
+        let slot = &mut self.slots[index];
+        if slot.instance.take().is_some() {
+            slot.generation = slot.generation.wrapping_add(1);
+            self.free.push(index);
+        }
+    }
+
+    fn get_mut(&mut self, handle: &AudioInstanceHandle) -> Option<&mut AudioInstance> {
+        match self.slots.get_mut(handle.index) {
+            Some(slot) if slot.generation == handle.generation => slot.instance.as_mut(),
+            _ => None,
         }
     }
+
+    fn find(&self, f: impl Fn(&AudioInstance) -> bool) -> Option<AudioInstanceHandle> {
+        self.slots
+            .iter()
+            .enumerate()
+            .find_map(|(index, slot)| match &slot.instance {
+                Some(instance) if f(instance) => Some(AudioInstanceHandle {
+                    index,
+                    generation: slot.generation,
+                }),
+                _ => None,
+            })
+    }
 }
 
+#[derive(Clone)]
 pub struct Audio {
     buffer: Arc<Vec<i16>>,
+    sample_rate: u32,
+    channels: usize,
+    // Interleaved sample range of `buffer` this clip plays
+    start: usize,
+    end: usize,
+}
+
+impl Audio {
+    fn empty() -> Audio {
+        Audio {
+            buffer: Arc::new(Vec::new()),
+            sample_rate: 0,
+            channels: 0,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Creates a sub-clip sharing this clip's sample buffer. Times are relative to the start of
+    /// this clip and are clamped to its length.
+    pub fn slice(&self, start_secs: f32, end_secs: f32) -> Audio {
+        let frames = (self.end - self.start) / self.channels.max(1);
+        let to_frame = |secs: f32| -> usize {
+            ((secs.max(0.) * self.sample_rate as f32).floor() as usize).min(frames)
+        };
+        let start_frame = to_frame(start_secs);
+        let end_frame = to_frame(end_secs).max(start_frame);
+
+        Audio {
+            buffer: self.buffer.clone(),
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            start: self.start + start_frame * self.channels,
+            end: self.start + end_frame * self.channels,
+        }
+    }
+
+    fn same_clip(&self, other: &Audio) -> bool {
+        Arc::ptr_eq(&self.buffer, &other.buffer)
+            && self.start == other.start
+            && self.end == other.end
+    }
+
+    fn samples(&self) -> &[i16] {
+        &self.buffer[self.start..self.end]
+    }
+}
+
+/// Audio that is still being decoded, see `Mixer::load_ogg_async`.
+pub struct AudioFuture {
+    state: LoadState,
+    queued: Vec<AudioInstanceHandle>,
+}
+
+enum LoadState {
+    #[cfg(not(target_arch = "wasm32"))]
+    Decoding(std::sync::mpsc::Receiver<Result<Audio, Error>>),
+    #[cfg(target_arch = "wasm32")]
+    Decoding(OggDecoder<'static>),
+    Loaded(Audio),
+    // Holds the error until it has been returned from `poll_loading`
+    Failed(Option<Error>),
+}
+
+// Decodes an OGG a number of packets at a time, so the work can be spread out or moved to
+// another thread
+struct OggDecoder<'a> {
+    reader: OggStreamReader<Cursor<&'a [u8]>>,
+    buffer: Vec<i16>,
+}
+
+impl<'a> OggDecoder<'a> {
+    fn new(bytes: &'a [u8]) -> Result<OggDecoder<'a>, Error> {
+        Ok(OggDecoder {
+            reader: OggStreamReader::new(Cursor::new(bytes))?,
+            buffer: Vec::new(),
+        })
+    }
+
+    // Returns true once the whole stream has been decoded
+    fn decode_packets(&mut self, max_packets: usize) -> Result<bool, Error> {
+        for _ in 0..max_packets {
+            match self.reader.read_dec_packet_itl()? {
+                Some(pck_samples) => self.buffer.extend(pck_samples),
+                None => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    fn finish(self) -> Audio {
+        let end = self.buffer.len();
+        Audio {
+            buffer: Arc::new(self.buffer),
+            sample_rate: self.reader.ident_hdr.audio_sample_rate,
+            channels: self.reader.ident_hdr.audio_channels as usize,
+            start: 0,
+            end,
+        }
+    }
+}
+
+/// Where a positional sound is, `area` being an id for the room it is in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SoundPosition {
+    pub area: usize,
+    pub position: RoomPoint,
+}
+
+#[derive(Clone, Copy)]
+struct Listener {
+    position: SoundPosition,
+    room_width: f32,
 }
 
 pub struct AudioInstance {
@@ -105,6 +616,407 @@ pub struct AudioInstance {
     index: usize,
     volume: f32,
     do_loop: bool,
+    started_at: usize,
+    // When it was triggered, see `Mixer::trigger_clock`
+    triggered_at: usize,
+    position: Option<SoundPosition>,
+    // Queued by `play_when_ready` and still waiting for its audio to load
+    waiting: bool,
+    // Whether any of it was mixed yet, for measuring the trigger latency
+    mixed: bool,
+}
+
+impl AudioInstance {
+    // Gain for each of a stereo pair of output channels, or the same gain twice when panning
+    // doesn't apply. Uses constant power panning over half a room width each side.
+    fn channel_gains(&self, listener: Option<&Listener>, output_channels: usize) -> [f32; 2] {
+        let (source, listener) = match (self.position, listener) {
+            (Some(source), Some(listener)) => (source, listener),
+            _ => return [1., 1.],
+        };
+        if source.area != listener.position.area {
+            return [0., 0.];
+        }
+
+        let offset = source.position - listener.position.position;
+        let falloff = ((offset.length() - FULL_VOLUME_DISTANCE)
+            / (listener.room_width - FULL_VOLUME_DISTANCE).max(0.0001))
+        .max(0.)
+        .min(1.);
+        let attenuation = 1. - falloff * (1. - ATTENUATION_FLOOR);
+        if output_channels != 2 {
+            return [attenuation, attenuation];
+        }
+
+        let pan = (offset.x / (listener.room_width / 2.).max(0.0001))
+            .max(-1.)
+            .min(1.);
+        let angle = (pan + 1.) * std::f32::consts::FRAC_PI_4;
+        // cos comes out a hair below zero at hard right
+        [angle.cos().max(0.) * attenuation, angle.sin() * attenuation]
+    }
+
+    // Adds this instance's next samples into `out` with `gains` applied to alternating channels,
+    // returns false once it has finished playing
+    fn mix(&mut self, out: &mut [i16], gains: [f32; 2]) -> bool {
+        let samples = self.audio.samples();
+        if samples.is_empty() {
+            return false;
+        }
+        let requested_samples = out.len();
+        let remaining_samples = if self.do_loop {
+            requested_samples
+        } else {
+            samples.len() - self.index
+        };
+        for i in 0..requested_samples.min(remaining_samples) {
+            let instance_i = (self.index + i) % samples.len();
+            out[i] += ((samples[instance_i] as f32 / i16::max_value() as f32)
+                * self.volume
+                * gains[i % 2]
+                * i16::max_value() as f32)
+                .floor() as i16;
+        }
+        if requested_samples >= remaining_samples && !self.do_loop {
+            false
+        } else {
+            self.index = (self.index + requested_samples) % samples.len();
+            true
+        }
+    }
 }
 
-pub struct AudioInstanceHandle(usize);
+pub struct AudioInstanceHandle {
+    index: usize,
+    generation: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A mono clip of `samples`
+    fn clip(samples: Vec<i16>, sample_rate: u32) -> Audio {
+        let end = samples.len();
+        Audio {
+            buffer: Arc::new(samples),
+            sample_rate,
+            channels: 1,
+            start: 0,
+            end,
+        }
+    }
+
+    fn same_instance(a: &AudioInstanceHandle, b: &AudioInstanceHandle) -> bool {
+        a.index == b.index && a.generation == b.generation
+    }
+
+    // What `audio` played on its own mixes into `len` samples of silence, one poll at a time
+    fn render(audio: &Audio, do_loop: bool, len: usize) -> Vec<i16> {
+        let mixer = Mixer::default();
+        mixer.play(audio, 1., do_loop);
+        let mut out = vec![0; len];
+        mixer.poll(&mut out);
+        out
+    }
+
+    #[test]
+    fn slices_play_only_their_range() {
+        let audio = clip((1..=10).map(|i| i * 100).collect(), 10);
+        let slice = audio.slice(0.2, 0.5);
+        assert_eq!(slice.samples(), [300, 400, 500]);
+        let same = clip(vec![300, 400, 500], 10);
+        assert_eq!(render(&slice, false, 6), render(&same, false, 6));
+        assert_eq!(render(&slice, false, 6)[3..], [0, 0, 0]);
+        assert_eq!(render(&slice, true, 7), render(&same, true, 7));
+
+        // times are relative to the slice sliced
+        assert_eq!(slice.slice(0.1, 1.).samples(), [400, 500]);
+        assert!(slice.same_clip(&audio.slice(0.2, 0.5)));
+        assert!(!slice.same_clip(&audio));
+    }
+
+    #[test]
+    fn slices_clamp_to_the_clip() {
+        let audio = clip((1..=10).collect(), 10);
+        assert_eq!(audio.slice(-1., 5.).samples(), audio.samples());
+        assert_eq!(audio.slice(0.8, 0.3).samples(), []);
+        assert_eq!(audio.slice(2., 3.).samples(), []);
+        // nothing to play ends right away
+        let mixer = Mixer::default();
+        mixer.play(&audio.slice(2., 3.), 1., true);
+        mixer.poll(&mut [0; 4]);
+        assert_eq!(mixer.instances().free.len(), 1);
+    }
+
+    #[test]
+    fn debounce_interval_is_in_output_time() {
+        let mixer = Mixer::default();
+        mixer.set_output_format(1000, 2);
+        // a clip at another rate than the output, which the interval must not depend on
+        let audio = clip(vec![1000; 4000], 8000);
+        let mut out = vec![0; 100];
+
+        let first = mixer.play_debounced(&audio, 1., false, 0.1);
+        // 0.1s at 1000Hz stereo is 200 samples
+        mixer.poll(&mut out);
+        assert!(same_instance(
+            &mixer.play_debounced(&audio, 1., false, 0.1),
+            &first
+        ));
+        mixer.poll(&mut out);
+        assert!(!same_instance(
+            &mixer.play_debounced(&audio, 1., false, 0.1),
+            &first
+        ));
+    }
+
+    #[test]
+    fn finished_instances_free_their_slots() {
+        let mixer = Mixer::default();
+        mixer.set_listener(
+            SoundPosition {
+                area: 0,
+                position: RoomPoint::new(0., 0.),
+            },
+            16.,
+        );
+        let short = clip(vec![1000; 300], 44100);
+        let long = clip(vec![-1000; 4000], 44100);
+        for i in 0..16 {
+            if i % 2 == 0 {
+                mixer.play(&short, 0.5, false);
+            } else {
+                let position = SoundPosition {
+                    area: 0,
+                    position: RoomPoint::new(i as f32, 0.),
+                };
+                mixer.play_at(&long, 0.5, true, position);
+            }
+        }
+
+        // 16 instances mixed into 1024 samples, with the short ones finishing along the way.
+        // tests/mixer_allocations.rs checks this doesn't allocate.
+        let mut out = vec![0; 1024];
+        for _ in 0..4 {
+            mixer.poll(&mut out);
+        }
+        assert_eq!(mixer.instances().free.len(), 8);
+    }
+
+    fn at(area: usize, x: f32, y: f32) -> SoundPosition {
+        SoundPosition {
+            area,
+            position: RoomPoint::new(x, y),
+        }
+    }
+
+    // The left and right gains a looping constant clip played at `source` gets, with the
+    // listener at the origin of area 0 in a 16 tile wide room
+    fn gains_at(mixer: &Mixer, source: SoundPosition) -> (f32, f32) {
+        mixer.set_listener(at(0, 0., 0.), 16.);
+        let handle = mixer.play_at(&clip(vec![10000; 2], 44100), 1., true, source);
+        let mut out = [0; 2];
+        mixer.poll(&mut out);
+        mixer.stop(&handle);
+        (out[0] as f32 / 10000., out[1] as f32 / 10000.)
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.001,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn pan_is_constant_power() {
+        let mixer = Mixer::default();
+        // within full volume distance, so this is all pan
+        for &x in &[-2., -1., 0., 0.375, 1., 2.] {
+            let (left, right) = gains_at(&mixer, at(0, x, 0.));
+            assert_near(left * left + right * right, 1.);
+        }
+        let (left, right) = gains_at(&mixer, at(0, 0., 1.));
+        assert_near(left, right);
+        // 2 tiles right is a quarter of the way to hard right, at half the room width
+        let (left, right) = gains_at(&mixer, at(0, 2., 0.));
+        let angle = 1.25 * std::f32::consts::FRAC_PI_4;
+        assert_near(right / left, angle.tan());
+        // past half the room width is hard left or right
+        let (left, right) = gains_at(&mixer, at(0, -10., 0.));
+        assert!(left > 0.);
+        assert_eq!(right, 0.);
+        let (left, right) = gains_at(&mixer, at(0, 12., 0.));
+        assert_eq!(left, 0.);
+        assert!(right > 0.);
+    }
+
+    #[test]
+    fn falloff_is_linear_down_to_the_floor() {
+        let mixer = Mixer::default();
+        // (distance, attenuation), full volume within 2 tiles and the floor at the room width
+        let curve = [
+            (0., 1.),
+            (2., 1.),
+            (5.5, 0.8),
+            (9., 0.6),
+            (16., 0.2),
+            (40., 0.2),
+        ];
+        for &(distance, attenuation) in &curve {
+            // right above the listener, so both sides get the centered pan's half power
+            let (left, right) = gains_at(&mixer, at(0, 0., distance));
+            assert_near(left, attenuation * std::f32::consts::FRAC_1_SQRT_2);
+            assert_near(right, left);
+        }
+    }
+
+    #[test]
+    fn other_areas_are_silent() {
+        let mixer = Mixer::default();
+        assert_eq!(gains_at(&mixer, at(1, 0., 0.)), (0., 0.));
+        // and sounds that aren't positional are left alone
+        mixer.play(&clip(vec![10000; 2], 44100), 1., false);
+        let mut out = [0; 2];
+        mixer.poll(&mut out);
+        assert_eq!(out, [10000, 10000]);
+    }
+
+    #[test]
+    fn mono_output_collapses_pan() {
+        let mixer = Mixer::default();
+        mixer.set_output_format(44100, 1);
+        // hard right at the edge of full volume is still heard at full volume
+        assert_eq!(gains_at(&mixer, at(0, 2., 0.)), (1., 1.));
+        // only the attenuation is left
+        let (gain, _) = gains_at(&mixer, at(0, -9., 0.));
+        assert_near(gain, 0.6);
+    }
+
+    #[test]
+    fn positions_and_the_listener_update_between_polls() {
+        let mixer = Mixer::default();
+        mixer.set_listener(at(0, 0., 0.), 16.);
+        let handle = mixer.play_at(&clip(vec![10000; 2], 44100), 1., true, at(0, 8., 0.));
+        let poll = || {
+            let mut out = [0; 2];
+            mixer.poll(&mut out);
+            (out[0], out[1])
+        };
+        assert_eq!(poll(), (0, 6571));
+        // the source moves, as its owner would each tick
+        mixer.set_position(&handle, at(0, -8., 0.));
+        assert_eq!(poll(), (6571, 0));
+        // the listener follows to the source
+        mixer.set_listener(at(0, -8., 0.), 16.);
+        assert_eq!(poll(), (7071, 7071));
+        // and moves to another area, leaving it behind
+        mixer.set_listener(at(1, -8., 0.), 16.);
+        assert_eq!(poll(), (0, 0));
+        // sounds that aren't positional keep their place
+        let plain = mixer.play(&clip(vec![10000; 2], 44100), 1., true);
+        mixer.set_position(&plain, at(1, 100., 0.));
+        mixer.stop(&handle);
+        assert_eq!(poll(), (10000, 10000));
+    }
+
+    #[test]
+    fn chunked_mixing_matches_whole_buffers() {
+        let start = |mixer: &Mixer| {
+            mixer.set_listener(
+                SoundPosition {
+                    area: 0,
+                    position: RoomPoint::new(0., 0.),
+                },
+                16.,
+            );
+            let ramp = clip((0..3000).map(|i| (i % 700) as i16 * 20).collect(), 44100);
+            let low = clip((0..900).map(|i| (i % 90) as i16 * -100).collect(), 22050);
+            let position = SoundPosition {
+                area: 0,
+                position: RoomPoint::new(5., 0.),
+            };
+            [
+                mixer.play(&ramp.slice(0.01, 0.05), 0.7, true),
+                mixer.play(&low, 0.5, false),
+                mixer.play_at(&ramp, 0.9, true, position),
+            ]
+        };
+        let (chunked, whole) = (Mixer::default(), Mixer::default());
+        let (chunked_sounds, whole_sounds) = (start(&chunked), start(&whole));
+
+        // callbacks that aren't a multiple of the chunk size, with a change between two of them
+        let (mut chunked_out, mut whole_out) = (vec![0; 1500], vec![0; 1500]);
+        for callback in 0..6 {
+            if callback == 3 {
+                chunked.set_volume(&chunked_sounds[0], 0.2);
+                whole.set_volume(&whole_sounds[0], 0.2);
+            }
+            chunked_out.iter_mut().for_each(|sample| *sample = 0);
+            whole_out.iter_mut().for_each(|sample| *sample = 0);
+            chunked.poll(&mut chunked_out);
+            whole.poll_in_chunks(&mut whole_out, 1500, || {});
+            assert_eq!(chunked_out, whole_out, "callback {}", callback);
+        }
+    }
+
+    #[test]
+    fn triggers_start_at_the_next_chunk() {
+        let click = clip(vec![10000; 8], 44100);
+        // Polls two buffers `chunk_len` samples at a time, triggering the click once `after`
+        // samples of the first are mixed. Returns where the click begins and the latency it
+        // measured.
+        let loopback = |chunk_len: usize, after: usize| {
+            let mixer = Mixer::default();
+            let mut out = vec![0; 4096];
+            let mut mixed = 0;
+            mixer.poll_in_chunks(&mut out[..2048], chunk_len, || {
+                mixed += chunk_len;
+                if mixed == after {
+                    // as if no time passed since the poll started
+                    let mut instances = mixer.instances();
+                    let (poll_start, _) = instances.last_poll.unwrap();
+                    let later = std::time::Instant::now() + std::time::Duration::from_secs(60);
+                    instances.last_poll = Some((poll_start, later));
+                    mixer.start_instance(&mut instances, &click, 1., false);
+                }
+            });
+            mixer.poll_in_chunks(&mut out[2048..], chunk_len, || {});
+            let onset = out.iter().position(|&sample| sample != 0);
+            (onset, mixer.trigger_delay.load(Ordering::Relaxed))
+        };
+
+        // triggered once two 256 sample chunks are mixed, it starts with the third
+        assert_eq!(loopback(256, 512), (Some(512), 512));
+        // mixed in one go, the buffer can't take it anymore and it waits for the next poll
+        assert_eq!(loopback(2048, 2048), (Some(2048), 2048));
+    }
+
+    #[test]
+    fn panics_holding_the_lock_dont_silence_the_mixer() {
+        let audio = clip((1..=8).map(|i| i * 100).collect(), 44100);
+        let mixer = Arc::new(Mixer::default());
+        let holder = Arc::clone(&mixer);
+        // as if the game panicked in the middle of playing a sound
+        let panicked = std::thread::spawn(move || {
+            let _instances = holder.instances();
+            panic!("Panic injected while holding the lock");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(mixer.playing.is_poisoned());
+
+        mixer.play(&audio, 1., false);
+        let mut out = vec![0; 16];
+        mixer.poll(&mut out);
+        assert_eq!(out, render(&audio, false, 16));
+        mixer.play(&audio, 1., true);
+        mixer.stop_all();
+        let mut out = vec![0; 16];
+        mixer.poll(&mut out);
+        assert_eq!(out, [0; 16]);
+    }
+}
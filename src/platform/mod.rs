@@ -1,9 +1,35 @@
 #[cfg(target_arch = "wasm32")]
 mod web;
 #[cfg(target_arch = "wasm32")]
-pub use web::{run, start_audio_playback};
+pub use web::{
+    audio_running, create_presence, load_progress, run, save_file, save_progress,
+    start_audio_playback_with,
+};
 
 #[cfg(not(target_arch = "wasm32"))]
 mod native;
+#[cfg(all(feature = "headless", not(target_arch = "wasm32")))]
+pub use native::create_headless_gl;
+#[cfg(all(test, feature = "headless", not(target_arch = "wasm32")))]
+pub use native::headless_gl_for_test;
 #[cfg(not(target_arch = "wasm32"))]
-pub use native::{run, start_audio_playback};
+pub use native::{
+    audio_running, create_presence, load_progress, run, save_file, save_progress,
+    start_audio_playback_with,
+};
+
+/// What to ask the audio device for. Anything left as `None` is chosen by the platform, and
+/// requests the device can't satisfy fall back to whatever it supports.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AudioConfig {
+    pub buffer_frames: Option<u32>,
+    pub sample_rate: Option<u32>,
+}
+
+/// What an audio stream actually got. `buffer_frames` is `None` when the device picked it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioStreamInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub buffer_frames: Option<u32>,
+}
@@ -1,51 +1,478 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Sample,
+    BufferSize, Device, Sample, SampleFormat, SampleRate, Stream, StreamConfig,
+    SupportedBufferSize, SupportedStreamConfig, SupportedStreamConfigRange,
 };
 
-pub fn start_audio_playback<F: FnMut(&mut [i16]) + 'static + Send>(mut f: F) {
-    std::thread::spawn(move || {
-        let host = cpal::default_host();
+use crate::platform::{AudioConfig, AudioStreamInfo};
+
+// Unless configured otherwise, ask for the smallest buffer the device allows but no smaller than
+// this
+const MIN_BUFFER_FRAMES: u32 = 64;
+// Sample rates to ask for unless configured otherwise, most preferred first. Most of the game's
+// sounds are 44.1kHz and the mixer doesn't resample, while going much higher only wastes CPU
+const PREFERRED_SAMPLE_RATES: &[u32] = &[44100, 48000];
+// The mixer produces interleaved stereo
+const PREFERRED_CHANNELS: u16 = 2;
+// How often to look for an output device again while there is none
+const REOPEN_INTERVAL: Duration = Duration::from_secs(2);
+
+static AUDIO_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// False until the output stream has started playing, and while it is being reopened.
+pub fn audio_running() -> bool {
+    AUDIO_RUNNING.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy)]
+enum AudioCommand {
+    Pause,
+    Resume,
+    Stop,
+    // Sent from the stream's error callback, with the generation of the stream that failed
+    StreamFailed(u32),
+}
+
+/// Owns the output stream, which lives on its own thread. Dropping this stops playback and joins
+/// the thread.
+pub struct AudioOutput {
+    commands: Sender<AudioCommand>,
+    thread: Option<JoinHandle<()>>,
+    info: Arc<Mutex<Option<AudioStreamInfo>>>,
+}
+
+impl AudioOutput {
+    pub fn pause(&self) {
+        let _ = self.commands.send(AudioCommand::Pause);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.commands.send(AudioCommand::Resume);
+    }
+
+    /// What the current stream actually got, `None` while no stream is open. The stream is opened
+    /// on the audio thread, so this is `None` for a moment after starting and can change when the
+    /// stream is reopened.
+    pub fn stream_info(&self) -> Option<AudioStreamInfo> {
+        *self.info.lock().unwrap()
+    }
+}
 
-        let device = host
-            .default_output_device()
-            .expect("no output device available");
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        let _ = self.commands.send(AudioCommand::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Plays audio from `f` on the default output device. If the stream fails, for example because
+/// the device was unplugged, it is reopened on whatever the default device is by then.
+pub fn start_audio_playback_with<F: FnMut(&mut [i16]) + 'static + Send>(
+    config: AudioConfig,
+    f: F,
+) -> AudioOutput {
+    let (commands, command_receiver) = mpsc::channel();
+    let info = Arc::new(Mutex::new(None));
+    let output = OutputState {
+        config,
+        f: Arc::new(Mutex::new(f)),
+        failures: commands.clone(),
+        info: Arc::clone(&info),
+    };
+    let thread_info = Arc::clone(&info);
+    let thread = std::thread::spawn(move || {
+        let mut control =
+            StreamControl::new(|generation| output.open_stream(generation), &AUDIO_RUNNING);
+
+        loop {
+            if control.stream.is_some() {
+                match command_receiver.recv() {
+                    Ok(command) if control.handle(command) => {}
+                    _ => break,
+                }
+            } else {
+                match command_receiver.recv_timeout(REOPEN_INTERVAL) {
+                    Ok(command) if control.handle(command) => {}
+                    Err(RecvTimeoutError::Timeout) => control.reopen(),
+                    _ => break,
+                }
+            }
+            // a stream that opened but didn't start is closed again
+            if control.stream.is_none() {
+                *thread_info.lock().unwrap() = None;
+            }
+        }
+        AUDIO_RUNNING.store(false, Ordering::Relaxed);
+    });
+
+    AudioOutput {
+        commands,
+        thread: Some(thread),
+        info,
+    }
+}
+
+// What the audio thread does with its stream, so the command handling can be tested without an
+// audio device
+trait OutputStream {
+    fn play(&self) -> Result<(), String>;
+    fn pause(&self) -> Result<(), String>;
+}
+
+impl OutputStream for Stream {
+    fn play(&self) -> Result<(), String> {
+        StreamTrait::play(self).map_err(|e| e.to_string())
+    }
+
+    fn pause(&self) -> Result<(), String> {
+        StreamTrait::pause(self).map_err(|e| e.to_string())
+    }
+}
+
+// The audio thread's stream and what the game last asked of it. `open` opens a stream without
+// starting it, for the generation its errors are reported with. `running` is set while the
+// stream is playing.
+struct StreamControl<'a, S, O> {
+    open: O,
+    stream: Option<S>,
+    generation: u32,
+    paused: bool,
+    running: &'a AtomicBool,
+}
+
+impl<'a, S: OutputStream, O: FnMut(u32) -> Option<S>> StreamControl<'a, S, O> {
+    fn new(open: O, running: &'a AtomicBool) -> StreamControl<'a, S, O> {
+        let mut control = StreamControl {
+            open,
+            stream: None,
+            generation: 0,
+            paused: false,
+            running,
+        };
+        control.stream = control.open_stream();
+        control
+    }
+
+    // Returns false once the thread should stop
+    fn handle(&mut self, command: AudioCommand) -> bool {
+        let result = match (command, &self.stream) {
+            (AudioCommand::Stop, _) => return false,
+            (AudioCommand::Pause, Some(stream)) => {
+                self.paused = true;
+                let result = stream.pause();
+                // a stream that didn't pause is still playing
+                self.running.store(result.is_err(), Ordering::Relaxed);
+                result
+            }
+            (AudioCommand::Resume, Some(stream)) => {
+                self.paused = false;
+                let result = stream.play();
+                self.running.store(result.is_ok(), Ordering::Relaxed);
+                result
+            }
+            (AudioCommand::Pause, None) => {
+                self.paused = true;
+                Ok(())
+            }
+            (AudioCommand::Resume, None) => {
+                self.paused = false;
+                Ok(())
+            }
+            // a failing stream may report several errors, only the first one matters
+            (AudioCommand::StreamFailed(failed), _) if failed != self.generation => Ok(()),
+            (AudioCommand::StreamFailed(_), _) => {
+                log::warn!("Audio stream failed, reopening on the default output device");
+                self.running.store(false, Ordering::Relaxed);
+                // close the old stream before opening a new one in case it holds the device
+                self.stream = None;
+                self.reopen();
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("Could not change audio playback state: {}", e);
+        }
+        true
+    }
 
-        let supported_output_config = device
-            .supported_output_configs()
-            .unwrap()
-            .next()
-            .unwrap()
-            .with_max_sample_rate();
+    fn reopen(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.stream = self.open_stream();
+        if self.stream.is_some() {
+            log::warn!("Audio output reopened");
+        }
+    }
 
-        match supported_output_config.sample_format() {
-            cpal::SampleFormat::F32 => {}
-            _ => {
-                panic!("Output format not supported");
+    // Starts the new stream unless the game paused playback in the meantime
+    fn open_stream(&mut self) -> Option<S> {
+        let stream = (self.open)(self.generation)?;
+        if !self.paused {
+            if let Err(e) = stream.play() {
+                log::warn!("Could not start audio stream: {}", e);
+                return None;
             }
+            self.running.store(true, Ordering::Relaxed);
         }
+        Some(stream)
+    }
+}
+
+// Everything the audio thread needs to open a stream again
+struct OutputState<F> {
+    config: AudioConfig,
+    f: Arc<Mutex<F>>,
+    failures: Sender<AudioCommand>,
+    info: Arc<Mutex<Option<AudioStreamInfo>>>,
+}
+
+impl<F: FnMut(&mut [i16]) + 'static + Send> OutputState<F> {
+    // Logs and returns `None` if there is no usable output device right now. Errors of the
+    // stream are reported as failures of `generation`.
+    fn open_stream(&self, generation: u32) -> Option<Stream> {
+        *self.info.lock().unwrap() = None;
+
+        let host = cpal::default_host();
+
+        let device = match host.default_output_device() {
+            Some(device) => device,
+            None => {
+                log::warn!("No audio output device available");
+                return None;
+            }
+        };
 
-        let output_config = supported_output_config.config();
+        let preferred_sample_rates = match &self.config.sample_rate {
+            Some(sample_rate) => std::slice::from_ref(sample_rate),
+            None => PREFERRED_SAMPLE_RATES,
+        };
+        let supported_output_config = match choose_output_config(&device, preferred_sample_rates) {
+            Some(config) => config,
+            None => {
+                log::warn!("No supported audio output config available");
+                return None;
+            }
+        };
 
-        let mut intermediate_buffer = Vec::new();
+        let mut output_config = supported_output_config.config();
+        if let SupportedBufferSize::Range { min, max } = supported_output_config.buffer_size() {
+            let frames = self.config.buffer_frames.unwrap_or(MIN_BUFFER_FRAMES);
+            output_config.buffer_size = BufferSize::Fixed(frames.max(*min).min(*max));
+        }
 
-        let stream = device
-            .build_output_stream(
-                &output_config,
-                move |data, callback_info| {
-                    intermediate_buffer.clear();
-                    intermediate_buffer.resize(data.len(), 0);
-                    f(&mut intermediate_buffer);
-                    for (i, sample) in intermediate_buffer.drain(0..).enumerate() {
-                        data[i] = sample.to_f32();
+        let sample_format = supported_output_config.sample_format();
+        let build_stream = |config: &StreamConfig| {
+            let failures = self.failures.clone();
+            let on_error = move |e| {
+                log::warn!("Audio stream error: {}", e);
+                let _ = failures.send(AudioCommand::StreamFailed(generation));
+            };
+            let f = &self.f;
+            match sample_format {
+                SampleFormat::F32 => build_output_stream::<f32, _, _>(&device, config, f, on_error),
+                SampleFormat::I16 => build_output_stream::<i16, _, _>(&device, config, f, on_error),
+                SampleFormat::U16 => build_output_stream::<u16, _, _>(&device, config, f, on_error),
+            }
+        };
+
+        let stream = match build_stream(&output_config) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!(
+                    "Could not open audio stream with buffer size {:?}, falling back to default: {}",
+                    output_config.buffer_size,
+                    e
+                );
+                output_config.buffer_size = BufferSize::Default;
+                match build_stream(&output_config) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("Could not open audio stream: {}", e);
+                        return None;
                     }
-                },
-                |e| panic!("{}", e),
+                }
+            }
+        };
+        log::info!(
+            "Audio output: {:?}, {} channels at {} Hz, buffer size {:?} (supported {:?})",
+            sample_format,
+            output_config.channels,
+            output_config.sample_rate.0,
+            output_config.buffer_size,
+            supported_output_config.buffer_size()
+        );
+        *self.info.lock().unwrap() = Some(AudioStreamInfo {
+            sample_rate: output_config.sample_rate.0,
+            channels: output_config.channels,
+            buffer_frames: match output_config.buffer_size {
+                BufferSize::Fixed(frames) => Some(frames),
+                BufferSize::Default => None,
+            },
+        });
+
+        Some(stream)
+    }
+}
+
+fn build_output_stream<T: Sample, F: FnMut(&mut [i16]) + 'static + Send, E>(
+    device: &Device,
+    config: &StreamConfig,
+    f: &Arc<Mutex<F>>,
+    on_error: E,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    E: FnMut(cpal::StreamError) + Send + 'static,
+{
+    let f = Arc::clone(f);
+    let mut intermediate_buffer = Vec::new();
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut f = f.lock().unwrap();
+            intermediate_buffer.clear();
+            intermediate_buffer.resize(data.len(), 0);
+            f(&mut intermediate_buffer);
+            for (i, sample) in intermediate_buffer.drain(0..).enumerate() {
+                data[i] = T::from(&sample);
+            }
+        },
+        on_error,
+    )
+}
+
+// Prefers stereo, then f32 over i16 over u16 samples, then a sample rate close to the preferred
+// ones, then the smallest buffer
+fn choose_output_config(
+    device: &Device,
+    preferred_sample_rates: &[u32],
+) -> Option<SupportedStreamConfig> {
+    device
+        .supported_output_configs()
+        .ok()?
+        .map(|config| {
+            let sample_rate = closest_sample_rate(&config, preferred_sample_rates);
+            (config, sample_rate)
+        })
+        .min_by_key(|(config, sample_rate)| {
+            let format_rank = match config.sample_format() {
+                SampleFormat::F32 => 0,
+                SampleFormat::I16 => 1,
+                SampleFormat::U16 => 2,
+            };
+            let buffer_min = match config.buffer_size() {
+                SupportedBufferSize::Range { min, .. } => *min,
+                SupportedBufferSize::Unknown => u32::max_value(),
+            };
+            (
+                config.channels() != PREFERRED_CHANNELS,
+                format_rank,
+                sample_rate_distance(*sample_rate, preferred_sample_rates),
+                buffer_min,
             )
-            .unwrap();
-        stream.play().unwrap();
+        })
+        .map(|(config, sample_rate)| config.with_sample_rate(sample_rate))
+}
 
-        // MEGA HACK: Keep the stream alive until the end of time by forgetting about it. RIP.
-        std::mem::forget(stream);
-    });
+fn closest_sample_rate(config: &SupportedStreamConfigRange, preferred: &[u32]) -> SampleRate {
+    let (min, max) = (config.min_sample_rate().0, config.max_sample_rate().0);
+    let rate = preferred
+        .iter()
+        .copied()
+        .find(|rate| (min..=max).contains(rate))
+        .unwrap_or_else(|| preferred[0].max(min).min(max));
+    SampleRate(rate)
+}
+
+fn sample_rate_distance(sample_rate: SampleRate, preferred: &[u32]) -> u32 {
+    preferred
+        .iter()
+        .map(|rate| (*rate as i64 - sample_rate.0 as i64).abs() as u32)
+        .min()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    // Records what was done to it in a log shared with the test
+    struct FakeStream {
+        generation: u32,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl OutputStream for FakeStream {
+        fn play(&self) -> Result<(), String> {
+            self.log
+                .borrow_mut()
+                .push(format!("play {}", self.generation));
+            Ok(())
+        }
+
+        fn pause(&self) -> Result<(), String> {
+            self.log
+                .borrow_mut()
+                .push(format!("pause {}", self.generation));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reopened_stream_stays_paused_until_resumed() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let device_present = Rc::new(RefCell::new(true));
+        let running = AtomicBool::new(false);
+        let open = {
+            let (log, device_present) = (Rc::clone(&log), Rc::clone(&device_present));
+            move |generation| {
+                log.borrow_mut().push(format!("open {}", generation));
+                if *device_present.borrow() {
+                    Some(FakeStream {
+                        generation,
+                        log: Rc::clone(&log),
+                    })
+                } else {
+                    None
+                }
+            }
+        };
+        let mut control = StreamControl::new(open, &running);
+        assert!(running.load(Ordering::Relaxed));
+
+        assert!(control.handle(AudioCommand::Pause));
+        assert!(!running.load(Ordering::Relaxed));
+
+        // the device goes away while paused and comes back before the game resumes
+        *device_present.borrow_mut() = false;
+        assert!(control.handle(AudioCommand::StreamFailed(0)));
+        assert!(control.stream.is_none());
+        // reported again by the same stream
+        assert!(control.handle(AudioCommand::StreamFailed(0)));
+        *device_present.borrow_mut() = true;
+        control.reopen();
+        assert!(control.stream.is_some());
+        assert!(!running.load(Ordering::Relaxed));
+
+        assert!(control.handle(AudioCommand::Resume));
+        assert!(running.load(Ordering::Relaxed));
+        assert!(!control.handle(AudioCommand::Stop));
+
+        assert_eq!(
+            *log.borrow(),
+            ["open 0", "play 0", "pause 0", "open 1", "open 2", "play 2"]
+        );
+    }
 }
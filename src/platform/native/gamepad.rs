@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use gilrs::{Axis as GilrsAxis, Button, EventType, GamepadId, Gilrs};
+
+use crate::input::{Axis, GamepadButton, InputEvent};
+
+/// Gamepad input through gilrs, which also picks up pads plugged in while the game runs.
+pub struct Gamepads {
+    gilrs: Option<Gilrs>,
+    // What each pad holds down or has off center, to release when it disconnects
+    held: HashMap<GamepadId, HeldInput>,
+}
+
+#[derive(Default)]
+struct HeldInput {
+    buttons: Vec<GamepadButton>,
+    axes: Vec<Axis>,
+}
+
+impl Gamepads {
+    /// Gamepads are left out with a warning if the platform doesn't support them.
+    pub fn new() -> Gamepads {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => {
+                for (_, gamepad) in gilrs.gamepads() {
+                    log::info!("Gamepad \"{}\" connected", gamepad.name());
+                }
+                Some(gilrs)
+            }
+            Err(e) => {
+                log::warn!("Gamepads are not available: {}", e);
+                None
+            }
+        };
+        Gamepads {
+            gilrs,
+            held: HashMap::new(),
+        }
+    }
+
+    /// Adds what happened since the last poll to `events`.
+    pub fn poll(&mut self, events: &mut Vec<InputEvent>) {
+        let gilrs = match &mut self.gilrs {
+            Some(gilrs) => gilrs,
+            None => return,
+        };
+        while let Some(event) = gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    if let Some(button) = get_button(button) {
+                        let held = self.held.entry(event.id).or_default();
+                        if !held.buttons.contains(&button) {
+                            held.buttons.push(button);
+                        }
+                        events.push(InputEvent::ButtonDown(button));
+                    }
+                }
+                EventType::ButtonReleased(button, _) => {
+                    if let Some(button) = get_button(button) {
+                        let held = self.held.entry(event.id).or_default();
+                        held.buttons.retain(|held| *held != button);
+                        events.push(InputEvent::ButtonUp(button));
+                    }
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    if let Some(axis) = get_axis(axis) {
+                        let held = self.held.entry(event.id).or_default();
+                        held.axes.retain(|held| *held != axis);
+                        if value != 0. {
+                            held.axes.push(axis);
+                        }
+                        events.push(InputEvent::AxisMoved(axis, value));
+                    }
+                }
+                EventType::Connected => {
+                    log::info!("Gamepad \"{}\" connected", gilrs.gamepad(event.id).name());
+                }
+                EventType::Disconnected => {
+                    log::info!(
+                        "Gamepad \"{}\" disconnected",
+                        gilrs.gamepad(event.id).name()
+                    );
+                    if let Some(held) = self.held.remove(&event.id) {
+                        for button in held.buttons {
+                            events.push(InputEvent::ButtonUp(button));
+                        }
+                        for axis in held.axes {
+                            events.push(InputEvent::AxisMoved(axis, 0.));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn get_button(button: Button) -> Option<GamepadButton> {
+    match button {
+        Button::South => Some(GamepadButton::South),
+        Button::East => Some(GamepadButton::East),
+        Button::North => Some(GamepadButton::North),
+        Button::West => Some(GamepadButton::West),
+        Button::LeftTrigger => Some(GamepadButton::LeftShoulder),
+        Button::RightTrigger => Some(GamepadButton::RightShoulder),
+        Button::LeftTrigger2 => Some(GamepadButton::LeftTrigger),
+        Button::RightTrigger2 => Some(GamepadButton::RightTrigger),
+        Button::Select => Some(GamepadButton::Select),
+        Button::Start => Some(GamepadButton::Start),
+        Button::Mode => Some(GamepadButton::Home),
+        Button::LeftThumb => Some(GamepadButton::LeftStick),
+        Button::RightThumb => Some(GamepadButton::RightStick),
+        Button::DPadUp => Some(GamepadButton::DPadUp),
+        Button::DPadDown => Some(GamepadButton::DPadDown),
+        Button::DPadLeft => Some(GamepadButton::DPadLeft),
+        Button::DPadRight => Some(GamepadButton::DPadRight),
+        _ => None,
+    }
+}
+
+// D-pads reported as axes come through as buttons, gilrs's default filters turn them into those
+fn get_axis(axis: GilrsAxis) -> Option<Axis> {
+    match axis {
+        GilrsAxis::LeftStickX => Some(Axis::LeftStickX),
+        GilrsAxis::LeftStickY => Some(Axis::LeftStickY),
+        GilrsAxis::RightStickX => Some(Axis::RightStickX),
+        GilrsAxis::RightStickY => Some(Axis::RightStickY),
+        _ => None,
+    }
+}
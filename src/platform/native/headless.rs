@@ -0,0 +1,124 @@
+use anyhow::Error;
+use glutin::{event_loop::EventLoop, ContextBuilder, PossiblyCurrent};
+
+use crate::gl;
+
+/// A GL context without a window, for running rendering code in tests and visual tests. Draw to
+/// `gl::RenderTarget::Screen` and read the results back with `gl::Context::read_pixels`.
+pub struct HeadlessGl {
+    pub context: gl::Context,
+    // stands in for the screen, since a headless context may not have a default framebuffer
+    _target: gl::RenderTexture,
+    // the glutin context has to outlive everything made with it, and the event loop the context
+    _glutin_context: glutin::Context<PossiblyCurrent>,
+    _event_loop: EventLoop<()>,
+}
+
+/// Native only. Made current on the calling thread, which any thread can be, so it works from
+/// tests.
+pub fn create_headless_gl(width: u32, height: u32) -> Result<HeadlessGl, Error> {
+    let event_loop = any_thread_event_loop()?;
+    let glutin_context = unsafe {
+        ContextBuilder::new()
+            .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGlEs, (2, 0)))
+            .with_gl_debug_flag(cfg!(feature = "debug-gl"))
+            .build_headless(&event_loop, glutin::dpi::PhysicalSize::new(width, height))?
+            .make_current()
+            .map_err(|(_, e)| e)?
+    };
+
+    let mut context = gl::Context::from_glow_context(glow::Context::from_loader_function(|addr| {
+        glutin_context.get_proc_address(addr)
+    }));
+    let target = unsafe {
+        let target = context.create_render_texture(gl::TextureFormat::RGBA8, width, height)?;
+        context.set_screen_framebuffer(&target)?;
+        target
+    };
+    Ok(HeadlessGl {
+        context,
+        _target: target,
+        _glutin_context: glutin_context,
+        _event_loop: event_loop,
+    })
+}
+
+/// `create_headless_gl` for tests, which skip with a notice instead of failing where there is no
+/// GL driver or display to get a context from.
+#[cfg(test)]
+pub fn headless_gl_for_test(width: u32, height: u32) -> Option<HeadlessGl> {
+    match create_headless_gl(width, height) {
+        Ok(gl) => Some(gl),
+        Err(e) => {
+            eprintln!("Skipping, no headless GL context: {:#}", e);
+            None
+        }
+    }
+}
+
+// Tests don't run on the main thread, which winit otherwise insists on where it can tell
+#[cfg(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn any_thread_event_loop() -> Result<EventLoop<()>, Error> {
+    use glutin::platform::unix::EventLoopExtUnix;
+    // winit panics instead of returning an error when it has no display server to connect to
+    if std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none() {
+        anyhow::bail!("Neither DISPLAY nor WAYLAND_DISPLAY is set");
+    }
+    Ok(EventLoop::new_any_thread())
+}
+
+#[cfg(target_os = "windows")]
+fn any_thread_event_loop() -> Result<EventLoop<()>, Error> {
+    use glutin::platform::windows::EventLoopExtWindows;
+    Ok(EventLoop::new_any_thread())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "windows"
+)))]
+fn any_thread_event_loop() -> Result<EventLoop<()>, Error> {
+    Ok(EventLoop::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use euclid::{point2, size2, Rect};
+
+    use super::*;
+
+    #[test]
+    fn clear_and_read_back() {
+        let mut gl = match headless_gl_for_test(8, 4) {
+            Some(gl) => gl,
+            None => return,
+        };
+        let pixels = unsafe {
+            gl.context
+                .pass(gl::RenderTarget::Screen)
+                .clear([1., 0.5, 0., 1.]);
+            gl.context
+                .read_pixels(
+                    gl::RenderTarget::Screen,
+                    Rect::new(point2(0, 0), size2(8, 4)),
+                )
+                .unwrap()
+        };
+        assert_eq!(pixels.len(), 8 * 4 * 4);
+        for pixel in pixels.chunks(4) {
+            assert_eq!(pixel[0], 255);
+            assert!((pixel[1] as i32 - 128).abs() <= 1, "green is {}", pixel[1]);
+            assert_eq!(&pixel[2..], &[0, 255]);
+        }
+    }
+}
@@ -1,16 +1,36 @@
 mod audio;
+mod gamepad;
+#[cfg(feature = "headless")]
+mod headless;
+mod presence;
+mod progress;
+
+use std::collections::HashSet;
 
 use crate::{
     gl,
-    input::{InputEvent, Key, MouseButton},
+    input::{InputEvent, Key, Modifiers, MouseButton},
 };
 
+use anyhow::{Context, Error};
 use euclid::{point2, vec2};
 use glutin::event::{
-    ElementState, KeyboardInput, MouseButton as GlutinMouseButton, MouseScrollDelta, VirtualKeyCode,
+    ElementState, KeyboardInput, ModifiersState, MouseButton as GlutinMouseButton,
+    MouseScrollDelta, VirtualKeyCode,
 };
 
-pub use audio::start_audio_playback;
+pub use audio::{audio_running, start_audio_playback_with};
+#[cfg(feature = "headless")]
+pub use headless::create_headless_gl;
+#[cfg(all(test, feature = "headless"))]
+pub use headless::headless_gl_for_test;
+pub use presence::create_presence;
+pub use progress::{load_progress, save_progress};
+
+/// Writes a file the game made, like a debug dump, to `name` in the working directory.
+pub fn save_file(name: &str, bytes: &[u8]) -> Result<(), Error> {
+    std::fs::write(name, bytes).with_context(|| format!("Could not write {}", name))
+}
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn run<
@@ -38,6 +58,10 @@ pub fn run<
     let windowed_context = unsafe {
         glutin::ContextBuilder::new()
             .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGlEs, (2, 0)))
+            .with_depth_buffer(24)
+            .with_stencil_buffer(8)
+            // lets the gl module get the driver's own messages through KHR_debug
+            .with_gl_debug_flag(cfg!(feature = "debug-gl"))
             .build_windowed(wb, &event_loop)
             .unwrap()
             .make_current()
@@ -48,20 +72,31 @@ pub fn run<
         gl::Context::from_glow_context(glow::Context::from_loader_function(|addr| {
             windowed_context.get_proc_address(addr)
         }));
+    let framebuffer_size = windowed_context.window().inner_size();
+    unsafe { gl_context.set_screen_size(framebuffer_size.width, framebuffer_size.height) };
 
     let mut update_fn = f(&mut gl_context);
 
+    let mut gamepads = gamepad::Gamepads::new();
     let mut input_events = Vec::new();
+    let mut modifiers = Modifiers::default();
+    // to release when focus is lost, since the key ups go to whatever has focus then
+    let mut held_keys = HashSet::new();
     let mut last_time = Instant::now();
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
         match event {
-            event::Event::MainEventsCleared => windowed_context.window().request_redraw(),
+            event::Event::MainEventsCleared => {
+                gamepads.poll(&mut input_events);
+                windowed_context.window().request_redraw();
+            }
             event::Event::WindowEvent {
                 event: WindowEvent::Resized(size),
                 ..
             } => {
                 log::info!("Resize to {:?}", size);
+                windowed_context.resize(size);
+                unsafe { gl_context.set_screen_size(size.width, size.height) };
             }
             event::Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
@@ -82,22 +117,27 @@ pub fn run<
                     if let Some(key) = get_key(key) {
                         match state {
                             ElementState::Pressed => {
-                                input_events.push(InputEvent::KeyDown(key));
+                                held_keys.insert(key);
+                                input_events.push(InputEvent::KeyDown(key, modifiers));
                             }
                             ElementState::Released => {
-                                input_events.push(InputEvent::KeyUp(key));
+                                held_keys.remove(&key);
+                                input_events.push(InputEvent::KeyUp(key, modifiers));
                             }
                         }
                     }
                 }
+                WindowEvent::ModifiersChanged(state) => {
+                    modifiers = get_modifiers(state);
+                }
                 WindowEvent::MouseInput { state, button, .. } => {
                     let button = get_mouse_button(button);
                     match state {
                         ElementState::Pressed => {
-                            input_events.push(InputEvent::MouseDown(button));
+                            input_events.push(InputEvent::MouseDown(button, modifiers));
                         }
                         ElementState::Released => {
-                            input_events.push(InputEvent::MouseUp(button));
+                            input_events.push(InputEvent::MouseUp(button, modifiers));
                         }
                     }
                 }
@@ -113,6 +153,15 @@ pub fn run<
                     let position = position.to_logical(1.0);
                     input_events.push(InputEvent::MouseMove(point2(position.x, position.y)));
                 }
+                WindowEvent::Focused(focused) => {
+                    if !focused {
+                        modifiers = Modifiers::default();
+                        for key in held_keys.drain() {
+                            input_events.push(InputEvent::KeyUp(key, modifiers));
+                        }
+                    }
+                    input_events.push(InputEvent::Focused(focused));
+                }
                 _ => {}
             },
             event::Event::RedrawRequested(_) => {
@@ -121,6 +170,9 @@ pub fn run<
                 last_time = now;
                 update_fn(dt, &input_events, &mut gl_context);
                 input_events.clear();
+                if let Err(e) = unsafe { gl_context.present() } {
+                    log::error!("Could not present frame: {}", e);
+                }
                 windowed_context.swap_buffers().unwrap();
                 unsafe { gl_context.maintain() };
             }
@@ -169,10 +221,26 @@ fn get_key(vk: VirtualKeyCode) -> Option<Key> {
         VirtualKeyCode::Up => Some(Key::Up),
         VirtualKeyCode::Right => Some(Key::Right),
         VirtualKeyCode::Down => Some(Key::Down),
+        VirtualKeyCode::F3 => Some(Key::F3),
+        VirtualKeyCode::F10 => Some(Key::F10),
+        VirtualKeyCode::LShift => Some(Key::LShift),
+        VirtualKeyCode::RShift => Some(Key::RShift),
+        VirtualKeyCode::LControl => Some(Key::LCtrl),
+        VirtualKeyCode::RControl => Some(Key::RCtrl),
+        VirtualKeyCode::LAlt => Some(Key::LAlt),
+        VirtualKeyCode::RAlt => Some(Key::RAlt),
         _ => None,
     }
 }
 
+fn get_modifiers(state: ModifiersState) -> Modifiers {
+    Modifiers {
+        shift: state.shift(),
+        ctrl: state.ctrl(),
+        alt: state.alt(),
+    }
+}
+
 fn get_mouse_button(button: GlutinMouseButton) -> MouseButton {
     match button {
         GlutinMouseButton::Left => MouseButton::Left,
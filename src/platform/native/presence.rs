@@ -0,0 +1,144 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use crate::presence::{NoPresence, Presence, PresenceState};
+
+const PRESENCE_FILE_ENV_VAR: &str = "LD48_PRESENCE_FILE";
+
+/// Writes the latest presence state as a single JSON line, replacing the previous contents so the
+/// file never grows and a reader that isn't running costs nothing. Writing happens on a thread of
+/// its own so a slow disk never holds up the game.
+pub struct FilePresence {
+    lines: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl FilePresence {
+    pub fn new(path: PathBuf) -> FilePresence {
+        let (lines, received) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("presence".to_owned())
+            .spawn(move || write_lines(&path, received))
+            .map_err(|e| log::warn!("Could not start the presence writer: {}", e))
+            .ok();
+        FilePresence {
+            lines: Some(lines),
+            writer,
+        }
+    }
+}
+
+impl Presence for FilePresence {
+    fn update(&mut self, state: &PresenceState) {
+        if let Some(lines) = &self.lines {
+            let _ = lines.send(state.to_json() + "\n");
+        }
+    }
+}
+
+impl Drop for FilePresence {
+    // Lets the writer finish the last line
+    fn drop(&mut self) {
+        self.lines = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// Writes each line received to `path` until the sender is gone, skipping to the latest when
+// several are waiting. Only the first of a run of failures is logged.
+fn write_lines(path: &Path, received: Receiver<String>) {
+    let mut failed = false;
+    while let Ok(mut line) = received.recv() {
+        while let Ok(later) = received.try_recv() {
+            line = later;
+        }
+        match replace_contents(path, &line) {
+            Ok(()) => failed = false,
+            Err(e) => {
+                if !failed {
+                    log::warn!("Could not write presence to {}: {}", path.display(), e);
+                }
+                failed = true;
+            }
+        }
+    }
+}
+
+// Write then rename so readers never see a partial line
+fn replace_contents(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Presence is written to the file named by `LD48_PRESENCE_FILE`, if set.
+pub fn create_presence() -> Box<dyn Presence> {
+    match std::env::var_os(PRESENCE_FILE_ENV_VAR) {
+        Some(path) => Box::new(FilePresence::new(PathBuf::from(path))),
+        None => Box::new(NoPresence),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::presence::PresenceMode;
+
+    fn state(playtime_secs: u32) -> PresenceState {
+        PresenceState {
+            room: Some("Red".to_owned()),
+            depth: Some(1),
+            playtime_secs,
+            mode: PresenceMode::Playing,
+        }
+    }
+
+    #[test]
+    fn file_holds_only_the_latest_line() {
+        let dir = env::temp_dir().join(format!("ld48-presence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("presence.json");
+
+        let mut presence = FilePresence::new(path.clone());
+        for playtime_secs in 0..20 {
+            presence.update(&state(playtime_secs));
+        }
+        drop(presence);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            state(19).to_json() + "\n"
+        );
+        assert!(!path.with_extension("tmp").exists());
+
+        // a reader that went away and left the file behind doesn't matter
+        let mut presence = FilePresence::new(path.clone());
+        presence.update(&state(20));
+        drop(presence);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            state(20).to_json() + "\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unwritable_paths_dont_stop_the_game() {
+        let path = env::temp_dir()
+            .join(format!("ld48-missing-{}", std::process::id()))
+            .join("presence.json");
+        let mut presence = FilePresence::new(path.clone());
+        presence.update(&state(0));
+        presence.update(&state(1));
+        drop(presence);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+
+const PROGRESS_FILE: &str = "ld48_progress.txt";
+const PROGRESS_FILE_ENV_VAR: &str = "LD48_PROGRESS_FILE";
+// Saves before the last one kept next to it, from `.1` for the newest to `.3`
+const BACKUP_COUNT: u32 = 3;
+
+/// Replaces the saved progress with `contents`, keeping the last few saves as backups.
+pub fn save_progress(contents: &str) -> Result<(), Error> {
+    let path = progress_path();
+    write_progress(&path, contents)
+        .with_context(|| format!("Could not save progress to {}", path.display()))
+}
+
+/// Every progress save still around, newest first: the one saved last and then its backups.
+/// Empty if nothing was saved yet.
+pub fn load_progress() -> Result<Vec<String>, Error> {
+    Ok(read_progress(&progress_path()))
+}
+
+// In the working directory unless `LD48_PROGRESS_FILE` says otherwise
+fn progress_path() -> PathBuf {
+    std::env::var_os(PROGRESS_FILE_ENV_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(PROGRESS_FILE))
+}
+
+// `path` with `.n` on the end
+fn backup_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// Write and sync then rename so quitting or crashing halfway through never leaves a partial save.
+// The save being replaced becomes backup 1 and the oldest backup is dropped.
+fn write_progress(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    for n in (1..BACKUP_COUNT).rev() {
+        rename_if_exists(&backup_path(path, n), &backup_path(path, n + 1))?;
+    }
+    rename_if_exists(path, &backup_path(path, 1))?;
+    fs::rename(&tmp_path, path)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+// Files that can't be read are left out with a warning, so a damaged save doesn't hide the
+// backups behind it
+fn read_progress(path: &Path) -> Vec<String> {
+    let paths =
+        std::iter::once(path.to_owned()).chain((1..=BACKUP_COUNT).map(|n| backup_path(path, n)));
+    let mut saves = Vec::new();
+    for path in paths {
+        match fs::read(&path) {
+            // bad bytes are kept for the game's check of the save to catch
+            Ok(bytes) => saves.push(String::from_utf8_lossy(&bytes).into_owned()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Could not read progress from {}: {}", path.display(), e),
+        }
+    }
+    saves
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ld48-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn saves_replace_each_other() {
+        let dir = temp_dir("progress");
+        let path = dir.join("progress.txt");
+
+        assert!(read_progress(&path).is_empty());
+        write_progress(&path, "visited = Blue, Red\n").unwrap();
+        write_progress(&path, "visited = Blue\n").unwrap();
+        assert_eq!(
+            read_progress(&path),
+            ["visited = Blue\n", "visited = Blue, Red\n"]
+        );
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_last_few_saves_are_kept() {
+        let dir = temp_dir("progress-backups");
+        let path = dir.join("progress.txt");
+
+        for i in 0..6 {
+            write_progress(&path, &format!("save {}\n", i)).unwrap();
+        }
+        assert_eq!(
+            read_progress(&path),
+            ["save 5\n", "save 4\n", "save 3\n", "save 2\n"]
+        );
+        assert!(!backup_path(&path, BACKUP_COUNT + 1).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_saves_still_leave_the_backups() {
+        let dir = temp_dir("progress-damaged");
+        let path = dir.join("progress.txt");
+
+        write_progress(&path, "visited = Blue, Red\n").unwrap();
+        write_progress(&path, "visited = Blue, Red, Green\n").unwrap();
+        // cut short and with bytes that aren't UTF-8, like a disk that failed partway
+        fs::write(&path, b"visited = Blue, R\xff").unwrap();
+        let saves = read_progress(&path);
+        assert_eq!(saves.len(), 2);
+        assert!(saves[0].starts_with("visited = Blue, R"));
+        assert_eq!(saves[1], "visited = Blue, Red\n");
+        // a temporary file left over from a write that never finished changes nothing
+        fs::write(path.with_extension("tmp"), "visi").unwrap();
+        assert_eq!(read_progress(&path), saves);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
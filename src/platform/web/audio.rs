@@ -1,11 +1,72 @@
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Sample,
+    BufferSize, Sample, SampleRate, Stream,
 };
 use wasm_bindgen::{closure::Closure, JsCast};
-use web_sys::AudioProcessingEvent;
+use web_sys::{AudioContext, AudioContextState};
+
+use crate::platform::{AudioConfig, AudioStreamInfo};
+
+// Browsers only let audio start from inside a handler for one of these
+const GESTURE_EVENTS: &[&str] = &["click", "keydown"];
+// Used unless configured otherwise. Most of the game's sounds are 44.1kHz and the mixer doesn't
+// resample
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// The mixer produces interleaved stereo
+const PREFERRED_CHANNELS: u16 = 2;
+
+static AUDIO_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// False until the browser has allowed audio to start, which may need a click or key press.
+pub fn audio_running() -> bool {
+    AUDIO_RUNNING.load(Ordering::Relaxed)
+}
+
+/// Owns the output stream. Dropping this closes the stream's audio context.
+pub struct AudioOutput {
+    stream: Rc<Stream>,
+    paused: Arc<AtomicBool>,
+    info: AudioStreamInfo,
+}
+
+impl AudioOutput {
+    // cpal's web stream schedules a new set of buffers every time it is played, so pausing is done
+    // by outputting silence instead of suspending the context
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
 
-pub fn start_audio_playback<F: FnMut(&mut [i16]) + 'static + Send>(mut f: F) {
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// What the stream actually got. Always available on the web, since the stream is opened
+    /// before `start_audio_playback_with` returns.
+    pub fn stream_info(&self) -> Option<AudioStreamInfo> {
+        Some(self.info)
+    }
+}
+
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        let _ = self.stream.pause();
+        AUDIO_RUNNING.store(false, Ordering::Relaxed);
+    }
+}
+
+pub fn start_audio_playback_with<F: FnMut(&mut [i16]) + 'static + Send>(
+    config: AudioConfig,
+    f: F,
+) -> AudioOutput {
     let host = cpal::default_host();
 
     let device = host
@@ -15,35 +76,152 @@ pub fn start_audio_playback<F: FnMut(&mut [i16]) + 'static + Send>(mut f: F) {
     let supported_output_config = device
         .supported_output_configs()
         .unwrap()
-        .next()
-        .unwrap()
-        .with_max_sample_rate();
+        .filter(|config| config.sample_format() == cpal::SampleFormat::F32)
+        .min_by_key(|config| config.channels() != PREFERRED_CHANNELS)
+        .expect("Output format not supported");
+    let sample_rate = config
+        .sample_rate
+        .unwrap_or(DEFAULT_SAMPLE_RATE)
+        .max(supported_output_config.min_sample_rate().0)
+        .min(supported_output_config.max_sample_rate().0);
+    let supported_output_config = supported_output_config.with_sample_rate(SampleRate(sample_rate));
 
-    match supported_output_config.sample_format() {
-        cpal::SampleFormat::F32 => {}
-        _ => {
-            panic!("Output format not supported");
+    let mut output_config = supported_output_config.config();
+    if let Some(frames) = config.buffer_frames {
+        output_config.buffer_size = BufferSize::Fixed(frames.max(1));
+    }
+
+    let paused = Arc::new(AtomicBool::new(false));
+    let f = Arc::new(Mutex::new(f));
+    let build_stream = |output_config: &cpal::StreamConfig| {
+        let mut intermediate_buffer = Vec::new();
+        let f = Arc::clone(&f);
+        device.build_output_stream(
+            output_config,
+            {
+                let paused = Arc::clone(&paused);
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    if paused.load(Ordering::Relaxed) {
+                        for sample in data.iter_mut() {
+                            *sample = 0.;
+                        }
+                        return;
+                    }
+                    intermediate_buffer.clear();
+                    intermediate_buffer.resize(data.len(), 0);
+                    (f.lock().unwrap())(&mut intermediate_buffer);
+                    for (i, sample) in intermediate_buffer.drain(0..).enumerate() {
+                        data[i] = sample.to_f32();
+                    }
+                }
+            },
+            |e| log::warn!("Audio stream error: {}", e),
+        )
+    };
+
+    let stream = match build_stream(&output_config) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!(
+                "Could not open audio stream with buffer size {:?}, falling back to default: {}",
+                output_config.buffer_size,
+                e
+            );
+            output_config.buffer_size = BufferSize::Default;
+            build_stream(&output_config).unwrap()
         }
+    };
+    let stream = Rc::new(stream);
+
+    // cpal's audio context is private, so check with a throwaway one whether the autoplay policy
+    // will let a context run yet
+    let blocked = match AudioContext::new() {
+        Ok(context) => {
+            let blocked = context.state() != AudioContextState::Running;
+            let _ = context.close();
+            blocked
+        }
+        Err(_) => true,
+    };
+    if blocked {
+        log::info!("Audio is blocked until the first click or key press");
+        play_on_first_gesture(Rc::downgrade(&stream));
+    } else {
+        play(&stream);
+    }
+
+    log::info!(
+        "Audio output: {} channels at {} Hz, buffer size {:?}",
+        output_config.channels,
+        output_config.sample_rate.0,
+        output_config.buffer_size
+    );
+    let info = AudioStreamInfo {
+        sample_rate: output_config.sample_rate.0,
+        channels: output_config.channels,
+        buffer_frames: match output_config.buffer_size {
+            BufferSize::Fixed(frames) => Some(frames),
+            BufferSize::Default => None,
+        },
+    };
+
+    AudioOutput {
+        stream,
+        paused,
+        info,
     }
+}
 
-    let output_config = supported_output_config.config();
+fn play(stream: &Stream) -> bool {
+    match stream.play() {
+        Ok(()) => {
+            AUDIO_RUNNING.store(true, Ordering::Relaxed);
+            true
+        }
+        Err(e) => {
+            log::warn!("Could not start audio: {}", e);
+            false
+        }
+    }
+}
 
-    let mut intermediate_buffer = Vec::new();
+// Plays the stream from inside the first gesture handler that manages to start it, then removes
+// the listeners again. Does nothing if the stream was dropped in the meantime.
+fn play_on_first_gesture(stream: Weak<Stream>) {
+    let document = web_sys::window()
+        .and_then(|win| win.document())
+        .expect("Cannot get document");
 
-    let stream = device
-        .build_output_stream(
-            &output_config,
-            move |data, _| {
-                intermediate_buffer.clear();
-                intermediate_buffer.resize(data.len(), 0);
-                f(&mut intermediate_buffer);
-                for (i, sample) in intermediate_buffer.drain(0..).enumerate() {
-                    data[i] = sample.to_f32();
+    let listener: Rc<RefCell<Option<Closure<dyn FnMut()>>>> = Rc::new(RefCell::new(None));
+    let on_gesture = Closure::wrap(Box::new({
+        let listener = Rc::clone(&listener);
+        let document = document.clone();
+        move || {
+            let started = match stream.upgrade() {
+                Some(stream) => play(&stream),
+                None => true,
+            };
+            if !started {
+                return;
+            }
+            if let Some(on_gesture) = listener.borrow_mut().take() {
+                for event in GESTURE_EVENTS {
+                    let _ = document.remove_event_listener_with_callback(
+                        event,
+                        on_gesture.as_ref().unchecked_ref(),
+                    );
                 }
-            },
-            |e| panic!("{}", e),
-        )
-        .unwrap();
-    stream.play().unwrap();
-    std::mem::forget(stream);
+                // A closure can't be dropped while it is running, so this one small closure is
+                // leaked instead
+                on_gesture.forget();
+            }
+        }
+    }) as Box<dyn FnMut()>);
+
+    for event in GESTURE_EVENTS {
+        document
+            .add_event_listener_with_callback(event, on_gesture.as_ref().unchecked_ref())
+            .unwrap();
+    }
+    *listener.borrow_mut() = Some(on_gesture);
 }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::JsCast;
+use web_sys::{Gamepad, GamepadMappingType};
+
+use crate::input::{Axis, GamepadButton, InputEvent};
+
+// Stick movement smaller than this isn't reported, sticks jitter around wherever they rest
+const AXIS_THRESHOLD: f32 = 0.01;
+
+/// Gamepad input through the Gamepad API. It only tells what pads are doing right now, so every
+/// poll compares that to the last one to make events. Pads plugged in later show up in the list
+/// by themselves.
+#[derive(Default)]
+pub struct Gamepads {
+    // Buttons and axes of each pad as of the last poll, by the pad's index
+    pads: HashMap<u32, PadState>,
+}
+
+#[derive(Default)]
+struct PadState {
+    buttons: Vec<bool>,
+    axes: Vec<f32>,
+}
+
+impl Gamepads {
+    /// Adds what changed since the last poll to `events`.
+    pub fn poll(&mut self, events: &mut Vec<InputEvent>) {
+        let pad_list = match web_sys::window().map(|window| window.navigator().get_gamepads()) {
+            Some(Ok(pad_list)) => pad_list,
+            // no Gamepad API
+            _ => return,
+        };
+        let mut connected = Vec::new();
+        for i in 0..pad_list.length() {
+            // slots of pads that were unplugged are null
+            let pad = match pad_list.get(i).dyn_into::<Gamepad>() {
+                Ok(pad) if pad.connected() => pad,
+                _ => continue,
+            };
+            // other mappings put buttons and axes wherever the pad has them
+            if pad.mapping() != GamepadMappingType::Standard {
+                continue;
+            }
+            connected.push(pad.index());
+            let state = self.pads.entry(pad.index()).or_insert_with(|| {
+                log::info!("Gamepad \"{}\" connected", pad.id());
+                PadState::default()
+            });
+
+            let buttons = pad.buttons();
+            state.buttons.resize(buttons.length() as usize, false);
+            for (index, was_pressed) in state.buttons.iter_mut().enumerate() {
+                let pressed = buttons
+                    .get(index as u32)
+                    .dyn_into::<web_sys::GamepadButton>()
+                    .map_or(false, |button| button.pressed());
+                if pressed == *was_pressed {
+                    continue;
+                }
+                *was_pressed = pressed;
+                if let Some(button) = get_button(index) {
+                    events.push(if pressed {
+                        InputEvent::ButtonDown(button)
+                    } else {
+                        InputEvent::ButtonUp(button)
+                    });
+                }
+            }
+
+            let axes = pad.axes();
+            state.axes.resize(axes.length() as usize, 0.);
+            for (index, last_value) in state.axes.iter_mut().enumerate() {
+                let value = axes.get(index as u32).as_f64().unwrap_or(0.) as f32;
+                if (value - *last_value).abs() < AXIS_THRESHOLD {
+                    continue;
+                }
+                *last_value = value;
+                if let Some((axis, value)) = get_axis(index, value) {
+                    events.push(InputEvent::AxisMoved(axis, value));
+                }
+            }
+        }
+
+        // pads that went away let go of everything
+        self.pads.retain(|index, state| {
+            if connected.contains(index) {
+                return true;
+            }
+            log::info!("Gamepad {} disconnected", index);
+            let held_buttons = (0..state.buttons.len()).filter(|&i| state.buttons[i]);
+            for button in held_buttons.filter_map(get_button) {
+                events.push(InputEvent::ButtonUp(button));
+            }
+            let moved_axes = (0..state.axes.len()).filter(|&i| state.axes[i] != 0.);
+            for (axis, _) in moved_axes.filter_map(|i| get_axis(i, 0.)) {
+                events.push(InputEvent::AxisMoved(axis, 0.));
+            }
+            false
+        });
+    }
+}
+
+// Button indices of the standard mapping
+fn get_button(index: usize) -> Option<GamepadButton> {
+    match index {
+        0 => Some(GamepadButton::South),
+        1 => Some(GamepadButton::East),
+        2 => Some(GamepadButton::West),
+        3 => Some(GamepadButton::North),
+        4 => Some(GamepadButton::LeftShoulder),
+        5 => Some(GamepadButton::RightShoulder),
+        6 => Some(GamepadButton::LeftTrigger),
+        7 => Some(GamepadButton::RightTrigger),
+        8 => Some(GamepadButton::Select),
+        9 => Some(GamepadButton::Start),
+        10 => Some(GamepadButton::LeftStick),
+        11 => Some(GamepadButton::RightStick),
+        12 => Some(GamepadButton::DPadUp),
+        13 => Some(GamepadButton::DPadDown),
+        14 => Some(GamepadButton::DPadLeft),
+        15 => Some(GamepadButton::DPadRight),
+        16 => Some(GamepadButton::Home),
+        _ => None,
+    }
+}
+
+// Axis indices of the standard mapping, which has y going down
+fn get_axis(index: usize, value: f32) -> Option<(Axis, f32)> {
+    match index {
+        0 => Some((Axis::LeftStickX, value)),
+        1 => Some((Axis::LeftStickY, -value)),
+        2 => Some((Axis::RightStickX, value)),
+        3 => Some((Axis::RightStickY, -value)),
+        _ => None,
+    }
+}
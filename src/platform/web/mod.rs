@@ -1,17 +1,65 @@
 mod audio;
+mod gamepad;
+mod presence;
+mod progress;
 
-use std::rc::Rc;
+use std::{collections::HashSet, rc::Rc};
 
+use anyhow::{format_err, Error};
 use euclid::{point2, vec2};
 use wasm_bindgen::{closure::Closure, JsCast};
-use web_sys::{HtmlElement, KeyboardEvent, MouseEvent, WheelEvent};
+use web_sys::{HtmlAnchorElement, HtmlElement, KeyboardEvent, MouseEvent, WheelEvent};
 
 use crate::{
     gl,
-    input::{InputEvent, Key, MouseButton},
+    input::{InputEvent, Key, Modifiers, MouseButton},
 };
 
-pub use audio::start_audio_playback;
+pub use audio::{audio_running, start_audio_playback_with};
+pub use presence::create_presence;
+pub use progress::{load_progress, save_progress};
+
+/// Offers a file the game made, like a debug dump, as a download named `name`, since the page
+/// can't write files itself.
+pub fn save_file(name: &str, bytes: &[u8]) -> Result<(), Error> {
+    let document = web_sys::window()
+        .and_then(|win| win.document())
+        .ok_or_else(|| format_err!("Cannot get document"))?;
+    let link = document
+        .create_element("a")
+        .ok()
+        .and_then(|element| element.dyn_into::<HtmlAnchorElement>().ok())
+        .ok_or_else(|| format_err!("Cannot create a download link"))?;
+    link.set_href(&format!(
+        "data:application/octet-stream;base64,{}",
+        base64(bytes)
+    ));
+    link.set_download(name);
+    link.click();
+    Ok(())
+}
+
+// Standard base64 with padding, for data URLs
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - i * 6)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
 
 pub fn run<
     F: Fn(&mut gl::Context) -> U,
@@ -48,15 +96,37 @@ pub fn run<
         .set_attribute("height", &format!("{}", size.1))
         .expect("cannot set height");
 
+    // WebGL contexts have a depth buffer by default, but no stencil buffer
+    let mut context_attributes = web_sys::WebGlContextAttributes::new();
+    context_attributes.stencil(true);
     let webgl1_context = canvas
-        .get_context("webgl")
+        .get_context_with_context_options("webgl", &context_attributes)
         .expect("1")
         .expect("2")
         .dyn_into::<web_sys::WebGlRenderingContext>()
         .expect("3");
 
+    // glow enables the extension itself, but doesn't say whether it's there
+    let instanced_arrays = matches!(
+        webgl1_context.get_extension("ANGLE_instanced_arrays"),
+        Ok(Some(_))
+    );
+    // float textures aren't known to glow, getting the extensions is what enables them
+    let float_textures = matches!(
+        webgl1_context.get_extension("OES_texture_float"),
+        Ok(Some(_))
+    );
+    let float_render_targets = matches!(
+        webgl1_context.get_extension("WEBGL_color_buffer_float"),
+        Ok(Some(_))
+    );
     let glow_context = glow::Context::from_webgl1_context(webgl1_context);
     let mut gl_context = gl::Context::from_glow_context(glow_context);
+    if instanced_arrays {
+        gl_context.enable_instancing_extension();
+    }
+    gl_context.enable_float_texture_extensions(float_textures, float_render_targets);
+    unsafe { gl_context.set_screen_size(size.0, size.1) };
 
     let mut update_fn = f(&mut gl_context);
 
@@ -65,31 +135,40 @@ pub fn run<
     let mut last_time = None;
 
     let input_events = Rc::new(RefCell::new(Vec::new()));
+    let mut gamepads = gamepad::Gamepads::default();
+    // to release when the page loses focus, since the key ups go to whatever has focus then
+    let held_keys = RefCell::new(HashSet::new());
 
     let input_stream = HtmlEventStream::new(canvas.clone().dyn_into().unwrap(), {
         let input_events = Rc::clone(&input_events);
         move |window_event| match window_event {
             HtmlEvent::KeyDown(key_event) => {
                 if let Some(key) = get_key_from_code(&key_event.code()) {
-                    input_events.borrow_mut().push(InputEvent::KeyDown(key));
+                    held_keys.borrow_mut().insert(key);
+                    input_events
+                        .borrow_mut()
+                        .push(InputEvent::KeyDown(key, get_keyboard_modifiers(&key_event)));
                 }
             }
             HtmlEvent::KeyUp(key_event) => {
                 if let Some(key) = get_key_from_code(&key_event.code()) {
-                    input_events.borrow_mut().push(InputEvent::KeyUp(key));
+                    held_keys.borrow_mut().remove(&key);
+                    input_events
+                        .borrow_mut()
+                        .push(InputEvent::KeyUp(key, get_keyboard_modifiers(&key_event)));
                 }
             }
             HtmlEvent::MouseDown(mouse_event) => {
-                input_events
-                    .borrow_mut()
-                    .push(InputEvent::MouseDown(get_mouse_button(
-                        mouse_event.button(),
-                    )));
+                input_events.borrow_mut().push(InputEvent::MouseDown(
+                    get_mouse_button(mouse_event.button()),
+                    get_mouse_modifiers(&mouse_event),
+                ));
             }
             HtmlEvent::MouseUp(mouse_event) => {
-                input_events
-                    .borrow_mut()
-                    .push(InputEvent::MouseUp(get_mouse_button(mouse_event.button())));
+                input_events.borrow_mut().push(InputEvent::MouseUp(
+                    get_mouse_button(mouse_event.button()),
+                    get_mouse_modifiers(&mouse_event),
+                ));
             }
             HtmlEvent::MouseMove(mouse_event) => {
                 input_events.borrow_mut().push(InputEvent::MouseMove(point2(
@@ -103,6 +182,17 @@ pub fn run<
                     -wheel_event.delta_y() as f32,
                 )));
             }
+            HtmlEvent::VisibilityChange(visible) => {
+                let mut input_events = input_events.borrow_mut();
+                if !visible {
+                    release_keys(&mut held_keys.borrow_mut(), &mut input_events);
+                }
+                input_events.push(InputEvent::Focused(visible));
+            }
+            // alt-tabbing to another window blurs the page without hiding it
+            HtmlEvent::Blur => {
+                release_keys(&mut held_keys.borrow_mut(), &mut input_events.borrow_mut());
+            }
         }
     });
 
@@ -111,9 +201,16 @@ pub fn run<
             // Keep input_stream alive for the lifetime of the client
             let _ = &input_stream;
 
+            // the Gamepad API has no events for buttons and sticks, only their current state
+            gamepads.poll(&mut input_events.borrow_mut());
             let dt = (time - last_time.unwrap_or(time)) / 1000.;
             update_fn(dt as f32, &input_events.borrow(), &mut gl_context);
             input_events.borrow_mut().clear();
+            if let Err(e) = unsafe { gl_context.present() } {
+                log::error!("Could not present frame: {}", e);
+            }
+            // deletes the GL objects dropped this frame, like native does after swapping buffers
+            unsafe { gl_context.maintain() };
             last_time = Some(time);
 
             web_sys::window()
@@ -136,6 +233,8 @@ pub enum HtmlEvent {
     MouseUp(MouseEvent),
     MouseMove(MouseEvent),
     MouseWheel(WheelEvent),
+    VisibilityChange(bool),
+    Blur,
 }
 
 /// Multiplexes different window-level input events into a single callback, automatically removing
@@ -148,6 +247,8 @@ pub struct HtmlEventStream {
     _on_mouse_up: Closure<dyn FnMut(MouseEvent)>,
     _on_mouse_move: Closure<dyn FnMut(MouseEvent)>,
     _on_mouse_wheel: Closure<dyn FnMut(WheelEvent)>,
+    _on_visibility_change: Closure<dyn FnMut()>,
+    _on_blur: Closure<dyn FnMut()>,
 }
 
 impl HtmlEventStream {
@@ -204,9 +305,32 @@ impl HtmlEventStream {
             }
         }) as Box<dyn FnMut(MouseEvent)>);
 
+        let on_visibility_change = Closure::wrap(Box::new({
+            let callback = Rc::clone(&callback);
+            move || {
+                let hidden = web_sys::window()
+                    .and_then(|win| win.document())
+                    .map(|document| document.hidden())
+                    .unwrap_or(false);
+                callback(HtmlEvent::VisibilityChange(!hidden));
+            }
+        }) as Box<dyn FnMut()>);
+
+        let on_blur = Closure::wrap(Box::new({
+            let callback = Rc::clone(&callback);
+            move || {
+                callback(HtmlEvent::Blur);
+            }
+        }) as Box<dyn FnMut()>);
+
         let window = web_sys::window().unwrap();
+        window
+            .document()
+            .unwrap()
+            .set_onvisibilitychange(Some(on_visibility_change.as_ref().unchecked_ref()));
         window.set_onkeydown(Some(on_key_down.as_ref().unchecked_ref()));
         window.set_onkeyup(Some(on_key_up.as_ref().unchecked_ref()));
+        window.set_onblur(Some(on_blur.as_ref().unchecked_ref()));
         mouse_element.set_onmousedown(Some(on_mouse_down.as_ref().unchecked_ref()));
         mouse_element.set_onmouseup(Some(on_mouse_up.as_ref().unchecked_ref()));
         mouse_element.set_onmousemove(Some(on_mouse_move.as_ref().unchecked_ref()));
@@ -220,6 +344,8 @@ impl HtmlEventStream {
             _on_mouse_up: on_mouse_up,
             _on_mouse_move: on_mouse_move,
             _on_mouse_wheel: on_mouse_wheel,
+            _on_visibility_change: on_visibility_change,
+            _on_blur: on_blur,
         }
     }
 }
@@ -227,8 +353,10 @@ impl HtmlEventStream {
 impl Drop for HtmlEventStream {
     fn drop(&mut self) {
         let window = web_sys::window().unwrap();
+        window.document().unwrap().set_onvisibilitychange(None);
         window.set_onkeydown(None);
         window.set_onkeyup(None);
+        window.set_onblur(None);
         self.mouse_element.set_onmousedown(None);
         self.mouse_element.set_onmouseup(None);
         self.mouse_element.set_onmousemove(None);
@@ -275,10 +403,41 @@ fn get_key_from_code(key: &str) -> Option<Key> {
         "ArrowUp" => Some(Key::Up),
         "ArrowRight" => Some(Key::Right),
         "ArrowDown" => Some(Key::Down),
+        "F3" => Some(Key::F3),
+        "F10" => Some(Key::F10),
+        "ShiftLeft" => Some(Key::LShift),
+        "ShiftRight" => Some(Key::RShift),
+        "ControlLeft" => Some(Key::LCtrl),
+        "ControlRight" => Some(Key::RCtrl),
+        "AltLeft" => Some(Key::LAlt),
+        "AltRight" => Some(Key::RAlt),
         _ => None,
     }
 }
 
+fn get_keyboard_modifiers(event: &KeyboardEvent) -> Modifiers {
+    Modifiers {
+        shift: event.shift_key(),
+        ctrl: event.ctrl_key(),
+        alt: event.alt_key(),
+    }
+}
+
+fn get_mouse_modifiers(event: &MouseEvent) -> Modifiers {
+    Modifiers {
+        shift: event.shift_key(),
+        ctrl: event.ctrl_key(),
+        alt: event.alt_key(),
+    }
+}
+
+// Key ups for everything still held, with no modifiers since those are let go too
+fn release_keys(held_keys: &mut HashSet<Key>, input_events: &mut Vec<InputEvent>) {
+    for key in held_keys.drain() {
+        input_events.push(InputEvent::KeyUp(key, Modifiers::default()));
+    }
+}
+
 fn get_mouse_button(button: i16) -> MouseButton {
     match button {
         0 => MouseButton::Left,
@@ -0,0 +1,41 @@
+use wasm_bindgen::JsValue;
+
+use crate::presence::{self, Presence, PresenceState};
+
+/// Shows a compact status in the page title and posts the full state to the embedding page.
+pub struct PagePresence {
+    title: String,
+    // Where the page embedding the game was loaded from, the only origin the state is posted to
+    parent_origin: Option<String>,
+}
+
+impl Presence for PagePresence {
+    fn update(&mut self, state: &PresenceState) {
+        let window = match web_sys::window() {
+            Some(window) => window,
+            None => return,
+        };
+        if let Some(document) = window.document() {
+            document.set_title(&format!("{} | {}", state.summary(), self.title));
+        }
+        if let (Ok(Some(parent)), Some(origin)) = (window.parent(), &self.parent_origin) {
+            let _ = parent.post_message(&JsValue::from_str(&state.to_json()), origin);
+        }
+    }
+}
+
+/// Embedded in another page, the referrer is that page. Without one, or with a referrer policy
+/// that leaves it out, nothing is posted.
+pub fn create_presence() -> Box<dyn Presence> {
+    let document = web_sys::window().and_then(|win| win.document());
+    let title = document
+        .as_ref()
+        .map(|document| document.title())
+        .unwrap_or_default();
+    let parent_origin =
+        document.and_then(|document| presence::url_origin(&document.referrer()).map(str::to_owned));
+    Box::new(PagePresence {
+        title,
+        parent_origin,
+    })
+}
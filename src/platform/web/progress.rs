@@ -0,0 +1,69 @@
+use anyhow::{format_err, Error};
+use web_sys::Storage;
+
+const PROGRESS_KEY: &str = "ld48.progress";
+// Saves before the last one kept under `ld48.progress.1` for the newest to `.3`
+const BACKUP_COUNT: u32 = 3;
+
+/// Replaces the saved progress with `contents`, kept in the page's local storage with the last
+/// few saves as backups. Backups are dropped oldest first if storage is full.
+pub fn save_progress(contents: &str) -> Result<(), Error> {
+    let storage = local_storage()?;
+    rotate_backups(&storage);
+    let mut result = storage.set_item(PROGRESS_KEY, contents);
+    // storage is most likely full, so make room but keep the save just moved to backup 1
+    for n in (2..=BACKUP_COUNT).rev() {
+        if result.is_ok() {
+            break;
+        }
+        let _ = storage.remove_item(&backup_key(n));
+        result = storage.set_item(PROGRESS_KEY, contents);
+    }
+    result.map_err(|e| {
+        format_err!(
+            "Could not save progress, this site's storage may be full. The browser's site \
+             settings can show what is using it: {:?}",
+            e
+        )
+    })
+}
+
+/// Every progress save still around, newest first: the one saved last and then its backups.
+/// Empty if nothing was saved yet.
+pub fn load_progress() -> Result<Vec<String>, Error> {
+    let storage = local_storage()?;
+    let keys = std::iter::once(PROGRESS_KEY.to_owned()).chain((1..=BACKUP_COUNT).map(backup_key));
+    let mut saves = Vec::new();
+    for key in keys {
+        match storage.get_item(&key) {
+            Ok(Some(save)) => saves.push(save),
+            Ok(None) => {}
+            Err(e) => log::warn!("Could not load progress from {}: {:?}", key, e),
+        }
+    }
+    Ok(saves)
+}
+
+fn backup_key(n: u32) -> String {
+    format!("{}.{}", PROGRESS_KEY, n)
+}
+
+// The current save becomes backup 1 and the oldest backup is dropped. A backup that doesn't fit
+// is left out rather than failing the save.
+fn rotate_backups(storage: &Storage) {
+    for n in (1..BACKUP_COUNT).rev() {
+        if let Ok(Some(save)) = storage.get_item(&backup_key(n)) {
+            let _ = storage.set_item(&backup_key(n + 1), &save);
+        }
+    }
+    if let Ok(Some(save)) = storage.get_item(PROGRESS_KEY) {
+        let _ = storage.set_item(&backup_key(1), &save);
+    }
+}
+
+// Missing when the browser blocks storage, like for some private windows
+fn local_storage() -> Result<Storage, Error> {
+    web_sys::window()
+        .and_then(|win| win.local_storage().ok().flatten())
+        .ok_or_else(|| format_err!("Local storage is not available"))
+}
@@ -0,0 +1,20 @@
+//! The atlas `build.rs` packs from the images in `assets/` with the `prebaked-atlas` feature, as
+//! one PNG and a `TextureRect` constant for each image, named after its file in upper case.
+
+use anyhow::{bail, Error};
+
+use crate::{atlas_packer, texture_atlas::TextureRect};
+
+include!(concat!(env!("OUT_DIR"), "/prebaked_atlas.rs"));
+
+/// The packed images, `ATLAS_SIZE` wide and only as tall as they reach.
+pub const PNG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/prebaked_atlas.png"));
+
+/// Errors if `PNG` isn't the one the constants were generated along with. Only checks in debug
+/// builds, since it hashes the whole PNG.
+pub fn check() -> Result<(), Error> {
+    if cfg!(debug_assertions) && atlas_packer::stable_hash(PNG) != PNG_HASH {
+        bail!("The prebaked atlas PNG doesn't match its texture rects, rebuild to bake it again");
+    }
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use crate::strings;
+
+// Minimum time between presence updates when nothing but the playtime changed
+const PRESENCE_INTERVAL: f32 = 5.;
+// Room names are cut short beyond this
+const ROOM_NAME_CHARS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceMode {
+    Playing,
+    Paused,
+}
+
+impl PresenceMode {
+    fn name(&self) -> &'static str {
+        match self {
+            PresenceMode::Playing => "playing",
+            PresenceMode::Paused => "paused",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PresenceState {
+    pub room: Option<String>,
+    pub depth: Option<u32>,
+    pub playtime_secs: u32,
+    pub mode: PresenceMode,
+}
+
+impl PresenceState {
+    /// The state of a game in the room shown as `room_name`, `depth` rooms deep.
+    pub fn new(room_name: &str, depth: u32, playtime: f32, paused: bool) -> PresenceState {
+        PresenceState {
+            room: Some(strings::truncate(room_name, ROOM_NAME_CHARS).into_owned()),
+            depth: Some(depth),
+            playtime_secs: playtime as u32,
+            mode: if paused {
+                PresenceMode::Paused
+            } else {
+                PresenceMode::Playing
+            },
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        match (&self.room, self.depth) {
+            (Some(room), Some(depth)) => {
+                format!("{} - depth {} ({})", room, depth, self.mode.name())
+            }
+            _ => self.mode.name().to_owned(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let room = match &self.room {
+            Some(room) => format!("\"{}\"", room.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => "null".to_owned(),
+        };
+        let depth = match self.depth {
+            Some(depth) => depth.to_string(),
+            None => "null".to_owned(),
+        };
+        format!(
+            "{{\"room\":{},\"depth\":{},\"playtime_secs\":{},\"mode\":\"{}\"}}",
+            room,
+            depth,
+            self.playtime_secs,
+            self.mode.name()
+        )
+    }
+
+    fn same_status(&self, other: &PresenceState) -> bool {
+        self.room == other.room && self.depth == other.depth && self.mode == other.mode
+    }
+}
+
+/// Something outside the game that wants to know what the player is doing, such as a helper that
+/// relays it to a chat client or a page embedding the web build.
+pub trait Presence {
+    fn update(&mut self, state: &PresenceState);
+}
+
+pub struct NoPresence;
+
+impl Presence for NoPresence {
+    fn update(&mut self, _: &PresenceState) {}
+}
+
+/// Forwards state to a `Presence` immediately when the status changes, and otherwise at most
+/// every few seconds.
+pub struct PresenceReporter {
+    presence: Box<dyn Presence>,
+    private: bool,
+    last_sent: Option<(PresenceState, f32)>,
+}
+
+impl PresenceReporter {
+    pub fn new(presence: Box<dyn Presence>) -> PresenceReporter {
+        PresenceReporter {
+            presence,
+            private: false,
+            last_sent: None,
+        }
+    }
+
+    /// When private, the room and depth are blanked out of every update.
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    pub fn private(&self) -> bool {
+        self.private
+    }
+
+    pub fn report(&mut self, mut state: PresenceState, now: f32) {
+        if self.private {
+            state.room = None;
+            state.depth = None;
+        }
+        if let Some((last_state, last_time)) = &self.last_sent {
+            if last_state.same_status(&state) && now - last_time < PRESENCE_INTERVAL {
+                return;
+            }
+        }
+        log::debug!("Presence: {}", state.summary());
+        self.presence.update(&state);
+        self.last_sent = Some((state, now));
+    }
+}
+
+/// The origin of `url`, its scheme, host and port, or None if it has none.
+#[cfg(any(target_arch = "wasm32", test))]
+pub fn url_origin(url: &str) -> Option<&str> {
+    let scheme_end = url.find("://")?;
+    let host_start = scheme_end + 3;
+    let host_end = url[host_start..]
+        .find(&['/', '?', '#'][..])
+        .map_or(url.len(), |i| host_start + i);
+    if host_end == host_start {
+        return None;
+    }
+    Some(&url[..host_end])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    // Keeps every update it gets
+    struct Recorder(Rc<RefCell<Vec<PresenceState>>>);
+
+    impl Presence for Recorder {
+        fn update(&mut self, state: &PresenceState) {
+            self.0.borrow_mut().push(state.clone());
+        }
+    }
+
+    fn recording_reporter() -> (PresenceReporter, Rc<RefCell<Vec<PresenceState>>>) {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let reporter = PresenceReporter::new(Box::new(Recorder(Rc::clone(&sent))));
+        (reporter, sent)
+    }
+
+    #[test]
+    fn state_is_assembled_from_the_game() {
+        let state = PresenceState::new("Red", 3, 61.9, false);
+        assert_eq!(
+            state,
+            PresenceState {
+                room: Some("Red".to_owned()),
+                depth: Some(3),
+                playtime_secs: 61,
+                mode: PresenceMode::Playing,
+            }
+        );
+        assert_eq!(state.summary(), "Red - depth 3 (playing)");
+        assert_eq!(
+            state.to_json(),
+            r#"{"room":"Red","depth":3,"playtime_secs":61,"mode":"playing"}"#
+        );
+
+        let long_name = "The Room Whose Name Goes On And On And On";
+        let state = PresenceState::new(long_name, 0, 0., true);
+        let room = state.room.as_ref().unwrap();
+        assert_eq!(room.chars().count(), ROOM_NAME_CHARS);
+        assert!(room.ends_with('…'));
+        assert_eq!(state.mode, PresenceMode::Paused);
+
+        let state = PresenceState::new("\"Quoted\" \\ room", 1, 0., false);
+        assert!(state
+            .to_json()
+            .starts_with(r#"{"room":"\"Quoted\" \\ room","#));
+    }
+
+    #[test]
+    fn updates_are_rate_limited_unless_the_status_changes() {
+        let (mut reporter, sent) = recording_reporter();
+        reporter.report(PresenceState::new("Red", 0, 0., false), 0.);
+        // only the playtime changed
+        reporter.report(PresenceState::new("Red", 0, 1., false), 1.);
+        reporter.report(PresenceState::new("Red", 0, 4.9, false), 4.9);
+        assert_eq!(sent.borrow().len(), 1);
+        reporter.report(PresenceState::new("Red", 0, 5., false), 5.);
+        assert_eq!(sent.borrow().len(), 2);
+        assert_eq!(sent.borrow()[1].playtime_secs, 5);
+
+        // a new room, depth or mode goes out right away
+        reporter.report(PresenceState::new("Blue", 1, 6., false), 6.);
+        reporter.report(PresenceState::new("Blue", 1, 6.5, true), 6.5);
+        reporter.report(PresenceState::new("Red", 0, 7., true), 7.);
+        assert_eq!(sent.borrow().len(), 5);
+    }
+
+    #[test]
+    fn private_blanks_the_details() {
+        let (mut reporter, sent) = recording_reporter();
+        reporter.set_private(true);
+        reporter.report(PresenceState::new("Red", 2, 10., false), 0.);
+        // the room can't be told from the updates, so changing it sends nothing new
+        reporter.report(PresenceState::new("Blue", 3, 11., false), 1.);
+        assert_eq!(
+            *sent.borrow(),
+            [PresenceState {
+                room: None,
+                depth: None,
+                playtime_secs: 10,
+                mode: PresenceMode::Playing,
+            }]
+        );
+        assert_eq!(sent.borrow()[0].summary(), "playing");
+
+        reporter.set_private(false);
+        reporter.report(PresenceState::new("Blue", 3, 12., false), 2.);
+        assert_eq!(sent.borrow()[1].room.as_deref(), Some("Blue"));
+    }
+
+    #[test]
+    fn origins_drop_the_path() {
+        assert_eq!(
+            url_origin("https://example.com/games/ld48/index.html?x=1"),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            url_origin("http://localhost:8000"),
+            Some("http://localhost:8000")
+        );
+        assert_eq!(
+            url_origin("http://localhost:8000#top"),
+            Some("http://localhost:8000")
+        );
+        assert_eq!(url_origin(""), None);
+        assert_eq!(url_origin("about:blank"), None);
+        assert_eq!(url_origin("file:///home/index.html"), None);
+    }
+}
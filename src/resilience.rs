@@ -0,0 +1,323 @@
+use std::{
+    any::Any,
+    collections::VecDeque,
+    fmt::{Debug, Write},
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{constants::TICK_DT, input::InputEvent};
+
+// Seconds of input the flight recorder keeps
+const RECORDED_SECS: f32 = 10.;
+// Ticks between the flight recorder's snapshots of the game
+const SNAPSHOT_TICKS: u64 = 60;
+// A panic within this many seconds of restoring from the last one is treated as unrecoverable
+const RESTORE_GRACE_SECS: f32 = 10.;
+
+/// Keeps the input of the last few seconds of updates and a recent snapshot of the game, to
+/// restore from and to write out when something goes wrong.
+pub struct FlightRecorder<S> {
+    // Tick number and the input it got, oldest first
+    ticks: VecDeque<(u64, Vec<InputEvent>)>,
+    max_ticks: usize,
+    next_tick: u64,
+    // The tick the snapshot was taken after
+    snapshot: Option<(u64, S)>,
+}
+
+impl<S: Clone + Debug> FlightRecorder<S> {
+    pub fn new() -> FlightRecorder<S> {
+        let max_ticks = (RECORDED_SECS / TICK_DT).round() as usize;
+        FlightRecorder {
+            ticks: VecDeque::with_capacity(max_ticks),
+            max_ticks,
+            next_tick: 0,
+            snapshot: None,
+        }
+    }
+
+    /// Records a tick that went through with `inputs`, taking a snapshot of what it left with
+    /// `snapshot` every so often. Nothing is recorded if `snapshot` panics.
+    pub fn record_tick(&mut self, inputs: &[InputEvent], snapshot: impl FnOnce() -> S) {
+        if self.next_tick % SNAPSHOT_TICKS == 0 {
+            self.snapshot = Some((self.next_tick, snapshot()));
+        }
+        if self.ticks.len() == self.max_ticks {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back((self.next_tick, inputs.to_vec()));
+        self.next_tick += 1;
+    }
+
+    pub fn snapshot(&self) -> Option<&S> {
+        self.snapshot.as_ref().map(|(_, snapshot)| snapshot)
+    }
+
+    /// What went wrong, the last snapshot and the input since the oldest tick kept, as text.
+    /// Ticks without input are left out.
+    pub fn dump(&self, message: &str) -> String {
+        let mut dump = format!("Panicked: {}\n", message);
+        match &self.snapshot {
+            Some((tick, snapshot)) => {
+                let _ = writeln!(dump, "Snapshot after tick {}: {:?}", tick, snapshot);
+            }
+            None => dump.push_str("No snapshot\n"),
+        }
+        if let (Some((first, _)), Some((last, _))) = (self.ticks.front(), self.ticks.back()) {
+            let _ = writeln!(dump, "Input for ticks {} to {}:", first, last);
+        }
+        for (tick, inputs) in self.ticks.iter().filter(|(_, inputs)| !inputs.is_empty()) {
+            let _ = writeln!(dump, "{}: {:?}", tick, inputs);
+        }
+        dump
+    }
+}
+
+/// What a panic caught by `Resilience::run` left behind.
+#[derive(Debug)]
+pub struct Crash {
+    pub message: String,
+    /// The flight recorder's dump
+    pub dump: String,
+    /// False if restoring was tried less than `RESTORE_GRACE_SECS` before, in which case it
+    /// would most likely just panic again
+    pub can_restore: bool,
+}
+
+/// Catches panics for `--resilient` mode, with the flight recorder to restore from and the
+/// record of recent restores that decides whether restoring is worth trying again.
+pub struct Resilience<S> {
+    pub recorder: FlightRecorder<S>,
+    last_restore: Option<f32>,
+}
+
+impl<S: Clone + Debug> Resilience<S> {
+    pub fn new() -> Resilience<S> {
+        Resilience {
+            recorder: FlightRecorder::new(),
+            last_restore: None,
+        }
+    }
+
+    /// Runs `f`, catching a panic in it. Whatever `f` was changing when it panicked may be left
+    /// half done, so it shouldn't be used again. The recorder is left as it was after the last
+    /// tick it recorded.
+    pub fn run<R>(
+        &mut self,
+        time: f32,
+        f: impl FnOnce(&mut FlightRecorder<S>) -> R,
+    ) -> Result<R, Crash> {
+        let recorder = &mut self.recorder;
+        panic::catch_unwind(AssertUnwindSafe(|| f(recorder))).map_err(|panic| {
+            let message = panic_message(&*panic);
+            Crash {
+                dump: self.recorder.dump(&message),
+                message,
+                can_restore: self
+                    .last_restore
+                    .map_or(true, |last| time - last >= RESTORE_GRACE_SECS),
+            }
+        })
+    }
+
+    /// Starts the grace period for restoring at `time` and returns the snapshot to restore, if
+    /// there is one yet.
+    pub fn restore(&mut self, time: f32) -> Option<S> {
+        self.last_restore = Some(time);
+        self.recorder.snapshot().cloned()
+    }
+}
+
+// The message `panic!` was given, which is a `&str` or a `String` unless it was given something
+// other than a format string
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{Key, Modifiers};
+
+    // Stands in for the game, which needs GL. Counts ticks and moves between rooms on key
+    // presses, panicking wherever it is told to.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Snapshot {
+        ticks: u32,
+        room: u32,
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum PanicPoint {
+        Update,
+        Draw,
+        Snapshot,
+    }
+
+    #[derive(Default)]
+    struct FakeGame {
+        state: Snapshot,
+        panic_at: Option<(u32, PanicPoint)>,
+    }
+
+    impl FakeGame {
+        fn restored(snapshot: Option<Snapshot>) -> FakeGame {
+            FakeGame {
+                state: snapshot.unwrap_or_default(),
+                panic_at: None,
+            }
+        }
+
+        fn check(&self, point: PanicPoint) {
+            if self.panic_at == Some((self.state.ticks, point)) {
+                panic!("injected panic at tick {}", self.state.ticks);
+            }
+        }
+
+        fn update(&mut self, inputs: &[InputEvent]) {
+            self.check(PanicPoint::Update);
+            self.state.ticks += 1;
+            for input in inputs {
+                if let InputEvent::KeyDown(Key::D, _) = input {
+                    self.state.room += 1;
+                }
+            }
+        }
+
+        fn draw(&self) {
+            self.check(PanicPoint::Draw);
+        }
+
+        fn snapshot(&self) -> Snapshot {
+            self.check(PanicPoint::Snapshot);
+            self.state.clone()
+        }
+    }
+
+    // Runs `ticks` frames of one tick each like main does, pressing D on the first
+    fn play(
+        resilience: &mut Resilience<Snapshot>,
+        game: &mut FakeGame,
+        time: f32,
+        ticks: u32,
+    ) -> Result<(), Crash> {
+        resilience.run(time, |recorder| {
+            for tick in 0..ticks {
+                let inputs = if tick == 0 {
+                    vec![InputEvent::KeyDown(Key::D, Modifiers::default())]
+                } else {
+                    Vec::new()
+                };
+                game.update(&inputs);
+                recorder.record_tick(&inputs, || game.snapshot());
+                game.draw();
+            }
+        })
+    }
+
+    #[test]
+    fn panics_anywhere_in_a_frame_restore_to_the_last_snapshot() {
+        // the game has had one more update than the recorder has ticks
+        let points = [
+            (100, PanicPoint::Update),
+            (100, PanicPoint::Draw),
+            (121, PanicPoint::Snapshot),
+        ];
+        for &panic_at in &points {
+            let mut resilience = Resilience::new();
+            let mut game = FakeGame::default();
+            play(&mut resilience, &mut game, 0., 70).unwrap();
+            game.panic_at = Some(panic_at);
+            let crash = play(&mut resilience, &mut game, 1., 70).unwrap_err();
+            assert_eq!(
+                crash.message,
+                format!("injected panic at tick {}", panic_at.0)
+            );
+            assert!(crash.can_restore);
+
+            // from after tick 60, the next one is due after 120
+            let snapshot = resilience.restore(2.);
+            assert_eq!(snapshot, Some(Snapshot { ticks: 61, room: 1 }));
+            let mut restored = FakeGame::restored(snapshot);
+            play(&mut resilience, &mut restored, 2., 100).unwrap();
+            assert_eq!(
+                restored.state,
+                Snapshot {
+                    ticks: 161,
+                    room: 2
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn panicking_again_soon_after_restoring_stops_offering_it() {
+        let mut resilience = Resilience::new();
+        let mut game = FakeGame {
+            panic_at: Some((5, PanicPoint::Update)),
+            ..FakeGame::default()
+        };
+        assert!(play(&mut resilience, &mut game, 0., 10).is_err());
+
+        // restored into the same panic
+        let mut game = FakeGame::restored(resilience.restore(1.));
+        game.panic_at = Some((5, PanicPoint::Update));
+        let crash = play(&mut resilience, &mut game, 1. + RESTORE_GRACE_SECS / 2., 10).unwrap_err();
+        assert!(!crash.can_restore);
+
+        // a panic long after the restore is a new problem
+        let mut game = FakeGame::restored(resilience.restore(20.));
+        game.panic_at = Some((5, PanicPoint::Draw));
+        let crash = play(&mut resilience, &mut game, 20. + RESTORE_GRACE_SECS, 10).unwrap_err();
+        assert!(crash.can_restore);
+    }
+
+    #[test]
+    fn dumps_hold_the_snapshot_and_recent_input() {
+        let mut recorder = FlightRecorder::new();
+        assert_eq!(recorder.dump("oops"), "Panicked: oops\nNo snapshot\n");
+
+        let press = vec![InputEvent::KeyDown(Key::D, Modifiers::default())];
+        // ten seconds of ticks and two more
+        for tick in 0..602 {
+            let inputs = if tick % 300 == 1 {
+                press.clone()
+            } else {
+                Vec::new()
+            };
+            recorder.record_tick(&inputs, || Snapshot {
+                ticks: tick,
+                room: 0,
+            });
+        }
+        // the press on tick 1 went out with it
+        assert_eq!(
+            recorder.dump("oops"),
+            format!(
+                "Panicked: oops\n\
+                 Snapshot after tick 600: Snapshot {{ ticks: 600, room: 0 }}\n\
+                 Input for ticks 2 to 601:\n\
+                 301: {:?}\n\
+                 601: {:?}\n",
+                press, press
+            )
+        );
+    }
+
+    #[test]
+    fn panic_messages_are_strs_or_strings() {
+        let message = |f: fn()| panic_message(&*panic::catch_unwind(f).unwrap_err());
+        assert_eq!(message(|| panic!("literal")), "literal");
+        assert_eq!(message(|| panic!("formatted {}", 1)), "formatted 1");
+        assert_eq!(
+            message(|| panic::resume_unwind(Box::new(1848))),
+            "unknown panic"
+        );
+    }
+}
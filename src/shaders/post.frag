@@ -0,0 +1,11 @@
+#version 100
+// Draws the frame as it is. Custom post shaders get the same v_uv and u_texture, and can also
+// declare `uniform highp float u_time` to get what was given to PostProcess::set_time.
+varying highp vec2 v_uv;
+
+uniform sampler2D u_texture;
+
+void main()
+{
+    gl_FragColor = texture2D(u_texture, v_uv);
+}
\ No newline at end of file
@@ -0,0 +1,11 @@
+#version 100
+attribute highp vec2 a_pos;
+attribute highp vec2 a_uv;
+
+varying highp vec2 v_uv;
+
+void main()
+{
+    v_uv = a_uv;
+    gl_Position = vec4(a_pos, 0.0, 1.0);
+}
\ No newline at end of file
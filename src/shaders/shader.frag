@@ -1,12 +1,67 @@
 #version 100
 varying highp vec2 v_uv;
 varying highp vec4 v_color;
+// rgb is the color to flash toward, a how far
+varying highp vec4 v_flash;
 
 uniform sampler2D u_texture;
 uniform highp float u_alpha;
+// Replaces the output when its alpha isn't 0, see DebugView::Overdraw
+uniform highp vec4 u_overdraw;
+#ifdef PALETTE
+// Picked from by the texel's red channel, see graphics::PaletteSwap
+uniform highp vec4 u_palette[PALETTE];
+#endif
+
+#ifdef SRGB
+// For when GL can't convert between sRGB and linear itself, as on WebGL 1. Everything is
+// linearized to be mixed and converted back at the end, but blending still happens on the sRGB
+// values in the framebuffer.
+highp vec3 to_linear(highp vec3 color)
+{
+    return pow(color, vec3(2.2));
+}
+#endif
+
+#ifdef PALETTE
+highp vec4 palette_color(highp vec4 texel)
+{
+    highp float red = texel.r;
+#ifdef LINEAR_SAMPLES
+    // GL already converted the texel from sRGB, but the index is the value in the image
+    red = pow(red, 1.0 / 2.2);
+#endif
+    int index = int(floor(red * float(PALETTE - 1) + 0.5));
+    highp vec4 color = u_palette[0];
+    // WebGL 1 can only index uniform arrays in fragment shaders with loop indices
+    for (int i = 1; i < PALETTE; i++) {
+        if (i == index) {
+            color = u_palette[i];
+        }
+    }
+    return vec4(color.rgb, color.a * texel.a);
+}
+#endif
 
 void main()
 {
     highp vec4 color = texture2D(u_texture, v_uv);
-    gl_FragColor =  vec4(color.rgb * u_alpha, color.a * u_alpha) * v_color;
+#ifdef PALETTE
+    color = palette_color(color);
+#endif
+    highp vec4 tint = v_color;
+    highp vec3 flash = v_flash.rgb;
+#ifdef SRGB
+    color.rgb = to_linear(color.rgb);
+    tint.rgb = to_linear(tint.rgb);
+    flash = to_linear(flash);
+#endif
+    color.rgb = mix(color.rgb, flash, v_flash.a);
+    gl_FragColor =  vec4(color.rgb * u_alpha, color.a * u_alpha) * tint;
+#ifdef SRGB
+    gl_FragColor.rgb = pow(gl_FragColor.rgb, vec3(1.0 / 2.2));
+#endif
+    if (u_overdraw.a > 0.0) {
+        gl_FragColor = u_overdraw;
+    }
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+//! Unit tags for the coordinate spaces used by the game, and the conversions between them. All y
+//! flips and space-to-space scales should happen here.
+
+use euclid::{point2, vec2, Point2D, Rect, Size2D, Transform2D, UnknownUnit, Vector2D};
+
+use crate::constants::{SCREEN_SIZE, TILE_SIZE};
+
+/// Tiles within a room. Origin at the bottom left of the room, y up.
+pub struct RoomSpace;
+/// Window pixels as reported by the platform. Origin at the top left, y down.
+pub struct ScreenSpace;
+/// Window pixels for drawing UI. Origin at the bottom left, y up.
+pub struct UiSpace;
+/// Texels of the texture atlas. Origin at the top left of the texture data.
+pub struct AtlasSpace;
+/// OpenGL clip space, -1 to 1 on both axes, y up.
+pub struct ClipSpace;
+
+pub type RoomPoint = Point2D<f32, RoomSpace>;
+pub type RoomVector = Vector2D<f32, RoomSpace>;
+pub type RoomRect = Rect<f32, RoomSpace>;
+pub type TilePoint = Point2D<i32, RoomSpace>;
+pub type ScreenPoint = Point2D<f32, ScreenSpace>;
+pub type UiPoint = Point2D<f32, UiSpace>;
+pub type UiRect = Rect<f32, UiSpace>;
+pub type UiSize = Size2D<f32, UiSpace>;
+
+pub fn screen_to_ui(p: ScreenPoint) -> UiPoint {
+    point2(p.x, SCREEN_SIZE.1 as f32 - p.y)
+}
+
+/// Room levels are written top row first, so the first line of text is the highest row.
+pub fn text_row_to_tile_y(row: usize, room_height: u32) -> i32 {
+    room_height as i32 - 1 - row as i32
+}
+
+/// Images are stored top row first while the game draws them y up.
+pub fn y_up_to_image_row(y: u32, image_height: u32) -> u32 {
+    image_height - 1 - y
+}
+
+pub fn ui_to_clip() -> Transform2D<f32, UiSpace, ClipSpace> {
+    Transform2D::scale(1.0 / SCREEN_SIZE.0 as f32, 1.0 / SCREEN_SIZE.0 as f32)
+        .then_scale(2., 2.)
+        .then_translate(vec2(-1.0, -1.0))
+}
+
+/// Maps a room of the given size in tiles onto the whole of a texture render target, for
+/// prebaking room textures. The same at any render scale.
+pub fn room_to_texture_clip(room_size: (u32, u32)) -> Transform2D<f32, RoomSpace, ClipSpace> {
+    let room_pixel_size = room_pixel_size(room_size, 1.);
+    Transform2D::scale(1.0 / room_pixel_size.width, 1.0 / room_pixel_size.height)
+        .then_scale(TILE_SIZE, TILE_SIZE)
+        .then_scale(2., 2.)
+        .then_translate(vec2(-1.0, -1.0))
+}
+
+/// Size in pixels of a room's prebaked texture, baked at `scale` times the art's resolution.
+pub fn room_pixel_size(room_size: (u32, u32), scale: f32) -> Size2D<f32, UnknownUnit> {
+    Size2D::new(room_size.0, room_size.1).to_f32() * TILE_SIZE * scale
+}
+
+pub fn to_mat3<S, D>(t: &Transform2D<f32, S, D>) -> [[f32; 3]; 3] {
+    [
+        [t.m11, t.m12, 0.0],
+        [t.m21, t.m22, 0.0],
+        [t.m31, t.m32, 1.0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screen_and_ui_flip_y() {
+        let top_left = point2(0., 0.);
+        assert_eq!(screen_to_ui(top_left), point2(0., SCREEN_SIZE.1 as f32));
+        let p = point2(12.5, 100.);
+        let ui = screen_to_ui(p);
+        assert_eq!(ui, point2(12.5, SCREEN_SIZE.1 as f32 - 100.));
+        // flipping back is the same flip
+        assert_eq!(
+            screen_to_ui(point2(ui.x, ui.y)).to_untyped(),
+            p.to_untyped()
+        );
+    }
+
+    #[test]
+    fn rows_count_down_from_the_top() {
+        assert_eq!(text_row_to_tile_y(0, 15), 14);
+        assert_eq!(text_row_to_tile_y(14, 15), 0);
+        assert_eq!(y_up_to_image_row(0, 8), 7);
+        assert_eq!(y_up_to_image_row(7, 8), 0);
+        for y in 0..8 {
+            assert_eq!(y_up_to_image_row(y_up_to_image_row(y, 8), 8), y);
+        }
+    }
+
+    #[test]
+    fn ui_covers_clip_space() {
+        let ui_to_clip = ui_to_clip();
+        let top_right = point2(SCREEN_SIZE.0 as f32, SCREEN_SIZE.1 as f32);
+        assert_eq!(ui_to_clip.transform_point(point2(0., 0.)), point2(-1., -1.));
+        assert_eq!(ui_to_clip.transform_point(top_right), point2(1., 1.));
+        let back = ui_to_clip
+            .inverse()
+            .unwrap()
+            .transform_point(point2(0.5, -0.25));
+        assert!((ui_to_clip.transform_point(back) - point2(0.5, -0.25)).length() < 1e-6);
+    }
+
+    #[test]
+    fn rooms_cover_their_texture() {
+        let room_to_clip = room_to_texture_clip((15, 10));
+        assert_eq!(
+            room_to_clip.transform_point(point2(0., 0.)),
+            point2(-1., -1.)
+        );
+        assert_eq!(
+            room_to_clip.transform_point(point2(15., 10.)),
+            point2(1., 1.)
+        );
+        let center = room_to_clip.transform_point(point2(7.5, 5.));
+        assert!(center.to_vector().length() < 1e-6);
+        assert_eq!(
+            room_pixel_size((15, 10), 2.),
+            Size2D::new(15. * TILE_SIZE * 2., 10. * TILE_SIZE * 2.)
+        );
+    }
+
+    #[test]
+    fn mat3_transforms_like_the_transform() {
+        let t = room_to_texture_clip((15, 15)).then_translate(vec2(0.25, -0.5));
+        let m = to_mat3(&t);
+        let p = point2(3., 11.);
+        // column-major, as GL takes it
+        let x = m[0][0] * p.x + m[1][0] * p.y + m[2][0];
+        let y = m[0][1] * p.x + m[1][1] * p.y + m[2][1];
+        let expected = t.transform_point(p);
+        assert!((point2(x, y) - expected).length() < 1e-6);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{borrow::Cow, collections::HashMap};
+
+const KEY_PREFIX: &str = "key:";
+
+/// Localized text, loaded from `key = value` lines. Blank lines and lines starting with `#` are
+/// ignored.
+pub struct Strings {
+    entries: HashMap<String, String>,
+}
+
+impl Strings {
+    pub fn english() -> Strings {
+        Strings::parse(include_str!("../assets/strings/en.txt"))
+    }
+
+    pub fn parse(source: &str) -> Strings {
+        let mut entries = HashMap::new();
+        for line in source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(i) => {
+                    entries.insert(line[..i].trim().to_owned(), line[i + 1..].trim().to_owned());
+                }
+                None => log::warn!("Ignoring malformed string entry '{}'", line),
+            }
+        }
+        Strings { entries }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+}
+
+/// Text given in data files, either written out directly or as `key:<name>` to look it up in
+/// `Strings`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Text {
+    Literal(String),
+    Key(String),
+}
+
+impl Text {
+    pub fn parse(text: &str) -> Text {
+        let text = text.trim();
+        match text.strip_prefix(KEY_PREFIX) {
+            Some(key) => Text::Key(key.trim().to_owned()),
+            None => Text::Literal(text.to_owned()),
+        }
+    }
+
+    /// Returns `None` for keys missing from `strings`, so callers can fall back to something
+    /// better than showing the raw key.
+    pub fn resolve<'a>(&'a self, strings: &'a Strings) -> Option<&'a str> {
+        match self {
+            Text::Literal(text) => Some(text),
+            Text::Key(key) => strings.get(key),
+        }
+    }
+}
+
+/// Shortens `text` to at most `max_chars` characters, ending in an ellipsis if anything was cut.
+pub fn truncate(text: &str, max_chars: usize) -> Cow<'_, str> {
+    if text.chars().count() <= max_chars {
+        return Cow::Borrowed(text);
+    }
+    if max_chars == 0 {
+        return Cow::Borrowed("");
+    }
+    let mut truncated: String = text.chars().take(max_chars - 1).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    Cow::Owned(truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_skip_comments_and_malformed_lines() {
+        let strings = Strings::parse(
+            "# room names\n\n room.red.name =  The Ember Vestibule \nno equals sign\nempty =\n",
+        );
+        assert_eq!(strings.get("room.red.name"), Some("The Ember Vestibule"));
+        assert_eq!(strings.get("empty"), Some(""));
+        assert_eq!(strings.get("no equals sign"), None);
+        assert_eq!(strings.get("# room names"), None);
+    }
+
+    #[test]
+    fn text_is_a_key_or_written_out() {
+        assert_eq!(
+            Text::parse("key:room.blue.name"),
+            Text::Key("room.blue.name".to_owned())
+        );
+        assert_eq!(
+            Text::parse("  key: room.blue.name "),
+            Text::Key("room.blue.name".to_owned())
+        );
+        assert_eq!(
+            Text::parse(" The Cobalt Antechamber "),
+            Text::Literal("The Cobalt Antechamber".to_owned())
+        );
+        // only a prefix makes a key
+        assert_eq!(
+            Text::parse("A key:hole"),
+            Text::Literal("A key:hole".to_owned())
+        );
+    }
+
+    #[test]
+    fn missing_keys_resolve_to_nothing() {
+        let strings = Strings::parse("room.blue.name = The Cobalt Antechamber");
+        assert_eq!(
+            Text::parse("key:room.blue.name").resolve(&strings),
+            Some("The Cobalt Antechamber")
+        );
+        assert_eq!(Text::parse("key:room.red.name").resolve(&strings), None);
+        assert_eq!(Text::parse("Red").resolve(&strings), Some("Red"));
+    }
+
+    #[test]
+    fn truncate_ends_in_an_ellipsis() {
+        assert_eq!(truncate("The Moss Cistern", 16), "The Moss Cistern");
+        assert!(matches!(truncate("The Moss Cistern", 20), Cow::Borrowed(_)));
+        assert_eq!(truncate("The Moss Cistern", 10), "The Moss…");
+        // spaces before the ellipsis are dropped
+        assert_eq!(truncate("The Moss Cistern", 5), "The…");
+        assert_eq!(truncate("The Moss Cistern", 1), "…");
+        assert_eq!(truncate("The Moss Cistern", 0), "");
+        // counted in characters, not bytes
+        assert_eq!(truncate("Fête foraine", 5), "Fête…");
+        assert_eq!(truncate("Fête", 4), "Fête");
+    }
+}
@@ -1,62 +1,832 @@
-use anyhow::{format_err, Error};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    mem,
+};
 
-pub type TextureRect = [u32; 4];
+use anyhow::{bail, format_err, Error};
 
-pub struct TextureAtlas {
+pub use crate::atlas_packer::packing_order;
+use crate::{
+    atlas_packer::{area, grow, intersects, AtlasPacker},
+    gl,
+};
+
+/// Texels `[min_x, min_y, max_x, max_y]` of an atlas, with y going down from the top. Also holds
+/// the atlas's size, so UVs can be worked out without knowing which atlas the rect is from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextureRect {
+    pub texels: [u32; 4],
+    pub atlas_size: (u32, u32),
+    /// The image is stored turned 90° clockwise, its top row running down the right edge of
+    /// `texels`. See `TextureAtlas::add_texture_rotatable`.
+    pub rotated: bool,
+}
+
+impl TextureRect {
+    /// Width of the image, which is the height of `texels` when rotated.
+    pub fn width(&self) -> u32 {
+        if self.rotated {
+            self.texels[3] - self.texels[1]
+        } else {
+            self.texels[2] - self.texels[0]
+        }
+    }
+
+    /// Height of the image, which is the width of `texels` when rotated.
+    pub fn height(&self) -> u32 {
+        if self.rotated {
+            self.texels[2] - self.texels[0]
+        } else {
+            self.texels[3] - self.texels[1]
+        }
+    }
+
+    /// The part of the image `x`, `y` texels from its top left. Panics if it reaches outside.
+    pub fn sub_rect(&self, x: u32, y: u32, width: u32, height: u32) -> TextureRect {
+        assert!(
+            x + width <= self.width() && y + height <= self.height(),
+            "sub rect outside of texture rect"
+        );
+        let texels = if self.rotated {
+            // image rows become atlas columns counted from the right
+            let max_x = self.texels[2] - y;
+            let min_y = self.texels[1] + x;
+            [max_x - height, min_y, max_x, min_y + width]
+        } else {
+            let (min_x, min_y) = (self.texels[0] + x, self.texels[1] + y);
+            [min_x, min_y, min_x + width, min_y + height]
+        };
+        TextureRect {
+            texels,
+            atlas_size: self.atlas_size,
+            rotated: self.rotated,
+        }
+    }
+}
+
+/// Refers to an atlas entry by identity rather than by place, so it stays valid when the atlas
+/// grows and moves its entries around. See `TextureAtlas::handle`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AtlasHandle(u32);
+
+/// Where the entries of an atlas went when it grew. Takes rects from before the grow, sub rects
+/// of entries included, to the same texels at their new place.
+#[derive(Clone, Debug, Default)]
+pub struct RectRemap {
+    steps: Vec<RemapStep>,
+}
+
+// One grow, with the old and new texels of every entry
+#[derive(Clone, Debug)]
+struct RemapStep {
+    old_size: (u32, u32),
+    new_size: (u32, u32),
+    moves: Vec<([u32; 4], [u32; 4])>,
+}
+
+impl RectRemap {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Where `rect` is now. Rects the grows didn't touch come back as they are, `None` means
+    /// `rect` was from a layout that was replaced but wasn't inside any of its entries.
+    pub fn remap(&self, rect: TextureRect) -> Option<TextureRect> {
+        let mut rect = rect;
+        for step in &self.steps {
+            if rect.atlas_size != step.old_size {
+                continue;
+            }
+            let (old, new) = step.moves.iter().find(|(old, _)| {
+                old[0] <= rect.texels[0]
+                    && old[1] <= rect.texels[1]
+                    && rect.texels[2] <= old[2]
+                    && rect.texels[3] <= old[3]
+            })?;
+            rect = TextureRect {
+                texels: [
+                    rect.texels[0] - old[0] + new[0],
+                    rect.texels[1] - old[1] + new[1],
+                    rect.texels[2] - old[0] + new[0],
+                    rect.texels[3] - old[1] + new[1],
+                ],
+                atlas_size: step.new_size,
+                rotated: rect.rotated,
+            };
+        }
+        Some(rect)
+    }
+
+    /// Adds the grows of a later remap, so rects from before either end up where `later` put
+    /// them.
+    pub fn extend(&mut self, later: RectRemap) {
+        self.steps.extend(later.steps);
+    }
+}
+
+/// Identifies an image by its size, gutter and a hash of its pixels, for finding images that are
+/// already in the atlas. See `TextureAtlas::reuse`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ContentKey {
     size: (u32, u32),
-    texture_rects: Vec<[u32; 4]>,
+    gutter: u32,
+    hash: u64,
+}
+
+impl ContentKey {
+    pub fn new(pixels: &[u8], size: (u32, u32), gutter: u32) -> ContentKey {
+        let mut hasher = DefaultHasher::new();
+        pixels.hash(&mut hasher);
+        ContentKey {
+            size,
+            gutter,
+            hash: hasher.finish(),
+        }
+    }
+}
+
+/// Packs rects into a fixed size texture with the skyline bottom-left algorithm, see
+/// `AtlasPacker`. The same adds and removes in the same order always give the same rects. Can
+/// grow into a bigger texture when it fills up, see `set_auto_grow`.
+pub struct TextureAtlas {
+    packer: AtlasPacker,
+    entries: Vec<AtlasEntry>,
+    next_id: u32,
+    // Largest width and height `graphics::load_images_batch` may grow the atlas to, when it is allowed to
+    auto_grow: Option<u32>,
+    // Each entry's texels with its gutter as RGBA rows by entry id, kept in auto grow mode to
+    // write them again after growing
+    retained_pixels: HashMap<u32, Vec<u8>>,
+    // The grows done by `grow_retained` that `take_remap` hasn't handed out yet
+    pending_remap: RectRemap,
+    // Entry ids by the content that was recorded for them
+    contents: HashMap<ContentKey, u32>,
+}
+
+// An added rect and the gutter reserved around it
+#[derive(Clone, Copy, Debug)]
+struct AtlasEntry {
+    texels: [u32; 4],
+    gutter: u32,
+    rotated: bool,
+    id: u32,
+    // How many adds got this entry, it is only freed once each of them removed it
+    refs: u32,
+}
+
+impl AtlasEntry {
+    fn outer(&self) -> [u32; 4] {
+        grow(self.texels, self.gutter)
+    }
 }
 
 impl TextureAtlas {
-    pub fn new(size: (u32, u32)) -> TextureAtlas {
+    /// An empty atlas keeping `padding` texels free between entries and along its edges.
+    pub fn new(size: (u32, u32), padding: u32) -> TextureAtlas {
         TextureAtlas {
-            size: size,
-            texture_rects: Vec::new(),
-        }
-    }
-    pub fn add_texture(&mut self, size: (u32, u32)) -> Result<[u32; 4], Error> {
-        let pad = |rect: [u32; 4]| [rect[0] - 1, rect[1] - 1, rect[2] + 1, rect[3] + 1];
-        let unpad = |rect: [u32; 4]| [rect[0] + 1, rect[1] + 1, rect[2] - 1, rect[3] - 1];
-        let tex_coords = {
-            let mut y = 1;
-            let mut x = 1;
-            let mut coords = None;
-            'outer: while y < self.size.1 - size.1 {
-                let mut next_y = self.size.1;
-                while x < self.size.0 - size.0 {
-                    let t1 = pad([x, y, x + size.0, y + size.1]);
-                    let overlap = self.texture_rects.iter().filter(|t2| {
-                        !(t1[0] >= t2[2] || t2[2] <= t2[0] || t1[1] >= t2[3] || t1[3] <= t2[1])
-                    });
-                    let mut any_intersect = false;
-                    // on the x axis, skip past any overlapping textures
-                    // on the y axis, jump up to the lowest top edge in the row
-                    for rect in overlap {
-                        if rect[3] < next_y {
-                            next_y = rect[3] + 1;
-                        }
-                        if rect[2] > x {
-                            x = rect[2] + 1;
-                        }
-                        any_intersect = true;
-                    }
-                    if !any_intersect {
-                        coords = Some(unpad(t1));
-                        break 'outer;
-                    }
+            packer: AtlasPacker::new(size, padding),
+            entries: Vec::new(),
+            next_id: 0,
+            auto_grow: None,
+            retained_pixels: HashMap::new(),
+            pending_remap: RectRemap::default(),
+            contents: HashMap::new(),
+        }
+    }
+
+    /// An atlas holding `rects` already, like the ones `build.rs` bakes with the
+    /// `prebaked-atlas` feature. They get one reference each and no gutter, later adds go below
+    /// and beside them.
+    #[cfg(feature = "prebaked-atlas")]
+    pub fn with_rects(
+        size: (u32, u32),
+        padding: u32,
+        rects: &[TextureRect],
+    ) -> Result<TextureAtlas, Error> {
+        let mut atlas = TextureAtlas::new(size, padding);
+        for (i, rect) in rects.iter().enumerate() {
+            if rect.atlas_size != size {
+                bail!(
+                    "Texture rect {:?} is from a {}x{} atlas, not {}x{}",
+                    rect.texels,
+                    rect.atlas_size.0,
+                    rect.atlas_size.1,
+                    size.0,
+                    size.1
+                );
+            }
+            if let Some(other) = rects[..i]
+                .iter()
+                .find(|other| intersects(grow(rect.texels, padding), other.texels))
+            {
+                bail!(
+                    "Texture rects {:?} and {:?} are closer than the padding",
+                    other.texels,
+                    rect.texels
+                );
+            }
+            atlas.entries.push(AtlasEntry {
+                texels: rect.texels,
+                gutter: 0,
+                rotated: rect.rotated,
+                id: i as u32,
+                refs: 1,
+            });
+        }
+        atlas.next_id = rects.len() as u32;
+        let occupied: Vec<[u32; 4]> = atlas.entries.iter().map(AtlasEntry::outer).collect();
+        atlas.packer = AtlasPacker::with_occupied(size, padding, &occupied);
+        Ok(atlas)
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.packer.size()
+    }
+
+    pub fn padding(&self) -> u32 {
+        self.packer.padding()
+    }
+
+    /// A handle for the entry `rect` was returned for, which keeps finding it after the atlas
+    /// grows.
+    pub fn handle(&self, rect: TextureRect) -> Option<AtlasHandle> {
+        self.entries
+            .iter()
+            .find(|entry| entry.texels == rect.texels)
+            .filter(|_| rect.atlas_size == self.size())
+            .map(|entry| AtlasHandle(entry.id))
+    }
+
+    /// Where the entry is now, or `None` if it was removed.
+    pub fn rect(&self, handle: AtlasHandle) -> Option<TextureRect> {
+        self.entries
+            .iter()
+            .find(|entry| entry.id == handle.0)
+            .map(|entry| TextureRect {
+                texels: entry.texels,
+                atlas_size: self.size(),
+                rotated: entry.rotated,
+            })
+    }
+
+    pub fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Texels taken up by entries and their gutters. Padding isn't counted.
+    pub fn used_area(&self) -> u64 {
+        self.entries.iter().map(|entry| area(entry.outer())).sum()
+    }
+
+    /// Texels not taken up by entries or their gutters, including padding and space too
+    /// fragmented to be used.
+    pub fn free_area(&self) -> u64 {
+        self.total_area() - self.used_area()
+    }
+
+    /// The fraction of the atlas taken up by entries and their gutters, from 0 to 1.
+    pub fn utilization(&self) -> f32 {
+        match self.total_area() {
+            0 => 0.,
+            total => (self.used_area() as f64 / total as f64) as f32,
+        }
+    }
+
+    /// The biggest image size by area that an add without a gutter would still find space for,
+    /// or `(0, 0)` if the atlas is full.
+    pub fn largest_free_extent(&self) -> (u32, u32) {
+        self.packer.largest_free_extent()
+    }
+
+    fn total_area(&self) -> u64 {
+        self.size().0 as u64 * self.size().1 as u64
+    }
+
+    /// Every rect currently in the atlas, in the order they were added.
+    pub fn rects(&self) -> impl Iterator<Item = TextureRect> + '_ {
+        let atlas_size = self.size();
+        self.entries.iter().map(move |entry| TextureRect {
+            texels: entry.texels,
+            atlas_size,
+            rotated: entry.rotated,
+        })
+    }
+
+    /// The layout as an SVG of rect outlines numbered in the order of `rects`, for looking at
+    /// the packing.
+    pub fn debug_layout_svg(&self) -> String {
+        let (width, height) = self.size();
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" \
+             viewBox=\"0 0 {0} {1}\">\n\
+             <rect width=\"{0}\" height=\"{1}\" fill=\"#202020\"/>\n",
+            width, height
+        );
+        for (i, entry) in self.entries.iter().enumerate() {
+            let [min_x, min_y, max_x, max_y] = entry.texels;
+            svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" \
+                 stroke=\"#ff00ff\" stroke-width=\"0.5\"/>\n\
+                 <text x=\"{}\" y=\"{}\" font-size=\"6\" fill=\"#ffffff\">{}</text>\n",
+                min_x,
+                min_y,
+                max_x - min_x,
+                max_y - min_y,
+                min_x + 1,
+                min_y + 6,
+                i
+            ));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Adds a rect of `size` with `gutter` more texels reserved on each side, for copies of its
+    /// edge texels so that filtering just outside the rect samples the right colors. The
+    /// returned rect leaves the gutter out.
+    pub fn add_texture_with_gutter(
+        &mut self,
+        size: (u32, u32),
+        gutter: u32,
+    ) -> Result<TextureRect, Error> {
+        self.add_entry(size, gutter, false)
+    }
+
+    /// `add_texture_with_gutter` that may store the image turned 90° clockwise when that packs
+    /// tighter, mostly for long strips. The returned rect's `rotated` says whether it was, and
+    /// its UVs need their axes swapped, which the graphics module's quads do.
+    pub fn add_texture_rotatable(
+        &mut self,
+        size: (u32, u32),
+        gutter: u32,
+    ) -> Result<TextureRect, Error> {
+        self.add_entry(size, gutter, true)
+    }
+
+    fn add_entry(
+        &mut self,
+        size: (u32, u32),
+        gutter: u32,
+        rotatable: bool,
+    ) -> Result<TextureRect, Error> {
+        let rotated = rotatable && self.packer.prefers_rotated(size, gutter);
+        let placed_size = if rotated { (size.1, size.0) } else { size };
+        let texels = self.place(placed_size, gutter).ok_or_else(|| {
+            let largest = self.largest_free_extent();
+            format_err!(
+                "Texture atlas overflow: no space for {}x{} with a {} texel gutter, \
+                 {:.1}% used by {} entries, largest free space is {}x{}",
+                size.0,
+                size.1,
+                gutter,
+                self.utilization() * 100.,
+                self.entries.len(),
+                largest.0,
+                largest.1
+            )
+        })?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(AtlasEntry {
+            texels,
+            gutter,
+            rotated,
+            id,
+            refs: 1,
+        });
+        Ok(TextureRect {
+            texels,
+            atlas_size: self.size(),
+            rotated,
+        })
+    }
+
+    /// Lets `graphics::load_images_batch` grow the atlas up to `max_size` when an image doesn't
+    /// fit, instead of failing. The atlas then keeps a copy of every image's texels to write them
+    /// again after growing, and collects the remaps for `take_remap`. Turning it off drops the
+    /// copies. The prebaked atlas can't grow, its images are only in the texture.
+    #[cfg(not(feature = "prebaked-atlas"))]
+    pub fn set_auto_grow(&mut self, max_size: Option<u32>) {
+        self.auto_grow = max_size;
+        if max_size.is_none() {
+            self.retained_pixels.clear();
+        }
+    }
+
+    pub fn auto_grow(&self) -> Option<u32> {
+        self.auto_grow
+    }
+
+    /// Keeps `pixels`, `rect`'s texels and gutter as tightly packed RGBA rows, for
+    /// `grow_retained`. Does nothing unless auto grow is on.
+    pub fn retain_pixels(&mut self, rect: TextureRect, pixels: Vec<u8>) {
+        if self.auto_grow.is_none() {
+            return;
+        }
+        if let Some(handle) = self.handle(rect) {
+            self.retained_pixels.insert(handle.0, pixels);
+        }
+    }
+
+    /// Doubles the atlas's width and height, up to the auto grow maximum, and packs every entry
+    /// again into the bigger space. `texture` is reallocated at the new size and the retained
+    /// texels written to it again. Rects from before need passing through the remap `take_remap`
+    /// returns, handles stay valid.
+    pub unsafe fn grow_retained(&mut self, texture: &mut gl::Texture) -> Result<(), Error> {
+        let max_size = match self.auto_grow {
+            Some(max_size) => max_size,
+            None => bail!("The texture atlas is not in auto grow mode"),
+        };
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| !self.retained_pixels.contains_key(&entry.id))
+        {
+            bail!(
+                "Texture rect {:?} was added without keeping its texels, cannot grow",
+                entry.texels
+            );
+        }
+        let (mut grown, remap) = self.grown(max_size)?;
+        let (width, height) = grown.size();
+        texture.reallocate(width, height);
+        for entry in &grown.entries {
+            let pixels = &grown.retained_pixels[&entry.id];
+            // empty images have nothing to write, not even a gutter
+            if pixels.is_empty() {
+                continue;
+            }
+            let outer = entry.outer();
+            texture.write(
+                outer[0],
+                outer[1],
+                outer[2] - outer[0],
+                outer[3] - outer[1],
+                pixels,
+            )?;
+        }
+        grown.pending_remap = mem::take(&mut self.pending_remap);
+        grown.pending_remap.extend(remap);
+        *self = grown;
+        Ok(())
+    }
+
+    /// The rect of an image with the same content added before, which then needs one more
+    /// `remove` to be freed.
+    pub fn reuse(&mut self, key: ContentKey) -> Option<TextureRect> {
+        let id = *self.contents.get(&key)?;
+        let atlas_size = self.size();
+        let entry = self.entries.iter_mut().find(|entry| entry.id == id)?;
+        entry.refs += 1;
+        Some(TextureRect {
+            texels: entry.texels,
+            atlas_size,
+            rotated: entry.rotated,
+        })
+    }
+
+    /// Notes that `rect` holds the image `key` identifies, for `reuse`.
+    pub fn record_content(&mut self, rect: TextureRect, key: ContentKey) {
+        if let Some(handle) = self.handle(rect) {
+            self.contents.insert(key, handle.0);
+        }
+    }
+
+    /// The grows `graphics::load_images_batch` did in auto grow mode since the last call, for fixing up the rects
+    /// handed out before them.
+    pub fn take_remap(&mut self) -> RectRemap {
+        mem::take(&mut self.pending_remap)
+    }
+
+    /// Gives `rect`'s space and its gutter back to be reused by later adds, once every add that
+    /// returned it was removed. Errors if `rect` isn't currently in this atlas.
+    pub fn remove(&mut self, rect: TextureRect) -> Result<(), Error> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.texels == rect.texels)
+            .filter(|_| rect.atlas_size == self.size());
+        let index = match index {
+            Some(index) => index,
+            None => bail!("Texture rect {:?} is not in the atlas", rect.texels),
+        };
+        if self.entries[index].refs > 1 {
+            self.entries[index].refs -= 1;
+            return Ok(());
+        }
+        let entry = self.entries.remove(index);
+        let (outer, id) = (entry.outer(), entry.id);
+        self.packer.free(outer);
+        self.retained_pixels.remove(&id);
+        self.contents.retain(|_, entry_id| *entry_id != id);
+        Ok(())
+    }
+
+    // Where an image of `size` with `gutter` goes, or `None` if it doesn't fit
+    fn place(&mut self, size: (u32, u32), gutter: u32) -> Option<[u32; 4]> {
+        let texels = self.packer.place(size, gutter)?;
+        debug_assert!(
+            !self
+                .entries
+                .iter()
+                .any(|other| intersects(grow(grow(texels, gutter), self.padding()), other.outer())),
+            "atlas entries overlap"
+        );
+        Some(texels)
+    }
+
+    // This atlas at double the size, up to `max_size`, with every entry placed again tallest
+    // first, and where they moved
+    fn grown(&self, max_size: u32) -> Result<(TextureAtlas, RectRemap), Error> {
+        let size = (
+            (self.size().0 * 2).min(max_size),
+            (self.size().1 * 2).min(max_size),
+        );
+        if size.0 <= self.size().0 && size.1 <= self.size().1 {
+            bail!(
+                "The {}x{} texture atlas is already at the maximum size of {}",
+                self.size().0,
+                self.size().1,
+                max_size
+            );
+        }
+
+        let mut grown = TextureAtlas::new(size, self.padding());
+        grown.next_id = self.next_id;
+        grown.auto_grow = self.auto_grow;
+        grown.retained_pixels = self.retained_pixels.clone();
+        grown.contents = self.contents.clone();
+        let mut order: Vec<usize> = (0..self.entries.len()).collect();
+        order.sort_by_key(|&i| Reverse(self.entries[i].outer()[3] - self.entries[i].outer()[1]));
+        let mut placed = Vec::with_capacity(order.len());
+        for &i in &order {
+            let entry = self.entries[i];
+            let entry_size = (
+                entry.texels[2] - entry.texels[0],
+                entry.texels[3] - entry.texels[1],
+            );
+            let texels = grown.place(entry_size, entry.gutter).ok_or_else(|| {
+                format_err!(
+                    "Could not repack the texture atlas at {}x{}",
+                    size.0,
+                    size.1
+                )
+            })?;
+            placed.push((i, AtlasEntry { texels, ..entry }));
+        }
+        // back in the order they were added
+        placed.sort_by_key(|&(i, _)| i);
+        grown.entries = placed.into_iter().map(|(_, entry)| entry).collect();
+
+        let moves = self
+            .entries
+            .iter()
+            .zip(&grown.entries)
+            .map(|(old, new)| (old.texels, new.texels))
+            .collect();
+        let remap = RectRemap {
+            steps: vec![RemapStep {
+                old_size: self.size(),
+                new_size: size,
+                moves,
+            }],
+        };
+        Ok((grown, remap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn grown_remaps_rects_and_sub_rects() {
+        let mut atlas = TextureAtlas::new((32, 32), 1);
+        let small = atlas.add_texture_with_gutter((4, 4), 0).unwrap();
+        let strip = atlas.add_texture_with_gutter((30, 8), 0).unwrap();
+        let handle = atlas.handle(strip).unwrap();
+        assert!(atlas.add_texture_with_gutter((20, 20), 0).is_err());
+
+        let (grown, remap) = atlas.grown(64).unwrap();
+        assert_eq!(grown.size(), (64, 64));
+        assert!(!remap.is_empty());
+        let new_strip = grown.rect(handle).unwrap();
+        assert_eq!(remap.remap(strip), Some(new_strip));
+        assert_eq!(
+            remap.remap(strip.sub_rect(10, 2, 5, 3)),
+            Some(new_strip.sub_rect(10, 2, 5, 3))
+        );
+        let new_small = remap.remap(small).unwrap();
+        assert_eq!((new_small.width(), new_small.height()), (4, 4));
+        assert_eq!(new_small.atlas_size, (64, 64));
+        // rects from the new layout are left alone
+        assert_eq!(remap.remap(new_strip), Some(new_strip));
+
+        assert!(grown.grown(64).is_err());
+    }
+
+    #[test]
+    fn remaps_chain_across_grows() {
+        let mut atlas = TextureAtlas::new((16, 16), 1);
+        let rect = atlas.add_texture_with_gutter((6, 6), 1).unwrap();
+        let (grown, mut remap) = atlas.grown(64).unwrap();
+        let (grown, later) = grown.grown(64).unwrap();
+        remap.extend(later);
+        let moved = remap.remap(rect).unwrap();
+        assert_eq!(grown.rects().next(), Some(moved));
+        assert_eq!(moved.atlas_size, (64, 64));
+    }
+
+    #[test]
+    fn utilization_counts_gutters_but_not_padding() {
+        let mut atlas = TextureAtlas::new((64, 64), 1);
+        assert_eq!((atlas.used_area(), atlas.free_area()), (0, 64 * 64));
+        assert_eq!(atlas.utilization(), 0.);
+
+        // 10x10 inside a 2 texel gutter takes 14x14, the padding around it none
+        atlas.add_texture_with_gutter((10, 10), 2).unwrap();
+        atlas.add_texture_with_gutter((20, 5), 0).unwrap();
+        assert_eq!(atlas.entry_count(), 2);
+        assert_eq!(atlas.used_area(), 14 * 14 + 20 * 5);
+        assert_eq!(atlas.free_area(), 64 * 64 - 296);
+        assert_eq!(atlas.utilization(), 296. / 4096.);
+
+        // a single entry filling everything inside the padding
+        let mut full = TextureAtlas::new((16, 8), 1);
+        full.add_texture_with_gutter((14, 6), 0).unwrap();
+        assert_eq!(full.utilization(), (14. * 6.) / (16. * 8.));
+        assert_eq!(full.largest_free_extent(), (0, 0));
+    }
+
+    #[test]
+    fn overflow_error_describes_the_atlas() {
+        let mut atlas = TextureAtlas::new((32, 32), 1);
+        atlas.add_texture_with_gutter((30, 14), 0).unwrap();
+        let error = atlas.add_texture_with_gutter((31, 2), 1).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Texture atlas overflow: no space for 31x2 with a 1 texel gutter, 41.0% used by 1 \
+             entries, largest free space is 30x15"
+        );
+    }
+
+    #[test]
+    fn rotated_strips_leave_room_for_squares() {
+        // tall strips next to the squares, like the animation strips and tiles
+        let sizes: Vec<(u32, u32)> = [(6, 90); 4]
+            .iter()
+            .chain(&[(20, 20); 12])
+            .copied()
+            .collect();
+        let add = |rotatable: bool| {
+            let mut atlas = TextureAtlas::new((128, 64), 1);
+            let mut rotated = 0;
+            for &size in &sizes {
+                let added = if rotatable {
+                    atlas.add_texture_rotatable(size, 0)
+                } else {
+                    atlas.add_texture_with_gutter(size, 0)
+                };
+                if let Ok(rect) = added {
+                    // the image's size either way, the texels are turned
+                    assert_eq!((rect.width(), rect.height()), size);
+                    let [min_x, min_y, max_x, max_y] = rect.texels;
+                    let placed = if rect.rotated { (size.1, size.0) } else { size };
+                    assert_eq!((max_x - min_x, max_y - min_y), placed);
+                    rotated += rect.rotated as usize;
                 }
-                x = 0;
-                y = next_y;
             }
-            coords
+            (atlas, rotated)
         };
 
-        match tex_coords {
-            Some(coords) => {
-                self.texture_rects.push(coords);
-                Ok(coords)
+        let (upright, none_rotated) = add(false);
+        let (turned, strips_rotated) = add(true);
+        assert_eq!(none_rotated, 0);
+        assert_eq!(strips_rotated, 4);
+        // the strips only fit on their side, and take more area than the squares they push out
+        assert!(upright.rects().all(|rect| rect.width() == 20));
+        assert_eq!(turned.entry_count(), upright.entry_count());
+        assert!(
+            turned.utilization() > upright.utilization(),
+            "rotatable {:.3}, upright {:.3}",
+            turned.utilization(),
+            upright.utilization()
+        );
+    }
+
+    #[test]
+    fn churn_reuses_freed_space() {
+        // what an editor re-baking room block previews does to the atlas, with never more than
+        // an eighth of it live at once
+        let mut rng = SmallRng::seed_from_u64(1856);
+        let mut atlas = TextureAtlas::new((128, 128), 1);
+        let mut live: Vec<TextureRect> = Vec::new();
+        for _ in 0..2000 {
+            while atlas.used_area() > 128 * 128 / 8 {
+                let rect = live.swap_remove(rng.gen_range(0, live.len()));
+                atlas.remove(rect).unwrap();
             }
-            None => Err(format_err!("Texture atlas overflow")),
+            let size = (rng.gen_range(1, 16), rng.gen_range(1, 16));
+            let rect = atlas.add_texture_with_gutter(size, 1).unwrap();
+            assert!(live
+                .iter()
+                .all(|other| !intersects(grow(rect.texels, 2), grow(other.texels, 1))));
+            live.push(rect);
         }
+
+        // with everything gone the whole atlas is free again
+        for rect in live.drain(..) {
+            atlas.remove(rect).unwrap();
+        }
+        assert_eq!(atlas.entry_count(), 0);
+        assert_eq!(atlas.used_area(), 0);
+        assert_eq!(atlas.largest_free_extent(), (126, 126));
+        assert!(atlas.add_texture_with_gutter((126, 126), 0).is_ok());
+    }
+
+    // What `graphics::load_images_batch` does with the atlas for a raw image, without writing any texels
+    fn add_content(atlas: &mut TextureAtlas, pixels: &[u8], size: (u32, u32)) -> TextureRect {
+        let key = ContentKey::new(pixels, size, 0);
+        if let Some(rect) = atlas.reuse(key) {
+            return rect;
+        }
+        let rect = atlas.add_texture_with_gutter(size, 0).unwrap();
+        atlas.record_content(rect, key);
+        rect
+    }
+
+    #[test]
+    fn duplicate_content_shares_an_entry() {
+        let mut atlas = TextureAtlas::new((64, 64), 1);
+        let pixels = [7; 4 * 4 * 4];
+        let first = add_content(&mut atlas, &pixels, (4, 4));
+        let used = atlas.used_area();
+        assert_eq!(add_content(&mut atlas, &pixels, (4, 4)), first);
+        assert_eq!(atlas.used_area(), used);
+        assert_eq!(atlas.entry_count(), 1);
+
+        // same bytes in another shape
+        let other = add_content(&mut atlas, &pixels, (8, 2));
+        assert_ne!(other, first);
+        assert_ne!(
+            ContentKey::new(&pixels, (4, 4), 0),
+            ContentKey::new(&pixels, (4, 4), 1)
+        );
+
+        // freed once both adds are removed, and not found again after
+        atlas.remove(first).unwrap();
+        assert_eq!(atlas.entry_count(), 2);
+        atlas.remove(first).unwrap();
+        assert_eq!(atlas.entry_count(), 1);
+        assert!(atlas.remove(first).is_err());
+        assert_eq!(atlas.reuse(ContentKey::new(&pixels, (4, 4), 0)), None);
+    }
+
+    // Adds `sizes` in the order given, leaving out what doesn't fit
+    fn add_all(atlas: &mut TextureAtlas, sizes: impl Iterator<Item = (u32, u32)>) {
+        for size in sizes {
+            let _ = atlas.add_texture_with_gutter(size, 0);
+        }
+    }
+
+    #[test]
+    fn batch_order_packs_tighter_than_one_at_a_time() {
+        // small sprites first and the room block previews last, as they used to be loaded
+        let mut sizes = vec![(16, 16), (9, 22), (4, 12), (135, 16), (2, 2), (1, 1)];
+        sizes.extend(vec![(17, 17); 11]);
+        sizes.extend((0..40).map(|i| (3 + i % 7 * 5, 2 + i % 5 * 6)));
+
+        let mut one_at_a_time = TextureAtlas::new((160, 128), 1);
+        add_all(&mut one_at_a_time, sizes.iter().copied());
+        let mut batch = TextureAtlas::new((160, 128), 1);
+        add_all(
+            &mut batch,
+            packing_order(&sizes).into_iter().map(|i| sizes[i]),
+        );
+
+        assert!(
+            batch.utilization() > one_at_a_time.utilization(),
+            "batch {:.3}, one at a time {:.3}",
+            batch.utilization(),
+            one_at_a_time.utilization()
+        );
+    }
+
+    #[test]
+    fn layout_svg_outlines_every_rect_in_order() {
+        let mut atlas = TextureAtlas::new((64, 32), 1);
+        atlas.add_texture_with_gutter((10, 20), 0).unwrap();
+        atlas.add_texture_with_gutter((5, 5), 0).unwrap();
+        let svg = atlas.debug_layout_svg();
+        assert!(svg.starts_with("<svg") && svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains("width=\"64\" height=\"32\""));
+        assert!(svg.contains("<rect x=\"1\" y=\"1\" width=\"10\" height=\"20\""));
+        let first = svg.find(">0</text>").unwrap();
+        let second = svg.find(">1</text>").unwrap();
+        assert!(first < second);
     }
 }
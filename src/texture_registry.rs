@@ -0,0 +1,242 @@
+use std::{collections::HashMap, rc::Rc};
+
+use anyhow::{bail, format_err, Error};
+
+use crate::{
+    gl,
+    graphics::{self, BatchImage},
+    texture_atlas::{TextureAtlas, TextureRect},
+};
+
+// How many close matches a missing name error lists
+const MAX_SUGGESTIONS: usize = 3;
+
+/// The texture atlas with its images by name, so code can look up what it needs instead of
+/// having every rect passed in. Images can only be added while nothing else holds on to the atlas
+/// texture, so load everything before handing `texture` out. Names keep up with the atlas when
+/// it grows in auto grow mode, rects looked up before don't.
+pub struct TextureRegistry {
+    atlas: TextureAtlas,
+    texture: Rc<gl::Texture>,
+    rects: HashMap<String, TextureRect>,
+}
+
+impl TextureRegistry {
+    pub fn new(atlas: TextureAtlas, texture: gl::Texture) -> TextureRegistry {
+        TextureRegistry {
+            atlas,
+            texture: Rc::new(texture),
+            rects: HashMap::new(),
+        }
+    }
+
+    pub fn atlas(&self) -> &TextureAtlas {
+        &self.atlas
+    }
+
+    pub fn texture(&self) -> &Rc<gl::Texture> {
+        &self.texture
+    }
+
+    /// Names a rect that's in the atlas already, like the ones `TextureAtlas::with_rects` starts
+    /// out with.
+    #[cfg(feature = "prebaked-atlas")]
+    pub fn insert_rect(&mut self, name: &str, rect: TextureRect) -> Result<(), Error> {
+        check_new_names(&self.rects, &[name])?;
+        if self.atlas.handle(rect).is_none() {
+            bail!(
+                "Texture rect {:?} for \"{}\" is not in the atlas",
+                rect.texels,
+                name
+            );
+        }
+        self.rects.insert(name.to_owned(), rect);
+        Ok(())
+    }
+
+    /// Adds all of `images` by name in one `graphics::load_images_batch`, which packs them
+    /// tighter than adding them one at a time. Returns the rects in the order of `images`.
+    /// Errors without adding anything if a name is taken or appears twice.
+    pub unsafe fn insert_batch(
+        &mut self,
+        images: &[(&str, BatchImage)],
+    ) -> Result<Vec<TextureRect>, Error> {
+        let names: Vec<&str> = images.iter().map(|(name, _)| *name).collect();
+        check_new_names(&self.rects, &names)?;
+        let texture = Rc::get_mut(&mut self.texture)
+            .ok_or_else(|| format_err!("Cannot add textures while the atlas texture is in use"))?;
+        let batch: Vec<BatchImage> = images.iter().map(|(_, image)| *image).collect();
+        let rects = graphics::load_images_batch(&batch, &mut self.atlas, texture)
+            .map_err(|e| e.context(format!("Could not add the textures {}", names.join(", "))))?;
+        self.follow_growth()?;
+        for (name, rect) in names.iter().zip(&rects) {
+            self.rects.insert((*name).to_owned(), *rect);
+        }
+        Ok(rects)
+    }
+
+    /// The rect added as `name`. The error for a missing name lists the closest names there are.
+    pub fn get(&self, name: &str) -> Result<TextureRect, Error> {
+        self.rects
+            .get(name)
+            .copied()
+            .ok_or_else(|| missing_name(name, &self.rects))
+    }
+
+    // Moves the named rects to where they are now if loading grew the atlas. Rects loaded since
+    // are where they are already.
+    fn follow_growth(&mut self) -> Result<(), Error> {
+        let remap = self.atlas.take_remap();
+        if remap.is_empty() {
+            return Ok(());
+        }
+        for (name, rect) in &mut self.rects {
+            *rect = remap
+                .remap(*rect)
+                .ok_or_else(|| format_err!("Texture \"{}\" was lost when the atlas grew", name))?;
+        }
+        Ok(())
+    }
+}
+
+// Errors on the first of `names` that is taken already or comes up twice
+fn check_new_names(rects: &HashMap<String, TextureRect>, names: &[&str]) -> Result<(), Error> {
+    for (i, name) in names.iter().enumerate() {
+        if rects.contains_key(*name) || names[..i].contains(name) {
+            bail!("There is already a texture named \"{}\"", name);
+        }
+    }
+    Ok(())
+}
+
+// The error for looking up `name` when it's not in `rects`
+fn missing_name(name: &str, rects: &HashMap<String, TextureRect>) -> Error {
+    let suggestions = close_matches(name, rects.keys().map(String::as_str));
+    if suggestions.is_empty() {
+        return format_err!(
+            "No texture named \"{}\" among {} textures",
+            name,
+            rects.len()
+        );
+    }
+    format_err!(
+        "No texture named \"{}\", did you mean {}?",
+        name,
+        suggestions
+            .iter()
+            .map(|name| format!("\"{}\"", name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+// The `names` within a third of `name`'s length in edits of it, or containing it, closest first
+fn close_matches<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut matches: Vec<(usize, &str)> = names
+        .filter_map(|candidate| {
+            let distance = edit_distance(name, candidate);
+            if distance <= max_distance || (!name.is_empty() && candidate.contains(name)) {
+                Some((distance, candidate))
+            } else {
+                None
+            }
+        })
+        .collect();
+    matches.sort();
+    matches
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+// Levenshtein distance in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + if a == *b { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rects(names: &[&str]) -> HashMap<String, TextureRect> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let rect = TextureRect {
+                    texels: [i as u32, 0, i as u32 + 1, 1],
+                    atlas_size: (16, 16),
+                    rotated: false,
+                };
+                ((*name).to_owned(), rect)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn taken_and_repeated_names_are_errors() {
+        let taken = rects(&["block", "player"]);
+        assert!(check_new_names(&taken, &["dust", "white"]).is_ok());
+        assert_eq!(
+            check_new_names(&taken, &["dust", "player"])
+                .unwrap_err()
+                .to_string(),
+            "There is already a texture named \"player\""
+        );
+        assert_eq!(
+            check_new_names(&taken, &["dust", "white", "dust"])
+                .unwrap_err()
+                .to_string(),
+            "There is already a texture named \"dust\""
+        );
+    }
+
+    #[test]
+    fn missing_names_suggest_close_ones() {
+        let names = rects(&["player", "room_block_red", "room_block_blue", "dust"]);
+        assert_eq!(
+            missing_name("playr", &names).to_string(),
+            "No texture named \"playr\", did you mean \"player\"?"
+        );
+        // containing the name counts however far apart they are, closest first
+        assert_eq!(
+            missing_name("room_block", &names).to_string(),
+            "No texture named \"room_block\", did you mean \"room_block_red\", \
+             \"room_block_blue\"?"
+        );
+        assert_eq!(
+            missing_name("music_icon", &names).to_string(),
+            "No texture named \"music_icon\" among 4 textures"
+        );
+    }
+
+    #[test]
+    fn close_matches_are_capped() {
+        let names = ["aa", "ab", "ac", "ad", "zz"];
+        assert_eq!(
+            close_matches("a", names.iter().copied()),
+            ["aa", "ab", "ac"]
+        );
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("", "dust"), 4);
+        assert_eq!(edit_distance("dust", "dust"), 0);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("blöck", "block"), 1);
+    }
+}
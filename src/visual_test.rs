@@ -0,0 +1,601 @@
+//! Visual regression tests. `--visual-test <scenario> <out dir>` plays a scenario from
+//! `visual_tests/` on a headless GL context, saves the frames it captures to the out dir and
+//! compares them with the scenario's reference PNGs, saving a diff image for each one that doesn't
+//! match. `--regenerate-references` saves the captures as the new references instead. Native
+//! only, with the `headless` feature.
+//!
+//! A scenario is a text file of one directive per line, `#` starting a comment:
+//!
+//! - `start <room> <x> <y>` starts the game in another room or place, in room coordinates
+//! - `press <tick> <key>` and `release <tick> <key>` give the tick a key press or release
+//! - `capture <tick>...` captures the frame drawn after each of the ticks
+//! - `threshold <delta>` and `max_pixels <count>` loosen the comparison, see `Tolerance`
+//! - `srgb` plays the scenario with sRGB rendering off and then on, and captures the two frames
+//!   side by side, the one without on the left
+//!
+//! Ticks are counted from 1 and tick 0 is the frame drawn before the first. The harness runs the
+//! ticks itself instead of following the clock, and the game seeds its random numbers the same
+//! every time, so a scenario plays the same on every run. Regenerating checks that by playing
+//! the scenario twice.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, format_err, Context, Error};
+use euclid::{point2, size2, Rect};
+use image::{Rgba, RgbaImage};
+
+use crate::{
+    constants::SCREEN_SIZE,
+    game::{Game, GameSnapshot, LevelSource, ProgressMode},
+    gl,
+    input::{InputEvent, Key, Modifiers},
+    mixer::Mixer,
+    platform,
+    presence::NoPresence,
+};
+
+// Scenarios are `<name>.scenario` in here, with their references in `<name>/<tick>.png`
+const VISUAL_TESTS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/visual_tests");
+const SCENARIO_EXTENSION: &str = "scenario";
+const REGENERATE_FLAG: &str = "--regenerate-references";
+const DEFAULT_THRESHOLD: f32 = 0.1;
+const DEFAULT_MAX_PIXELS: usize = 0;
+// The YIQ delta of the two most different colors, which `pixel_delta` is relative to
+const MAX_YIQ_DELTA: f32 = 35215.;
+// Differing pixels are this in diff images, and the rest is the reference faded towards white
+const DIFF_COLOR: Rgba<u8> = Rgba([255, 0, 0, 255]);
+const DIFF_FADE: f32 = 0.1;
+
+/// A replay of input and the ticks to capture the screen after.
+#[derive(Debug)]
+pub struct Scenario {
+    start: Option<GameSnapshot>,
+    // By the tick that gets them
+    inputs: BTreeMap<u32, Vec<InputEvent>>,
+    // In order, without repeats
+    captures: Vec<u32>,
+    tolerance: Tolerance,
+    srgb_side_by_side: bool,
+}
+
+/// How far a capture can be from its reference and still match.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tolerance {
+    /// The `pixel_delta` over which a pixel differs
+    pub threshold: f32,
+    /// How many pixels can differ
+    pub max_pixels: usize,
+}
+
+impl Scenario {
+    pub fn load(name: &str) -> Result<Scenario, Error> {
+        let path = scenario_path(name);
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read the scenario {}", path.display()))?;
+        Scenario::parse(&source).with_context(|| format!("In {}", path.display()))
+    }
+
+    pub fn parse(source: &str) -> Result<Scenario, Error> {
+        let mut scenario = Scenario {
+            start: None,
+            inputs: BTreeMap::new(),
+            captures: Vec::new(),
+            tolerance: Tolerance {
+                threshold: DEFAULT_THRESHOLD,
+                max_pixels: DEFAULT_MAX_PIXELS,
+            },
+            srgb_side_by_side: false,
+        };
+        for (i, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            scenario
+                .parse_directive(line)
+                .with_context(|| format!("Line {}: '{}'", i + 1, line))?;
+        }
+        if scenario.captures.is_empty() {
+            bail!("Nothing is captured");
+        }
+        scenario.captures.sort_unstable();
+        scenario.captures.dedup();
+        Ok(scenario)
+    }
+
+    fn parse_directive(&mut self, line: &str) -> Result<(), Error> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["start", room, x, y] => {
+                let position = point2(parse_number(x)?, parse_number(y)?);
+                self.start = Some(
+                    GameSnapshot::starting_in(room, position)
+                        .ok_or_else(|| format_err!("No room named {}", room))?,
+                );
+            }
+            [action @ "press", tick, key] | [action @ "release", tick, key] => {
+                let tick: u32 = parse_number(tick)?;
+                if tick == 0 {
+                    bail!("Ticks with input start at 1");
+                }
+                let key = Key::from_name(key).ok_or_else(|| format_err!("No key named {}", key))?;
+                let event = if *action == "press" {
+                    InputEvent::KeyDown(key, Modifiers::default())
+                } else {
+                    InputEvent::KeyUp(key, Modifiers::default())
+                };
+                self.inputs.entry(tick).or_default().push(event);
+            }
+            ["capture", ticks @ ..] if !ticks.is_empty() => {
+                for tick in ticks {
+                    self.captures.push(parse_number(tick)?);
+                }
+            }
+            ["threshold", threshold] => self.tolerance.threshold = parse_number(threshold)?,
+            ["max_pixels", max_pixels] => self.tolerance.max_pixels = parse_number(max_pixels)?,
+            ["srgb"] => self.srgb_side_by_side = true,
+            _ => bail!("Unknown directive"),
+        }
+        Ok(())
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str) -> Result<T, Error> {
+    word.parse()
+        .map_err(|_| format_err!("'{}' is not a valid number here", word))
+}
+
+fn scenario_path(name: &str) -> PathBuf {
+    Path::new(VISUAL_TESTS_DIR).join(format!("{}.{}", name, SCENARIO_EXTENSION))
+}
+
+fn reference_path(name: &str, tick: u32) -> PathBuf {
+    Path::new(VISUAL_TESTS_DIR)
+        .join(name)
+        .join(format!("{}.png", tick))
+}
+
+/// How different two colors look, from 0 for the same to 1 for the most different, going by
+/// their distance in the YIQ color space weighted like the eye. Alpha is ignored, captures are
+/// opaque.
+pub fn pixel_delta(a: Rgba<u8>, b: Rgba<u8>) -> f32 {
+    let yiq = |Rgba([r, g, b, _]): Rgba<u8>| {
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        (
+            r * 0.298_895_3 + g * 0.586_622_5 + b * 0.114_482_23,
+            r * 0.595_978 - g * 0.274_176_1 - b * 0.321_801_9,
+            r * 0.211_470_17 - g * 0.522_617_1 + b * 0.311_146_94,
+        )
+    };
+    let (y_a, i_a, q_a) = yiq(a);
+    let (y_b, i_b, q_b) = yiq(b);
+    let delta =
+        0.5053 * (y_a - y_b).powi(2) + 0.299 * (i_a - i_b).powi(2) + 0.1957 * (q_a - q_b).powi(2);
+    (delta / MAX_YIQ_DELTA).sqrt()
+}
+
+/// What `compare` found.
+pub struct Comparison {
+    /// Pixels further apart than the threshold
+    pub differing: usize,
+    /// The differing pixels in red over the faded reference
+    pub diff: RgbaImage,
+}
+
+/// Compares `actual` with `reference` pixel by pixel. They have to be the same size.
+pub fn compare(
+    reference: &RgbaImage,
+    actual: &RgbaImage,
+    threshold: f32,
+) -> Result<Comparison, Error> {
+    if reference.dimensions() != actual.dimensions() {
+        bail!(
+            "The capture is {:?} but the reference is {:?}",
+            actual.dimensions(),
+            reference.dimensions()
+        );
+    }
+    let mut differing = 0;
+    let diff = RgbaImage::from_fn(reference.width(), reference.height(), |x, y| {
+        let expected = *reference.get_pixel(x, y);
+        if pixel_delta(expected, *actual.get_pixel(x, y)) > threshold {
+            differing += 1;
+            DIFF_COLOR
+        } else {
+            let Rgba([r, g, b, _]) = expected;
+            let luma = r as f32 * 0.299 + g as f32 * 0.587 + b as f32 * 0.114;
+            let faded = (255. - (255. - luma) * DIFF_FADE).round() as u8;
+            Rgba([faded, faded, faded, 255])
+        }
+    });
+    Ok(Comparison { differing, diff })
+}
+
+/// Plays `scenario` in a new game drawing to `context`'s screen, drawing every tick like the game
+/// does at 60 frames a second. Returns the capture for each tick the scenario captures.
+pub fn play(
+    context: &mut gl::Context,
+    scenario: &Scenario,
+) -> Result<Vec<(u32, RgbaImage)>, Error> {
+    if !scenario.srgb_side_by_side {
+        return play_game(context, scenario);
+    }
+    let srgb = context.srgb();
+    unsafe { context.set_srgb(false) };
+    let without = play_game(context, scenario);
+    unsafe { context.set_srgb(true) };
+    let with = play_game(context, scenario);
+    unsafe { context.set_srgb(srgb) };
+    Ok(without?
+        .into_iter()
+        .zip(with?)
+        .map(|((tick, left), (_, right))| (tick, side_by_side(&left, &right)))
+        .collect())
+}
+
+// The game reads the context's sRGB setting when it's made
+fn play_game(
+    context: &mut gl::Context,
+    scenario: &Scenario,
+) -> Result<Vec<(u32, RgbaImage)>, Error> {
+    let mut game = Game::new(
+        context,
+        Arc::new(Mixer::default()),
+        Box::new(NoPresence),
+        LevelSource::Embedded,
+        ProgressMode::Ignored,
+    );
+    if let Some(start) = &scenario.start {
+        game.restore(start);
+    }
+
+    let last_tick = *scenario.captures.last().unwrap();
+    let mut captures = Vec::new();
+    for tick in 0..=last_tick {
+        if tick > 0 {
+            let inputs = scenario.inputs.get(&tick).map_or(&[][..], Vec::as_slice);
+            game.update(inputs);
+        }
+        game.draw(context);
+        unsafe { context.present()? };
+        if scenario.captures.contains(&tick) {
+            captures.push((tick, capture_screen(context)?));
+        }
+    }
+    Ok(captures)
+}
+
+fn side_by_side(left: &RgbaImage, right: &RgbaImage) -> RgbaImage {
+    let mut image = RgbaImage::new(
+        left.width() + right.width(),
+        left.height().max(right.height()),
+    );
+    image::imageops::replace(&mut image, left, 0, 0);
+    image::imageops::replace(&mut image, right, left.width(), 0);
+    image
+}
+
+// The window shows no alpha, so neither do captures
+fn capture_screen(context: &mut gl::Context) -> Result<RgbaImage, Error> {
+    let (width, height) = context.screen_size();
+    let mut pixels = unsafe {
+        context.read_pixels(
+            gl::RenderTarget::Screen,
+            Rect::new(point2(0, 0), size2(width, height)),
+        )?
+    };
+    for pixel in pixels.chunks_mut(4) {
+        pixel[3] = 255;
+    }
+    Ok(RgbaImage::from_raw(width, height, pixels).unwrap())
+}
+
+/// Runs the scenario named `name` as described at the top, printing how each capture went.
+/// Returns false if any of them didn't match its reference.
+pub fn run(
+    context: &mut gl::Context,
+    name: &str,
+    out_dir: &Path,
+    regenerate: bool,
+) -> Result<bool, Error> {
+    let scenario = Scenario::load(name)?;
+    let captures = play(context, &scenario)?;
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Could not create {}", out_dir.display()))?;
+    for (tick, capture) in &captures {
+        let path = out_dir.join(format!("{}-{}.png", name, tick));
+        capture
+            .save(&path)
+            .with_context(|| format!("Could not save {}", path.display()))?;
+    }
+
+    if regenerate {
+        for ((tick, capture), (_, again)) in captures.iter().zip(play(context, &scenario)?) {
+            if **capture != *again {
+                bail!(
+                    "Tick {} of {} came out differently when played again, so it can't be a \
+                     reference",
+                    tick,
+                    name
+                );
+            }
+        }
+        for (tick, capture) in &captures {
+            let path = reference_path(name, *tick);
+            fs::create_dir_all(path.parent().unwrap())?;
+            capture
+                .save(&path)
+                .with_context(|| format!("Could not save {}", path.display()))?;
+            println!("{} tick {}: saved {}", name, tick, path.display());
+        }
+        return Ok(true);
+    }
+
+    let mut matched = true;
+    for (tick, capture) in &captures {
+        let path = reference_path(name, *tick);
+        let reference = match image::open(&path) {
+            Ok(reference) => reference.to_rgba(),
+            Err(e) => {
+                println!(
+                    "{} tick {}: no reference at {} ({}), make one with {}",
+                    name,
+                    tick,
+                    path.display(),
+                    e,
+                    REGENERATE_FLAG
+                );
+                matched = false;
+                continue;
+            }
+        };
+        let comparison = compare(&reference, capture, scenario.tolerance.threshold)?;
+        if comparison.differing <= scenario.tolerance.max_pixels {
+            println!("{} tick {}: matched", name, tick);
+            continue;
+        }
+        matched = false;
+        let diff_path = out_dir.join(format!("{}-{}-diff.png", name, tick));
+        comparison
+            .diff
+            .save(&diff_path)
+            .with_context(|| format!("Could not save {}", diff_path.display()))?;
+        println!(
+            "{} tick {}: {} pixels differ, more than the {} allowed, see {}",
+            name,
+            tick,
+            comparison.differing,
+            scenario.tolerance.max_pixels,
+            diff_path.display()
+        );
+    }
+    Ok(matched)
+}
+
+/// Runs `--visual-test` with the arguments after it and returns the exit code. Skips with a
+/// notice and succeeds where there is no GL context to be had, like on CI machines without a
+/// display.
+pub fn main(args: &[String]) -> i32 {
+    let (name, out_dir) = match args {
+        [name, out_dir, ..] if !name.starts_with("--") && !out_dir.starts_with("--") => {
+            (name, Path::new(out_dir))
+        }
+        _ => {
+            eprintln!(
+                "Usage: --visual-test <scenario> <out dir> [{}]",
+                REGENERATE_FLAG
+            );
+            return 2;
+        }
+    };
+    let regenerate = args.iter().any(|arg| arg == REGENERATE_FLAG);
+    let mut headless = match platform::create_headless_gl(SCREEN_SIZE.0, SCREEN_SIZE.1) {
+        Ok(headless) => headless,
+        Err(e) => {
+            println!(
+                "Skipping visual test {}, no headless GL context: {:#}",
+                name, e
+            );
+            return 0;
+        }
+    };
+    match run(&mut headless.context, name, out_dir, regenerate) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("Visual test {} failed: {:?}", name, e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn key_down(key: Key) -> InputEvent {
+        InputEvent::KeyDown(key, Modifiers::default())
+    }
+
+    fn key_up(key: Key) -> InputEvent {
+        InputEvent::KeyUp(key, Modifiers::default())
+    }
+
+    #[test]
+    fn scenarios_parse() {
+        let scenario = Scenario::parse(
+            "# runs right
+             start Green 3 4.5
+             press 1 D
+             press 1 Space # and jumps
+             release 30 D
+
+             capture 40 10 40
+             threshold 0.05
+             max_pixels 12
+             srgb",
+        )
+        .unwrap();
+        let start = format!("{:?}", scenario.start.unwrap());
+        assert!(start.contains("Green"), "{}", start);
+        let inputs: Vec<(u32, String)> = scenario
+            .inputs
+            .iter()
+            .map(|(tick, events)| (*tick, format!("{:?}", events)))
+            .collect();
+        assert_eq!(
+            inputs,
+            [
+                (1, format!("{:?}", [key_down(Key::D), key_down(Key::Space)])),
+                (30, format!("{:?}", [key_up(Key::D)])),
+            ]
+        );
+        assert_eq!(scenario.captures, [10, 40]);
+        assert_eq!(
+            scenario.tolerance,
+            Tolerance {
+                threshold: 0.05,
+                max_pixels: 12
+            }
+        );
+        assert!(scenario.srgb_side_by_side);
+
+        let scenario = Scenario::parse("capture 0").unwrap();
+        assert!(scenario.start.is_none());
+        assert!(scenario.inputs.is_empty());
+        assert!(!scenario.srgb_side_by_side);
+        assert_eq!(
+            scenario.tolerance,
+            Tolerance {
+                threshold: DEFAULT_THRESHOLD,
+                max_pixels: DEFAULT_MAX_PIXELS
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_scenarios_are_errors() {
+        for source in &[
+            "",
+            "press 1 D",
+            "capture",
+            "capture -1",
+            "capture 1\nstart Grey 1 1",
+            "capture 1\nstart Blue 1",
+            "capture 1\npress 0 D",
+            "capture 1\npress 1 d",
+            "capture 1\nhold 1 D",
+            "capture 1\nmax_pixels 0.5",
+            "capture 1\nsrgb on",
+        ] {
+            assert!(Scenario::parse(source).is_err(), "{:?} parsed", source);
+        }
+        assert_eq!(
+            format!(
+                "{:#}",
+                Scenario::parse("capture 1\npress 2 Ctrl").unwrap_err()
+            ),
+            "Line 2: 'press 2 Ctrl': No key named Ctrl"
+        );
+    }
+
+    #[test]
+    fn shipped_scenarios_parse() {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(VISUAL_TESTS_DIR).unwrap() {
+            let path = entry.unwrap().path();
+            if path
+                .extension()
+                .map_or(false, |ext| ext == SCENARIO_EXTENSION)
+            {
+                let name = path.file_stem().unwrap().to_str().unwrap().to_owned();
+                Scenario::load(&name).unwrap();
+                names.push(name);
+            }
+        }
+        names.sort();
+        assert_eq!(names, ["idle", "running", "srgb", "transition"]);
+    }
+
+    #[test]
+    fn pixel_deltas_follow_the_eye() {
+        let gray = |v| Rgba([v, v, v, 255]);
+        assert_eq!(pixel_delta(gray(40), gray(40)), 0.);
+        assert!(pixel_delta(gray(0), gray(255)) > 0.95);
+        assert!(pixel_delta(Rgba([255, 0, 0, 255]), Rgba([0, 255, 255, 255])) <= 1.);
+        // alpha doesn't count
+        assert_eq!(pixel_delta(gray(40), Rgba([40, 40, 40, 0])), 0.);
+        // the eye is more sensitive to green than to blue
+        let base = Rgba([100, 100, 100, 255]);
+        let greener = Rgba([100, 130, 100, 255]);
+        let bluer = Rgba([100, 100, 130, 255]);
+        assert!(pixel_delta(base, greener) > pixel_delta(base, bluer));
+        // one step of rounding is well under the default threshold
+        assert!(pixel_delta(base, Rgba([101, 101, 100, 255])) < DEFAULT_THRESHOLD / 10.);
+    }
+
+    #[test]
+    fn comparisons_count_and_draw_differing_pixels() {
+        let reference = RgbaImage::from_pixel(4, 3, Rgba([0, 0, 0, 255]));
+        let mut actual = reference.clone();
+        // too small a change to see, and two that aren't
+        actual.put_pixel(0, 0, Rgba([1, 1, 1, 255]));
+        actual.put_pixel(1, 2, Rgba([255, 255, 255, 255]));
+        actual.put_pixel(3, 1, Rgba([0, 200, 0, 255]));
+
+        let comparison = compare(&reference, &actual, DEFAULT_THRESHOLD).unwrap();
+        assert_eq!(comparison.differing, 2);
+        let faded = Rgba([230, 230, 230, 255]);
+        for (x, y, pixel) in comparison.diff.enumerate_pixels() {
+            let expected = if (x, y) == (1, 2) || (x, y) == (3, 1) {
+                DIFF_COLOR
+            } else {
+                faded
+            };
+            assert_eq!(*pixel, expected, "at {}, {}", x, y);
+        }
+
+        // a looser threshold lets the green through
+        assert_eq!(compare(&reference, &actual, 0.9).unwrap().differing, 1);
+        assert!(compare(&reference, &RgbaImage::new(3, 4), DEFAULT_THRESHOLD).is_err());
+    }
+
+    #[test]
+    fn side_by_side_puts_the_first_image_on_the_left() {
+        let left = RgbaImage::from_pixel(2, 3, Rgba([255, 0, 0, 255]));
+        let right = RgbaImage::from_pixel(3, 2, Rgba([0, 0, 255, 255]));
+        let image = side_by_side(&left, &right);
+        assert_eq!(image.dimensions(), (5, 3));
+        assert_eq!(*image.get_pixel(1, 2), Rgba([255, 0, 0, 255]));
+        assert_eq!(*image.get_pixel(2, 0), Rgba([0, 0, 255, 255]));
+        assert_eq!(*image.get_pixel(4, 1), Rgba([0, 0, 255, 255]));
+        // below the shorter one
+        assert_eq!(*image.get_pixel(4, 2), Rgba([0, 0, 0, 0]));
+    }
+
+    // The real thing, where there is a GL context to run it on
+    #[test]
+    fn shipped_scenarios_match_their_references() {
+        let mut headless = match platform::headless_gl_for_test(SCREEN_SIZE.0, SCREEN_SIZE.1) {
+            Some(headless) => headless,
+            None => return,
+        };
+        let out_dir = env::temp_dir().join(format!("ld48-visual-tests-{}", std::process::id()));
+        for name in &["idle", "running", "srgb", "transition"] {
+            assert!(
+                run(&mut headless.context, name, &out_dir, false).unwrap(),
+                "{} doesn't match, see {}",
+                name,
+                out_dir.display()
+            );
+        }
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
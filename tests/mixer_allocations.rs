@@ -0,0 +1,79 @@
+//! Checks that polling the mixer doesn't allocate, with a global allocator that counts. This is
+//! its own test binary without the test harness, so swapping the allocator leaves the unit tests
+//! alone and nothing else runs while it counts.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Just the mixer and what it uses. Without the harness their unit tests are left unused.
+#[path = "../src/constants.rs"]
+#[allow(dead_code, unused_imports)]
+mod constants;
+#[path = "../src/mixer.rs"]
+#[allow(dead_code, unused_imports)]
+mod mixer;
+#[path = "../src/spaces.rs"]
+#[allow(dead_code, unused_imports)]
+mod spaces;
+
+use mixer::{Mixer, SoundPosition};
+use spaces::RoomPoint;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let mixer = Mixer::default();
+    mixer.set_listener(
+        SoundPosition {
+            area: 0,
+            position: RoomPoint::new(0., 0.),
+        },
+        16.,
+    );
+    let clip = mixer
+        .load_ogg(include_bytes!("../assets/jump.ogg"))
+        .unwrap();
+    let short = clip.slice(0., 0.001);
+    let long = clip.slice(0., 0.05);
+    // quiet enough that all 16 together don't overflow the samples
+    for i in 0..16 {
+        if i % 2 == 0 {
+            mixer.play(&short, 0.05, false);
+        } else {
+            let position = SoundPosition {
+                area: 0,
+                position: RoomPoint::new(i as f32, 0.),
+            };
+            mixer.play_at(&long, 0.05, true, position);
+        }
+    }
+
+    // 16 instances mixed into 1024 samples, with the short ones finishing and freeing their
+    // slots along the way
+    let mut out = vec![0; 1024];
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0..4 {
+        mixer.poll(&mut out);
+    }
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+    assert_eq!(allocations, 0, "polling allocated {} times", allocations);
+    println!("polling 16 instances made no allocations");
+}